@@ -0,0 +1,202 @@
+//! Benchmarks of the nadi commands on synthetic datasets
+//!
+//! The datasets of each size are generated in a temporary directory
+//! that is removed after the group; the library functions of the
+//! commands are called directly, after the inputs are read.
+use std::fmt::Write as _;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use clap::Parser;
+use criterion::{criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion};
+use polars::export::chrono::{Duration, NaiveDate};
+use tempfile::TempDir;
+
+use nadi::connection::nearest_locations;
+use nadi::network::{Network, TributaryOrder};
+use nadi::script::cumulate_expression;
+use nadi::timeseries::{self, Discharges};
+
+const SIZES: [usize; 3] = [100, 1_000, 10_000];
+
+type Coords = Vec<(f64, f64)>;
+
+/// Arguments of the timeseries command, for the functions that need them
+#[derive(Parser)]
+struct TsArgs {
+    #[command(flatten)]
+    args: timeseries::CliArgs,
+}
+
+/// Deterministic pseudo random numbers, so the datasets are the same
+/// for every run
+struct Lcg(u64);
+
+impl Lcg {
+    fn next(&mut self, max: usize) -> usize {
+        self.0 = self
+            .0
+            .wrapping_mul(6364136223846793005)
+            .wrapping_add(1442695040888963407);
+        ((self.0 >> 33) as usize) % max.max(1)
+    }
+}
+
+/// Output of each node of a random tree of `n` nodes, the node 0 is
+/// the outlet and the others flow into a node with a smaller index
+fn random_tree(n: usize) -> Vec<Option<usize>> {
+    let mut rng = Lcg(n as u64);
+    (0..n)
+        .map(|i| if i == 0 { None } else { Some(rng.next(i)) })
+        .collect()
+}
+
+/// Connections file and node attributes for a network of `n` nodes
+fn write_network(dir: &Path, n: usize) -> PathBuf {
+    let tree = random_tree(n);
+    let mut net = String::new();
+    fs::create_dir_all(dir.join("nodes")).unwrap();
+    for (i, out) in tree.iter().enumerate() {
+        if let Some(o) = out {
+            writeln!(net, "n{i} -> n{o}").unwrap();
+        }
+        fs::write(
+            dir.join("nodes").join(format!("n{i}.txt")),
+            format!("area={}\nlength={}\n", 10 + i % 90, 1 + i % 7),
+        )
+        .unwrap();
+    }
+    fs::write(dir.join("net.txt"), net).unwrap();
+    dir.join("net.txt")
+}
+
+/// Points and the stream end points to snap them to, for a random
+/// tree of `n` streams with a point near each confluence
+fn snapping_inputs(n: usize) -> (Coords, Coords) {
+    let tree = random_tree(n);
+    // the nodes are placed by their depth in the tree
+    let mut depth = vec![0usize; n];
+    for i in 1..n {
+        depth[i] = depth[tree[i].unwrap()] + 1;
+    }
+    let coord = |i: usize| (depth[i] as f64 * 100.0, i as f64 * 10.0);
+    let locations: Vec<(f64, f64)> = (0..n).map(coord).collect();
+    let points = locations.iter().map(|(x, y)| (x + 1.0, y + 1.0)).collect();
+    (points, locations)
+}
+
+/// Daily timeseries of `days` days starting from 1900-01-01
+fn write_timeseries(dir: &Path, days: usize) -> PathBuf {
+    let mut rng = Lcg(days as u64);
+    let mut csv = String::from("date,flow\n");
+    let start = NaiveDate::from_ymd_opt(1900, 1, 1).unwrap();
+    for d in 0..days {
+        let date = start + Duration::days(d as i64);
+        let flow = 100.0 + rng.next(10_000) as f64 / 100.0;
+        writeln!(csv, "{date},{flow:.2}").unwrap();
+    }
+    fs::write(dir.join("flow.csv"), csv).unwrap();
+    dir.join("flow.csv")
+}
+
+fn network_order(c: &mut Criterion) {
+    let mut group = c.benchmark_group("network_order");
+    group.sample_size(10);
+    for size in SIZES {
+        let dir = TempDir::new().unwrap();
+        let path = write_network(dir.path(), size);
+        group.bench_with_input(BenchmarkId::new("order", size), &path, |b, path| {
+            b.iter(|| Network::from_file(path).unwrap())
+        });
+        let net = Network::from_file(&path).unwrap();
+        group.bench_with_input(BenchmarkId::new("area", size), &net, |b, net| {
+            b.iter_batched(
+                || net.clone(),
+                |mut net| {
+                    let ranks = net.tributary_ranks(TributaryOrder::Area, "area", "length");
+                    net.reindex_by(&ranks);
+                    net
+                },
+                BatchSize::SmallInput,
+            )
+        });
+    }
+    group.finish();
+}
+
+fn network_cumulate(c: &mut Criterion) {
+    let mut group = c.benchmark_group("network_cumulate");
+    group.sample_size(10);
+    for size in SIZES {
+        let dir = TempDir::new().unwrap();
+        let net = Network::from_file(&write_network(dir.path(), size)).unwrap();
+        group.bench_with_input(BenchmarkId::new("template", size), &net, |b, net| {
+            b.iter_batched(
+                || net.clone(),
+                |mut net| {
+                    net.cumulate(vec!["++area"]).unwrap();
+                    net
+                },
+                BatchSize::SmallInput,
+            )
+        });
+        group.bench_with_input(BenchmarkId::new("expression", size), &net, |b, net| {
+            b.iter_batched(
+                || net.clone(),
+                |mut net| {
+                    cumulate_expression(&mut net, "cum_area = sum(inputs.cum_area) + area")
+                        .unwrap();
+                    net
+                },
+                BatchSize::SmallInput,
+            )
+        });
+    }
+    group.finish();
+}
+
+fn connection_snapping(c: &mut Criterion) {
+    let mut group = c.benchmark_group("connection_snapping");
+    group.sample_size(10);
+    for size in SIZES {
+        let inputs = snapping_inputs(size);
+        group.bench_with_input(
+            BenchmarkId::new("nearest", size),
+            &inputs,
+            |b, (points, locations)| b.iter(|| nearest_locations(points, locations, || ())),
+        );
+    }
+    group.finish();
+}
+
+fn timeseries_monthly(c: &mut Criterion) {
+    let mut group = c.benchmark_group("timeseries_monthly");
+    group.sample_size(10);
+    // 10, 100 and 1000 years of daily values
+    for days in SIZES.map(|s| s * 36 + s / 2) {
+        let dir = TempDir::new().unwrap();
+        let path = write_timeseries(dir.path(), days);
+        let args = TsArgs::parse_from(["timeseries", "flow.csv"]).args;
+        let ts = Discharges::new(&path, "date", "flow", None).unwrap();
+        group.bench_with_input(BenchmarkId::new("agg-monthly", days), &ts, |b, ts| {
+            b.iter(|| timeseries::monthly_mean(ts, &args, &mut std::io::sink()).unwrap())
+        });
+        group.bench_with_input(
+            BenchmarkId::new("monthly-seasonality", days),
+            &ts,
+            |b, ts| {
+                b.iter(|| timeseries::monthly_seasonality(ts, &args, &mut std::io::sink()).unwrap())
+            },
+        );
+    }
+    group.finish();
+}
+
+criterion_group!(
+    benches,
+    network_order,
+    network_cumulate,
+    connection_snapping,
+    timeseries_monthly
+);
+criterion_main!(benches);
@@ -1,5 +1,106 @@
-use anyhow::Result;
+use std::io::IsTerminal;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use clap::{Args, ValueEnum, ValueHint};
+
+use crate::connection::parse_new_layer;
+use crate::drivers;
+use crate::journal;
+use crate::timeseries::{create_output, Output};
 
 pub trait CliAction {
-    fn run(self) -> Result<()>;
+    fn run(self, global: &GlobalArgs) -> Result<()>;
+}
+
+/// Options common to all the commands, they can be given before or
+/// after the command name
+#[derive(Args)]
+#[command(next_help_heading = "Global Options")]
+pub struct GlobalArgs {
+    /// Don't print the warnings to stderr
+    #[arg(short, long, global = true)]
+    pub quiet: bool,
+    /// Print the progress of the command
+    #[arg(short, long, global = true)]
+    pub verbose: bool,
+    /// Output file [default: stdout]
+    #[arg(short, long, global = true, value_hint=ValueHint::FilePath)]
+    pub output: Option<PathBuf>,
+    /// Format of the table outputs [default: csv]
+    #[arg(long, global = true, rename_all = "lower", value_enum)]
+    pub format: Option<OutputFormat>,
+    /// Number of threads [default: all cores]
+    #[arg(
+        short,
+        long,
+        global = true,
+        visible_alias = "threads",
+        default_value = "0"
+    )]
+    pub jobs: usize,
+    /// Write the command, inputs, outputs and warnings of the run as json
+    #[arg(long, global = true, value_hint=ValueHint::FilePath)]
+    pub journal: Option<PathBuf>,
+    /// GDAL configuration option, e.g. OGR_GEOJSON_MAX_OBJ_SIZE=0
+    #[arg(long, global = true, value_name = "KEY=VALUE", value_parser=drivers::parse_config)]
+    pub gdal_config: Vec<(String, String)>,
+}
+
+#[derive(Copy, Clone, Default, PartialEq, Eq, ValueEnum)]
+pub enum OutputFormat {
+    #[default]
+    Csv,
+    /// JSON array of the rows
+    Json,
+    /// One JSON object per row (line delimited JSON)
+    Ndjson,
+}
+
+impl GlobalArgs {
+    /// Output file for the commands that can't write to stdout
+    pub fn output(&self) -> Result<&Path> {
+        self.output
+            .as_deref()
+            .context("Output file is required for this command, use --output")
+    }
+
+    /// Output GIS file with the optional layer name (FILE:LAYER)
+    pub fn output_layer(&self) -> Result<Option<(PathBuf, Option<String>)>> {
+        self.output
+            .as_ref()
+            .map(|p| parse_new_layer(&p.to_string_lossy()))
+            .transpose()
+    }
+
+    /// Writer to the output file (compressed if it ends with .gz or
+    /// .zst), or stdout if not given; call [`Output::finish`] after
+    /// writing
+    pub fn writer(&self) -> Result<Output> {
+        Ok(match &self.output {
+            Some(path) => {
+                journal::output(path);
+                create_output(path)?
+            }
+            None => Output::Stdout(std::io::stdout()),
+        })
+    }
+
+    /// Whether the output goes to a terminal, for the colors
+    pub fn is_terminal(&self) -> bool {
+        self.output.is_none() && std::io::stdout().is_terminal()
+    }
+
+    /// Format of the output if the command supports it, the first
+    /// one is the default
+    pub fn format(&self, supported: &[OutputFormat]) -> Result<OutputFormat> {
+        match self.format {
+            None => Ok(supported[0]),
+            Some(f) if supported.contains(&f) => Ok(f),
+            Some(f) => anyhow::bail!(
+                "Format {} isn't supported by this command",
+                f.to_possible_value().unwrap().get_name()
+            ),
+        }
+    }
 }
@@ -0,0 +1,185 @@
+use std::io::Write;
+use std::path::PathBuf;
+
+use clap::Args;
+use polars::export::chrono::Datelike;
+
+use crate::cliargs::{CliAction, GlobalArgs};
+use crate::journal;
+use crate::locale::LocaleArgs;
+use crate::network::html_escape;
+use crate::theme::Theme;
+use crate::timeseries::{Discharges, NodeSeries};
+
+const MONTHS: [&str; 12] = ["J", "F", "M", "A", "M", "J", "J", "A", "S", "O", "N", "D"];
+const PANEL_WIDTH: f64 = 180.0;
+const PANEL_HEIGHT: f64 = 120.0;
+
+/// Value rounded to two decimals for the labels
+fn round(value: f64) -> f64 {
+    (value * 100.0).round() / 100.0
+}
+
+#[derive(Args)]
+pub struct CliArgs {
+    #[command(flatten)]
+    series: NodeSeries,
+    /// Number of charts in a row of the grid
+    #[arg(short, long, default_value = "4", value_parser=clap::value_parser!(u16).range(1..))]
+    columns: u16,
+    /// Use the same flow axis for all the nodes
+    ///
+    /// By default each chart is scaled to its own maximum, which shows
+    /// the shape of the regime instead of the size of the flows
+    #[arg(short, long)]
+    shared_scale: bool,
+    /// Theme for the colors and font of the figure
+    #[arg(long, default_value = "default", value_parser=Theme::parse)]
+    theme: Theme,
+    #[command(flatten)]
+    locale: LocaleArgs,
+    /// Connection file of the network
+    network: PathBuf,
+}
+
+/// Mean of the daily values in each calendar month, None for the
+/// months without values
+fn monthly_means(ts: &Discharges) -> anyhow::Result<[Option<f64>; 12]> {
+    let mut sums = [(0.0, 0usize); 12];
+    for (date, value) in ts.values()? {
+        let month = &mut sums[date.month0() as usize];
+        month.0 += value;
+        month.1 += 1;
+    }
+    Ok(sums.map(|(sum, n)| (n > 0).then_some(sum / n as f64)))
+}
+
+/// Range of the bars, including the zero baseline
+fn value_range(means: &[Option<f64>; 12]) -> (f64, f64) {
+    means
+        .iter()
+        .flatten()
+        .fold((0.0, 0.0), |(min, max), v| (v.min(min), v.max(max)))
+}
+
+impl CliArgs {
+    /// Bar chart of the monthly means of a node at the given position,
+    /// the bars are drawn from zero and scaled to the `(min, max)` range
+    fn panel(
+        &self,
+        name: &str,
+        means: &[Option<f64>; 12],
+        (min, max): (f64, f64),
+        x0: f64,
+        y0: f64,
+    ) -> String {
+        let (pad, top, bottom) = (8.0, 30.0, 18.0);
+        let bar_width = (PANEL_WIDTH - 2.0 * pad) / 12.0;
+        let height = PANEL_HEIGHT - top - bottom;
+        let range = if max > min { max - min } else { 1.0 };
+        let y = |v: f64| top + height * (max - v) / range;
+        let color = self.theme.line_color(0);
+        let mut svg = format!(
+            "<g transform=\"translate({x0},{y0})\"><title>{0}</title><text x=\"{pad}\" y=\"14\" font-weight=\"bold\">{0}</text><text x=\"{1}\" y=\"26\" font-size=\"9\" text-anchor=\"end\">{2}</text>",
+            html_escape(name),
+            PANEL_WIDTH - pad,
+            self.locale.number(round(max)),
+        );
+        if min < 0.0 {
+            svg.push_str(&format!(
+                "<text x=\"{0}\" y=\"{1}\" font-size=\"9\" text-anchor=\"end\">{2}</text>",
+                PANEL_WIDTH - pad,
+                top + height + 9.0,
+                self.locale.number(round(min)),
+            ));
+        }
+        for (m, mean) in means.iter().enumerate() {
+            let x = pad + bar_width * m as f64;
+            if let Some(v) = mean {
+                svg.push_str(&format!(
+                    "<rect x=\"{:.1}\" y=\"{:.1}\" width=\"{:.1}\" height=\"{:.1}\" fill=\"{color}\"><title>{}: {}</title></rect>",
+                    x + 1.0,
+                    y(v.max(0.0)),
+                    bar_width - 2.0,
+                    (y(0.0) - y(*v)).abs(),
+                    m + 1,
+                    self.locale.number(round(*v)),
+                ));
+            }
+            svg.push_str(&format!(
+                "<text x=\"{:.1}\" y=\"{}\" font-size=\"9\" text-anchor=\"middle\">{}</text>",
+                x + bar_width / 2.0,
+                PANEL_HEIGHT - 5.0,
+                MONTHS[m]
+            ));
+        }
+        svg.push_str(&format!(
+            "<line x1=\"{pad}\" y1=\"{0:.1}\" x2=\"{1}\" y2=\"{0:.1}\" stroke=\"{2}\"/></g>",
+            y(0.0),
+            PANEL_WIDTH - pad,
+            self.theme.foreground.as_deref().unwrap_or("black"),
+        ));
+        svg
+    }
+}
+
+impl CliAction for CliArgs {
+    fn run(self, global: &GlobalArgs) -> anyhow::Result<()> {
+        if global.format.is_some() {
+            anyhow::bail!("--format can't be used, the output is an svg figure");
+        }
+        let net = self.series.network(&self.network)?;
+        let mut nodes = Vec::new();
+        for (node, _, ts) in self.series.nodes(&net) {
+            let means = match monthly_means(&ts) {
+                Ok(m) => m,
+                Err(e) => {
+                    journal::warn(format!("Skipping {}: {e:#}", node.get_name()));
+                    continue;
+                }
+            };
+            if means.iter().all(|m| m.is_none()) {
+                journal::warn(format!(
+                    "No values in the timeseries of {}",
+                    node.get_name()
+                ));
+                continue;
+            }
+            nodes.push((node.get_name(), means));
+        }
+        if nodes.is_empty() {
+            anyhow::bail!("No timeseries files found for the nodes");
+        }
+        journal::count("nodes", nodes.len());
+
+        let shared_range = nodes
+            .iter()
+            .map(|(_, m)| value_range(m))
+            .fold((0.0, 0.0), |a: (f64, f64), b| (a.0.min(b.0), a.1.max(b.1)));
+        let columns = (self.columns as usize).min(nodes.len());
+        let rows = nodes.len().div_ceil(columns);
+        let mut svg = self
+            .theme
+            .svg_start(PANEL_WIDTH * columns as f64, PANEL_HEIGHT * rows as f64);
+        for (i, (name, means)) in nodes.iter().enumerate() {
+            let range = if self.shared_scale {
+                shared_range
+            } else {
+                value_range(means)
+            };
+            svg.push_str(&self.panel(
+                name,
+                means,
+                range,
+                PANEL_WIDTH * (i % columns) as f64,
+                PANEL_HEIGHT * (i / columns) as f64,
+            ));
+        }
+        svg.push_str("</svg>");
+
+        let mut writer = global.writer()?;
+        writeln!(writer, "{svg}")?;
+        writer.finish()?;
+        Ok(())
+    }
+}
@@ -1,15 +1,32 @@
 use std::collections::hash_map::Entry;
 use std::collections::HashMap;
 use std::fmt;
-use std::path::PathBuf;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::SystemTime;
 
 use anyhow::Context;
-use clap::Args;
+use clap::{Args, ValueEnum, ValueHint};
+use gdal::raster::RasterBand;
+use gdal::spatial_ref::SpatialRef;
 use gdal::vector::{FieldValue, Geometry, Layer, LayerAccess, OGRFieldType};
 use gdal::{Dataset, Driver, DriverManager, GdalOpenFlags, LayerOptions, Metadata};
+use memmap2::Mmap;
 use ordered_float::NotNan;
+use rayon::prelude::*;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 
-use crate::cliargs::CliAction;
+use crate::cliargs::{CliAction, GlobalArgs};
+use crate::drivers;
+use crate::errors::NadiError;
+use crate::gis::spatial_ref;
+use crate::journal;
+use crate::list::{open_layer, parse_layer};
+use crate::network::read_csv;
 
 #[derive(Args)]
 pub struct CliArgs {
@@ -19,33 +36,130 @@ pub struct CliArgs {
     /// Fields to use as id for Points file
     #[arg(short, long)]
     points_field: Option<String>,
-    /// Fields to use as id for Streams vector file
-    #[arg(short, long)]
+    /// Field with the names of the streams, saved in the stream field
+    /// of the output streams
+    #[arg(short, long, conflicts_with_all = ["connections_only", "flow_dir"])]
     streams_field: Option<String>,
     /// Output driver [default: based on file extension]
     #[arg(short, long)]
     driver: Option<String>,
-    /// Output file
-    #[arg(short, long, value_parser=parse_new_layer)]
-    output: Option<(PathBuf, Option<String>)>,
     /// Connections only on the output file instead of whole streams
     #[arg(short, long)]
     connections_only: bool,
-    /// Print progress
+    /// Field with the upstream node id of the streams (e.g. FromNode)
+    ///
+    /// With --to-field, the connections are made from the attribute
+    /// table instead of matching the end points of the streams; for
+    /// datasets like NHDPlus use FromNode/ToNode or COMID/ToCOMID
+    #[arg(short = 'F', long, requires = "to_field")]
+    from_field: Option<String>,
+    /// Field with the downstream node id of the streams (e.g. ToNode)
+    #[arg(short = 'T', long, requires = "from_field")]
+    to_field: Option<String>,
+    /// Distance within which the stream end points are merged
+    ///
+    /// Useful for streams that don't exactly touch each other at the
+    /// end points, 0 means the end points need to match exactly; the
+    /// --from-field/--to-field connections don't use the end points
+    #[arg(short, long, default_value = "0", conflicts_with = "from_field")]
+    tolerance: f64,
+    /// Where to snap the points on the streams network
+    ///
+    /// nearest-segment snaps to the closest vertex of any stream,
+    /// nearest-node to the closest stream end points, and confluence
+    /// to the closest junction of two or more streams
+    #[arg(short = 'S', long, value_enum, default_value = "nearest-segment")]
+    snap_to: SnapTo,
+    /// Report the disconnected components of the streams network
     #[arg(short, long)]
-    verbose: bool,
+    report_components: bool,
+    /// Directory to save the progress of the run in, to --resume it
+    ///
+    /// The streams network, the snapped points and the traced
+    /// connections (every few thousand points) are saved as json
+    /// files; the files are kept after the run
+    #[arg(long, value_hint=ValueHint::DirPath, conflicts_with = "flow_dir")]
+    checkpoint_dir: Option<PathBuf>,
+    /// Resume the run from the files in --checkpoint-dir
+    ///
+    /// The files are only used if they were made with the same input
+    /// files and options, otherwise it is an error
+    #[arg(long, requires = "checkpoint_dir")]
+    resume: bool,
+    /// Directory to cache the streams network index in
+    ///
+    /// The end points and connections of the streams are saved in a
+    /// file named by the hash of the streams file and the options that
+    /// change them, later runs with the same streams skip reading them
+    #[arg(long, value_hint=ValueHint::DirPath, conflicts_with = "flow_dir")]
+    index_cache: Option<PathBuf>,
     /// Nodes file, if provided save the nodes of the graph as points with nodeid
     #[arg(short, long, value_parser=parse_new_layer)]
     nodes: Option<(PathBuf, Option<String>)>,
+    /// Csv file to save the attributes of the connections
+    ///
+    /// The reach length along the streams, and for streams with Z
+    /// values the elevations at the start and end nodes and the slope;
+    /// the file can be loaded with `network --edges-csv`
+    #[arg(long, value_hint=ValueHint::FilePath, value_name = "CSV_FILE", conflicts_with = "flow_dir")]
+    edges_csv: Option<PathBuf>,
+    /// Flow direction raster (D8) to trace instead of the streams file
+    ///
+    /// Uses the ESRI convention for the direction codes (1=E, 2=SE,
+    /// 4=S, ..., 128=NE), the flow paths are saved as the streams in
+    /// the output file.
+    #[arg(short, long, conflicts_with = "streams", value_name = "FLOWDIR_RASTER")]
+    flow_dir: Option<PathBuf>,
+    /// Read POINTS_FILE as a csv with the coordinates of the points
+    ///
+    /// The coordinates are read from the --x-field and --y-field columns,
+    /// and the ids from the --points-field column (row number if not
+    /// given); the spatial reference has to be given with --points-srs
+    #[arg(short = 'P', long, requires = "points_srs")]
+    points_csv: bool,
+    /// Spatial reference of the --points-csv coordinates, e.g. EPSG:4326
+    #[arg(long, value_name = "SRS", requires = "points_csv")]
+    points_srs: Option<String>,
+    /// Fields with the x coordinate of the points without geometry
+    ///
+    /// The first of the names present in the points layer is used,
+    /// ignoring the case
+    #[arg(
+        short = 'X',
+        long,
+        visible_alias = "x-col",
+        value_delimiter = ',',
+        default_value = "lon,longitude,long,x,dec_long_va"
+    )]
+    x_field: Vec<String>,
+    /// Fields with the y coordinate of the points without geometry
+    #[arg(
+        short = 'Y',
+        long,
+        visible_alias = "y-col",
+        value_delimiter = ',',
+        default_value = "lat,latitude,y,dec_lat_va"
+    )]
+    y_field: Vec<String>,
     /// Points file with points of interest
     #[arg(value_parser=parse_layer, value_name="POINTS_FILE[:LAYER]")]
-    points: (PathBuf, String),
+    points: (PathBuf, Option<String>),
     /// Streams vector file with streams network
-    #[arg(value_parser=parse_layer, value_name="STREAMS_FILE[:LAYER]")]
-    streams: (PathBuf, String),
+    #[arg(value_parser=parse_layer, value_name="STREAMS_FILE[:LAYER]", required_unless_present = "flow_dir")]
+    streams: Option<(PathBuf, Option<String>)>,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, ValueEnum)]
+pub enum SnapTo {
+    #[value(alias = "s")]
+    NearestSegment,
+    #[value(alias = "n")]
+    NearestNode,
+    #[value(alias = "c")]
+    Confluence,
 }
 
-fn parse_new_layer(arg: &str) -> Result<(PathBuf, Option<String>), anyhow::Error> {
+pub fn parse_new_layer(arg: &str) -> Result<(PathBuf, Option<String>), anyhow::Error> {
     if let Some((path, layer)) = arg.split_once(':') {
         Ok((PathBuf::from(path), Some(layer.to_string())))
     } else {
@@ -53,47 +167,76 @@ fn parse_new_layer(arg: &str) -> Result<(PathBuf, Option<String>), anyhow::Error
     }
 }
 
-fn parse_layer(arg: &str) -> Result<(PathBuf, String), anyhow::Error> {
-    if let Some((path, layer)) = arg.split_once(':') {
-        let data = Dataset::open(path)?;
-        if data.layer_by_name(layer).is_err() {
-            Err(std::io::Error::new(
-                std::io::ErrorKind::NotFound,
-                format!("Layer name {layer} doesn't exist in the file {path}"),
-            )
-            .into())
-        } else {
-            Ok((PathBuf::from(path), layer.to_string()))
-        }
-    } else {
-        let data = Dataset::open(arg)?;
-        if data.layer_count() == 1 {
-            let layer = data.layer(0)?;
-            Ok((PathBuf::from(&arg), layer.name()))
+impl CliAction for CliArgs {
+    fn run(self, global: &GlobalArgs) -> Result<(), anyhow::Error> {
+        let points_file = &self.points;
+        let points_path = &points_file.0;
+        journal::input(points_path);
+        let (points, points_srs) = if self.points_csv {
+            if points_file.1.is_some() {
+                anyhow::bail!("The csv file of --points-csv doesn't have layers");
+            }
+            let srs = self
+                .points_srs
+                .as_deref()
+                .context("--points-csv needs the spatial reference in --points-srs")?;
+            let points = read_points_csv(
+                points_path,
+                &self.points_field,
+                &self.x_field,
+                &self.y_field,
+            )?;
+            (points, Some(spatial_ref(srs)?))
         } else {
-            eprintln!("Provide a layer name to choose layer \"FILENAME:LAYERNAME\"");
-            eprintln!("Available Layers:");
-            data.layers().for_each(|l| eprintln!("  {}", l.name()));
-            let layer = data.layer(0)?;
-            Ok((PathBuf::from(&arg), layer.name()))
+            let points_data = Dataset::open(points_path)?;
+            let mut points_lyr = open_layer(&points_data, points_file)?;
+            let points = get_geometries(
+                &mut points_lyr,
+                &self.points_field,
+                &self.x_field,
+                &self.y_field,
+            )?;
+            (points, points_lyr.spatial_ref())
+        };
+        journal::count("points", points.len());
+        if points.is_empty() {
+            anyhow::bail!(
+                "No points in {:?}, check the file or the layer name (FILE:LAYER)",
+                points_path
+            );
         }
-    }
-}
 
-impl CliAction for CliArgs {
-    fn run(self) -> Result<(), anyhow::Error> {
-        let points_data = Dataset::open(&self.points.0).unwrap();
-        let points = points_data.layer_by_name(&self.points.1).unwrap();
-
-        let streams_data = Dataset::open(&self.streams.0).unwrap();
-        let streams = streams_data.layer_by_name(&self.streams.1).unwrap();
+        if let Some(flow_dir) = &self.flow_dir {
+            print_header(global, &[points_path, flow_dir]);
+            return self.print_raster_connections(points, &points_srs, flow_dir, global);
+        }
 
-        if self.ignore_spatial_reference
-            || check_spatial_ref_system_compatibility(&points, &streams).is_ok()
+        let streams_file = self.streams.as_ref().context("Streams file is required")?;
+        let streams_data = Dataset::open(&streams_file.0)?;
+        journal::input(&streams_file.0);
+        let streams = open_layer(&streams_data, streams_file)?;
+        if streams.feature_count() == 0 {
+            anyhow::bail!(
+                "No streams in the layer {} of {:?}, check the layer name (FILE:LAYER)",
+                streams.name(),
+                streams_file.0
+            );
+        }
         // TODO streams is line GIS layer
+        if !self.ignore_spatial_reference
+            && check_spatial_ref_system_compatibility(&points_srs, &streams.spatial_ref()).is_err()
         {
-            self.print_connections(points, streams, &self.output)?;
+            anyhow::bail!(
+                "Points and streams have different spatial references, reproject one of them or use --ignore-spatial-reference"
+            );
         }
+        print_header(global, &[points_path, &streams_file.0]);
+
+        let index_cache = match &self.index_cache {
+            Some(dir) => Some(self.index_cache_file(dir, &streams_file.0, &streams.name())?),
+            None => None,
+        };
+        self.print_connections(points, streams, index_cache.as_deref(), global)?;
 
         Ok(())
     }
@@ -102,87 +245,110 @@ impl CliAction for CliArgs {
 impl CliArgs {
     fn print_connections(
         &self,
-        mut points_lyr: Layer,
+        points: Vec<(String, Geometry)>,
         mut streams_lyr: Layer,
-        output: &Option<(PathBuf, Option<String>)>,
+        index_cache: Option<&Path>,
+        global: &GlobalArgs,
     ) -> Result<(), anyhow::Error> {
-        let points = get_geometries(&mut points_lyr, &self.points_field)?;
-        let streams = get_geometries(&mut streams_lyr, &self.streams_field)?;
-        if points.is_empty() || streams.is_empty() {
-            return Ok(());
-        }
-
-        // node: point to node number
-        let nodes_count = streams_lyr.feature_count() as usize + 1;
-        let points_count = points_lyr.feature_count() as usize;
-        let mut nodes: HashMap<Point2D, usize> = HashMap::with_capacity(nodes_count);
-        // node number to geometry index in streams file
-        let mut streams_geo_location: HashMap<(usize, usize), usize> =
-            HashMap::with_capacity(nodes_count);
-        // geometries of the streams
-        let mut streams_touched: HashMap<(usize, usize), Geometry> =
-            HashMap::with_capacity(nodes_count);
-        // edge: node to another node at the end
-        let mut edges: HashMap<usize, usize> = HashMap::with_capacity(points_count);
-        let mut branches: HashMap<usize, usize> = HashMap::with_capacity(points_count);
-        let mut all_pts: HashMap<Point2D, (usize, usize)> = HashMap::new();
-
-        let mut progress: usize = 0;
-        let total = streams.len();
-        for (i, (_name, geom)) in streams.iter().enumerate() {
-            let start = Point2D::new(geom.get_point(0));
-            let end = Point2D::new(geom.get_point((geom.point_count() - 1) as i32));
-            let l = nodes.len();
-            let start_ind = *nodes.entry(start.clone()).or_insert(l);
-            let l = nodes.len();
-            let end_ind = *nodes.entry(end.clone()).or_insert(l);
-            streams_geo_location.insert((start_ind, end_ind), i);
-            if let Entry::Vacant(e) = edges.entry(start_ind) {
-                e.insert(end_ind);
-            } else {
-                branches.insert(start_ind, end_ind);
-            }
-
-            geom.get_point_vec().iter().for_each(|p| {
-                all_pts
-                    .entry(Point2D::new(*p))
-                    .or_insert((start_ind, end_ind));
-            });
-
-            if self.verbose {
-                progress += 1;
-                println!("Reading Streams: {}", progress * 100 / total);
+        journal::count("streams", streams_lyr.feature_count() as usize);
+        let checkpoint = match &self.checkpoint_dir {
+            Some(dir) => Some(Checkpoint::new(
+                dir,
+                self.checkpoint_key(&points, &streams_lyr),
+                self.resume,
+            )?),
+            None => None,
+        };
+        let graph = match checkpoint.as_ref().map(|c| c.load("streams")).transpose()? {
+            Some(Some(graph)) => graph,
+            _ => {
+                let graph = match index_cache.and_then(load_index) {
+                    Some(graph) => graph,
+                    None => {
+                        let graph = self.read_streams(&mut streams_lyr, global.verbose)?;
+                        if let Some(path) = index_cache {
+                            save_index(path, &graph)?;
+                        }
+                        graph
+                    }
+                };
+                if let Some(c) = &checkpoint {
+                    c.save("streams", &graph)?;
+                }
+                graph
             }
+        };
+        let StreamsGraph {
+            nodes,
+            streams_geo_location,
+            streams_fids,
+            streams_length,
+            nodes_z,
+            edges,
+            branches,
+            candidates,
+        } = graph;
+        let mut streams_touched: HashMap<(usize, usize), usize> =
+            HashMap::with_capacity(nodes.len());
+        if candidates.is_empty() {
+            journal::warn("No locations found to snap the points to".to_string());
+            return Ok(());
         }
 
-        let mut points_closest: HashMap<&str, (usize, usize)> = points
+        // the drivers are registered before the threads use them
+        drivers::register();
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(global.jobs)
+            .build()?;
+        let verbose = global.verbose;
+        let progress = AtomicUsize::new(0);
+        let total = points.len();
+        let coords: Vec<(f64, f64)> = points
             .iter()
-            .map(|(k, _)| (k.as_str(), (0usize, 0usize)))
+            .map(|(_, p)| {
+                let (x, y, _) = p.get_point(0);
+                (x, y)
+            })
             .collect();
-        let mut progress: usize = 0;
-        let total = points.len();
-        for (k, p) in points.iter() {
-            let (x, y, _) = p.get_point(0);
-            let (mut min_pt, mut min_dist) = ((0usize, 0usize), f64::INFINITY);
-            for (np, ni) in all_pts.iter() {
-                let (sx, sy, _) = np.coord();
-                let dist = (sx - x).powi(2) + (sy - y).powi(2);
-                if dist < min_dist {
-                    min_dist = dist;
-                    min_pt = *ni;
+        let closest: Vec<(usize, usize)> =
+            match checkpoint.as_ref().map(|c| c.load("snapped")).transpose()? {
+                Some(Some(closest)) => closest,
+                _ => {
+                    let locations: Vec<(f64, f64)> = candidates
+                        .iter()
+                        .map(|(np, _)| {
+                            let (x, y, _) = np.coord();
+                            (x, y)
+                        })
+                        .collect();
+                    let closest = pool
+                        .install(|| {
+                            nearest_locations(&coords, &locations, || {
+                                if verbose {
+                                    let p = progress.fetch_add(1, Ordering::Relaxed) + 1;
+                                    println!("Snapping Points: {}", p * 100 / total);
+                                }
+                            })
+                        })
+                        .into_iter()
+                        .map(|i| candidates[i].1)
+                        .collect();
+                    if let Some(c) = &checkpoint {
+                        c.save("snapped", &closest)?;
+                    }
+                    closest
                 }
-            }
-            points_closest.insert(k.as_str(), min_pt);
-            if self.verbose {
-                progress += 1;
-                println!("Snapping Points: {}", progress * 100 / total);
-            }
-        }
+            };
+        let points_closest: HashMap<&str, (usize, usize)> = points
+            .iter()
+            .zip(closest)
+            .map(|((k, _), c)| (k.as_str(), c))
+            .collect();
 
-        for (_, (start, end)) in &points_closest {
-            let edge = (*start, *end);
-            let i = streams_geo_location[&edge];
-            streams_touched.insert(edge, streams[i].1.clone());
+        for edge in points_closest.values() {
+            if let Some(&i) = streams_geo_location.get(edge) {
+                streams_touched.insert(*edge, i);
+            }
         }
         if let Some((filename, lyr)) = &self.nodes {
             let driver = get_driver_by_filename(&filename, &self.driver)?;
@@ -197,121 +363,1024 @@ impl CliArgs {
             layer.create_defn_fields(&[("id", OGRFieldType::OFTInteger)])?;
             let fields = ["id"];
 
-            for (pt, id) in &nodes {
+            for (id, pt) in nodes.iter().enumerate() {
                 let mut edge_geometry = Geometry::empty(gdal_sys::OGRwkbGeometryType::wkbPoint)?;
                 edge_geometry.add_point(pt.coord());
                 layer.create_feature_fields(
                     edge_geometry,
                     &fields,
-                    &[FieldValue::IntegerValue(*id as i32)],
+                    &[FieldValue::IntegerValue(id as i32)],
                 )?;
             }
             // txn.commit()?;
         }
 
-        let points_nodes: HashMap<usize, &str> =
-            points_closest.iter().map(|(&k, (_, v))| (*v, k)).collect();
+        // the first point in the file keeps the node when multiple
+        // points snap to the same one
+        let mut points_nodes: HashMap<usize, &str> = HashMap::new();
+        for (k, _) in &points {
+            let (_, node) = points_closest[k.as_str()];
+            match points_nodes.entry(node) {
+                Entry::Vacant(e) => {
+                    e.insert(k);
+                }
+                Entry::Occupied(e) if *e.get() != k => journal::warn(format!(
+                    "Point {} snapped to the same node as {}, ignoring it",
+                    k,
+                    e.get()
+                )),
+                Entry::Occupied(_) => (),
+            }
+        }
         let mut points_edges: HashMap<usize, usize> = HashMap::new();
-        let nodes_rev: HashMap<usize, &Point2D> = nodes.iter().map(|(k, &v)| (v, k)).collect();
-
-        progress = 0;
-        let total = points_nodes.len();
-        for pt in points_nodes.keys() {
-            let mut outlet = *pt;
-            // eprint!("{}", pt);
-            let mut curr_branches: Vec<&usize> = Vec::new();
-            let mut final_outlet = None;
-            loop {
-                if let Some(&o) = edges.get(&outlet) {
-                    if let Some(bout) = branches.get(&outlet) {
-                        if let Some(&i) = streams_geo_location.get(&(outlet, *bout)) {
-                            streams_touched.insert((outlet, i), streams[i].1.clone());
+        let mut reach_attrs: Vec<String> = Vec::new();
+
+        if self.report_components {
+            report_components(&nodes, &edges, &branches, &points_nodes);
+        }
+
+        // the downstream walks are independent of each other, so
+        // they are traced in parallel and collected in order.
+        let mut starts: Vec<usize> = points_nodes.keys().copied().collect();
+        starts.sort();
+        let mut done = match &checkpoint {
+            Some(c) => c.load_traces()?,
+            None => HashMap::new(),
+        };
+        let todo: Vec<usize> = starts
+            .iter()
+            .copied()
+            .filter(|pt| !done.contains_key(pt))
+            .collect();
+        let mut trace_log = match &checkpoint {
+            Some(c) => Some(c.trace_log(&done)?),
+            None => None,
+        };
+        // traced in batches to save them to the checkpoint as they go
+        let batch = if trace_log.is_some() {
+            CHECKPOINT_BATCH
+        } else {
+            todo.len().max(1)
+        };
+        let progress = AtomicUsize::new(done.len());
+        let total = starts.len();
+        for pts in todo.chunks(batch) {
+            let traces: Vec<Trace> = pool.install(|| {
+                pts.par_iter()
+                    .map(|&pt| {
+                        let trace = trace_downstream(
+                            pt,
+                            &edges,
+                            &branches,
+                            &streams_geo_location,
+                            &points_nodes,
+                        );
+                        if verbose {
+                            let p = progress.fetch_add(1, Ordering::Relaxed) + 1;
+                            println!("Searching Connections: {}", p * 100 / total);
                         }
-                        curr_branches.push(bout);
-                    }
-                    if let Some(&i) = streams_geo_location.get(&(outlet, o)) {
-                        streams_touched.insert((outlet, i), streams[i].1.clone());
-                    }
-                    // eprint!(" -> {}", outlet);
-                    outlet = o;
-                    if points_nodes.contains_key(&o) {
-                        println!("{} -> {}", points_nodes[pt], points_nodes[&outlet]);
-                        points_edges.insert(*pt, outlet);
-                        final_outlet = Some(outlet);
-                        break;
-                    }
+                        trace
+                    })
+                    .collect()
+            });
+            for (&pt, trace) in pts.iter().zip(traces) {
+                if let Some(log) = &mut trace_log {
+                    serde_json::to_writer(&mut *log, &(pt, &trace))?;
+                    writeln!(log)?;
+                }
+                done.insert(pt, trace);
+            }
+            if let Some(log) = &mut trace_log {
+                log.flush()?;
+            }
+        }
+        for pt in &starts {
+            let trace = done.remove(pt).unwrap_or_default();
+            for (key, i) in trace.touched {
+                streams_touched.insert(key, i);
+            }
+            if let Some(outlet) = trace.outlet {
+                println!("{} -> {}", points_nodes[pt], points_nodes[&outlet]);
+                points_edges.insert(*pt, outlet);
+                let length: f64 = trace.path.iter().map(|&i| streams_length[i]).sum();
+                let (start, end) = (nodes_z.get(pt), nodes_z.get(&outlet));
+                let elev = |z: Option<&f64>| z.map(|z| z.to_string()).unwrap_or_default();
+                let slope = match (start, end) {
+                    (Some(s), Some(e)) if length > 0.0 => ((s - e) / length).to_string(),
+                    _ => String::new(),
+                };
+                reach_attrs.push(format!(
+                    "{},{},{},{},{},{}",
+                    points_nodes[pt],
+                    points_nodes[&outlet],
+                    length,
+                    elev(start),
+                    elev(end),
+                    slope
+                ));
+            }
+            if let Some(end) = trace.dead_end {
+                eprintln!("{} {} -> None {}", points_nodes[pt], nodes[*pt], nodes[end]);
+            }
+            for b in trace.branches {
+                journal::warn(format!(
+                    "Branch detected from node {} downstream of {}",
+                    b, points_nodes[pt]
+                ));
+            }
+        }
+
+        if let Some(filename) = &self.edges_csv {
+            journal::output(filename);
+            let mut file = BufWriter::new(File::create(filename)?);
+            writeln!(file, "from,to,length,start_elev,end_elev,slope")?;
+            for row in reach_attrs {
+                writeln!(file, "{row}")?;
+            }
+            file.flush()?;
+        }
+
+        if let Some(output) = &global.output_layer()? {
+            let mut stream_names: HashMap<(usize, usize), String> = HashMap::new();
+            let streams_touched: HashMap<(usize, usize), Geometry> = if self.connections_only {
+                HashMap::new()
+            } else {
+                streams_touched
+                    .into_iter()
+                    .filter_map(|(k, i)| {
+                        let (fid, part) = streams_fids[i];
+                        let feat = streams_lyr.feature(fid)?;
+                        if let Some(field) = &self.streams_field {
+                            if let Ok(Some(name)) = feat.field_as_string_by_name(field) {
+                                stream_names.insert(k, name);
+                            }
+                        }
+                        let geom = feat.geometry()?;
+                        let geom = match part {
+                            Some(p) => (*geom.get_geometry(p)).clone(),
+                            None => geom.clone(),
+                        };
+                        Some((k, geom))
+                    })
+                    .collect()
+            };
+            save_connections_file(
+                &self.driver,
+                output,
+                streams_lyr.spatial_ref().as_ref(),
+                &points,
+                &points_nodes,
+                &points_edges,
+                streams_touched,
+                self.streams_field.as_ref().map(|_| &stream_names),
+                self.connections_only,
+            )?;
+        }
+
+        Ok(())
+    }
+
+    /// Nodes and connections of the streams network, and the
+    /// locations to snap the points to
+    fn read_streams(&self, streams_lyr: &mut Layer, verbose: bool) -> anyhow::Result<StreamsGraph> {
+        // node: point to node number
+        let nodes_count = streams_lyr.feature_count() as usize + 1;
+        let mut nodes = NodesMap::new(self.tolerance, nodes_count);
+        // node number to geometry index in streams file
+        let mut streams_geo_location: HashMap<(usize, usize), usize> =
+            HashMap::with_capacity(nodes_count);
+        // feature ids (and part number of multi-part geometries) of
+        // the streams, the geometries are read again only for the
+        // touched streams while saving the output
+        let mut streams_fids: Vec<(u64, Option<usize>)> = Vec::with_capacity(nodes_count);
+        let mut streams_length: Vec<f64> = Vec::with_capacity(nodes_count);
+        // elevations of the nodes from the Z values of the streams
+        let mut nodes_z: HashMap<usize, f64> = HashMap::new();
+        let has_z = streams_lyr
+            .defn()
+            .geom_fields()
+            .next()
+            // SAFETY: only checks the flags of the geometry type
+            .map(|g| unsafe { gdal_sys::OGR_GT_HasZ(g.field_type()) } != 0)
+            .unwrap_or(false);
+        // edge: node to another node at the end
+        let mut edges: HashMap<usize, usize> = HashMap::with_capacity(nodes_count);
+        let mut branches: HashMap<usize, usize> = HashMap::new();
+        let mut all_pts: HashMap<Point2D, (usize, usize)> = HashMap::new();
+
+        // feature ids of the streams with parts that are skipped
+        let mut empty_parts: Vec<u64> = Vec::new();
+        let mut collapsed_parts: Vec<u64> = Vec::new();
+        let mut progress: usize = 0;
+        let total = nodes_count - 1;
+        for feat in streams_lyr.features() {
+            let (Some(geom), Some(fid)) = (feat.geometry(), feat.fid()) else {
+                continue;
+            };
+            let mut parts = line_parts(geom);
+            let ids = if let (Some(from), Some(to)) = (&self.from_field, &self.to_field) {
+                let (Some(from), Some(to)) = (
+                    feat.field_as_string_by_name(from)?,
+                    feat.field_as_string_by_name(to)?,
+                ) else {
+                    journal::warn(format!("Stream {} doesn't have the from/to node ids", fid));
+                    continue;
+                };
+                // the node ids are for the whole feature, so the parts
+                // are joined into a single stream
+                if parts.len() > 1 {
+                    let points = parts.drain(..).flat_map(|(_, p)| p).collect();
+                    parts.push((None, points));
+                }
+                Some((from, to))
+            } else {
+                None
+            };
+            for (part, points) in parts {
+                if points.len() < 2 {
+                    empty_parts.push(fid);
+                    continue;
+                }
+                let i = streams_fids.len();
+                streams_fids.push((fid, part));
+                streams_length.push(
+                    points
+                        .windows(2)
+                        .map(|w| (w[1].0 - w[0].0).hypot(w[1].1 - w[0].1))
+                        .sum(),
+                );
+                let start = Point2D::new(points[0]);
+                let end = Point2D::new(points[points.len() - 1]);
+                let (start_ind, end_ind) = match &ids {
+                    Some((from, to)) => (
+                        nodes.get_or_insert_id(from.clone(), start),
+                        nodes.get_or_insert_id(to.clone(), end),
+                    ),
+                    None => (nodes.get_or_insert(start), nodes.get_or_insert(end)),
+                };
+                if start_ind == end_ind {
+                    // rings and streams shorter than the tolerance
+                    // would be a loop
+                    collapsed_parts.push(fid);
+                    continue;
+                }
+                if has_z {
+                    nodes_z.insert(start_ind, points[0].2);
+                    nodes_z.insert(end_ind, points[points.len() - 1].2);
+                }
+                streams_geo_location.insert((start_ind, end_ind), i);
+                if let Entry::Vacant(e) = edges.entry(start_ind) {
+                    e.insert(end_ind);
                 } else {
-                    eprintln!(
-                        "{} {} -> None {}",
-                        points_nodes[pt], nodes_rev[pt], nodes_rev[&outlet]
-                    );
-                    break;
+                    branches.insert(start_ind, end_ind);
                 }
+
+                points.iter().for_each(|p| {
+                    all_pts
+                        .entry(Point2D::new(*p))
+                        .or_insert((start_ind, end_ind));
+                });
             }
 
-            for b in curr_branches {
-                // currently can't detect branches in the branch,
-                // maybe we can call it recursively after separating
-                // it in a function
-                let mut converses = false;
-                let mut b = *b;
-                while let Some(&co) = edges.get(&b) {
-                    if let Some(&i) = streams_geo_location.get(&(b, co)) {
-                        streams_touched.insert((outlet, i), streams[i].1.clone());
-                    }
-                    if Some(co) == final_outlet {
-                        converses = true;
-                        break;
-                    }
-                    b = co;
+            if verbose {
+                progress += 1;
+                println!("Reading Streams: {}", progress * 100 / total);
+            }
+        }
+
+        for (msg, fids) in [
+            ("without a line", empty_parts),
+            ("collapsed into a single node", collapsed_parts),
+        ] {
+            if !fids.is_empty() {
+                let fids: Vec<String> = fids.iter().map(|f| f.to_string()).collect();
+                journal::warn(format!(
+                    "Skipped stream parts {msg}, feature ids: {}",
+                    fids.join(", ")
+                ));
+            }
+        }
+        let nodes = nodes.points;
+
+        // candidate locations to snap the points to, with the edge
+        // whose end node becomes the node of the point
+        let candidates: Vec<(Point2D, (usize, usize))> = match self.snap_to {
+            SnapTo::NearestSegment => {
+                // sorted so the ties in the distance go to the same
+                // stream on every run
+                let mut pts: Vec<(Point2D, (usize, usize))> = all_pts.into_iter().collect();
+                pts.sort_by(|a, b| a.0.cmp(&b.0));
+                pts
+            }
+            SnapTo::NearestNode | SnapTo::Confluence => {
+                let mut incoming: HashMap<usize, ((usize, usize), usize)> = HashMap::new();
+                for &(s, e) in streams_geo_location.keys() {
+                    incoming
+                        .entry(e)
+                        .and_modify(|(edge, count)| {
+                            *count += 1;
+                            // smallest start node keeps the choice deterministic
+                            if s < edge.0 {
+                                *edge = (s, e);
+                            }
+                        })
+                        .or_insert(((s, e), 1));
                 }
-                if final_outlet.is_some() && !converses {
-                    eprintln!(
-                        "Branch detected from node {} downstream of {}",
-                        b, points_nodes[pt]
-                    );
+                nodes
+                    .iter()
+                    .enumerate()
+                    .filter_map(|(n, pt)| match incoming.get(&n) {
+                        Some(&(_, c)) if self.snap_to == SnapTo::Confluence && c < 2 => None,
+                        Some(&(edge, _)) => Some((pt.clone(), edge)),
+                        // headwater nodes don't have a stream ending at them
+                        None if self.snap_to == SnapTo::NearestNode => Some((pt.clone(), (n, n))),
+                        None => None,
+                    })
+                    .collect()
+            }
+        };
+        Ok(StreamsGraph {
+            nodes,
+            streams_geo_location,
+            streams_fids,
+            streams_length,
+            nodes_z,
+            edges,
+            branches,
+            candidates,
+        })
+    }
+
+    /// Key of the inputs and the options that change the checkpoint
+    /// files, to not resume from the files of a different run
+    fn checkpoint_key(&self, points: &[(String, Geometry)], streams_lyr: &Layer) -> String {
+        let mut hasher = Sha256::new();
+        for (name, geom) in points {
+            let (x, y, _) = geom.get_point(0);
+            hasher.update((name.len() as u64).to_le_bytes());
+            hasher.update(name.as_bytes());
+            hasher.update(x.to_le_bytes());
+            hasher.update(y.to_le_bytes());
+        }
+        let modified: Vec<SystemTime> = std::iter::once(&self.points)
+            .chain(self.streams.iter())
+            .map(|(path, _)| path)
+            .filter_map(|path| std::fs::metadata(path).and_then(|m| m.modified()).ok())
+            .collect();
+        format!(
+            "{} {:x} {} {:?} {} {:?} {:?} {:?}",
+            env!("CARGO_PKG_VERSION"),
+            hasher.finalize(),
+            streams_lyr.feature_count(),
+            modified,
+            self.tolerance,
+            self.snap_to
+                .to_possible_value()
+                .map(|v| v.get_name().to_string()),
+            self.from_field,
+            self.to_field,
+        )
+    }
+
+    /// File in the --index-cache directory for the streams file, named
+    /// by the hash of its contents and the options used to read it
+    fn index_cache_file(&self, dir: &Path, streams: &Path, layer: &str) -> anyhow::Result<PathBuf> {
+        let mut hasher = Sha256::new();
+        for file in dataset_files(streams)? {
+            let ext = file.extension().unwrap_or_default().to_string_lossy();
+            hasher.update(ext.as_bytes());
+            std::io::copy(&mut File::open(&file)?, &mut hasher)
+                .with_context(|| format!("Couldn't read {:?} to hash it", file))?;
+        }
+        hasher.update(format!(
+            "{} {} {} {:?} {:?} {:?}",
+            env!("CARGO_PKG_VERSION"),
+            layer,
+            self.tolerance,
+            self.snap_to
+                .to_possible_value()
+                .map(|v| v.get_name().to_string()),
+            self.from_field,
+            self.to_field,
+        ));
+        std::fs::create_dir_all(dir)?;
+        Ok(dir.join(format!("{:x}.bin", hasher.finalize())))
+    }
+
+    fn print_raster_connections(
+        &self,
+        points: Vec<(String, Geometry)>,
+        points_srs: &Option<SpatialRef>,
+        flow_dir: &PathBuf,
+        global: &GlobalArgs,
+    ) -> Result<(), anyhow::Error> {
+        let raster = Dataset::open(flow_dir)?;
+        journal::input(flow_dir);
+        if !self.ignore_spatial_reference
+            && check_spatial_ref_system_compatibility(points_srs, &raster.spatial_ref().ok())
+                .is_err()
+        {
+            anyhow::bail!(
+                "Points and flow direction raster have different spatial references, reproject one of them or use --ignore-spatial-reference"
+            );
+        }
+        let mut dirs = RasterBlocks::new(raster.rasterband(1)?);
+        let (cols, rows) = dirs.size;
+        // rotated rasters are not supported
+        let gt = raster.geo_transform()?;
+        let cell = |x: f64, y: f64| -> Option<(usize, usize)> {
+            let c = ((x - gt[0]) / gt[1]).floor();
+            let r = ((y - gt[3]) / gt[5]).floor();
+            if c < 0.0 || r < 0.0 || c >= cols as f64 || r >= rows as f64 {
+                None
+            } else {
+                Some((c as usize, r as usize))
+            }
+        };
+        let center = |(c, r): (usize, usize)| {
+            (
+                gt[0] + (c as f64 + 0.5) * gt[1],
+                gt[3] + (r as f64 + 0.5) * gt[5],
+                0.0,
+            )
+        };
+
+        let points_nodes: HashMap<usize, &str> = points
+            .iter()
+            .enumerate()
+            .map(|(i, (k, _))| (i, k.as_str()))
+            .collect();
+        let mut points_cells: HashMap<(usize, usize), usize> = HashMap::new();
+        let mut start_cells: Vec<(usize, (usize, usize))> = Vec::with_capacity(points.len());
+        for (i, (k, p)) in points.iter().enumerate() {
+            let (x, y, _) = p.get_point(0);
+            if let Some(c) = cell(x, y) {
+                if let Some(j) = points_cells.insert(c, i) {
+                    journal::warn(format!(
+                        "Points {} and {} are in the same cell",
+                        points_nodes[&j], k
+                    ));
                 }
+                start_cells.push((i, c));
+            } else {
+                journal::warn(format!("Point {} is outside the flow direction raster", k));
             }
-            if self.verbose {
+        }
+
+        let mut points_edges: HashMap<usize, usize> = HashMap::new();
+        let mut flow_paths: HashMap<(usize, usize), Geometry> = HashMap::new();
+        let mut progress: usize = 0;
+        let total = start_cells.len();
+        for (i, start) in start_cells {
+            let mut path = Geometry::empty(gdal_sys::OGRwkbGeometryType::wkbLineString)?;
+            path.add_point(center(start));
+            let mut curr = start;
+            let mut outlet = None;
+            // a flow path can't be longer than the number of cells,
+            // this prevents infinite loops on bad rasters
+            for _ in 0..cols * rows {
+                let Some((dc, dr)) = d8_offset(dirs.get(curr)?) else {
+                    break;
+                };
+                let (c, r) = (curr.0 as isize + dc, curr.1 as isize + dr);
+                if c < 0 || r < 0 || c >= cols as isize || r >= rows as isize {
+                    break;
+                }
+                curr = (c as usize, r as usize);
+                path.add_point(center(curr));
+                if let Some(&j) = points_cells.get(&curr) {
+                    outlet = Some(j);
+                    break;
+                }
+            }
+            if let Some(o) = outlet {
+                println!("{} -> {}", points_nodes[&i], points_nodes[&o]);
+                points_edges.insert(i, o);
+                flow_paths.insert((i, o), path);
+            } else {
+                eprintln!("{} -> None", points_nodes[&i]);
+                flow_paths.insert((i, usize::MAX), path);
+            }
+            if global.verbose {
                 progress += 1;
-                println!("Searching Connections: {}", progress * 100 / total);
+                println!("Tracing Flow Paths: {}", progress * 100 / total);
             }
         }
 
-        if let Some(output) = output {
+        if let Some(output) = &global.output_layer()? {
             save_connections_file(
                 &self.driver,
                 output,
-                &streams_lyr,
+                raster.spatial_ref().ok().as_ref(),
                 &points,
                 &points_nodes,
                 &points_edges,
-                streams_touched,
+                flow_paths,
+                None,
                 self.connections_only,
             )?;
         }
+        Ok(())
+    }
+}
+
+/// Metadata header before the connections printed to stdout, it is
+/// left out when the connections are saved as a GIS file
+fn print_header(global: &GlobalArgs, inputs: &[&Path]) {
+    if global.output.is_none() {
+        print!("{}", journal::metadata_header(inputs));
+    }
+}
+
+fn find_root(parent: &mut [usize], mut n: usize) -> usize {
+    while parent[n] != n {
+        parent[n] = parent[parent[n]];
+        n = parent[n];
+    }
+    n
+}
+
+/// Print the disconnected components of the streams network and the
+/// points that are not in the component with the main outlet
+fn report_components(
+    nodes: &[Point2D],
+    edges: &HashMap<usize, usize>,
+    branches: &HashMap<usize, usize>,
+    points_nodes: &HashMap<usize, &str>,
+) {
+    let nodes_count = nodes.len();
+    let mut parent: Vec<usize> = (0..nodes_count).collect();
+    // sorted so the roots, and the largest component on ties, are
+    // the same on every run
+    let mut links: Vec<(usize, usize)> = edges
+        .iter()
+        .chain(branches.iter())
+        .map(|(&s, &e)| (s, e))
+        .collect();
+    links.sort();
+    for (s, e) in links {
+        let (rs, re) = (find_root(&mut parent, s), find_root(&mut parent, e));
+        if rs != re {
+            parent[rs] = re;
+        }
+    }
+    let mut sizes: HashMap<usize, usize> = HashMap::new();
+    for n in 0..nodes_count {
+        *sizes.entry(find_root(&mut parent, n)).or_insert(0) += 1;
+    }
+    let Some((&main, &main_size)) = sizes.iter().max_by_key(|(&r, &s)| (s, r)) else {
+        return;
+    };
+    eprintln!(
+        "Streams network has {} components, the largest has {} of {} nodes",
+        sizes.len(),
+        main_size,
+        nodes_count
+    );
+    let mut outlets: Vec<usize> = (0..nodes_count)
+        .filter(|n| !edges.contains_key(n) && find_root(&mut parent, *n) == main)
+        .collect();
+    outlets.sort();
+    for o in outlets {
+        eprintln!("Main outlet at node {} {}", o, nodes[o]);
+    }
+    let mut points: Vec<(&usize, &&str)> = points_nodes.iter().collect();
+    points.sort();
+    for (n, name) in points {
+        let root = find_root(&mut parent, *n);
+        if root != main {
+            eprintln!(
+                "Point {} is in a component of {} nodes that doesn't reach the main outlet",
+                name, sizes[&root]
+            );
+        }
+    }
+}
+
+/// Index of the closest location to each point, searched in parallel
+/// in the current thread pool; `snapped` is called after each point
+pub fn nearest_locations(
+    points: &[(f64, f64)],
+    locations: &[(f64, f64)],
+    snapped: impl Fn() + Sync,
+) -> Vec<usize> {
+    points
+        .par_iter()
+        .map(|&(x, y)| {
+            let (mut min_i, mut min_dist) = (0, f64::INFINITY);
+            for (i, (sx, sy)) in locations.iter().enumerate() {
+                let dist = (sx - x).powi(2) + (sy - y).powi(2);
+                if dist < min_dist {
+                    min_dist = dist;
+                    min_i = i;
+                }
+            }
+            snapped();
+            min_i
+        })
+        .collect()
+}
+
+/// Nodes and connections of the streams network
+#[derive(Serialize, Deserialize)]
+struct StreamsGraph {
+    /// location of the nodes by their index
+    nodes: Vec<Point2D>,
+    /// start and end nodes of the streams to their index
+    #[serde(with = "pairs")]
+    streams_geo_location: HashMap<(usize, usize), usize>,
+    /// feature ids (and part number of multi-part geometries) of
+    /// the streams by their index
+    streams_fids: Vec<(u64, Option<usize>)>,
+    streams_length: Vec<f64>,
+    /// elevations of the nodes from the Z values of the streams
+    #[serde(with = "pairs")]
+    nodes_z: HashMap<usize, f64>,
+    /// node to the node at the other end of its stream
+    #[serde(with = "pairs")]
+    edges: HashMap<usize, usize>,
+    /// second stream of the nodes with two outgoing streams
+    #[serde(with = "pairs")]
+    branches: HashMap<usize, usize>,
+    /// locations to snap the points to, with the stream whose end
+    /// node becomes the node of the point
+    candidates: Vec<(Point2D, (usize, usize))>,
+}
+
+/// Maps saved as lists of key value pairs, as json only has string keys
+mod pairs {
+    use std::collections::HashMap;
+    use std::hash::Hash;
+
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    pub fn serialize<K: Serialize, V: Serialize, S: Serializer>(
+        map: &HashMap<K, V>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(map.iter())
+    }
+
+    pub fn deserialize<'de, K, V, D>(deserializer: D) -> Result<HashMap<K, V>, D::Error>
+    where
+        K: Deserialize<'de> + Eq + Hash,
+        V: Deserialize<'de>,
+        D: Deserializer<'de>,
+    {
+        Ok(Vec::<(K, V)>::deserialize(deserializer)?
+            .into_iter()
+            .collect())
+    }
+}
+
+/// Streams network from the --index-cache file, a missing or
+/// unreadable cache is read again from the streams file
+fn load_index(path: &Path) -> Option<StreamsGraph> {
+    let file = File::open(path).ok()?;
+    // SAFETY: the cache files are only replaced by renaming a new
+    // file over them, so the mapped file isn't changed while reading
+    let map = unsafe { Mmap::map(&file) }.ok()?;
+    match bincode::deserialize(&map) {
+        Ok(graph) => {
+            journal::input(path);
+            Some(graph)
+        }
+        Err(e) => {
+            journal::warn(format!("Ignoring the index cache {:?}: {}", path, e));
+            None
+        }
+    }
+}
+
+/// Files of the dataset: the file with the sidecar files of the same
+/// name (like the .dbf and .shx of a shapefile), or the files in the
+/// dataset directory, sorted by the name
+fn dataset_files(path: &Path) -> anyhow::Result<Vec<PathBuf>> {
+    let (dir, stem) = if path.is_dir() {
+        (path, None)
+    } else {
+        (
+            path.parent()
+                .filter(|p| !p.as_os_str().is_empty())
+                .unwrap_or(Path::new(".")),
+            path.file_stem(),
+        )
+    };
+    let mut files = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        let file = entry?.path();
+        if file.is_file() && (stem.is_none() || file.file_stem() == stem) {
+            files.push(file);
+        }
+    }
+    files.sort();
+    Ok(files)
+}
 
+fn save_index(path: &Path, graph: &StreamsGraph) -> anyhow::Result<()> {
+    // written to a temporary file first, so other runs never map a
+    // partially written cache; the process id keeps the runs sharing
+    // the cache from writing to the same temporary file
+    let tmp = path.with_extension(format!("bin.{}.tmp", std::process::id()));
+    let mut file = BufWriter::new(File::create(&tmp)?);
+    bincode::serialize_into(&mut file, graph)?;
+    file.flush()?;
+    drop(file);
+    std::fs::rename(&tmp, path)?;
+    journal::output(path);
+    Ok(())
+}
+
+/// Number of traced points between the saves to the checkpoint
+const CHECKPOINT_BATCH: usize = 5000;
+
+#[derive(Serialize, Deserialize)]
+struct Saved<T> {
+    key: String,
+    data: T,
+}
+
+/// Files with the partial results of a connection run in the
+/// --checkpoint-dir, with the key of the inputs they were made from
+struct Checkpoint {
+    dir: PathBuf,
+    key: String,
+    resume: bool,
+}
+
+impl Checkpoint {
+    fn new(dir: &Path, key: String, resume: bool) -> anyhow::Result<Self> {
+        std::fs::create_dir_all(dir)?;
+        Ok(Self {
+            dir: dir.to_path_buf(),
+            key,
+            resume,
+        })
+    }
+
+    fn check_key(&self, key: &str, path: &Path) -> anyhow::Result<()> {
+        if key != self.key {
+            anyhow::bail!(
+                "Checkpoint {:?} is from a run with different inputs or options, run without --resume to start over",
+                path
+            );
+        }
+        Ok(())
+    }
+
+    /// Data saved in the stage, if resuming and it has been saved
+    fn load<T: DeserializeOwned>(&self, stage: &str) -> anyhow::Result<Option<T>> {
+        let path = self.dir.join(format!("{stage}.json"));
+        if !self.resume || !path.exists() {
+            return Ok(None);
+        }
+        let saved: Saved<T> = serde_json::from_reader(BufReader::new(File::open(&path)?))
+            .with_context(|| format!("Couldn't read the checkpoint {:?}", path))?;
+        self.check_key(&saved.key, &path)?;
+        journal::input(&path);
+        eprintln!("Resuming from {:?}", path);
+        Ok(Some(saved.data))
+    }
+
+    fn save<T: Serialize>(&self, stage: &str, data: &T) -> anyhow::Result<()> {
+        let path = self.dir.join(format!("{stage}.json"));
+        // written to a temporary file first, so an interrupted save
+        // keeps the previous checkpoint
+        let tmp = path.with_extension("json.tmp");
+        let mut file = BufWriter::new(File::create(&tmp)?);
+        serde_json::to_writer(
+            &mut file,
+            &Saved {
+                key: self.key.clone(),
+                data,
+            },
+        )?;
+        file.flush()?;
+        drop(file);
+        std::fs::rename(&tmp, &path)?;
+        journal::output(&path);
         Ok(())
     }
+
+    /// Traces saved in the traces.jsonl file, the first line has the
+    /// key and the other lines the start node and its trace
+    fn load_traces(&self) -> anyhow::Result<HashMap<usize, Trace>> {
+        let path = self.dir.join("traces.jsonl");
+        let mut traces = HashMap::new();
+        if !self.resume || !path.exists() {
+            return Ok(traces);
+        }
+        let mut lines = BufReader::new(File::open(&path)?).lines();
+        let key: String = match lines.next() {
+            Some(line) => serde_json::from_str(&line?)?,
+            None => return Ok(traces),
+        };
+        self.check_key(&key, &path)?;
+        for line in lines {
+            // the last line is incomplete if the run was interrupted
+            // while writing it
+            let Ok((pt, trace)) = serde_json::from_str::<(usize, Trace)>(&line?) else {
+                break;
+            };
+            traces.insert(pt, trace);
+        }
+        journal::input(&path);
+        eprintln!(
+            "Resuming from {:?} with {} traced points",
+            path,
+            traces.len()
+        );
+        Ok(traces)
+    }
+
+    /// Writer appending to the traces.jsonl file with the traces done
+    /// so far
+    fn trace_log(&self, done: &HashMap<usize, Trace>) -> anyhow::Result<BufWriter<File>> {
+        let path = self.dir.join("traces.jsonl");
+        // rewritten in a temporary file first, so an interrupted run
+        // keeps the traces of the previous one
+        let tmp = path.with_extension("jsonl.tmp");
+        let mut log = BufWriter::new(File::create(&tmp)?);
+        serde_json::to_writer(&mut log, &self.key)?;
+        writeln!(log)?;
+        for (pt, trace) in done {
+            serde_json::to_writer(&mut log, &(pt, trace))?;
+            writeln!(log)?;
+        }
+        log.flush()?;
+        drop(log);
+        std::fs::rename(&tmp, &path)?;
+        journal::output(&path);
+        Ok(BufWriter::new(OpenOptions::new().append(true).open(&path)?))
+    }
+}
+
+/// Result of the downstream walk from a point
+#[derive(Default, Serialize, Deserialize)]
+struct Trace {
+    /// Node of the next point downstream
+    outlet: Option<usize>,
+    /// Node where the walk stopped without reaching another point
+    dead_end: Option<usize>,
+    /// Keys for the touched streams with the stream geometry index
+    touched: Vec<((usize, usize), usize)>,
+    /// Stream geometry indices from the point to the outlet
+    path: Vec<usize>,
+    /// Branches that don't converge back before the outlet
+    branches: Vec<usize>,
+}
+
+fn trace_downstream(
+    pt: usize,
+    edges: &HashMap<usize, usize>,
+    branches: &HashMap<usize, usize>,
+    streams_geo_location: &HashMap<(usize, usize), usize>,
+    points_nodes: &HashMap<usize, &str>,
+) -> Trace {
+    let mut trace = Trace::default();
+    let mut outlet = pt;
+    let mut curr_branches: Vec<usize> = Vec::new();
+    loop {
+        if let Some(&o) = edges.get(&outlet) {
+            if let Some(&bout) = branches.get(&outlet) {
+                if let Some(&i) = streams_geo_location.get(&(outlet, bout)) {
+                    trace.touched.push(((outlet, i), i));
+                }
+                curr_branches.push(bout);
+            }
+            if let Some(&i) = streams_geo_location.get(&(outlet, o)) {
+                trace.touched.push(((outlet, i), i));
+                trace.path.push(i);
+            }
+            outlet = o;
+            if points_nodes.contains_key(&o) {
+                trace.outlet = Some(outlet);
+                break;
+            }
+        } else {
+            trace.dead_end = Some(outlet);
+            break;
+        }
+    }
+
+    for mut b in curr_branches {
+        // currently can't detect branches in the branch,
+        // maybe we can call it recursively
+        let mut converses = false;
+        while let Some(&co) = edges.get(&b) {
+            if let Some(&i) = streams_geo_location.get(&(b, co)) {
+                trace.touched.push(((outlet, i), i));
+            }
+            if Some(co) == trace.outlet {
+                converses = true;
+                break;
+            }
+            b = co;
+        }
+        if trace.outlet.is_some() && !converses {
+            trace.branches.push(b);
+        }
+    }
+    trace
+}
+
+/// Size of the square blocks of the flow direction raster read at a time
+const RASTER_BLOCK: usize = 256;
+/// Number of blocks kept in memory, the ones not used recently are
+/// read again when the flow paths reach them
+const RASTER_BLOCKS_KEPT: usize = 64;
+
+/// Values of a raster band read in blocks as they are needed, instead
+/// of reading the whole raster into memory
+struct RasterBlocks<'a> {
+    band: RasterBand<'a>,
+    size: (usize, usize),
+    blocks: HashMap<(usize, usize), (Vec<i32>, usize)>,
+    /// Counter for the last use of the blocks
+    uses: usize,
+}
+
+impl<'a> RasterBlocks<'a> {
+    fn new(band: RasterBand<'a>) -> Self {
+        let size = band.size();
+        Self {
+            band,
+            size,
+            blocks: HashMap::new(),
+            uses: 0,
+        }
+    }
+
+    /// Value at the (column, row) cell inside the raster
+    fn get(&mut self, (c, r): (usize, usize)) -> anyhow::Result<i32> {
+        let key = (c / RASTER_BLOCK, r / RASTER_BLOCK);
+        let (x0, y0) = (key.0 * RASTER_BLOCK, key.1 * RASTER_BLOCK);
+        let width = RASTER_BLOCK.min(self.size.0 - x0);
+        self.uses += 1;
+        if let Some(block) = self.blocks.get_mut(&key) {
+            block.1 = self.uses;
+            return Ok(block.0[(r - y0) * width + (c - x0)]);
+        }
+        if self.blocks.len() >= RASTER_BLOCKS_KEPT {
+            let oldest = self
+                .blocks
+                .iter()
+                .min_by_key(|(_, b)| b.1)
+                .map(|(k, _)| *k);
+            if let Some(k) = oldest {
+                self.blocks.remove(&k);
+            }
+        }
+        let height = RASTER_BLOCK.min(self.size.1 - y0);
+        let mut values = vec![0; width * height];
+        self.band.read_into_slice(
+            (x0 as isize, y0 as isize),
+            (width, height),
+            (width, height),
+            &mut values,
+            None,
+        )?;
+        let value = values[(r - y0) * width + (c - x0)];
+        self.blocks.insert(key, (values, self.uses));
+        Ok(value)
+    }
+}
+
+/// Offset (column, row) of the downstream cell for the D8 flow
+/// direction code in the ESRI convention
+fn d8_offset(code: i32) -> Option<(isize, isize)> {
+    match code {
+        1 => Some((1, 0)),
+        2 => Some((1, 1)),
+        4 => Some((0, 1)),
+        8 => Some((-1, 1)),
+        16 => Some((-1, 0)),
+        32 => Some((-1, -1)),
+        64 => Some((0, -1)),
+        128 => Some((1, -1)),
+        _ => None,
+    }
 }
 
 fn save_connections_file(
     driver: &Option<String>,
     output: &(PathBuf, Option<String>),
-    streams_lyr: &Layer,
+    srs: Option<&SpatialRef>,
     points: &Vec<(String, Geometry)>,
     points_nodes: &HashMap<usize, &str>,
     points_edges: &HashMap<usize, usize>,
     streams_touched: HashMap<(usize, usize), Geometry>,
+    stream_names: Option<&HashMap<(usize, usize), String>>,
     connections_only: bool,
 ) -> Result<(), anyhow::Error> {
     let driver = get_driver_by_filename(&output.0, driver)?;
+    journal::output(&output.0);
     let mut out_data = driver.create_vector_only(&output.0)?;
     // Not supported in all the formats, so removing it.
     // let mut txn = out_data.start_transaction()?;
     let mut layer = out_data.create_layer(LayerOptions {
         name: output.1.as_ref().unwrap_or(&"network".to_string()),
-        srs: streams_lyr.spatial_ref().as_ref(),
+        srs,
         ty: gdal_sys::OGRwkbGeometryType::wkbLineString,
         ..Default::default()
     })?;
@@ -327,6 +1396,8 @@ fn save_connections_file(
             .iter()
             .map(|(k, g)| (k.as_str(), g.get_point(0)))
             .collect();
+        let mut points_edges: Vec<(&usize, &usize)> = points_edges.iter().collect();
+        points_edges.sort();
         for (start, end) in points_edges {
             let mut edge_geometry = Geometry::empty(gdal_sys::OGRwkbGeometryType::wkbLineString)?;
             edge_geometry.add_point(points_map[points_nodes[start]]);
@@ -343,23 +1414,34 @@ fn save_connections_file(
     } else {
         layer.create_defn_fields(&[("start", OGRFieldType::OFTString)])?;
         layer.create_defn_fields(&[("end", OGRFieldType::OFTString)])?;
-        let fields = ["start", "end"];
-        for ((start, end), geo) in streams_touched {
-            layer.create_feature_fields(
-                geo,
-                &fields,
-                &[
-                    FieldValue::StringValue(points_nodes.get(&start).unwrap_or(&"").to_string()),
-                    FieldValue::StringValue(points_nodes.get(&end).unwrap_or(&"").to_string()),
-                ],
-            )?;
+        let mut fields = vec!["start", "end"];
+        if stream_names.is_some() {
+            layer.create_defn_fields(&[("stream", OGRFieldType::OFTString)])?;
+            fields.push("stream");
+        }
+        let mut streams_touched: Vec<((usize, usize), Geometry)> =
+            streams_touched.into_iter().collect();
+        streams_touched.sort_by_key(|(k, _)| *k);
+        for (key, geo) in streams_touched {
+            let (start, end) = key;
+            let mut values = vec![
+                FieldValue::StringValue(points_nodes.get(&start).unwrap_or(&"").to_string()),
+                FieldValue::StringValue(points_nodes.get(&end).unwrap_or(&"").to_string()),
+            ];
+            if let Some(names) = stream_names {
+                values.push(FieldValue::StringValue(
+                    names.get(&key).cloned().unwrap_or_default(),
+                ));
+            }
+            layer.create_feature_fields(geo, &fields, &values)?;
         }
     }
     // txn.commit()?;
     Ok(())
 }
 
-#[derive(Eq, PartialEq, Hash, Clone, Debug)]
+#[derive(Eq, PartialEq, Ord, PartialOrd, Hash, Clone, Debug, Serialize, Deserialize)]
+#[serde(from = "(f64, f64)", into = "(f64, f64)")]
 struct Point2D {
     x: NotNan<f64>,
     y: NotNan<f64>,
@@ -378,16 +1460,125 @@ impl Point2D {
     }
 }
 
+impl From<(f64, f64)> for Point2D {
+    fn from(coord: (f64, f64)) -> Self {
+        Self::new((coord.0, coord.1, 0.0))
+    }
+}
+
+impl From<Point2D> for (f64, f64) {
+    fn from(pt: Point2D) -> Self {
+        (pt.x.into_inner(), pt.y.into_inner())
+    }
+}
+
 impl fmt::Display for Point2D {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(f, "({}, {})", self.x, self.y)
     }
 }
 
+/// Map of the stream end points to the node index, the end points
+/// within the tolerance distance of an existing node are merged to it
+struct NodesMap {
+    tolerance: f64,
+    nodes: HashMap<Point2D, usize>,
+    /// location of the nodes by their index
+    points: Vec<Point2D>,
+    /// node index by the id from the streams attribute table
+    ids: HashMap<String, usize>,
+    /// grid of tolerance sized cells for the neighbour search
+    grid: HashMap<(i64, i64), Vec<(Point2D, usize)>>,
+}
+
+impl NodesMap {
+    fn new(tolerance: f64, capacity: usize) -> Self {
+        Self {
+            tolerance,
+            nodes: HashMap::with_capacity(capacity),
+            points: Vec::with_capacity(capacity),
+            ids: HashMap::new(),
+            grid: HashMap::new(),
+        }
+    }
+
+    /// Node for the id from the attribute table, the location is
+    /// only used for snapping and saving the nodes
+    fn get_or_insert_id(&mut self, id: String, pt: Point2D) -> usize {
+        if let Some(&n) = self.ids.get(&id) {
+            return n;
+        }
+        let n = self.points.len();
+        self.ids.insert(id, n);
+        self.nodes.entry(pt.clone()).or_insert(n);
+        self.points.push(pt);
+        n
+    }
+
+    fn cell(&self, pt: &Point2D) -> (i64, i64) {
+        let (x, y, _) = pt.coord();
+        (
+            (x / self.tolerance).floor() as i64,
+            (y / self.tolerance).floor() as i64,
+        )
+    }
+
+    fn get_or_insert(&mut self, pt: Point2D) -> usize {
+        if let Some(&n) = self.nodes.get(&pt) {
+            return n;
+        }
+        if self.tolerance <= 0.0 {
+            let n = self.points.len();
+            self.nodes.insert(pt.clone(), n);
+            self.points.push(pt);
+            return n;
+        }
+        let (cx, cy) = self.cell(&pt);
+        let (x, y, _) = pt.coord();
+        for dx in -1..=1 {
+            for dy in -1..=1 {
+                let Some(pts) = self.grid.get(&(cx + dx, cy + dy)) else {
+                    continue;
+                };
+                for (p, n) in pts {
+                    let (px, py, _) = p.coord();
+                    if (px - x).powi(2) + (py - y).powi(2) <= self.tolerance.powi(2) {
+                        return *n;
+                    }
+                }
+            }
+        }
+        let n = self.points.len();
+        self.grid.entry((cx, cy)).or_default().push((pt.clone(), n));
+        self.nodes.insert(pt.clone(), n);
+        self.points.push(pt);
+        n
+    }
+}
+
+/// Part index (for multi-part geometries) and the points of a line
+type LinePart = (Option<usize>, Vec<(f64, f64, f64)>);
+
+/// Line parts of the stream geometry, multi-part geometries are
+/// exploded into their parts
+fn line_parts(geom: &Geometry) -> Vec<LinePart> {
+    match geom.geometry_count() {
+        0 => vec![(None, geom.get_point_vec())],
+        n => (0..n)
+            .map(|i| (Some(i), geom.get_geometry(i).get_point_vec()))
+            .collect(),
+    }
+}
+
 fn get_geometries(
     layer: &mut Layer,
     field: &Option<String>,
-) -> Result<Vec<(String, Geometry)>, anyhow::Error> {
+    x_field: &[String],
+    y_field: &[String],
+) -> Result<Vec<(String, Geometry)>, NadiError> {
+    let fields: Vec<String> = layer.defn().fields().map(|f| f.name()).collect();
+    let x_col = find_field(&fields, x_field).map(|i| fields[i].as_str());
+    let y_col = find_field(&fields, y_field).map(|i| fields[i].as_str());
     layer
         .features()
         .enumerate()
@@ -395,9 +1586,20 @@ fn get_geometries(
             let geom = match f.geometry() {
                 Some(g) => g.clone(),
                 None => {
-                    // TODO take X,Y possible names as Vec<String>
-                    let x = f.field_as_double_by_name("lon")?.unwrap();
-                    let y = f.field_as_double_by_name("lat")?.unwrap();
+                    let coord = |col: Option<&str>, names: &[String]| {
+                        let value = match col {
+                            Some(c) => f.field_as_double_by_name(c)?,
+                            None => None,
+                        };
+                        value.ok_or_else(|| {
+                            NadiError::AttributeError(format!(
+                                "Feature {i} has neither geometry nor {} field",
+                                col.map(String::from).unwrap_or_else(|| names.join("/"))
+                            ))
+                        })
+                    };
+                    let x = coord(x_col, x_field)?;
+                    let y = coord(y_col, y_field)?;
                     let mut pt = Geometry::empty(gdal_sys::OGRwkbGeometryType::wkbPoint)?;
                     pt.add_point((x, y, 0.0));
                     pt
@@ -413,10 +1615,73 @@ fn get_geometries(
         .collect()
 }
 
-fn check_spatial_ref_system_compatibility(points: &Layer, streams: &Layer) -> Result<(), ()> {
+/// Points from the csv file, with the ids from the `field` column
+/// or the row numbers
+fn read_points_csv(
+    filename: &PathBuf,
+    field: &Option<String>,
+    x_field: &[String],
+    y_field: &[String],
+) -> Result<Vec<(String, Geometry)>, NadiError> {
+    let (header, rows) = read_csv(filename)?;
+    let coord_col = |names: &[String]| {
+        find_field(&header, names).ok_or_else(|| NadiError::ParseError {
+            file: filename.clone(),
+            line: 1,
+            msg: format!("No {} column", names.join("/")),
+        })
+    };
+    let (x, y) = (coord_col(x_field)?, coord_col(y_field)?);
+    let col = |name: &str| {
+        header
+            .iter()
+            .position(|c| c == name)
+            .ok_or_else(|| NadiError::ParseError {
+                file: filename.clone(),
+                line: 1,
+                msg: format!("No {name} column"),
+            })
+    };
+    let id = field.as_deref().map(col).transpose()?;
+    rows.iter()
+        .filter(|r| !r.iter().all(|c| c.is_empty()))
+        .enumerate()
+        .map(|(i, row)| {
+            let coord = |c: usize| {
+                row.get(c)
+                    .and_then(|v| v.parse::<f64>().ok())
+                    .ok_or_else(|| {
+                        NadiError::AttributeError(format!(
+                            "Row {i} of {:?} has invalid {} value",
+                            filename, header[c]
+                        ))
+                    })
+            };
+            let mut pt = Geometry::empty(gdal_sys::OGRwkbGeometryType::wkbPoint)?;
+            pt.add_point((coord(x)?, coord(y)?, 0.0));
+            let name = match id {
+                Some(c) => row.get(c).cloned().unwrap_or_default(),
+                None => i.to_string(),
+            };
+            Ok((name, pt))
+        })
+        .collect()
+}
+
+/// Index of the first of the names in the fields, ignoring the case
+fn find_field(fields: &[String], names: &[String]) -> Option<usize> {
+    names
+        .iter()
+        .find_map(|n| fields.iter().position(|f| f.eq_ignore_ascii_case(n)))
+}
+
+fn check_spatial_ref_system_compatibility(
+    points: &Option<SpatialRef>,
+    streams: &Option<SpatialRef>,
+) -> Result<(), ()> {
     match (
-        points.spatial_ref().and_then(|r| r.to_proj4().ok()),
-        streams.spatial_ref().and_then(|r| r.to_proj4().ok()),
+        points.as_ref().and_then(|r| r.to_proj4().ok()),
+        streams.as_ref().and_then(|r| r.to_proj4().ok()),
     ) {
         (Some(p), Some(s)) => {
             if p != s {
@@ -439,7 +1704,10 @@ fn check_spatial_ref_system_compatibility(points: &Layer, streams: &Layer) -> Re
     Ok(())
 }
 
-fn get_driver_by_filename(filename: &PathBuf, driver: &Option<String>) -> anyhow::Result<Driver> {
+pub fn get_driver_by_filename(
+    filename: &PathBuf,
+    driver: &Option<String>,
+) -> anyhow::Result<Driver> {
     let drivers =
         get_drivers_for_filename(filename.to_str().unwrap(), &GdalOpenFlags::GDAL_OF_VECTOR);
 
@@ -490,6 +1758,7 @@ fn get_drivers_for_filename(filename: &str, options: &GdalOpenFlags) -> Vec<Driv
         e.to_string()
     };
 
+    drivers::register();
     let mut drivers: Vec<Driver> = Vec::new();
     for i in 0..DriverManager::count() {
         let d = DriverManager::get_driver(i).expect("Index for this loop should be valid");
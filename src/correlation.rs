@@ -0,0 +1,87 @@
+use std::io::Write;
+use std::path::PathBuf;
+
+use clap::Args;
+
+use crate::cliargs::{CliAction, GlobalArgs, OutputFormat};
+use crate::journal;
+use crate::network::{csv_field, Network};
+use crate::timeseries::NodeSeries;
+
+#[derive(Args)]
+pub struct CliArgs {
+    #[command(flatten)]
+    series: NodeSeries,
+    /// Minimum number of overlapping days to calculate the correlation
+    #[arg(short, long, default_value = "365")]
+    min_overlap: usize,
+    /// Only the pairs of nodes where one is downstream of the other
+    #[arg(short, long)]
+    connected_only: bool,
+    /// Connection file
+    connection_file: PathBuf,
+}
+
+/// Whether the second node is upstream or downstream of the first
+/// one, or they are on different branches
+fn connection(net: &Network, a: usize, b: usize) -> &'static str {
+    let downstream_of = |from: usize, to: usize| {
+        let mut node = net.nodes[from].get_output();
+        while let Some(n) = node {
+            if n == to {
+                return true;
+            }
+            node = net.nodes[n].get_output();
+        }
+        false
+    };
+    if downstream_of(b, a) {
+        "upstream"
+    } else if downstream_of(a, b) {
+        "downstream"
+    } else {
+        "none"
+    }
+}
+
+impl CliAction for CliArgs {
+    fn run(self, global: &GlobalArgs) -> anyhow::Result<()> {
+        global.format(&[OutputFormat::Csv])?;
+        let net = self.series.network(&self.connection_file)?;
+        let stations: Vec<_> = self
+            .series
+            .nodes(&net)
+            .map(|(node, _, ts)| (node.get_index(), ts))
+            .collect();
+        journal::count("nodes", stations.len());
+
+        let mut writer = global.writer()?;
+        writeln!(
+            writer,
+            "node1,node2,connection,distance,overlap,correlation"
+        )?;
+        for (i, (a, ts_a)) in stations.iter().enumerate() {
+            let distances = net.link_distances(*a);
+            for (b, ts_b) in &stations[i + 1..] {
+                let conn = connection(&net, *a, *b);
+                if self.connected_only && conn == "none" {
+                    continue;
+                }
+                let (overlap, correlation) = ts_a.overlap_correlation(ts_b)?;
+                let correlation = correlation.filter(|_| overlap >= self.min_overlap);
+                writeln!(
+                    writer,
+                    "{},{},{},{},{},{}",
+                    csv_field(net.nodes[*a].get_name()),
+                    csv_field(net.nodes[*b].get_name()),
+                    conn,
+                    distances.get(b).map(|d| d.to_string()).unwrap_or_default(),
+                    overlap,
+                    correlation.map(|r| r.to_string()).unwrap_or_default(),
+                )?;
+            }
+        }
+        writer.finish()?;
+        Ok(())
+    }
+}
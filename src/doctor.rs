@@ -0,0 +1,282 @@
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::time::{Duration, Instant};
+
+use clap::{Args, ValueHint};
+use comfy_table::{presets, Table};
+use gdal::DriverManager;
+
+use crate::cliargs::{CliAction, GlobalArgs};
+use crate::drivers;
+use crate::journal;
+use crate::timeseries::NodeSeries;
+use crate::usgs::HttpArgs;
+
+/// Small requests to the USGS servers used by the usgs command
+const USGS_ENDPOINTS: [(&str, &str); 3] = [
+    (
+        "daily values",
+        "https://waterservices.usgs.gov/nwis/dv/?format=rdb&sites=01646500&period=P1D",
+    ),
+    (
+        "peak flows",
+        "https://nwis.waterdata.usgs.gov/nwis/peak?site_no=01646500&agency_cd=USGS&format=rdb",
+    ),
+    (
+        "NLDI",
+        "https://labs.waterdata.usgs.gov/api/nldi/linked-data/nwissite/USGS-01646500?f=json",
+    ),
+];
+
+#[derive(Args)]
+pub struct CliArgs {
+    /// GDAL drivers needed for the GIS files
+    #[arg(
+        short,
+        long,
+        value_delimiter = ',',
+        default_value = "ESRI Shapefile,GPKG,GeoJSON"
+    )]
+    driver: Vec<String>,
+    /// Don't check the connection to the USGS servers
+    #[arg(long)]
+    offline: bool,
+    /// Seconds to wait for each USGS server
+    #[arg(long, default_value = "10")]
+    timeout: u64,
+    #[command(flatten)]
+    http: HttpArgs,
+    #[command(flatten)]
+    series: NodeSeries,
+    /// Number of timeseries files of the network to read
+    #[arg(short, long, default_value = "5")]
+    sample: usize,
+    /// Connection file of the network to check the data files of
+    #[arg(value_hint=ValueHint::FilePath)]
+    network: Option<PathBuf>,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq)]
+enum Status {
+    Ok,
+    Warn,
+    Fail,
+}
+
+impl std::fmt::Display for Status {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let s = match self {
+            Self::Ok => "ok",
+            Self::Warn => "warn",
+            Self::Fail => "FAIL",
+        };
+        write!(f, "{s}")
+    }
+}
+
+struct Check {
+    name: String,
+    status: Status,
+    detail: String,
+}
+
+impl Check {
+    fn new(name: impl Into<String>, status: Status, detail: impl Into<String>) -> Self {
+        Self {
+            name: name.into(),
+            status,
+            detail: detail.into(),
+        }
+    }
+}
+
+impl CliArgs {
+    fn check_gdal(&self, checks: &mut Vec<Check>) {
+        drivers::register();
+        let version = gdal::version::version_info("RELEASE_NAME");
+        checks.push(Check::new(
+            "gdal",
+            Status::Ok,
+            format!("GDAL {version} with {} drivers", DriverManager::count()),
+        ));
+        for name in &self.driver {
+            checks.push(match DriverManager::get_driver_by_name(name) {
+                Ok(_) => Check::new(format!("gdal driver {name}"), Status::Ok, "available"),
+                Err(_) => Check::new(
+                    format!("gdal driver {name}"),
+                    Status::Fail,
+                    "not in this GDAL build; install GDAL with the driver or convert the files with ogr2ogr",
+                ),
+            });
+        }
+    }
+
+    /// Graphviz is only needed to render the figures, so missing
+    /// programs are warnings
+    fn check_graphviz(&self, checks: &mut Vec<Check>) {
+        for program in ["dot", "neato"] {
+            let name = format!("graphviz {program}");
+            checks.push(match Command::new(program).arg("-V").output() {
+                Ok(out) if out.status.success() => {
+                    // graphviz prints the version to stderr
+                    let version = String::from_utf8_lossy(&out.stderr);
+                    Check::new(name, Status::Ok, version.lines().next().unwrap_or_default())
+                }
+                Ok(out) => Check::new(
+                    name,
+                    Status::Warn,
+                    format!("`{program} -V` exited with {}", out.status),
+                ),
+                Err(_) => Check::new(
+                    name,
+                    Status::Warn,
+                    "not found on PATH; install graphviz to render the network figures and the --animate frames",
+                ),
+            });
+        }
+    }
+
+    fn check_usgs(&self, checks: &mut Vec<Check>) {
+        let client = match self.http.client() {
+            Ok(c) => c,
+            Err(e) => {
+                checks.push(Check::new("usgs", Status::Fail, format!("{e:#}")));
+                return;
+            }
+        };
+        for (name, url) in USGS_ENDPOINTS {
+            let name = format!("usgs {name}");
+            let start = Instant::now();
+            let response = client
+                .get(url)
+                .timeout(Duration::from_secs(self.timeout))
+                .send();
+            let elapsed = start.elapsed().as_millis();
+            checks.push(match response {
+                Ok(r) if r.status().is_success() => {
+                    Check::new(name, Status::Ok, format!("{} in {elapsed} ms", r.status()))
+                }
+                Ok(r) => Check::new(
+                    name,
+                    Status::Warn,
+                    format!("{} from {url}; the server may be down, try again later", r.status()),
+                ),
+                Err(e) => Check::new(
+                    name,
+                    Status::Fail,
+                    format!(
+                        "{}; check the internet connection, --proxy, --ca-bundle or the HTTPS_PROXY variable",
+                        if e.is_timeout() {
+                            format!("no response in {} s", self.timeout)
+                        } else {
+                            e.to_string()
+                        }
+                    ),
+                ),
+            });
+        }
+    }
+
+    fn check_data(&self, network: &Path, checks: &mut Vec<Check>) {
+        let net = match self.series.network(&network.to_path_buf()) {
+            Ok(n) => n,
+            Err(e) => {
+                checks.push(Check::new(
+                    "network",
+                    Status::Fail,
+                    format!("{}: {e}", network.display()),
+                ));
+                return;
+            }
+        };
+        checks.push(Check::new(
+            "network",
+            Status::Ok,
+            format!("{} nodes in {}", net.nodes.len(), network.display()),
+        ));
+        let no_attrs: Vec<&str> = net
+            .nodes
+            .iter()
+            .map(|n| n.get_name())
+            .filter(|n| !net.has_attrs_file(n))
+            .collect();
+        checks.push(if no_attrs.is_empty() {
+            Check::new("attributes", Status::Ok, "all nodes have attribute files")
+        } else {
+            Check::new(
+                "attributes",
+                Status::Warn,
+                format!(
+                    "{} nodes without files in the --attrs-dir: {}",
+                    no_attrs.len(),
+                    no_attrs.join(", ")
+                ),
+            )
+        });
+
+        let files: Vec<PathBuf> = net
+            .nodes
+            .iter()
+            .filter_map(|n| self.series.file(n))
+            .collect();
+        if files.is_empty() {
+            checks.push(Check::new(
+                "timeseries",
+                Status::Warn,
+                "no files for the nodes; check the --timeseries template",
+            ));
+        }
+        for path in files.iter().take(self.sample) {
+            let name = format!("timeseries {}", path.display());
+            let values = self.series.load(path).and_then(|ts| ts.values());
+            checks.push(match values {
+                Ok(v) if v.is_empty() => Check::new(name, Status::Warn, "no values"),
+                Ok(v) => Check::new(
+                    name,
+                    Status::Ok,
+                    format!("{} values from {} to {}", v.len(), v[0].0, v[v.len() - 1].0),
+                ),
+                Err(e) => Check::new(
+                    name,
+                    Status::Fail,
+                    format!("{e:#}; check the --datetime-col and --discharge-col"),
+                ),
+            });
+        }
+    }
+}
+
+impl CliAction for CliArgs {
+    fn run(self, global: &GlobalArgs) -> anyhow::Result<()> {
+        let mut checks = Vec::new();
+        self.check_gdal(&mut checks);
+        self.check_graphviz(&mut checks);
+        if !self.offline {
+            self.check_usgs(&mut checks);
+        }
+        if let Some(network) = &self.network {
+            self.check_data(network, &mut checks);
+        }
+
+        let mut table = Table::new();
+        table
+            .load_preset(presets::UTF8_FULL_CONDENSED)
+            .set_header(["Check", "Status", "Details"]);
+        for c in &checks {
+            table.add_row([c.name.clone(), c.status.to_string(), c.detail.clone()]);
+        }
+        let mut writer = global.writer()?;
+        writeln!(writer, "{table}")?;
+        writer.finish()?;
+
+        let count = |s: Status| checks.iter().filter(|c| c.status == s).count();
+        journal::count("checks", checks.len());
+        journal::count("warnings", count(Status::Warn));
+        journal::count("failures", count(Status::Fail));
+        match count(Status::Fail) {
+            0 => Ok(()),
+            n => anyhow::bail!("{n} of the {} checks failed", checks.len()),
+        }
+    }
+}
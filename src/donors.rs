@@ -0,0 +1,115 @@
+use std::io::Write;
+use std::path::PathBuf;
+
+use anyhow::Context;
+use clap::Args;
+
+use crate::cliargs::{CliAction, GlobalArgs, OutputFormat};
+use crate::journal;
+use crate::network::csv_field;
+use crate::timeseries::NodeSeries;
+
+#[derive(Args)]
+pub struct CliArgs {
+    /// Attribute with the drainage area of the nodes
+    #[arg(short, long, default_value = "area")]
+    area_attr: String,
+    // the nodes without the timeseries file are not considered as
+    // donors
+    #[command(flatten)]
+    series: NodeSeries,
+    /// Minimum number of overlapping days to calculate the correlation
+    #[arg(short, long, default_value = "365")]
+    min_overlap: usize,
+    /// Connection file
+    connection_file: PathBuf,
+    /// Name of the target node
+    target: String,
+}
+
+struct Candidate {
+    name: String,
+    distance: Option<usize>,
+    area_ratio: Option<f32>,
+    overlap: usize,
+    correlation: Option<f64>,
+    score: f64,
+}
+
+impl CliAction for CliArgs {
+    fn run(self, global: &GlobalArgs) -> anyhow::Result<()> {
+        let net = self.series.network(&self.connection_file)?;
+        let target = *net
+            .indices
+            .get(&self.target)
+            .with_context(|| format!("Node {} not in the network", self.target))?;
+        let area = |n: usize| {
+            net.nodes[n]
+                .get_attr(&self.area_attr)
+                .and_then(|a| a.read_value())
+        };
+        let target_ts = self
+            .series
+            .file(&net.nodes[target])
+            .map(|path| self.series.load(&path))
+            .transpose()?;
+        if target_ts.is_none() {
+            journal::warn(format!("Target {} doesn't have a timeseries", self.target));
+        }
+        let distances = net.link_distances(target);
+        let mut candidates = Vec::new();
+        for (node, _, ts) in self.series.nodes(&net) {
+            let n = node.get_index();
+            if n == target {
+                continue;
+            }
+            let (overlap, correlation) = match &target_ts {
+                Some(t) => t.overlap_correlation(&ts)?,
+                None => (0, None),
+            };
+            let correlation = correlation.filter(|_| overlap >= self.min_overlap);
+            let distance = distances.get(&n).copied();
+            let area_ratio = area(target).zip(area(n)).map(|(t, d)| t / d);
+            // mean of the scores between 0 and 1: 1/(1+links) for the
+            // network distance, smaller/larger area for the area
+            // similarity and the correlation; missing ones count as 0
+            let score = (distance.map(|d| 1.0 / (1.0 + d as f64)).unwrap_or(0.0)
+                + area_ratio.map(|r| r.min(1.0 / r) as f64).unwrap_or(0.0)
+                + correlation.unwrap_or(0.0).max(0.0))
+                / 3.0;
+            candidates.push(Candidate {
+                name: node.get_name().to_string(),
+                distance,
+                area_ratio,
+                overlap,
+                correlation,
+                score,
+            });
+        }
+        candidates.sort_by(|a, b| b.score.total_cmp(&a.score));
+        journal::count("candidates", candidates.len());
+
+        global.format(&[OutputFormat::Csv])?;
+        let mut writer = global.writer()?;
+        let opt = |v: Option<String>| v.unwrap_or_default();
+        writeln!(
+            writer,
+            "rank,node,distance,area_ratio,overlap,correlation,score"
+        )?;
+        for (i, c) in candidates.iter().enumerate() {
+            writeln!(
+                writer,
+                "{},{},{},{},{},{},{}",
+                i + 1,
+                csv_field(&c.name),
+                opt(c.distance.map(|d| d.to_string())),
+                opt(c.area_ratio.map(|r| r.to_string())),
+                c.overlap,
+                opt(c.correlation.map(|r| r.to_string())),
+                c.score
+            )?;
+        }
+        writer.finish()?;
+        Ok(())
+    }
+}
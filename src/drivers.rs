@@ -0,0 +1,33 @@
+//! GDAL configuration options and the driver registration shared by
+//! the commands
+use std::sync::Once;
+
+use anyhow::Context;
+use gdal::DriverManager;
+
+use crate::errors::NadiError;
+
+static REGISTER: Once = Once::new();
+
+/// Parse the KEY=VALUE of the --gdal-config option
+pub fn parse_config(arg: &str) -> anyhow::Result<(String, String)> {
+    let (key, val) = arg
+        .split_once('=')
+        .context("GDAL config should be in KEY=VALUE format")?;
+    Ok((key.trim().to_string(), val.trim().to_string()))
+}
+
+/// Set the GDAL config options, they have to be set before the
+/// drivers are registered and the files are opened
+pub fn configure(options: &[(String, String)]) -> Result<(), NadiError> {
+    for (key, val) in options {
+        gdal::config::set_config_option(key, val)?;
+    }
+    Ok(())
+}
+
+/// Register the GDAL drivers, only the first call registers them so
+/// it can be called from any thread
+pub fn register() {
+    REGISTER.call_once(DriverManager::register_all);
+}
@@ -0,0 +1,50 @@
+use std::path::{Path, PathBuf};
+
+use serde::de::DeserializeOwned;
+use thiserror::Error;
+
+/// Errors from the network, timeseries and GIS functions; the
+/// subcommands convert them into anyhow errors for printing
+#[allow(clippy::enum_variant_names)]
+#[derive(Error, Debug)]
+pub enum NadiError {
+    #[error(transparent)]
+    IoError(#[from] std::io::Error),
+    #[error("{}:{}: {}", .file.display(), .line, .msg)]
+    ParseError {
+        file: PathBuf,
+        line: usize,
+        msg: String,
+    },
+    #[error("Invalid network: {0}")]
+    TopologyError(String),
+    #[error("{0}")]
+    AttributeError(String),
+    #[error(transparent)]
+    GdalError(#[from] gdal::errors::GdalError),
+    #[error(transparent)]
+    PolarsError(#[from] polars::prelude::PolarsError),
+    #[error(transparent)]
+    HttpError(#[from] reqwest::Error),
+    #[error("Template error: {0}")]
+    TemplateError(String),
+    #[error("{0}")]
+    CommandError(String),
+    #[error("Plugin error: {0}")]
+    PluginError(String),
+    #[error("Script error: {0}")]
+    ScriptError(String),
+}
+
+/// Parse the toml contents of the file, the errors have the line
+/// number of the problem in the file
+pub fn parse_toml<T: DeserializeOwned>(file: &Path, contents: &str) -> Result<T, NadiError> {
+    toml::from_str(contents).map_err(|e| NadiError::ParseError {
+        file: file.to_path_buf(),
+        line: e
+            .span()
+            .map(|s| contents[..s.start].matches('\n').count() + 1)
+            .unwrap_or(0),
+        msg: e.message().to_string(),
+    })
+}
@@ -0,0 +1,199 @@
+use std::path::PathBuf;
+
+use anyhow::Context;
+use clap::{Args, Subcommand};
+use gdal::spatial_ref::{CoordTransform, SpatialRef};
+use gdal::vector::{Feature, Layer, LayerAccess, OGRFieldType};
+use gdal::{Dataset, LayerOptions};
+
+use crate::cliargs::{CliAction, GlobalArgs};
+use crate::connection::{get_driver_by_filename, parse_new_layer};
+use crate::journal;
+use crate::list::{open_layer, parse_layer};
+
+#[derive(Args)]
+pub struct CliArgs {
+    #[command(subcommand)]
+    action: GisAction,
+}
+
+#[derive(Subcommand)]
+enum GisAction {
+    /// Save the features matching the filters as a new layer
+    Filter(FilterArgs),
+    /// Save the layer in another spatial reference system
+    Reproject(ReprojectArgs),
+}
+
+#[derive(Args)]
+struct FilterArgs {
+    /// Only the features matching this attribute filter
+    ///
+    /// OGR SQL where clause, e.g. "STATE = 'OH' AND AREA > 100"
+    #[arg(short, long = "where", value_name = "FILTER")]
+    filter: Option<String>,
+    /// Only the features intersecting the box (MINX,MINY,MAXX,MAXY)
+    #[arg(short, long, value_parser=parse_bbox)]
+    bbox: Option<(f64, f64, f64, f64)>,
+    /// Output driver [default: based on file extension]
+    #[arg(short, long)]
+    driver: Option<String>,
+    /// GIS file to filter
+    #[arg(value_parser=parse_layer, value_name="INPUT_FILE[:LAYER]")]
+    input: (PathBuf, Option<String>),
+}
+
+#[derive(Args)]
+struct ReprojectArgs {
+    /// Target spatial reference, e.g. EPSG:5070 or a proj string
+    #[arg(short, long, value_name = "SRS")]
+    t_srs: String,
+    /// Source spatial reference, if the layer doesn't have it or it is wrong
+    #[arg(short, long, value_name = "SRS")]
+    s_srs: Option<String>,
+    /// Output driver [default: based on file extension]
+    #[arg(short, long)]
+    driver: Option<String>,
+    /// GIS file to reproject
+    #[arg(value_parser=parse_layer, value_name="INPUT_FILE[:LAYER]")]
+    input: (PathBuf, Option<String>),
+}
+
+/// Spatial reference from the definition with the x, y axis order
+/// regardless of the authority's order, as in the GIS files
+pub fn spatial_ref(definition: &str) -> anyhow::Result<SpatialRef> {
+    let srs = SpatialRef::from_definition(definition)
+        .with_context(|| format!("Invalid spatial reference {definition:?}"))?;
+    // SAFETY: the handle is valid for the lifetime of srs
+    unsafe {
+        gdal_sys::OSRSetAxisMappingStrategy(
+            srs.to_c_hsrs(),
+            gdal_sys::OSRAxisMappingStrategy::OAMS_TRADITIONAL_GIS_ORDER,
+        );
+    }
+    Ok(srs)
+}
+
+fn parse_bbox(arg: &str) -> anyhow::Result<(f64, f64, f64, f64)> {
+    let values = arg
+        .split(',')
+        .map(|v| v.trim().parse::<f64>())
+        .collect::<Result<Vec<f64>, _>>()?;
+    match values[..] {
+        [x0, y0, x1, y1] if x0 <= x1 && y0 <= y1 => Ok((x0, y0, x1, y1)),
+        _ => anyhow::bail!("Bounding box should be MINX,MINY,MAXX,MAXY"),
+    }
+}
+
+impl CliAction for CliArgs {
+    fn run(self, global: &GlobalArgs) -> anyhow::Result<()> {
+        match self.action {
+            GisAction::Filter(args) => args.run(global),
+            GisAction::Reproject(args) => args.run(global),
+        }
+    }
+}
+
+impl CliAction for FilterArgs {
+    fn run(self, global: &GlobalArgs) -> anyhow::Result<()> {
+        let data = Dataset::open(&self.input.0)?;
+        journal::input(&self.input.0);
+        let mut input = open_layer(&data, &self.input)?;
+        if let Some(filter) = &self.filter {
+            input
+                .set_attribute_filter(filter)
+                .map_err(|e| anyhow::Error::msg(format!("Invalid filter {filter:?}: {e}")))?;
+        }
+        if let Some((x0, y0, x1, y1)) = self.bbox {
+            input.set_spatial_filter_rect(x0, y0, x1, y1);
+        }
+
+        let output = parse_new_layer(&global.output()?.to_string_lossy())?;
+        save_layer(&mut input, &output, &self.driver, None)?;
+        Ok(())
+    }
+}
+
+impl CliAction for ReprojectArgs {
+    fn run(self, global: &GlobalArgs) -> anyhow::Result<()> {
+        let data = Dataset::open(&self.input.0)?;
+        journal::input(&self.input.0);
+        let mut input = open_layer(&data, &self.input)?;
+        let source = match &self.s_srs {
+            Some(s) => spatial_ref(s)?,
+            None => input
+                .spatial_ref()
+                .context("Input layer doesn't have a spatial reference, use --s-srs")?,
+        };
+        let target = spatial_ref(&self.t_srs)?;
+        let output = parse_new_layer(&global.output()?.to_string_lossy())?;
+        save_layer(&mut input, &output, &self.driver, Some((&source, &target)))?;
+        Ok(())
+    }
+}
+
+/// Copy the features of the layer to a new file, transformed from
+/// the source to the target spatial reference if given
+fn save_layer(
+    input: &mut Layer,
+    output: &(PathBuf, Option<String>),
+    driver: &Option<String>,
+    transform: Option<(&SpatialRef, &SpatialRef)>,
+) -> anyhow::Result<()> {
+    let driver = get_driver_by_filename(&output.0, driver)?;
+    journal::output(&output.0);
+    let mut out_data = driver.create_vector_only(&output.0)?;
+    let srs = match transform {
+        Some((_, target)) => Some(target.clone()),
+        None => input.spatial_ref(),
+    };
+    let ct = transform
+        .map(|(source, target)| CoordTransform::new(source, target))
+        .transpose()?;
+    let ty = input
+        .defn()
+        .geom_fields()
+        .next()
+        .map(|g| g.field_type())
+        .unwrap_or(gdal_sys::OGRwkbGeometryType::wkbUnknown);
+    let output = out_data.create_layer(LayerOptions {
+        name: output.1.as_deref().unwrap_or(&input.name()),
+        srs: srs.as_ref(),
+        ty,
+        ..Default::default()
+    })?;
+    let fields: Vec<(String, OGRFieldType::Type)> = input
+        .defn()
+        .fields()
+        .map(|f| (f.name(), f.field_type()))
+        .collect();
+    let defn: Vec<(&str, OGRFieldType::Type)> =
+        fields.iter().map(|(n, t)| (n.as_str(), *t)).collect();
+    output.create_defn_fields(&defn)?;
+
+    let mut count = 0;
+    let mut no_geom = 0;
+    for feature in input.features() {
+        let mut ft = Feature::new(output.defn())?;
+        // features without geometry are kept with their attributes
+        match (feature.geometry(), &ct) {
+            (Some(geom), Some(ct)) => ft.set_geometry(geom.transform(ct)?)?,
+            (Some(geom), None) => ft.set_geometry(geom.clone())?,
+            (None, _) => no_geom += 1,
+        }
+        for (name, val) in feature.fields() {
+            if let Some(val) = val {
+                ft.set_field(&name, &val)?;
+            }
+        }
+        ft.create(&output)?;
+        count += 1;
+    }
+    if no_geom > 0 {
+        journal::warn(format!(
+            "{no_geom} features without geometry are saved with only the attributes"
+        ));
+    }
+    journal::count("features", count);
+    Ok(())
+}
@@ -0,0 +1,160 @@
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use polars::export::chrono::DateTime;
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+
+/// Start of the metadata lines in the files written by nadi, they
+/// are replaced instead of kept when the files are rewritten
+pub const METADATA_PREFIX: &str = "#:";
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static QUIET: AtomicBool = AtomicBool::new(false);
+static JOURNAL: Mutex<Journal> = Mutex::new(Journal {
+    inputs: Vec::new(),
+    outputs: Vec::new(),
+    counts: BTreeMap::new(),
+    warnings: Vec::new(),
+});
+
+/// Information about the files and the data used in a run
+struct Journal {
+    inputs: Vec<PathBuf>,
+    outputs: Vec<PathBuf>,
+    counts: BTreeMap<String, usize>,
+    warnings: Vec<String>,
+}
+
+/// Start recording the run; nothing is recorded unless the
+/// `--journal` file is given
+pub fn enable() {
+    ENABLED.store(true, Ordering::Relaxed);
+}
+
+/// Only record the warnings without printing them (--quiet)
+pub fn set_quiet(quiet: bool) {
+    QUIET.store(quiet, Ordering::Relaxed);
+}
+
+fn record(f: impl FnOnce(&mut Journal)) {
+    if ENABLED.load(Ordering::Relaxed) {
+        if let Ok(mut journal) = JOURNAL.lock() {
+            f(&mut journal);
+        }
+    }
+}
+
+/// Record a file read by the run
+pub fn input<P: AsRef<Path>>(path: P) {
+    record(|j| j.inputs.push(path.as_ref().to_path_buf()));
+}
+
+/// Record a file written by the run
+pub fn output<P: AsRef<Path>>(path: P) {
+    record(|j| j.outputs.push(path.as_ref().to_path_buf()));
+}
+
+/// Record the number of rows, nodes, etc. processed by the run
+pub fn count(name: &str, n: usize) {
+    record(|j| {
+        j.counts.insert(name.to_string(), n);
+    });
+}
+
+/// Print the warning to stderr and record it
+pub fn warn(msg: String) {
+    if !QUIET.load(Ordering::Relaxed) {
+        eprintln!("{}", msg);
+    }
+    record(|j| j.warnings.push(msg));
+}
+
+fn sha256(path: &Path) -> io::Result<String> {
+    let mut hasher = Sha256::new();
+    io::copy(&mut File::open(path)?, &mut hasher)?;
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+fn files_json(files: &[PathBuf]) -> Value {
+    let mut seen = Vec::new();
+    for file in files {
+        if !seen.contains(&file) {
+            seen.push(file);
+        }
+    }
+    seen.into_iter()
+        .map(|file| {
+            json!({
+                "path": file.to_string_lossy(),
+                "bytes": file.metadata().ok().map(|m| m.len()),
+                "sha256": sha256(file).ok(),
+            })
+        })
+        .collect()
+}
+
+fn unix_time(time: SystemTime) -> f64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs_f64())
+        .unwrap_or_default()
+}
+
+/// UTC date and time as `YYYY-MM-DDTHH:MM:SSZ`
+fn iso_time(time: SystemTime) -> String {
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or_default();
+    DateTime::from_timestamp(secs, 0)
+        .map(|t| t.format("%Y-%m-%dT%H:%M:%SZ").to_string())
+        .unwrap_or_default()
+}
+
+/// Comment lines with the command, date and source files to put at
+/// the top of the text files written by nadi
+pub fn metadata_header(sources: &[&Path]) -> String {
+    let mut header = format!(
+        "{METADATA_PREFIX} nadi {}\n{METADATA_PREFIX} command: {}\n{METADATA_PREFIX} date: {}\n",
+        env!("CARGO_PKG_VERSION"),
+        std::env::args().collect::<Vec<String>>().join(" "),
+        iso_time(SystemTime::now())
+    );
+    for src in sources {
+        header.push_str(&format!("{METADATA_PREFIX} source: {}\n", src.display()));
+    }
+    header
+}
+
+/// Write the recorded information along with the command and its
+/// result as json, inputs and outputs are hashed at this point
+pub fn write(path: &Path, started: SystemTime, result: &anyhow::Result<()>) -> anyhow::Result<()> {
+    let finished = SystemTime::now();
+    let journal = JOURNAL
+        .lock()
+        .map_err(|_| anyhow::Error::msg("Journal lock poisoned"))?;
+    let value = json!({
+        "command": std::env::args().collect::<Vec<String>>(),
+        "version": env!("CARGO_PKG_VERSION"),
+        "working_dir": std::env::current_dir().ok().map(|d| d.to_string_lossy().to_string()),
+        "started": unix_time(started),
+        "finished": unix_time(finished),
+        "duration": finished.duration_since(started).map(|d| d.as_secs_f64()).unwrap_or_default(),
+        "success": result.is_ok(),
+        "error": result.as_ref().err().map(|e| format!("{:#}", e)),
+        "inputs": files_json(&journal.inputs),
+        "outputs": files_json(&journal.outputs),
+        "counts": journal.counts,
+        "warnings": journal.warnings,
+    });
+    let mut writer = BufWriter::new(File::create(path)?);
+    serde_json::to_writer_pretty(&mut writer, &value)?;
+    writeln!(writer)?;
+    writer.flush()?;
+    Ok(())
+}
@@ -0,0 +1,29 @@
+//! Commands of the nadi binary, also used by the benchmarks
+pub mod cliargs;
+pub mod climatology;
+pub mod connection;
+pub mod correlation;
+pub mod doctor;
+pub mod donors;
+pub mod drivers;
+pub mod errors;
+pub mod gis;
+pub mod journal;
+pub mod list;
+pub mod locale;
+pub mod localflow;
+pub mod matching;
+pub mod network;
+pub mod pipeline;
+pub mod plugins;
+pub mod query;
+pub mod report;
+pub mod reservoir;
+pub mod schema;
+pub mod script;
+pub mod synthetic;
+pub mod theme;
+pub mod timeseries;
+pub mod trend;
+pub mod usgs;
+pub mod yeartype;
@@ -1,10 +1,15 @@
-use std::path::PathBuf;
+use std::collections::HashSet;
+use std::io::{IsTerminal, Write};
+use std::path::{Path, PathBuf};
 
+use anyhow::Context;
 use clap::Args;
+use comfy_table::{presets, CellAlignment, Table};
 use gdal::vector::{FieldValue, Layer, LayerAccess};
 use gdal::Dataset;
 
-use crate::cliargs::CliAction;
+use crate::cliargs::{CliAction, GlobalArgs, OutputFormat};
+use crate::journal;
 
 #[derive(Args)]
 pub struct CliArgs {
@@ -12,81 +17,324 @@ pub struct CliArgs {
     #[arg(short, long, default_value = "::")]
     key_sep: String,
     /// variable and value separator
-    #[arg(short, long, default_value = "=")]
+    #[arg(long, default_value = "=")]
     var_sep: String,
     /// Fields to use as id for file
     #[arg(short, long)]
     primary_key: Option<String>,
+    /// Print the statistics of each field instead of the values
+    ///
+    /// Number of values, distinct values, and the min, max and mean
+    /// of the numeric fields
+    #[arg(short, long)]
+    stats: bool,
+    /// Only use the features matching this attribute filter
+    ///
+    /// OGR SQL where clause, e.g. "STATE = 'OH' AND AREA > 100"
+    #[arg(short, long = "where", value_name = "FILTER")]
+    filter: Option<String>,
+    /// Print the geometry types, total length and area, extent and
+    /// the CRS of the layer
+    #[arg(short, long, conflicts_with = "stats")]
+    geometry: bool,
+    /// Summarize the geometries of all the layers in the file
+    #[arg(short, long, requires = "geometry")]
+    all_layers: bool,
     /// GIS file with points of interest
     #[arg(value_parser=parse_layer, value_name="POINTS_FILE[:LAYER]")]
-    file: (PathBuf, String),
+    file: (PathBuf, Option<String>),
 }
 
-fn parse_layer(arg: &str) -> Result<(PathBuf, String), anyhow::Error> {
-    if let Some((path, layer)) = arg.split_once(':') {
-        let data = Dataset::open(path)?;
-        if data.layer_by_name(layer).is_err() {
-            Err(std::io::Error::new(
-                std::io::ErrorKind::NotFound,
-                format!("Layer name {layer} doesn't exist in the file {path}"),
-            )
-            .into())
-        } else {
-            Ok((PathBuf::from(path), layer.to_string()))
+/// GIS file with the optional layer (FILE:LAYER), the layer is the
+/// name or the index of the layer in the file, and is chosen with
+/// [`open_layer`] if not given
+pub fn parse_layer(arg: &str) -> Result<(PathBuf, Option<String>), anyhow::Error> {
+    let (path, layer) = match arg.split_once(':') {
+        Some((path, layer)) => (path, Some(layer.to_string())),
+        None => (arg, None),
+    };
+    let data = Dataset::open(path)?;
+    let layer = match layer {
+        Some(layer) if data.layer_by_name(&layer).is_ok() => Some(layer),
+        Some(layer) => {
+            let names: Vec<String> = data.layers().map(|l| l.name()).collect();
+            match layer.parse::<usize>().ok().and_then(|i| names.get(i)) {
+                Some(name) => Some(name.clone()),
+                None => anyhow::bail!(
+                    "Layer {layer} doesn't exist in the file {path}, the layers are:\n{}",
+                    layer_list(&names)
+                ),
+            }
         }
-    } else {
-        let data = Dataset::open(arg)?;
-        if data.layer_count() == 1 {
-            let layer = data.layer(0)?;
-            Ok((PathBuf::from(&arg), layer.name()))
-        } else {
-            eprintln!("Provide a layer name to choose layer \"FILENAME:LAYERNAME\"");
-            eprintln!("Available Layers:");
-            data.layers().for_each(|l| eprintln!("  {}", l.name()));
-            let layer = data.layer(0)?;
-            Ok((PathBuf::from(&arg), layer.name()))
+        None => None,
+    };
+    Ok((PathBuf::from(path), layer))
+}
+
+/// Layer of the file from FILE:LAYER, the only layer of the file or a
+/// prompt on the terminal, in that order
+pub fn open_layer<'a>(
+    data: &'a Dataset,
+    file: &(PathBuf, Option<String>),
+) -> anyhow::Result<Layer<'a>> {
+    if let Some(name) = &file.1 {
+        return Ok(data.layer_by_name(name)?);
+    }
+    let names: Vec<String> = data.layers().map(|l| l.name()).collect();
+    let index = match names.len() {
+        0 => anyhow::bail!("No layers in the file {:?}", file.0),
+        1 => 0,
+        _ if std::io::stdin().is_terminal() => prompt_layer(&file.0, &names)?,
+        _ => anyhow::bail!(
+            "{:?} has multiple layers, choose one with FILE:LAYER (the layer name or index):\n{}",
+            file.0,
+            layer_list(&names)
+        ),
+    };
+    data.layers().nth(index).context("Couldn't read the layer")
+}
+
+fn layer_list(names: &[String]) -> String {
+    names
+        .iter()
+        .enumerate()
+        .map(|(i, n)| format!("  {i}: {n}"))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+fn prompt_layer(path: &Path, names: &[String]) -> anyhow::Result<usize> {
+    eprintln!("Layers in {:?}:\n{}", path, layer_list(names));
+    let mut line = String::new();
+    loop {
+        eprint!("Layer to use [0-{}]: ", names.len() - 1);
+        line.clear();
+        if std::io::stdin().read_line(&mut line)? == 0 {
+            anyhow::bail!("No layer chosen for {:?}", path);
+        }
+        let choice = line.trim();
+        if let Ok(i) = choice.parse::<usize>() {
+            if i < names.len() {
+                return Ok(i);
+            }
+        } else if let Some(i) = names.iter().position(|n| n == choice) {
+            return Ok(i);
         }
     }
 }
 
 impl CliAction for CliArgs {
-    fn run(self) -> Result<(), anyhow::Error> {
-        let file_data = Dataset::open(&self.file.0).unwrap();
-        let file = file_data.layer_by_name(&self.file.1).unwrap();
-        self.print_attrs(file, &self.primary_key)?;
+    fn run(self, global: &GlobalArgs) -> Result<(), anyhow::Error> {
+        global.format(&[OutputFormat::Csv])?;
+        let file_data = Dataset::open(&self.file.0)?;
+        journal::input(&self.file.0);
+        let mut writer = global.writer()?;
+        if self.all_layers {
+            for mut layer in file_data.layers() {
+                self.apply_filter(&mut layer)?;
+                print_geometry_summary(layer, &mut writer)?;
+                writeln!(writer)?;
+            }
+            writer.finish()?;
+            return Ok(());
+        }
+        let mut file = open_layer(&file_data, &self.file)?;
+        self.apply_filter(&mut file)?;
+        if self.geometry {
+            print_geometry_summary(file, &mut writer)?;
+        } else if self.stats {
+            print_stats(file, &mut writer)?;
+        } else {
+            self.print_attrs(file, &self.primary_key, &mut writer)?;
+        }
+        writer.finish()?;
         Ok(())
     }
 }
 
 impl CliArgs {
-    fn print_attrs(&self, mut lyr: Layer, field: &Option<String>) -> Result<(), anyhow::Error> {
+    fn apply_filter(&self, lyr: &mut Layer) -> anyhow::Result<()> {
+        if let Some(filter) = &self.filter {
+            lyr.set_attribute_filter(filter)
+                .map_err(|e| anyhow::Error::msg(format!("Invalid filter {filter:?}: {e}")))?;
+        }
+        Ok(())
+    }
+
+    fn print_attrs(
+        &self,
+        mut lyr: Layer,
+        field: &Option<String>,
+        writer: &mut impl Write,
+    ) -> Result<(), anyhow::Error> {
         for (i, f) in lyr.features().enumerate() {
             let name = if let Some(name) = field {
                 f.field_as_string_by_name(name)?.unwrap_or("".to_string())
             } else {
                 i.to_string()
             };
-            f.fields().for_each(|(s, v)| {
-                if let Some(val) = v {
-                    match val {
-                        FieldValue::Integer64Value(i) => self.print_single_attr(&name, &s, i),
-                        FieldValue::StringValue(i) => self.print_single_attr(&name, &s, i),
-                        FieldValue::RealValue(i) => self.print_single_attr(&name, &s, i),
-                        FieldValue::DateValue(i) => self.print_single_attr(&name, &s, i),
-                        _ => (),
-                    }
-                }
-            });
+            for (s, v) in f.fields() {
+                let val = match v {
+                    Some(FieldValue::Integer64Value(i)) => i.to_string(),
+                    Some(FieldValue::StringValue(i)) => i,
+                    Some(FieldValue::RealValue(i)) => i.to_string(),
+                    Some(FieldValue::DateValue(i)) => i.to_string(),
+                    _ => continue,
+                };
+                self.print_single_attr(&name, &s, &val, writer)?;
+            }
         }
         Ok(())
     }
 
-    fn print_single_attr<T: ToString>(&self, name: &str, key: &str, val: T) {
-        println!(
-            "{name}{}{key}{}{}",
-            self.key_sep,
-            self.var_sep,
-            val.to_string()
-        );
+    fn print_single_attr(
+        &self,
+        name: &str,
+        key: &str,
+        val: &str,
+        writer: &mut impl Write,
+    ) -> std::io::Result<()> {
+        writeln!(writer, "{name}{}{key}{}{val}", self.key_sep, self.var_sep)
+    }
+}
+
+#[derive(Default)]
+struct FieldStats {
+    count: usize,
+    distinct: HashSet<String>,
+    numbers: usize,
+    sum: f64,
+    min: f64,
+    max: f64,
+}
+
+impl FieldStats {
+    fn add(&mut self, val: &FieldValue) {
+        let (text, num) = match val {
+            FieldValue::IntegerValue(i) => (i.to_string(), Some(*i as f64)),
+            FieldValue::Integer64Value(i) => (i.to_string(), Some(*i as f64)),
+            FieldValue::RealValue(r) => (r.to_string(), Some(*r)),
+            FieldValue::StringValue(s) => (s.clone(), None),
+            FieldValue::DateValue(d) => (d.to_string(), None),
+            FieldValue::DateTimeValue(d) => (d.to_string(), None),
+            _ => return,
+        };
+        self.count += 1;
+        self.distinct.insert(text);
+        if let Some(n) = num {
+            if self.numbers == 0 {
+                (self.min, self.max) = (n, n);
+            }
+            self.numbers += 1;
+            self.sum += n;
+            self.min = self.min.min(n);
+            self.max = self.max.max(n);
+        }
+    }
+}
+
+fn print_stats(mut lyr: Layer, writer: &mut impl Write) -> std::io::Result<()> {
+    let mut features = 0;
+    let mut fields: Vec<(String, FieldStats)> = Vec::new();
+    for f in lyr.features() {
+        features += 1;
+        for (name, val) in f.fields() {
+            let i = match fields.iter().position(|(n, _)| *n == name) {
+                Some(i) => i,
+                None => {
+                    fields.push((name, FieldStats::default()));
+                    fields.len() - 1
+                }
+            };
+            if let Some(val) = val {
+                fields[i].1.add(&val);
+            }
+        }
+    }
+    journal::count("features", features);
+    let mut table = Table::new();
+    table
+        .load_preset(presets::UTF8_FULL_CONDENSED)
+        .set_header(["Field", "Count", "Distinct", "Min", "Max", "Mean"]);
+    for (name, st) in &fields {
+        let num = |v: f64| {
+            if st.numbers > 0 {
+                v.to_string()
+            } else {
+                String::new()
+            }
+        };
+        table.add_row([
+            name.clone(),
+            st.count.to_string(),
+            st.distinct.len().to_string(),
+            num(st.min),
+            num(st.max),
+            num(st.sum / st.numbers.max(1) as f64),
+        ]);
+    }
+    for c in 1..6 {
+        if let Some(col) = table.column_mut(c) {
+            col.set_cell_alignment(CellAlignment::Right);
+        }
+    }
+    writeln!(writer, "{} features", features)?;
+    writeln!(writer, "{table}")
+}
+
+fn print_geometry_summary(mut lyr: Layer, writer: &mut impl Write) -> std::io::Result<()> {
+    let crs = match lyr.spatial_ref() {
+        Some(srs) => {
+            let name = srs.name().unwrap_or_else(|_| "unknown".to_string());
+            match (srs.auth_name(), srs.auth_code()) {
+                (Ok(auth), Ok(code)) => format!("{name} ({auth}:{code})"),
+                _ => name,
+            }
+        }
+        None => "none".to_string(),
+    };
+    writeln!(writer, "Layer: {}", lyr.name())?;
+    writeln!(writer, "CRS: {}", crs)?;
+    let mut features = 0;
+    let mut types: Vec<(String, usize)> = Vec::new();
+    let (mut length, mut area) = (0.0, 0.0);
+    let mut extent: Option<(f64, f64, f64, f64)> = None;
+    for f in lyr.features() {
+        features += 1;
+        let Some(geom) = f.geometry() else {
+            continue;
+        };
+        let name = geom.geometry_name();
+        match types.iter_mut().find(|(t, _)| *t == name) {
+            Some((_, n)) => *n += 1,
+            None => types.push((name, 1)),
+        }
+        length += geom.length();
+        area += geom.area();
+        let env = geom.envelope();
+        extent = Some(match extent {
+            Some((x0, y0, x1, y1)) => (
+                x0.min(env.MinX),
+                y0.min(env.MinY),
+                x1.max(env.MaxX),
+                y1.max(env.MaxY),
+            ),
+            None => (env.MinX, env.MinY, env.MaxX, env.MaxY),
+        });
+    }
+    journal::count("features", features);
+    writeln!(writer, "Features: {}", features)?;
+    for (t, n) in &types {
+        writeln!(writer, "  {}: {}", t, n)?;
+    }
+    if length > 0.0 {
+        writeln!(writer, "Total length: {}", length)?;
+    }
+    if area > 0.0 {
+        writeln!(writer, "Total area: {}", area)?;
+    }
+    if let Some((x0, y0, x1, y1)) = extent {
+        writeln!(writer, "Extent: ({}, {}) - ({}, {})", x0, y0, x1, y1)?;
     }
+    Ok(())
 }
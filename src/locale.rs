@@ -0,0 +1,117 @@
+use clap::{Args, ValueEnum};
+use polars::export::chrono::NaiveDate;
+
+/// Formatting of the numbers and dates in the tables and plots, the
+/// csv and other machine readable outputs are not changed
+#[derive(Args, Clone)]
+pub struct LocaleArgs {
+    /// Locale for the numbers and dates in the tables and plots
+    ///
+    /// Sets the decimal separator, the thousands separator and the
+    /// date format, which can be changed with the options below
+    #[arg(long, rename_all = "lower", value_enum, default_value = "iso")]
+    locale: Locale,
+    /// Decimal separator of the numbers
+    #[arg(long, value_name = "CHAR")]
+    decimal_sep: Option<char>,
+    /// Thousands separator for the numbers with more than four digits
+    /// before the decimal separator
+    #[arg(long, value_name = "SEP")]
+    thousands_sep: Option<String>,
+    /// Format of the dates (e.g. %d.%m.%Y)
+    #[arg(long, value_name = "FORMAT")]
+    date_format: Option<String>,
+}
+
+#[derive(ValueEnum, Clone, Copy)]
+pub enum Locale {
+    /// 1234567.5 and 2000-12-31
+    Iso,
+    /// 1,234,567.5 and 12/31/2000
+    En,
+    /// 1.234.567,5 and 31.12.2000
+    De,
+    /// 1 234 567,5 and 31/12/2000
+    Fr,
+    /// 1.234.567,5 and 31/12/2000
+    Es,
+}
+
+impl Locale {
+    fn separators(&self) -> (char, &'static str, &'static str) {
+        match self {
+            Self::Iso => ('.', "", "%Y-%m-%d"),
+            Self::En => ('.', ",", "%m/%d/%Y"),
+            Self::De => (',', ".", "%d.%m.%Y"),
+            Self::Fr => (',', " ", "%d/%m/%Y"),
+            Self::Es => (',', ".", "%d/%m/%Y"),
+        }
+    }
+}
+
+impl LocaleArgs {
+    pub fn decimal_sep(&self) -> char {
+        self.decimal_sep.unwrap_or(self.locale.separators().0)
+    }
+
+    pub fn thousands_sep(&self) -> &str {
+        self.thousands_sep
+            .as_deref()
+            .unwrap_or(self.locale.separators().1)
+    }
+
+    pub fn date_format(&self) -> &str {
+        self.date_format
+            .as_deref()
+            .unwrap_or(self.locale.separators().2)
+    }
+
+    /// Number with the separators of the locale
+    pub fn number(&self, value: f64) -> String {
+        self.localize(&value.to_string())
+    }
+
+    /// Date in the format of the locale
+    pub fn date(&self, date: NaiveDate) -> String {
+        date.format(self.date_format()).to_string()
+    }
+
+    /// Number or YYYY-MM-DD date in the text with the separators and
+    /// the date format of the locale, other text is unchanged; the
+    /// numbers with leading zeros (like site numbers) are kept as they are
+    ///
+    /// Only use it on the values known to be numbers or dates, text
+    /// like node names can look like numbers too
+    pub fn localize(&self, text: &str) -> String {
+        let trimmed = text.trim();
+        if let Ok(date) = NaiveDate::parse_from_str(trimmed, "%Y-%m-%d") {
+            return self.date(date);
+        }
+        let (sign, digits) = match trimmed.strip_prefix('-') {
+            Some(d) => ("-", d),
+            None => ("", trimmed),
+        };
+        let (int, frac) = digits.split_once('.').unwrap_or((digits, ""));
+        let leading_zero = int.len() > 1 && int.starts_with('0');
+        if int.is_empty()
+            || leading_zero
+            || !int.chars().all(|c| c.is_ascii_digit())
+            || !frac.chars().all(|c| c.is_ascii_digit())
+        {
+            return text.to_string();
+        }
+        let sep = self.thousands_sep();
+        let mut grouped = String::new();
+        for (i, c) in int.chars().enumerate() {
+            if int.len() > 4 && i > 0 && (int.len() - i) % 3 == 0 {
+                grouped.push_str(sep);
+            }
+            grouped.push(c);
+        }
+        if digits.contains('.') {
+            grouped.push(self.decimal_sep());
+            grouped.push_str(frac);
+        }
+        format!("{sign}{grouped}")
+    }
+}
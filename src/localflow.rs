@@ -0,0 +1,210 @@
+use std::collections::{HashMap, HashSet};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use clap::Args;
+use polars::export::chrono::{Duration, NaiveDate};
+use string_template_plus::Template;
+
+use crate::cliargs::{CliAction, GlobalArgs, OutputFormat};
+use crate::journal;
+use crate::network::{csv_field, Network};
+use crate::timeseries::{write_daily, NodeSeries};
+
+#[derive(Args)]
+pub struct CliArgs {
+    #[command(flatten)]
+    series: NodeSeries,
+    /// Template for the local flow csv files written for the nodes
+    ///
+    /// Relative to the connection file directory, the directories are
+    /// created if needed; the nodes without the timeseries file are
+    /// ungauged and their flows are included in the local flows of
+    /// the gauged nodes downstream; the upstream flows are split
+    /// at the diversions and braided branches like in the network
+    /// matrix, and the upstream column notes the partial fractions
+    #[arg(short, long, default_value = "local/{name}.csv", value_parser=Template::parse_template)]
+    local: Template,
+    /// Days the upstream flows take to reach the node
+    #[arg(long, default_value = "0")]
+    lag: i64,
+    /// Attribute of the upstream nodes with their lag in days, the
+    /// nodes without it use --lag
+    #[arg(long)]
+    lag_attr: Option<String>,
+    /// Keep the nodes with multiple outputs, the upstream flows are
+    /// split between the outputs like in the network command
+    #[arg(long)]
+    allow_braided: bool,
+    /// Connection file
+    connection_file: PathBuf,
+}
+
+impl CliArgs {
+    /// Gauged nodes nearest to the node on each of its upstream
+    /// branches, going through the ungauged nodes, with the fraction
+    /// of their flow that reaches the node through the diversions and
+    /// the braided branches
+    fn upstream_gauged(
+        &self,
+        net: &Network,
+        upstream: &[Vec<usize>],
+        index: usize,
+        gauged: &[bool],
+    ) -> Vec<(usize, f64)> {
+        let mut region: HashSet<usize> = HashSet::from([index]);
+        let mut stack = vec![index];
+        while let Some(n) = stack.pop() {
+            if n != index && gauged[n] {
+                continue;
+            }
+            for &u in &upstream[n] {
+                if region.insert(u) {
+                    stack.push(u);
+                }
+            }
+        }
+        // the flow only reaches the node through the ungauged nodes,
+        // so the downstream fractions are calculated first
+        let through = |d: usize| d == index || (region.contains(&d) && !gauged[d]);
+        let mut fraction: HashMap<usize, f64> = HashMap::from([(index, 1.0)]);
+        let mut stack: Vec<(usize, bool)> = region
+            .iter()
+            .filter(|&&n| n != index && gauged[n])
+            .map(|&n| (n, false))
+            .collect();
+        while let Some((n, ready)) = stack.pop() {
+            if fraction.contains_key(&n) {
+                continue;
+            }
+            let shares = net.downstream_shares(n);
+            if ready {
+                let f = shares
+                    .iter()
+                    .filter(|(d, _)| through(*d))
+                    .map(|(d, s)| *s as f64 * fraction.get(d).copied().unwrap_or(0.0))
+                    .sum();
+                fraction.insert(n, f);
+            } else {
+                stack.push((n, true));
+                stack.extend(
+                    shares
+                        .iter()
+                        .filter(|(d, _)| through(*d) && !fraction.contains_key(d))
+                        .map(|(d, _)| (*d, false)),
+                );
+            }
+        }
+        let mut found: Vec<(usize, f64)> = region
+            .into_iter()
+            .filter(|&n| n != index && gauged[n] && fraction[&n] > 0.0)
+            .map(|n| (n, fraction[&n]))
+            .collect();
+        found.sort_unstable_by_key(|(n, _)| *n);
+        found
+    }
+
+    fn lag(&self, net: &Network, index: usize) -> i64 {
+        self.lag_attr
+            .as_ref()
+            .and_then(|a| net.nodes[index].get_attr(a))
+            .and_then(|v| v.read_value())
+            .map(|v| v.round() as i64)
+            .unwrap_or(self.lag)
+    }
+}
+
+impl CliAction for CliArgs {
+    fn run(self, global: &GlobalArgs) -> anyhow::Result<()> {
+        global.format(&[OutputFormat::Csv])?;
+        let net = Network::from_file_attrs(
+            &self.connection_file,
+            self.allow_braided,
+            &self.series.attrs,
+        )?;
+        let mut ts_files: HashMap<usize, PathBuf> = HashMap::new();
+        let mut values: HashMap<usize, HashMap<NaiveDate, f64>> = HashMap::new();
+        for (node, path, ts) in self.series.nodes(&net) {
+            match ts.values() {
+                Ok(v) => {
+                    values.insert(node.get_index(), v.into_iter().collect());
+                    ts_files.insert(node.get_index(), path);
+                }
+                Err(e) => journal::warn(format!("Skipping {}: {e}", node.get_name())),
+            }
+        }
+        // the nodes with the files that can't be read are ungauged
+        let gauged: Vec<bool> = (0..net.nodes.len())
+            .map(|n| values.contains_key(&n))
+            .collect();
+
+        let mut upstream: Vec<Vec<usize>> = vec![Vec::new(); net.nodes.len()];
+        for n in 0..net.nodes.len() {
+            for (d, _) in net.downstream_shares(n) {
+                upstream[d].push(n);
+            }
+        }
+
+        let mut writer = global.writer()?;
+        writeln!(writer, "node,upstream,days,missing_days,negative_days")?;
+        for node in net.nodes.iter().filter(|n| gauged[n.get_index()]) {
+            let n = node.get_index();
+            let gauged_up = self.upstream_gauged(&net, &upstream, n, &gauged);
+            let lags: Vec<i64> = gauged_up.iter().map(|(u, _)| self.lag(&net, *u)).collect();
+            let mut dates: Vec<&NaiveDate> = values[&n].keys().collect();
+            dates.sort();
+            // days without the values of all the upstream nodes are left blank
+            let local: Vec<(NaiveDate, Option<f64>)> = dates
+                .into_iter()
+                .map(|date| {
+                    let inflow: Option<f64> = gauged_up
+                        .iter()
+                        .zip(&lags)
+                        .map(|((u, f), lag)| {
+                            values[u]
+                                .get(&(*date - Duration::days(*lag)))
+                                .map(|v| v * f)
+                        })
+                        .sum::<Option<f64>>();
+                    (*date, inflow.map(|i| values[&n][date] - i))
+                })
+                .collect();
+            let missing = local.iter().filter(|(_, v)| v.is_none()).count();
+            let negative = local
+                .iter()
+                .filter(|(_, v)| v.is_some_and(|v| v < 0.0))
+                .count();
+            if negative > 0 {
+                journal::warn(format!(
+                    "{} days of negative local flows for {}",
+                    negative,
+                    node.get_name()
+                ));
+            }
+            let mut sources: Vec<&Path> = vec![&ts_files[&n]];
+            sources.extend(gauged_up.iter().map(|(u, _)| ts_files[u].as_path()));
+            let columns = [self.series.datetime_col(), &self.series.discharge_col];
+            write_daily(&node.file(&self.local), &sources, columns, &local)?;
+            // the partial flows are written with their fraction
+            let names: Vec<String> = gauged_up
+                .iter()
+                .map(|&(u, f)| match f {
+                    f if (1.0 - f).abs() > 1e-6 => format!("{}*{f:.4}", net.nodes[u].get_name()),
+                    _ => net.nodes[u].get_name().to_string(),
+                })
+                .collect();
+            writeln!(
+                writer,
+                "{},{},{},{},{}",
+                csv_field(node.get_name()),
+                csv_field(&names.join(";")),
+                local.len(),
+                missing,
+                negative
+            )?;
+        }
+        journal::count("nodes", gauged.iter().filter(|g| **g).count());
+        writer.finish()?;
+        Ok(())
+    }
+}
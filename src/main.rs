@@ -1,19 +1,18 @@
-use clap::{Parser, Subcommand};
+use std::process::ExitCode;
+use std::time::SystemTime;
 
-mod cliargs;
-mod connection;
-mod list;
-mod network;
-mod timeseries;
-mod usgs;
+use clap::{Parser, Subcommand};
 
-use crate::cliargs::CliAction;
+use nadi::cliargs::{CliAction, GlobalArgs};
+use nadi::{
+    climatology, connection, correlation, doctor, donors, drivers, gis, journal, list, localflow,
+    matching, network, pipeline, report, reservoir, synthetic, timeseries, trend, usgs, yeartype,
+};
 
 #[derive(Parser)]
 struct Cli {
-    /// Don't print the stderr outputs
-    #[arg(short, long, action)]
-    quiet: bool,
+    #[command(flatten)]
+    global: GlobalArgs,
     /// Command to run
     #[command(subcommand)]
     action: Action,
@@ -27,27 +26,83 @@ enum Action {
     Network(network::CliArgs),
     /// Connection
     Connection(connection::CliArgs),
+    /// Rank the donor stations for a node
+    Donors(donors::CliArgs),
+    /// Pairwise correlation of the node flows with their connection
+    Correlation(correlation::CliArgs),
     /// List
     List(list::CliArgs),
+    /// Match network nodes with USGS sites
+    Match(matching::CliArgs),
     /// Timeseries
     Timeseries(timeseries::CliArgs),
+    /// Local inflow of the nodes, the flow minus the upstream flows
+    LocalFlow(localflow::CliArgs),
+    /// Inflow of the reservoirs from the storage and the releases
+    Reservoir(reservoir::CliArgs),
+    /// Synthetic monthly flows from the Thomas-Fiering or AR(1) model
+    Synthetic(synthetic::CliArgs),
+    /// Mann-Kendall trend of the timeseries
+    Trend(trend::CliArgs),
+    /// Classify the years as dry, normal or wet from the annual flows
+    YearType(yeartype::CliArgs),
+    /// Grid of the mean monthly flows of the nodes in network order (svg)
+    Climatology(climatology::CliArgs),
+    /// PDF report with a page of plots and statistics for each node
+    Report(report::CliArgs),
+    /// Run the out of date steps of a pipeline file
+    Run(pipeline::CliArgs),
+    /// Filter the features of GIS files
+    Gis(gis::CliArgs),
+    /// Check the GDAL drivers, graphviz, USGS servers and data files
+    Doctor(doctor::CliArgs),
 }
 
 impl CliAction for Action {
-    fn run(self) -> anyhow::Result<()> {
+    fn run(self, global: &GlobalArgs) -> anyhow::Result<()> {
         match self {
-            Self::Usgs(v) => v.run(),
-            Self::Network(v) => v.run(),
-            Self::Connection(v) => v.run(),
-            Self::List(v) => v.run(),
-            Self::Timeseries(v) => v.run(),
+            Self::Usgs(v) => v.run(global),
+            Self::Network(v) => v.run(global),
+            Self::Connection(v) => v.run(global),
+            Self::Donors(v) => v.run(global),
+            Self::Correlation(v) => v.run(global),
+            Self::List(v) => v.run(global),
+            Self::Match(v) => v.run(global),
+            Self::Timeseries(v) => v.run(global),
+            Self::LocalFlow(v) => v.run(global),
+            Self::Reservoir(v) => v.run(global),
+            Self::Synthetic(v) => v.run(global),
+            Self::Trend(v) => v.run(global),
+            Self::YearType(v) => v.run(global),
+            Self::Climatology(v) => v.run(global),
+            Self::Report(v) => v.run(global),
+            Self::Run(v) => v.run(global),
+            Self::Gis(v) => v.run(global),
+            Self::Doctor(v) => v.run(global),
         }
     }
 }
 
-fn main() {
+fn main() -> ExitCode {
     let args = Cli::parse();
-    if let Err(e) = args.action.run() {
+    if args.global.journal.is_some() {
+        journal::enable();
+    }
+    journal::set_quiet(args.global.quiet);
+    let started = SystemTime::now();
+    let result = drivers::configure(&args.global.gdal_config)
+        .map_err(anyhow::Error::from)
+        .and_then(|_| args.action.run(&args.global));
+    if let Err(e) = &result {
         eprintln!("{:?}", e);
     }
+    if let Some(path) = &args.global.journal {
+        if let Err(e) = journal::write(path, started, &result) {
+            eprintln!("Couldn't write the journal: {:?}", e);
+        }
+    }
+    match result {
+        Ok(_) => ExitCode::SUCCESS,
+        Err(_) => ExitCode::FAILURE,
+    }
 }
@@ -0,0 +1,139 @@
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::path::PathBuf;
+
+use clap::Args;
+use gdal::vector::LayerAccess;
+use gdal::Dataset;
+
+use crate::cliargs::{CliAction, GlobalArgs, OutputFormat};
+use crate::journal;
+use crate::list::{open_layer, parse_layer};
+use crate::network::{csv_field, AttrsFiles, Network};
+
+#[derive(Args)]
+pub struct CliArgs {
+    /// Field with the site id in the sites file
+    #[arg(short, long, default_value = "identifier")]
+    site_field: String,
+    /// Prefix to strip from the site ids and node names
+    #[arg(short, long, default_value = "USGS-")]
+    prefix: String,
+    /// Node attribute with the x coordinate (longitude)
+    #[arg(short, long, default_value = "lon")]
+    x_attr: String,
+    /// Node attribute with the y coordinate (latitude)
+    #[arg(short, long, default_value = "lat")]
+    y_attr: String,
+    /// Maximum distance for the nearest coordinate match
+    ///
+    /// In the units of the sites file coordinates
+    #[arg(short = 'D', long, default_value = "0.01")]
+    max_distance: f64,
+    #[command(flatten)]
+    attrs: AttrsFiles,
+    /// Connection file
+    connection_file: PathBuf,
+    /// GIS file with the USGS sites
+    #[arg(value_parser=parse_layer, value_name="SITES_FILE[:LAYER]")]
+    sites: (PathBuf, Option<String>),
+}
+
+struct Site {
+    id: String,
+    key: String,
+    point: Option<(f64, f64)>,
+}
+
+/// Key for comparing the ids with the prefix and the leading zeros
+/// removed, case insensitive
+fn site_key(id: &str, prefix: &str) -> String {
+    let id = id.trim().to_lowercase();
+    let prefix = prefix.to_lowercase();
+    id.strip_prefix(&prefix)
+        .unwrap_or(&id)
+        .trim_start_matches('0')
+        .to_string()
+}
+
+impl CliAction for CliArgs {
+    fn run(self, global: &GlobalArgs) -> anyhow::Result<()> {
+        let net = Network::from_file_attrs(&self.connection_file, false, &self.attrs)?;
+        let sites_data = Dataset::open(&self.sites.0)?;
+        journal::input(&self.sites.0);
+        let mut sites_lyr = open_layer(&sites_data, &self.sites)?;
+        let sites: Vec<Site> = sites_lyr
+            .features()
+            .map(|f| {
+                let id = f
+                    .field_as_string_by_name(&self.site_field)?
+                    .unwrap_or_default();
+                let point = f.geometry().map(|g| {
+                    let (x, y, _) = g.get_point(0);
+                    (x, y)
+                });
+                Ok(Site {
+                    key: site_key(&id, &self.prefix),
+                    id,
+                    point,
+                })
+            })
+            .collect::<anyhow::Result<_>>()?;
+        journal::count("sites", sites.len());
+
+        global.format(&[OutputFormat::Csv])?;
+        let mut writer = global.writer()?;
+        writeln!(writer, "node,site,method,distance")?;
+        let mut matched: BTreeMap<&str, Vec<&str>> = BTreeMap::new();
+        for node in &net.nodes {
+            let name = node.get_name();
+            let key = site_key(name, &self.prefix);
+            let coords = node
+                .get_attr(&self.x_attr)
+                .and_then(|x| x.read_value())
+                .zip(node.get_attr(&self.y_attr).and_then(|y| y.read_value()));
+            let site = if let Some(s) = sites.iter().find(|s| s.id == name) {
+                Some((s, "exact", 0.0))
+            } else if let Some(s) = sites.iter().find(|s| !key.is_empty() && s.key == key) {
+                Some((s, "prefix", 0.0))
+            } else if let Some((x, y)) = coords {
+                sites
+                    .iter()
+                    .filter_map(|s| {
+                        s.point
+                            .map(|(sx, sy)| (s, (sx - x as f64).hypot(sy - y as f64)))
+                    })
+                    .filter(|(_, d)| *d <= self.max_distance)
+                    .min_by(|a, b| a.1.total_cmp(&b.1))
+                    .map(|(s, d)| (s, "nearest", d))
+            } else {
+                None
+            };
+            match site {
+                Some((s, method, dist)) => {
+                    writeln!(
+                        writer,
+                        "{},{},{},{}",
+                        csv_field(name),
+                        csv_field(&s.id),
+                        method,
+                        dist
+                    )?;
+                    matched.entry(&s.id).or_default().push(name);
+                }
+                None => journal::warn(format!("Node {} doesn't match any site", name)),
+            }
+        }
+        for (site, nodes) in matched {
+            if nodes.len() > 1 {
+                journal::warn(format!(
+                    "Site {} matched by nodes: {}",
+                    site,
+                    nodes.join(", ")
+                ));
+            }
+        }
+        writer.finish()?;
+        Ok(())
+    }
+}
@@ -1,16 +1,30 @@
 use anyhow::{Context, Error};
-use clap::{Args, ValueEnum};
+use clap::{Args, ValueEnum, ValueHint};
+use percent_encoding::{utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use std::collections::hash_map::Entry;
 use std::collections::VecDeque;
+use std::borrow::Cow;
 use std::fmt;
 use std::fs::File;
-use std::io::{BufRead, BufReader};
+use std::io::{BufRead, BufReader, BufWriter, Write};
 use std::{
-    collections::{HashMap, HashSet},
-    path::PathBuf,
+    collections::{BTreeSet, HashMap, HashSet},
+    path::{Path, PathBuf},
 };
-use string_template_plus::{Render, RenderOptions, Template};
-
-use crate::cliargs::CliAction;
+use string_template_plus::{Render, RenderOptions, Template, TemplatePart};
+use polars::prelude::{CsvReader, PolarsError, SerReader};
+use unicode_width::{UnicodeWidthChar, UnicodeWidthStr};
+
+use crate::cliargs::{CliAction, GlobalArgs};
+use crate::errors::NadiError;
+use crate::journal;
+use crate::locale::LocaleArgs;
+use crate::plugins::{self, Plugin};
+use crate::query::NetworkQuery;
+use crate::schema::Schema;
+use crate::script;
+use crate::theme::Theme;
+use crate::timeseries;
 
 #[derive(Args)]
 pub struct CliArgs {
@@ -27,9 +41,14 @@ pub struct CliArgs {
         requires = "graphviz"
     )]
     direction: GraphVizDirection,
-    /// Shape of the node
-    #[arg(short = 'S', long, requires = "graphviz", default_value = "circle")]
-    node_shape: String,
+    /// Mirror the levels of the graph, the tributaries and the labels
+    /// are drawn on the other side of the main stem (left of a
+    /// vertical graph and below a horizontal one)
+    #[arg(short = 'Y', long, requires = "graphviz")]
+    flip_y: bool,
+    /// Shape of the node [default: circle, or the one from the theme]
+    #[arg(short = 'S', long, requires = "graphviz")]
+    node_shape: Option<String>,
     /// Shape of the label
     #[arg(short = 'O', long, requires = "graphviz", default_value = "1")]
     node_offset: f64,
@@ -37,8 +56,18 @@ pub struct CliArgs {
     #[arg(short = 'A', long, requires = "graphviz", default_value = "plain")]
     label_shape: String,
     /// Shape of the label
-    #[arg(short = 'o', long, requires = "graphviz", default_value = "1")]
+    #[arg(long, requires = "graphviz", default_value = "1")]
     label_offset: f64,
+    /// Position of the node labels
+    #[arg(
+        short = 'P',
+        long,
+        rename_all = "lower",
+        default_value = "right",
+        value_enum,
+        requires = "graphviz"
+    )]
+    label_position: LabelPosition,
     /// size of the node
     #[arg(short = 'N', long, requires = "graphviz", default_value = "30")]
     node_size: usize,
@@ -48,6 +77,12 @@ pub struct CliArgs {
     /// URL Template for Node URL
     #[arg(short, long, default_value = "", value_parser=Template::parse_template)]
     url_template: Template,
+    /// Resolve relative node URLs as file:// links
+    ///
+    /// The paths are relative to the connection file directory, so the
+    /// links work in the SVG/PDF regardless of where they are saved
+    #[arg(short = 'F', long)]
+    file_urls: bool,
     /// Template for Node Label
     #[arg(short, long, default_value = "{index}", value_parser=Template::parse_template)]
     label_template: Template,
@@ -63,10 +98,343 @@ pub struct CliArgs {
     #[arg(short = 'D', long, conflicts_with = "graphviz")]
     debug_print: bool,
     /// Sort by this attribute
+    ///
+    /// Orders the nodes in the graphviz figure, the rows of the LaTeX
+    /// table and the debug print; numbers are compared by their value
+    /// and the nodes without the attribute go at the end
     #[arg(short, long)]
     sort_by: Option<String>,
+    /// Sort in descending order
+    #[arg(long, requires = "sort_by")]
+    descending: bool,
+    /// Color the ASCII graph nodes by this attribute (e.g. order)
+    ///
+    /// The colors are only used when writing to a terminal
+    #[arg(short = 'C', long, conflicts_with = "graphviz")]
+    color_by: Option<String>,
+    /// Use unicode box drawing characters for the ASCII graph
+    #[arg(short = 'U', long, conflicts_with = "graphviz")]
+    unicode: bool,
+    /// Template for a right aligned column in the ASCII graph
+    #[arg(short = 'R', long, conflicts_with = "graphviz", value_parser=Template::parse_template)]
+    right_template: Option<Template>,
+    /// Truncate the labels in the ASCII graph to this width
+    #[arg(long, conflicts_with = "graphviz")]
+    max_label_width: Option<usize>,
+    /// Print at most this many nodes in the ASCII graph
+    ///
+    /// The branches that are left out are shown as a single line
+    /// with the number of nodes in them
+    #[arg(long, conflicts_with = "graphviz")]
+    max_nodes: Option<usize>,
+    /// Print the nodes up to this many links from the outlet in the
+    /// ASCII graph
+    #[arg(long, conflicts_with = "graphviz")]
+    max_depth: Option<usize>,
+    /// Estimate the attributes for the nodes without them
+    ///
+    /// The value is scaled by the drainage area from the closest node
+    /// with the attribute in the network, and the nodes get the
+    /// {VAR}_estimated (0/1) and {VAR}_donor attributes
+    #[arg(short = 'E', long, value_delimiter = ',')]
+    estimate: Vec<String>,
+    /// Attribute with the drainage area of the nodes for --estimate
+    #[arg(long, default_value = "area")]
+    area_attr: String,
+    /// Print the leave-one-out validation of --estimate instead
+    ///
+    /// Each node with the attribute is predicted from its donor, the
+    /// errors are printed as csv after the summary metrics in comments
+    #[arg(long, requires = "estimate")]
+    validate: bool,
+    /// Replace the non numeric values of the --estimate attributes
+    #[arg(long, requires = "estimate")]
+    overwrite: bool,
+    /// Position the graphviz nodes by their lon and lat attributes
+    ///
+    /// The value is the length of a degree in the graph units
+    #[arg(long, requires = "graphviz", value_name = "SCALE")]
+    geo_layout: Option<f64>,
+    /// Draw a scale bar (in km) for the --geo-layout
+    #[arg(long, requires = "geo_layout")]
+    scale_bar: bool,
+    /// Draw a north arrow for the --geo-layout
+    #[arg(long, requires = "geo_layout")]
+    north_arrow: bool,
+    /// Title of the graphviz figure
+    ///
+    /// The network variables like {net.node_count} and the
+    /// environment variables like {env.USER} can be used
+    #[arg(long, requires = "graphviz")]
+    title: Option<String>,
+    /// Subtitle of the graphviz figure, below the title
+    #[arg(long, requires = "title")]
+    subtitle: Option<String>,
+    /// Theme for the graphviz figure and the html site
+    ///
+    /// Builtin themes are default, dark, print and colorblind; or a
+    /// toml file with the background, foreground, font, node_fill,
+    /// node_shape, edge_color, line_width, palette (plot line colors)
+    /// and scale (low and high colors of the node fill) to change
+    /// from the default theme, the colors as #rrggbb
+    #[arg(long, default_value = "default", value_parser=Theme::parse)]
+    theme: Theme,
+    /// File with the graph attributes, styles, etc. inserted as it is
+    /// before the nodes in the graphviz file
+    #[arg(long, requires = "graphviz", value_hint=ValueHint::FilePath)]
+    dot_prelude: Option<PathBuf>,
+    /// File with the packages, commands, etc. inserted as it is in the
+    /// preamble of the LaTeX table
+    #[arg(long, conflicts_with = "graphviz", value_hint=ValueHint::FilePath)]
+    latex_prelude: Option<PathBuf>,
+    /// Write a graphviz frame for each date of the time varying attribute
+    ///
+    /// The nodes are filled with colors by the attribute values from
+    /// their nodes/{name}.csv files; {ATTR} and {frame} (the date) can
+    /// be used in the templates
+    #[arg(long, requires = "graphviz")]
+    animate: Option<String>,
+    /// Directory to save the animation frames in
+    #[arg(long, default_value = "frames", requires = "animate")]
+    frames_dir: PathBuf,
+    /// Format of the frames, other than dot are rendered with neato
+    #[arg(long, default_value = "dot", requires = "animate")]
+    frame_format: String,
+    /// Export a static html site with a page for each node
+    ///
+    /// The index page has the network figure (rendered with neato) and
+    /// the node pages have the attributes, upstream and downstream
+    /// nodes, and the plots of the csv files in --data-dir
+    #[arg(long, requires = "graphviz", value_name = "DIR")]
+    html: Option<PathBuf>,
+    /// Directory with the csv files of the nodes for --html
+    ///
+    /// The files are {name}.csv and {name}/*.csv, relative to the
+    /// connection file directory
+    #[arg(long, default_value = "data", requires = "html")]
+    data_dir: PathBuf,
+    /// Load the node functions from the dynamic library
+    ///
+    /// The functions can be used in the templates as {PLUGIN.FUNC}
+    /// where PLUGIN is the library name without lib prefix and
+    /// extension
+    #[arg(long, value_hint=ValueHint::FilePath)]
+    plugin: Vec<PathBuf>,
+    /// Set the attribute FUNC of the nodes from the plugin function
+    #[arg(long, value_name = "PLUGIN:FUNC", requires = "plugin")]
+    apply: Vec<String>,
+    /// Calculate an attribute from the upstream nodes, as NAME = EXPR
+    ///
+    /// The expression can use the attributes of the node and the
+    /// arrays upstream.ATTR (all upstream nodes) and inputs.ATTR
+    /// (immediate inputs) reduced with sum, min, max, mean or count;
+    /// e.g. "cum_flow = sum(upstream.flow) + flow" or
+    /// "max_dam = max(inputs.max_dam + [dam_height])"
+    #[arg(long, value_name = "NAME = EXPR")]
+    cumulate: Vec<String>,
+    /// Run the rhai script on the network before printing it
+    ///
+    /// The attributes set in the script can be used in the templates
+    #[arg(long, value_hint=ValueHint::FilePath)]
+    script: Option<PathBuf>,
+    /// Validate the node attributes with the schema before running
+    ///
+    /// Toml file with [attrs.NAME] tables that can have type
+    /// (string, integer, float or list), required, min, max, values
+    /// (allowed values) and units (compared to the NAME_units
+    /// attribute)
+    #[arg(long, value_hint=ValueHint::FilePath)]
+    schema: Option<PathBuf>,
+    /// Set the importance and betweenness attributes of the nodes
+    ///
+    /// The importance is the number of nodes upstream of the node and
+    /// the betweenness the number of upstream and downstream node
+    /// pairs with the flow path through it; the attributes already in
+    /// the node files are kept
+    #[arg(long)]
+    importance: bool,
+    /// Attribute with the length of the stream from the node to its output
+    #[arg(long, default_value = "length")]
+    length_attr: String,
+    /// Which input continues the level of its output in the layout
+    #[arg(long, value_enum, default_value = "order")]
+    tributary_order: TributaryOrder,
+    /// Csv file with the attributes of the reaches
+    ///
+    /// The `from` and `to` columns have the node names, the other
+    /// columns are the attributes; they can also be in the
+    /// edges/{from}--{to}.txt files next to the nodes directory
+    #[arg(long, value_hint=ValueHint::FilePath)]
+    edges_csv: Option<PathBuf>,
+    /// Template for the labels of the connections in graphviz
+    ///
+    /// Has the reach attributes and {from} and {to} for the node names
+    #[arg(long, requires = "graphviz", value_parser=Template::parse_template)]
+    edge_template: Option<Template>,
+    /// Write the connection file of the network to this file
+    ///
+    /// The comments of the original file are kept, and a header with
+    /// the command, date and the source file is added
+    #[arg(long, value_hint=ValueHint::FilePath)]
+    write_connections: Option<PathBuf>,
+    /// Write the matrix of the network as csv to this file
+    ///
+    /// The header and the first column have the node names in the
+    /// order of their index
+    #[arg(long, value_hint=ValueHint::FilePath)]
+    export_matrix: Option<PathBuf>,
+    /// Matrix to write with --export-matrix
+    #[arg(
+        long,
+        value_enum,
+        default_value = "adjacency",
+        requires = "export_matrix"
+    )]
+    matrix_type: MatrixType,
+    /// Write the connections as a csv edge list (from,to) to this file
+    #[arg(long, value_hint=ValueHint::FilePath)]
+    export_edges: Option<PathBuf>,
+    /// Attribute for the weight column of --export-edges
+    ///
+    /// The attribute of the reach if it has one, otherwise the
+    /// attribute of the upstream node
+    #[arg(long, requires = "export_edges")]
+    edge_weight: Option<String>,
+    /// Keep the nodes with multiple outputs instead of an error
+    ///
+    /// The first output is used for the tree, the others are drawn as
+    /// dashed lines in graphviz and the cumulative values are split
+    /// equally between the outputs; the nodes get braided (0/1) and
+    /// branches attributes. Nodes with the diversion attribute (1)
+    /// send the split_fraction of the cumulative values to their
+    /// second output and the rest to the first.
+    #[arg(long)]
+    allow_braided: bool,
+    /// Load only the node attributes used by the templates and the
+    /// options instead of all the attributes in the files
+    ///
+    /// Speeds up reading the huge networks; it can't be used with the
+    /// options that can use any attribute (scripts, plugins, schema,
+    /// html site and the queries)
+    #[arg(long, conflicts_with_all = ["script", "cumulate", "schema", "html", "plugin"])]
+    used_attrs: bool,
+    /// Run the shell commands in the templates, e.g. $(git rev-parse HEAD)
+    ///
+    /// They are left as they are by default, so that the templates
+    /// from others can't run commands. Only use it with the templates
+    /// you trust: the commands run with your permissions. The node
+    /// names and attributes in the commands are quoted as single
+    /// arguments, but the commands can still use them in any way.
+    #[arg(long)]
+    allow_shell_templates: bool,
+    #[command(flatten)]
+    attrs: AttrsFiles,
+    #[command(flatten)]
+    locale: LocaleArgs,
     /// Connection file
     connection_file: PathBuf,
+    /// Query the network instead of printing it
+    #[command(subcommand)]
+    query: Option<NetworkQuery>,
+}
+
+/// Location of the node and edge attribute files
+#[derive(Args, Clone)]
+pub struct AttrsFiles {
+    /// Directory of the node attribute files, relative to the
+    /// connection file directory (or the current directory for a
+    /// single timeseries file)
+    ///
+    /// Can be given multiple times, the attributes in the earlier
+    /// directories take precedence over the same ones in the later
+    /// directories; the {name}.csv files of the time varying
+    /// attributes are taken from the first directory that has them
+    #[arg(long, value_name = "DIR", default_value = "nodes")]
+    attrs_dir: Vec<PathBuf>,
+    /// Extension of the node and edge attribute files
+    ///
+    /// The files without extension ({name}) are also read
+    #[arg(long, value_name = "EXT", default_value = "txt")]
+    attrs_ext: String,
+    /// Column with the dates in the {name}.csv files of the time
+    /// varying attributes, and in the timeseries csv files
+    #[arg(long, default_value = "date")]
+    datetime_col: String,
+    /// Names of the attributes to load, all if not set
+    #[arg(skip)]
+    only: Option<HashSet<String>>,
+}
+
+impl Default for AttrsFiles {
+    fn default() -> Self {
+        Self {
+            attrs_dir: vec![PathBuf::from("nodes")],
+            attrs_ext: "txt".to_string(),
+            datetime_col: "date".to_string(),
+            only: None,
+        }
+    }
+}
+
+impl AttrsFiles {
+    /// Attribute file name with the extension
+    fn file_name(&self, name: &str) -> String {
+        match self.attrs_ext.trim_start_matches('.') {
+            "" => name.to_string(),
+            ext => format!("{name}.{ext}"),
+        }
+    }
+
+    pub fn datetime_col(&self) -> &str {
+        &self.datetime_col
+    }
+
+    /// Attribute file of the node in the first --attrs-dir that has it
+    pub fn find_file(&self, name: &str) -> Option<PathBuf> {
+        self.attrs_dir
+            .iter()
+            .flat_map(|d| [d.join(self.file_name(name)), d.join(name)])
+            .find(|f| f.exists())
+    }
+
+    /// Save the attributes in the file of the node in the first
+    /// --attrs-dir, keeping its other attributes
+    pub fn save(&self, name: &str, attrs: Vec<(String, String)>) -> anyhow::Result<PathBuf> {
+        let dir = self
+            .attrs_dir
+            .first()
+            .context("No directory for the attribute files")?;
+        self.save_in(dir, name, attrs)
+    }
+
+    fn save_in(
+        &self,
+        dir: &Path,
+        name: &str,
+        attrs: Vec<(String, String)>,
+    ) -> anyhow::Result<PathBuf> {
+        std::fs::create_dir_all(dir)?;
+        let filename = dir.join(self.file_name(name));
+        timeseries::update_attrs_file(&filename, attrs)?;
+        Ok(filename)
+    }
+
+    /// Whether the attribute is loaded from the files
+    fn keeps(&self, attr: &str) -> bool {
+        match &self.only {
+            Some(only) => only.contains(attr),
+            None => true,
+        }
+    }
+}
+
+fn read_prelude(path: &Option<PathBuf>) -> anyhow::Result<Option<String>> {
+    path.as_ref()
+        .map(|p| {
+            journal::input(p);
+            std::fs::read_to_string(p).with_context(|| format!("Couldn't read {:?}", p))
+        })
+        .transpose()
 }
 
 fn parse_latex_table(arg: &str) -> Result<(String, char, Template), Error> {
@@ -112,15 +480,34 @@ fn parse_latex_table_from_file(filename: &str) -> Result<Vec<(String, char, Temp
 // as well. The link type will use the anek template to open the
 // links. I can make it easy to change link template so the same link
 // can work to open multiple files for me.
+#[derive(Clone)]
 pub struct GraphVizSettings<'a> {
     direction: &'a GraphVizDirection,
     sort_by: &'a Option<String>,
+    descending: bool,
     node_shape: &'a str,
     node_offset: f64,
     label_shape: &'a str,
     label_offset: f64,
+    label_position: &'a LabelPosition,
     node_size: usize,
+    flip_y: bool,
+    file_urls: bool,
+    /// percent-encode the rendered URLs, for the links between the
+    /// pages of the html export
+    encode_urls: bool,
     templates: Templates<'a>,
+    /// attribute to fill the nodes by, with its min and max values
+    fill: Option<(&'a str, f32, f32)>,
+    title: Option<&'a str>,
+    subtitle: Option<&'a str>,
+    prelude: Option<&'a str>,
+    edge: Option<&'a Template>,
+    geo_scale: Option<f64>,
+    scale_bar: bool,
+    north_arrow: bool,
+    theme: &'a Theme,
+    locale: &'a LocaleArgs,
 }
 
 impl<'a> GraphVizSettings<'a> {
@@ -128,16 +515,73 @@ impl<'a> GraphVizSettings<'a> {
         Self {
             direction: &args.direction,
             sort_by: &args.sort_by,
-            node_shape: &args.node_shape,
+            descending: args.descending,
+            node_shape: args
+                .node_shape
+                .as_deref()
+                .or(args.theme.node_shape.as_deref())
+                .unwrap_or("circle"),
             node_offset: args.node_offset,
             label_shape: &args.label_shape,
             label_offset: args.label_offset,
+            label_position: &args.label_position,
             node_size: args.node_size,
+            flip_y: args.flip_y,
+            file_urls: args.file_urls,
+            encode_urls: false,
             templates,
+            fill: None,
+            title: args.title.as_deref(),
+            subtitle: args.subtitle.as_deref(),
+            prelude: None,
+            edge: args.edge_template.as_ref(),
+            geo_scale: args.geo_layout,
+            scale_bar: args.scale_bar,
+            north_arrow: args.north_arrow,
+            theme: &args.theme,
+            locale: &args.locale,
+        }
+    }
+}
+
+struct LatexSettings<'a> {
+    columns: &'a [(String, char, Template)],
+    url: &'a Template,
+    file_urls: bool,
+    sort: Option<(&'a str, bool)>,
+    prelude: Option<&'a str>,
+    locale: &'a LocaleArgs,
+}
+
+pub struct AsciiSettings<'a> {
+    color_by: &'a Option<String>,
+    colors: bool,
+    unicode: bool,
+    right_template: &'a Option<Template>,
+    label: &'a Template,
+    max_label_width: Option<usize>,
+    max_nodes: Option<usize>,
+    max_depth: Option<usize>,
+}
+
+impl<'a> AsciiSettings<'a> {
+    fn new(args: &'a CliArgs, templates: Templates<'a>, colors: bool) -> Self {
+        Self {
+            color_by: &args.color_by,
+            colors,
+            unicode: args.unicode,
+            right_template: &args.right_template,
+            label: templates.label,
+            max_label_width: args.max_label_width,
+            max_nodes: args.max_nodes,
+            max_depth: args.max_depth,
         }
     }
 }
 
+/// ANSI color codes cycled through for the distinct attribute values
+const ANSI_COLORS: [u8; 6] = [31, 32, 33, 34, 35, 36];
+
 #[derive(Clone)]
 struct Templates<'a> {
     node: &'a Template,
@@ -153,42 +597,290 @@ pub enum GraphVizDirection {
     BottomToTop,
     #[value(alias = "rl", alias = "l")]
     RightToLeft,
-    #[value(alias = "rl", alias = "r")]
+    #[value(alias = "lr", alias = "r")]
     LeftToRight,
 }
 
+/// Matrix of the network for --export-matrix
+#[derive(Copy, Clone, PartialEq, Eq, ValueEnum)]
+pub enum MatrixType {
+    /// 1 where the node of the row flows directly into the node of
+    /// the column
+    Adjacency,
+    /// Fraction of the flow of the node of the column reaching the
+    /// node of the row (1 for itself), the product with a column of
+    /// the node values gives the cumulative values
+    Upstream,
+}
+
+/// Which input of a node continues its level in the layout, the
+/// others are drawn as tributaries
+#[derive(Copy, Clone, PartialEq, Eq, ValueEnum)]
+pub enum TributaryOrder {
+    /// Most nodes upstream
+    Order,
+    /// Largest area attribute
+    Area,
+    /// Longest flow path by the length attribute
+    Length,
+    /// First name alphabetically
+    Name,
+    /// First one in the connection file
+    File,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, ValueEnum)]
+pub enum LabelPosition {
+    /// Label inside the node, in place of the node text
+    #[value(alias = "i")]
+    Inline,
+    /// Labels in a separate column after all the nodes
+    #[value(alias = "r")]
+    Right,
+    /// Label below each node
+    #[value(alias = "b")]
+    Below,
+    /// No labels
+    #[value(alias = "n")]
+    None,
+}
+
+impl GraphVizDirection {
+    /// Transform the position from the default layout, where the
+    /// level is in x and the node order is in y, to this direction
+    pub fn position(&self, x: f64, y: f64, max_y: f64) -> (f64, f64) {
+        match self {
+            Self::TopToBottom => (x, y),
+            Self::BottomToTop => (x, max_y - y),
+            Self::RightToLeft => (y, x),
+            Self::LeftToRight => (max_y - y, x),
+        }
+    }
+}
+
+impl CliArgs {
+    /// Node attributes used by the templates and the options
+    fn used_attrs(&self) -> HashSet<String> {
+        // the errors in the titles are reported when they are rendered
+        let titles: Vec<Template> = [&self.title, &self.subtitle]
+            .into_iter()
+            .flatten()
+            .filter_map(|t| Template::parse_template(t).ok())
+            .collect();
+        let templates = self
+            .columns_file
+            .iter()
+            .chain(&self.latex_table)
+            .map(|(_, _, t)| t)
+            .chain([
+                &self.node_template,
+                &self.label_template,
+                &self.url_template,
+            ])
+            .chain(self.right_template.iter())
+            .chain(titles.iter());
+        let mut attrs: HashSet<String> = templates
+            .flat_map(|t| t.parts())
+            .flat_map(|p| p.variables())
+            .map(|v| v.trim_start_matches(['+', '!']).to_string())
+            .collect();
+        attrs.extend(
+            [&self.area_attr, &self.length_attr]
+                .into_iter()
+                .chain(&self.sort_by)
+                .chain(&self.color_by)
+                .chain(&self.estimate)
+                .chain(&self.animate)
+                .chain(&self.edge_weight)
+                .cloned(),
+        );
+        // used by the diversions and the --geo-layout
+        attrs.extend(["diversion", "split_fraction", "lon", "lat"].map(String::from));
+        attrs
+    }
+}
+
 impl CliAction for CliArgs {
-    fn run(self) -> anyhow::Result<()> {
+    fn run(mut self, global: &GlobalArgs) -> anyhow::Result<()> {
+        if self.used_attrs {
+            if self.query.is_some() {
+                anyhow::bail!("--used-attrs can't be used with the queries");
+            }
+            self.attrs.only = Some(self.used_attrs());
+        }
         let templ = Templates {
             node: &self.node_template,
             label: &self.label_template,
             url: &self.url_template,
         };
-        let mut net = Network::from_file(&self.connection_file);
+        let mut net =
+            Network::from_file_attrs(&self.connection_file, self.allow_braided, &self.attrs)?;
+        if let Some(csv) = &self.edges_csv {
+            net.load_edges_csv(csv)?;
+        }
+        if self.tributary_order != TributaryOrder::Order {
+            let ranks =
+                net.tributary_ranks(self.tributary_order, &self.area_attr, &self.length_attr);
+            net.reindex_by(&ranks);
+        }
+        if self.importance {
+            net.importance();
+        }
+        if let Some(path) = &self.write_connections {
+            journal::output(path);
+            let mut writer = BufWriter::new(File::create(path)?);
+            net.write_connections(&mut writer, &[&self.connection_file])?;
+            writer.flush()?;
+        }
+        if let Some(schema) = &self.schema {
+            let violations = Schema::from_file(schema)?.violations(&net);
+            for (node, attr, problem) in &violations {
+                journal::warn(format!("Node {}: attribute {} {}", node, attr, problem));
+            }
+            if !violations.is_empty() {
+                anyhow::bail!("{} violations of the schema {:?}", violations.len(), schema);
+            }
+        }
 
         let mut tab = self.columns_file.clone();
         tab.extend(self.latex_table.clone());
         let mut cumulate = Vec::new();
-        for (_, _, templ) in &tab {
+        let mut time_vars = Vec::new();
+        let mut plugin_vars = Vec::new();
+        let mut env_vars = Vec::new();
+        let titles = [&self.title, &self.subtitle]
+            .into_iter()
+            .flatten()
+            .map(|t| Template::parse_template(t))
+            .collect::<Result<Vec<Template>, _>>()?;
+        let all_templates = tab
+            .iter()
+            .map(|(_, _, t)| t)
+            .chain([templ.node, templ.label, templ.url])
+            .chain(self.right_template.iter())
+            .chain(titles.iter());
+        for templ in all_templates {
             for p in templ.parts() {
                 for v in p.variables() {
                     if v.starts_with("++") || v.starts_with("+!") {
                         cumulate.push(v);
                     }
+                    if v.contains('@') {
+                        time_vars.push(v.trim_start_matches(['+', '!']));
+                    } else if v.contains('.') {
+                        let var = v.trim_start_matches(['+', '!']);
+                        if let Some(name) = var.strip_prefix("env.") {
+                            env_vars.push(name);
+                        } else if !var.starts_with("net.") {
+                            plugin_vars.push(var);
+                        }
+                    }
                 }
             }
         }
+        net.load_time_attrs(&time_vars)?;
+        let plugins = self
+            .plugin
+            .iter()
+            .map(|p| Plugin::load(p))
+            .collect::<Result<Vec<Plugin>, NadiError>>()?;
+        for spec in &self.apply {
+            let (plugin, func) = plugins::find(&plugins, spec)
+                .with_context(|| format!("No plugin for {spec}, use PLUGIN:FUNC"))?;
+            net.apply_plugin(plugin, func, func)?;
+        }
+        for var in plugin_vars {
+            // other variables with dots are left for the templates
+            if let Some((plugin, func)) = plugins::find(&plugins, var) {
+                net.apply_plugin(plugin, func, var)?;
+            }
+        }
+        let mut writer = global.writer()?;
+        if self.validate {
+            for var in &self.estimate {
+                net.validate_estimate(&mut writer, var, &self.area_attr)?;
+            }
+            writer.finish()?;
+            return Ok(());
+        }
+        for var in &self.estimate {
+            net.estimate_ungauged(var, &self.area_attr, self.overwrite)?;
+        }
+        for expr in &self.cumulate {
+            script::cumulate_expression(&mut net, expr)?;
+        }
+        if let Some(script) = &self.script {
+            net = script::run_script(net, script)?;
+        }
         net.cumulate(cumulate)?;
+        net.set_net_vars(&self.area_attr);
+        env_vars.sort();
+        env_vars.dedup();
+        net.set_env_vars(&env_vars);
+        if self.allow_shell_templates {
+            net.allow_shell_commands();
+        }
+        if let Some(title) = &self.title {
+            self.title = Some(net.render_net(title)?);
+        }
+        if let Some(sub) = &self.subtitle {
+            self.subtitle = Some(net.render_net(sub)?);
+        }
+        if let Some(path) = &self.export_matrix {
+            journal::output(path);
+            let mut writer = BufWriter::new(File::create(path)?);
+            net.write_matrix(&mut writer, self.matrix_type)?;
+            writer.flush()?;
+        }
+        if let Some(path) = &self.export_edges {
+            journal::output(path);
+            let mut writer = BufWriter::new(File::create(path)?);
+            net.write_edge_list(&mut writer, self.edge_weight.as_deref())?;
+            writer.flush()?;
+        }
+        if let Some(query) = &self.query {
+            query.run(&net, &self.length_attr, &self.locale, &mut writer)?;
+            writer.finish()?;
+            return Ok(());
+        }
+        let sort = self.sort_by.as_deref().map(|s| (s, self.descending));
+        let dot_prelude = read_prelude(&self.dot_prelude)?;
+        let latex_prelude = read_prelude(&self.latex_prelude)?;
         if self.debug_print {
-            net.simple_print(&templ.label);
+            net.simple_print(&mut writer, templ.label, sort)?;
+        } else if let Some(dir) = &self.html {
+            let mut settings = GraphVizSettings::new(&self, templ);
+            settings.prelude = dot_prelude.as_deref();
+            net.export_html(&mut writer, &settings, dir, &self.data_dir)?;
+        } else if let Some(attr) = &self.animate {
+            let mut settings = GraphVizSettings::new(&self, templ);
+            settings.prelude = dot_prelude.as_deref();
+            net.animation_frames(
+                &mut writer,
+                &settings,
+                attr,
+                &self.frames_dir,
+                &self.frame_format,
+            )?;
         } else if self.graphviz {
-            let settings = GraphVizSettings::new(&self, templ);
-            net.graph_print_dot(&settings);
+            let mut settings = GraphVizSettings::new(&self, templ);
+            settings.prelude = dot_prelude.as_deref();
+            net.graph_print_dot(&mut writer, &settings)?;
         } else if !tab.is_empty() {
-            net.generate_latex_table(&tab, &templ.url);
+            let settings = LatexSettings {
+                columns: &tab,
+                url: templ.url,
+                file_urls: self.file_urls,
+                sort,
+                prelude: latex_prelude.as_deref(),
+                locale: &self.locale,
+            };
+            net.generate_latex_table(&mut writer, &settings)?;
         } else {
-            net.graph_print(&templ.label);
+            let settings = AsciiSettings::new(&self, templ, global.is_terminal());
+            net.graph_print(&mut writer, &settings)?;
         }
+        writer.finish()?;
         Ok(())
     }
 }
@@ -213,6 +905,15 @@ impl fmt::Display for NodeAttr {
 }
 
 impl NodeAttr {
+    /// Numbers with the separators of the locale, the strings (like
+    /// node names or site numbers) are left as they are
+    pub fn localized(&self, locale: &LocaleArgs) -> String {
+        match self {
+            NodeAttr::Number(_) | NodeAttr::Value(_) => locale.localize(&self.to_string()),
+            _ => self.to_string(),
+        }
+    }
+
     pub fn string(val: impl ToString) -> Self {
         Self::String(val.to_string())
     }
@@ -229,6 +930,18 @@ impl NodeAttr {
         Self::Value(val.into())
     }
 
+    /// Number, value or string attribute, whichever the text parses as
+    pub fn parse(val: &str) -> Self {
+        let val = val.trim();
+        if let Ok(n) = val.parse::<usize>() {
+            Self::number(n)
+        } else if let Ok(n) = val.parse::<f32>() {
+            Self::value(n)
+        } else {
+            Self::string(val)
+        }
+    }
+
     pub fn read_string(&self) -> Option<&str> {
         if let Self::String(v) = self {
             Some(v)
@@ -267,7 +980,45 @@ struct GraphNode {
     pre: usize,
     post: usize,
     merge: bool,
+    /// Line in place of the branch left out of the graph
+    truncated: bool,
     text: String,
+    right: String,
+    color: Option<u8>,
+}
+
+/// Shorten the text to the display width with an ellipsis at the end
+fn truncate_width(text: &str, width: usize, ellipsis: &str) -> String {
+    if text.width() <= width {
+        return text.to_string();
+    }
+    let limit = width.saturating_sub(ellipsis.width());
+    let mut short = String::new();
+    let mut w = 0;
+    for c in text.chars() {
+        w += c.width().unwrap_or(0);
+        if w > limit {
+            break;
+        }
+        short.push(c);
+    }
+    if width >= ellipsis.width() {
+        short.push_str(ellipsis);
+    }
+    short
+}
+
+/// Wrap the right-to-left text in a directional isolate so it
+/// doesn't reorder the graph characters around it
+fn isolate_rtl(text: &str) -> String {
+    let rtl = text.chars().any(|c| {
+        matches!(c as u32, 0x0590..=0x08FF | 0xFB1D..=0xFDFF | 0xFE70..=0xFEFF | 0x10800..=0x10FFF | 0x1E800..=0x1EFFF)
+    });
+    if rtl {
+        format!("\u{2068}{text}\u{2069}")
+    } else {
+        text.to_string()
+    }
 }
 
 #[derive(Clone)]
@@ -276,6 +1027,10 @@ pub struct Node {
     name: String,
     inputs: Vec<usize>,
     output: Option<usize>,
+    /// Outputs other than the first one for the braided networks
+    branches: Vec<usize>,
+    /// Line of the connection file with the output of the node
+    line: usize,
     attrs: HashMap<String, NodeAttr>,
     render_ops: RenderOptions,
 }
@@ -293,6 +1048,8 @@ impl Node {
             name: name.clone(),
             inputs: inputs.clone(),
             output,
+            branches: Vec::new(),
+            line: 0,
             attrs: HashMap::new(),
             render_ops: RenderOptions {
                 wd,
@@ -316,6 +1073,33 @@ impl Node {
         self.set_attr("output", NodeAttr::number(output));
     }
 
+    pub fn set_branches(&mut self, branches: Vec<usize>) {
+        self.branches = branches.clone();
+        self.set_attr("braided", NodeAttr::number(!branches.is_empty()));
+        self.set_attr("branches", NodeAttr::vec(branches));
+    }
+
+    pub fn get_branches(&self) -> &[usize] {
+        &self.branches
+    }
+
+    /// Node is marked as a diversion with a non zero diversion attribute
+    pub fn is_diversion(&self) -> bool {
+        self.get_attr("diversion")
+            .and_then(|d| d.read_value())
+            .map(|d| d != 0.0)
+            .unwrap_or(false)
+    }
+
+    /// Fraction of the flow sent to the second output of a diversion
+    pub fn split_fraction(&self) -> Option<f32> {
+        if self.is_diversion() {
+            self.get_attr("split_fraction").and_then(|f| f.read_value())
+        } else {
+            None
+        }
+    }
+
     pub fn set_index(&mut self, index: usize) {
         self.index = index;
         self.set_attr("index", NodeAttr::number(index));
@@ -329,27 +1113,59 @@ impl Node {
         &self.name
     }
 
+    pub fn get_inputs(&self) -> &[usize] {
+        &self.inputs
+    }
+
+    pub fn get_output(&self) -> Option<usize> {
+        self.output
+    }
+
     pub fn get_attr(&self, key: &str) -> Option<&NodeAttr> {
         self.attrs.get(key)
     }
 
-    pub fn load_attrs_from_file(&mut self, filename: PathBuf) -> anyhow::Result<()> {
-        let file = File::open(&filename)?;
-        let reader_lines = BufReader::new(file).lines();
-        for line in reader_lines {
-            let line = line?.trim().to_string();
-            if line.starts_with('#') || line.is_empty() {
-                continue;
-            }
-            if let Some((key, val)) = line.split_once('=') {
-                let val = val.trim();
-                if let Ok(n) = val.parse::<usize>() {
-                    self.set_attr(key.trim(), NodeAttr::number(n));
-                } else if let Ok(n) = val.parse::<f32>() {
-                    self.set_attr(key.trim(), NodeAttr::value(n));
-                } else {
-                    self.set_attr(key.trim(), NodeAttr::string(val.trim()));
-                }
+    pub fn get_attrs(&self) -> &HashMap<String, NodeAttr> {
+        &self.attrs
+    }
+
+    /// Load the attributes in the file that pass the filter
+    pub fn load_attrs_from_file(
+        &mut self,
+        filename: PathBuf,
+        keep: impl Fn(&str) -> bool,
+    ) -> Result<(), NadiError> {
+        for (key, val) in read_attrs_file_with(&filename, keep)? {
+            self.set_attr(&key, val);
+        }
+        Ok(())
+    }
+
+    /// Load the time varying attributes (`attr@period`) from the csv
+    /// file with a date column and a column for each attribute; the
+    /// value is from the first row with the date starting with the
+    /// period (e.g. `storage@2010-06`)
+    pub fn load_time_attrs_from_file(
+        &mut self,
+        filename: PathBuf,
+        variables: &[&str],
+        date_col: &str,
+    ) -> Result<(), NadiError> {
+        let (header, rows, date_col) = read_time_attrs_csv(&filename, date_col)?;
+        journal::input(&filename);
+        for var in variables {
+            let (attr, period) = var.split_once('@').unwrap();
+            let col = match header.iter().position(|c| c == attr) {
+                Some(c) => c,
+                None => continue,
+            };
+            if let Some(val) = rows
+                .iter()
+                .find(|r| r.get(date_col).map(|d| d.starts_with(period)) == Some(true))
+                .and_then(|r| r.get(col))
+                .filter(|v| !v.is_empty())
+            {
+                self.set_attr(var, NodeAttr::parse(val));
             }
         }
         Ok(())
@@ -370,14 +1186,145 @@ impl Node {
     }
 
     pub fn format(&self, template: &Template) -> String {
-        template.render(&self.render_ops).unwrap()
+        render_template(template, &self.render_ops).unwrap()
+    }
+
+    /// Render the template with the number attributes in the format
+    /// of the locale
+    pub fn format_localized(&self, template: &Template, locale: &LocaleArgs) -> String {
+        let mut render_ops = self.render_ops.clone();
+        for (key, val) in &self.attrs {
+            if matches!(val, NodeAttr::Number(_) | NodeAttr::Value(_)) {
+                render_ops
+                    .variables
+                    .insert(key.clone(), val.localized(locale));
+            }
+        }
+        render_template(template, &render_ops).unwrap()
+    }
+
+    /// File of the node from the template, relative to the connection
+    /// file directory
+    pub fn file(&self, template: &Template) -> PathBuf {
+        self.render_ops.wd.join(self.format(template))
+    }
+
+    /// Render the URL template, with `file_urls` the relative paths
+    /// are resolved from the connection file directory to `file://`
+    /// links, URLs with a scheme are left as they are.
+    pub fn format_url(&self, template: &Template, file_urls: bool) -> String {
+        let url = self.format(template);
+        if !file_urls || url.is_empty() || url.contains("://") {
+            return url;
+        }
+        let path = self.render_ops.wd.join(&url);
+        if !path.exists() {
+            journal::warn(format!(
+                "Node {}: linked file {:?} doesn't exist",
+                self.name, path
+            ));
+        }
+        let path = path.canonicalize().unwrap_or_else(|_| {
+            std::env::current_dir()
+                .map(|d| d.join(&path))
+                .unwrap_or(path)
+        });
+        format!("file://{}", encode_path(&path.to_string_lossy()))
+    }
+}
+
+/// Characters of the paths left as they are in the links
+const PATH_CHARS: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'/')
+    .remove(b'-')
+    .remove(b'_')
+    .remove(b'.')
+    .remove(b'~');
+
+/// Position of the nodes in an order where each node comes after all
+/// the nodes it flows into, from the downstream shares of the nodes
+/// and the nodes flowing into them
+fn downstream_first(shares: &[Vec<(usize, f32)>], upstream: &[Vec<usize>]) -> Vec<usize> {
+    let mut remaining: Vec<usize> = shares.iter().map(|s| s.len()).collect();
+    let mut queue: VecDeque<usize> = (0..shares.len()).filter(|&i| remaining[i] == 0).collect();
+    let mut position = vec![usize::MAX; shares.len()];
+    let mut next = 0;
+    while let Some(d) = queue.pop_front() {
+        position[d] = next;
+        next += 1;
+        for &u in &upstream[d] {
+            remaining[u] -= 1;
+            if remaining[u] == 0 {
+                queue.push_back(u);
+            }
+        }
+    }
+    position
+}
+
+/// Quote the text as a single argument for sh
+fn shell_quote(text: &str) -> String {
+    format!("'{}'", text.replace('\'', r"'\''"))
+}
+
+/// Shell command parts with the variables replaced by their quoted
+/// values, so the attribute values can't add to the commands
+fn quote_command_vars(part: &TemplatePart, op: &RenderOptions) -> TemplatePart {
+    let quote_vars = |parts: &[TemplatePart]| -> Vec<TemplatePart> {
+        parts
+            .iter()
+            .map(|p| match p {
+                TemplatePart::Var(..) => match p.render(op) {
+                    Ok(v) => TemplatePart::Lit(shell_quote(&v)),
+                    // the error is reported when the command is rendered
+                    Err(_) => p.clone(),
+                },
+                p => quote_command_vars(p, op),
+            })
+            .collect()
+    };
+    match part {
+        TemplatePart::Cmd(parts) => TemplatePart::Cmd(quote_vars(parts)),
+        TemplatePart::Any(parts) => {
+            TemplatePart::Any(parts.iter().map(|p| quote_command_vars(p, op)).collect())
+        }
+        p => p.clone(),
+    }
+}
+
+/// Render the template, with the variables in the shell commands
+/// quoted when the commands are run
+fn render_template(template: &Template, op: &RenderOptions) -> anyhow::Result<String> {
+    if !op.shell_commands {
+        return template.render(op);
     }
+    template
+        .parts()
+        .iter()
+        .map(|p| quote_command_vars(p, op).render(op))
+        .collect()
+}
+
+/// Percent-encode the path for a link
+pub fn encode_path(path: &str) -> String {
+    utf8_percent_encode(path, PATH_CHARS).to_string()
 }
 
 #[derive(Clone)]
 pub struct Network {
     pub indices: HashMap<String, usize>,
     pub nodes: Vec<Node>,
+    /// Comments of the connection file with the line they are before,
+    /// the `#:` metadata lines are not kept
+    comments: Vec<(usize, String)>,
+    /// Attributes of the reaches between the nodes, by the names of
+    /// the nodes on both ends
+    edges: HashMap<(String, String), HashMap<String, NodeAttr>>,
+    /// Directories of the node attribute files, in the order of
+    /// precedence
+    attrs_dirs: Vec<PathBuf>,
+    /// Extension and date column of the attribute files
+    attrs: AttrsFiles,
 }
 
 fn insert_ifnot_node(
@@ -392,38 +1339,114 @@ fn insert_ifnot_node(
 }
 
 impl Network {
-    pub fn from_file(filename: &PathBuf) -> Self {
+    pub fn from_file(filename: &PathBuf) -> Result<Self, NadiError> {
+        Self::read_connections(filename, false, &AttrsFiles::default())
+    }
+
+    /// Read the network keeping the nodes with multiple outputs
+    /// (anabranches), the first output of the node is used for the
+    /// tree and the others are kept as its branches
+    pub fn from_file_braided(filename: &PathBuf) -> Result<Self, NadiError> {
+        Self::read_connections(filename, true, &AttrsFiles::default())
+    }
+
+    /// Read the network with the attributes from the given
+    /// directories and extension instead of nodes/{name}.txt
+    pub fn from_file_attrs(
+        filename: &PathBuf,
+        braided: bool,
+        attrs: &AttrsFiles,
+    ) -> Result<Self, NadiError> {
+        Self::read_connections(filename, braided, attrs)
+    }
+
+    fn read_connections(
+        filename: &PathBuf,
+        braided: bool,
+        attrs: &AttrsFiles,
+    ) -> Result<Self, NadiError> {
         // first read the file contents and fill the node indices,
         // inputs and outputs for those nodes.
         let mut indices: HashMap<String, usize> = HashMap::new();
         let mut inputs: Vec<Vec<usize>> = Vec::new();
         let mut output_map: HashMap<usize, usize> = HashMap::new();
-        let file = File::open(filename).unwrap();
+        let mut branch_map: HashMap<usize, Vec<usize>> = HashMap::new();
+        let mut lines: HashMap<usize, usize> = HashMap::new();
+        let mut multiple_outputs: Vec<String> = Vec::new();
+        let mut comments: Vec<(usize, String)> = Vec::new();
+        let mut pending: Vec<String> = Vec::new();
+        let file = File::open(filename)?;
+        journal::input(filename);
         let reader = BufReader::new(file);
-        for line in reader.lines() {
-            let line = line.unwrap().trim().to_string();
-            if line.is_empty() || line.starts_with('#') {
+        for (i, line) in reader.lines().enumerate() {
+            let line = line?.trim().to_string();
+            if line.starts_with('#') {
+                if !line.starts_with(journal::METADATA_PREFIX) {
+                    pending.push(line);
+                }
                 continue;
             }
+            if line.is_empty() {
+                continue;
+            }
+            comments.extend(pending.drain(..).map(|c| (i + 1, c)));
             if let Some((inp, out)) = line.split_once("->") {
                 let inp = inp.trim();
                 let out = out.trim();
                 // eprintln!("{} {}", inp, out);
                 insert_ifnot_node(&mut indices, &mut inputs, inp);
                 insert_ifnot_node(&mut indices, &mut inputs, out);
+                if let Some(o) = output_map.get(&indices[inp]) {
+                    if *o != indices[out] {
+                        if braided {
+                            let branches = branch_map.entry(indices[inp]).or_default();
+                            if !branches.contains(&indices[out]) {
+                                branches.push(indices[out]);
+                            }
+                        } else {
+                            multiple_outputs.push(format!("{} (line {})", inp, i + 1));
+                        }
+                    }
+                    continue;
+                }
                 output_map.insert(indices[inp], indices[out]);
+                lines.insert(indices[inp], i + 1);
                 inputs[indices[out]].push(indices[inp])
             } else {
                 insert_ifnot_node(&mut indices, &mut inputs, &line);
+                lines.entry(indices[&line]).or_insert(i + 1);
             }
         }
+        comments.extend(pending.into_iter().map(|c| (usize::MAX, c)));
+
+        if !multiple_outputs.is_empty() {
+            return Err(NadiError::TopologyError(format!(
+                "Nodes with multiple outputs: {}; use --allow-braided to keep them",
+                multiple_outputs.join(", ")
+            )));
+        }
 
         let names: HashMap<usize, String> =
             indices.clone().into_iter().map(|(k, v)| (v, k)).collect();
-        let nodes_attrs_dir = filename
+        let links = |n: usize| {
+            output_map
+                .get(&n)
+                .into_iter()
+                .chain(branch_map.get(&n).into_iter().flatten())
+                .copied()
+        };
+        if let Some(cycle) = find_loop(names.len(), links) {
+            let cycle: Vec<&str> = cycle.iter().map(|n| names[n].as_str()).collect();
+            return Err(NadiError::TopologyError(format!(
+                "Loop in the network: {}",
+                cycle.join(" -> ")
+            )));
+        }
+        let wd = filename
             .parent()
             .unwrap_or(&PathBuf::from("."))
-            .join("nodes/");
+            .to_path_buf();
+        let attrs_dirs: Vec<PathBuf> = attrs.attrs_dir.iter().map(|d| wd.join(d)).collect();
         let nodes: Vec<Node> = inputs
             .into_iter()
             .enumerate()
@@ -433,43 +1456,606 @@ impl Network {
                     names[&i].clone(),
                     input,
                     output_map.get(&i).copied(),
-                    filename
-                        .parent()
-                        .unwrap_or(&PathBuf::from("."))
-                        .to_path_buf(),
+                    wd.clone(),
                 );
-                n.load_attrs_from_file(nodes_attrs_dir.join(format!("{}.txt", n.name)))
-                    .ok();
-                n.load_attrs_from_file(nodes_attrs_dir.join(format!("{}", n.name)))
-                    .ok();
+                // later files overwrite the attributes of the earlier
+                for dir in attrs_dirs.iter().rev() {
+                    n.load_attrs_from_file(dir.join(attrs.file_name(&n.name)), |a| attrs.keeps(a))
+                        .ok();
+                    n.load_attrs_from_file(dir.join(&n.name), |a| attrs.keeps(a))
+                        .ok();
+                }
+                if let Some(branches) = branch_map.remove(&i) {
+                    n.set_branches(branches);
+                }
+                n.line = lines.get(&i).copied().unwrap_or(0);
                 n
             })
             .collect::<Vec<Node>>();
-        let mut net = Self { indices, nodes };
+        // the diversions only have their second output in the braided
+        // networks
+        let diversions = nodes.iter().filter(|n| n.is_diversion());
+        if !braided {
+            let count = diversions.count();
+            if count > 0 {
+                journal::warn(format!(
+                    "{count} diversion nodes are treated as normal nodes without --allow-braided"
+                ));
+            }
+        } else {
+            for node in diversions {
+                if node.branches.len() != 1 {
+                    return Err(NadiError::TopologyError(format!(
+                        "Diversion {} should have two outputs",
+                        node.name
+                    )));
+                }
+                match node.split_fraction() {
+                    Some(f) if (0.0..=1.0).contains(&f) => (),
+                    _ => {
+                        return Err(NadiError::AttributeError(format!(
+                            "Diversion {} needs split_fraction between 0 and 1",
+                            node.name
+                        )))
+                    }
+                }
+            }
+        }
+        let edges_attrs_dir = wd.join("edges");
+        let mut edges = HashMap::new();
+        for node in &nodes {
+            for o in node.output.iter().chain(node.branches.iter()) {
+                let key = (node.name.clone(), nodes[*o].name.clone());
+                let file = edges_attrs_dir.join(attrs.file_name(&format!("{}--{}", key.0, key.1)));
+                if let Ok(attrs) = read_attrs_file(&file) {
+                    edges.insert(key, attrs.into_iter().collect());
+                }
+            }
+        }
+        let mut net = Self {
+            indices,
+            nodes,
+            comments,
+            edges,
+            attrs_dirs,
+            attrs: attrs.clone(),
+        };
         net.order();
         net.reindex();
-        net
+        journal::count("nodes", net.nodes.len());
+        Ok(net)
     }
 
-    pub fn order(&mut self) {
-        let mut all_nodes: HashSet<usize> = (0..self.nodes.len()).collect();
-        let mut order_queue: Vec<usize> = Vec::with_capacity(self.nodes.len());
-        loop {
-            if all_nodes.is_empty() && order_queue.is_empty() {
-                break;
-            }
+    /// Attribute of the reach from the node to the output
+    pub fn edge_attr(&self, from: usize, to: usize, attr: &str) -> Option<&NodeAttr> {
+        self.edges
+            .get(&(self.nodes[from].name.clone(), self.nodes[to].name.clone()))
+            .and_then(|e| e.get(attr))
+    }
 
-            if order_queue.is_empty() {
-                let elem = *all_nodes.iter().next().unwrap();
-                order_queue.push(elem);
-                all_nodes.remove(&elem);
-            }
+    /// Attribute of the reach from the node to its output, the node
+    /// attribute is used if the reach doesn't have it
+    pub fn reach_attr(&self, node: usize, attr: &str) -> Option<&NodeAttr> {
+        self.nodes[node]
+            .output
+            .and_then(|o| self.edge_attr(node, o, attr))
+            .or_else(|| self.nodes[node].get_attr(attr))
+    }
 
-            let n = order_queue.pop().unwrap();
-            let node: &Node = &self.nodes[n];
-            if node.inputs.is_empty() {
-                self.nodes[n].set_attr("order", NodeAttr::Number(1));
-            } else {
+    /// Load the reach attributes from a csv file with `from` and `to`
+    /// columns for the node names and a column for each attribute
+    pub fn load_edges_csv(&mut self, filename: &PathBuf) -> Result<(), NadiError> {
+        let (header, rows) = read_csv(filename)?;
+        journal::input(filename);
+        let col = |name: &str| {
+            header
+                .iter()
+                .position(|c| c == name)
+                .ok_or_else(|| NadiError::ParseError {
+                    file: filename.clone(),
+                    line: 1,
+                    msg: format!("No {name} column"),
+                })
+        };
+        let (from, to) = (col("from")?, col("to")?);
+        for row in rows.iter().filter(|r| r.len() > from.max(to)) {
+            let connected = match (self.indices.get(&row[from]), self.indices.get(&row[to])) {
+                (Some(&f), Some(&t)) => {
+                    self.nodes[f].output == Some(t) || self.nodes[f].branches.contains(&t)
+                }
+                _ => false,
+            };
+            if !connected {
+                return Err(NadiError::AttributeError(format!(
+                    "No connection {} -> {} in the network for the edge attributes",
+                    row[from], row[to]
+                )));
+            }
+            let attrs = self
+                .edges
+                .entry((row[from].clone(), row[to].clone()))
+                .or_default();
+            for (i, val) in row.iter().enumerate() {
+                if i != from && i != to && i < header.len() && !val.is_empty() {
+                    attrs.insert(header[i].clone(), NodeAttr::parse(val));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Render the template for the reach with its attributes and the
+    /// `from` and `to` node names
+    pub fn format_edge(
+        &self,
+        from: usize,
+        to: usize,
+        template: &Template,
+    ) -> Result<String, NadiError> {
+        let (f, t) = (&self.nodes[from], &self.nodes[to]);
+        let mut variables: HashMap<String, String> = self
+            .edges
+            .get(&(f.name.clone(), t.name.clone()))
+            .map(|e| e.iter().map(|(k, v)| (k.clone(), v.to_string())).collect())
+            .unwrap_or_default();
+        variables.insert("from".to_string(), f.name.clone());
+        variables.insert("to".to_string(), t.name.clone());
+        let op = RenderOptions {
+            wd: f.render_ops.wd.clone(),
+            variables,
+            shell_commands: f.render_ops.shell_commands,
+        };
+        render_template(template, &op)
+            .map_err(|e| NadiError::TemplateError(format!("{} -> {}: {}", f.name, t.name, e)))
+    }
+
+    /// Write the connections in the order of the file they were read
+    /// from along with its comments, and a metadata header with the
+    /// command and the source files
+    pub fn write_connections(
+        &self,
+        writer: &mut impl Write,
+        sources: &[&Path],
+    ) -> Result<(), NadiError> {
+        write!(writer, "{}", journal::metadata_header(sources))?;
+        let mut nodes: Vec<&Node> = self.nodes.iter().collect();
+        // nodes not from the file go at the end
+        nodes.sort_by_key(|n| if n.line == 0 { usize::MAX } else { n.line });
+        let mut comments = self.comments.iter().peekable();
+        for node in nodes {
+            while let Some((_, c)) = comments.next_if(|(l, _)| *l <= node.line) {
+                writeln!(writer, "{c}")?;
+            }
+            match node.output {
+                Some(o) => writeln!(writer, "{} -> {}", node.name, self.nodes[o].name)?,
+                None if node.inputs.is_empty() => writeln!(writer, "{}", node.name)?,
+                None => (),
+            }
+            for &b in &node.branches {
+                writeln!(writer, "{} -> {}", node.name, self.nodes[b].name)?;
+            }
+        }
+        for (_, c) in comments {
+            writeln!(writer, "{c}")?;
+        }
+        Ok(())
+    }
+
+    /// Write the adjacency or upstream matrix as csv with the node
+    /// names in the header and the first column, one row at a time
+    pub fn write_matrix(
+        &self,
+        writer: &mut impl Write,
+        matrix: MatrixType,
+    ) -> Result<(), NadiError> {
+        let n = self.nodes.len();
+        let shares: Vec<Vec<(usize, f32)>> = (0..n).map(|i| self.downstream_shares(i)).collect();
+        let mut upstream: Vec<Vec<usize>> = vec![Vec::new(); n];
+        for (i, outs) in shares.iter().enumerate() {
+            for (o, _) in outs {
+                upstream[*o].push(i);
+            }
+        }
+        let order = downstream_first(&shares, &upstream);
+        let mut row = vec![0.0f32; n];
+        let mut visited = vec![false; n];
+
+        write!(writer, "node")?;
+        for node in &self.nodes {
+            write!(writer, ",{}", csv_field(node.get_name()))?;
+        }
+        writeln!(writer)?;
+        for node in &self.nodes {
+            let i = node.index;
+            let touched = match matrix {
+                MatrixType::Adjacency => {
+                    let outs: Vec<usize> = shares[i].iter().map(|(o, _)| *o).collect();
+                    for &o in &outs {
+                        row[o] = 1.0;
+                    }
+                    outs
+                }
+                MatrixType::Upstream => {
+                    // nodes upstream of this one, in the order where the
+                    // downstream nodes come first so their fractions
+                    // are known before the nodes flowing into them
+                    let mut reached = vec![i];
+                    let mut queue = VecDeque::from([i]);
+                    visited[i] = true;
+                    while let Some(d) = queue.pop_front() {
+                        for &u in &upstream[d] {
+                            if !visited[u] {
+                                visited[u] = true;
+                                reached.push(u);
+                                queue.push_back(u);
+                            }
+                        }
+                    }
+                    row[i] = 1.0;
+                    reached[1..].sort_by_key(|u| order[*u]);
+                    for &u in &reached[1..] {
+                        row[u] = shares[u].iter().map(|(d, s)| s * row[*d]).sum();
+                    }
+                    reached
+                }
+            };
+            write!(writer, "{}", csv_field(node.get_name()))?;
+            for v in &row {
+                write!(writer, ",{v}")?;
+            }
+            writeln!(writer)?;
+            for t in touched {
+                row[t] = 0.0;
+                visited[t] = false;
+            }
+        }
+        Ok(())
+    }
+
+    /// Write the connections as a csv edge list with the optional
+    /// weight attribute of the reaches or the upstream nodes
+    pub fn write_edge_list(
+        &self,
+        writer: &mut impl Write,
+        weight: Option<&str>,
+    ) -> Result<(), NadiError> {
+        match weight {
+            Some(attr) => writeln!(writer, "from,to,{}", csv_field(attr))?,
+            None => writeln!(writer, "from,to")?,
+        }
+        for node in &self.nodes {
+            for &o in node.output.iter().chain(&node.branches) {
+                write!(
+                    writer,
+                    "{},{}",
+                    csv_field(&node.name),
+                    csv_field(&self.nodes[o].name)
+                )?;
+                if let Some(attr) = weight {
+                    let val = self
+                        .edge_attr(node.index, o, attr)
+                        .or_else(|| node.get_attr(attr))
+                        .map(|v| v.to_string())
+                        .unwrap_or_default();
+                    write!(writer, ",{}", csv_field(&val))?;
+                }
+                writeln!(writer)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Write a graphviz frame for each date in the time varying
+    /// attribute files of the nodes, the node fill colors are scaled
+    /// between the minimum and maximum value of all the frames
+    pub fn animation_frames(
+        &mut self,
+        writer: &mut impl Write,
+        settings: &GraphVizSettings,
+        attr: &str,
+        dir: &PathBuf,
+        format: &str,
+    ) -> Result<(), NadiError> {
+        let mut series: Vec<HashMap<String, f32>> = Vec::with_capacity(self.nodes.len());
+        let mut dates: Vec<String> = Vec::new();
+        for node in &self.nodes {
+            let mut values = HashMap::new();
+            if let Some(filename) = self.time_attrs_file(&node.name) {
+                let (header, rows, date_col) =
+                    read_time_attrs_csv(&filename, &self.attrs.datetime_col)?;
+                if let Some(col) = header.iter().position(|c| c == attr) {
+                    for row in rows {
+                        if let (Some(d), Some(v)) = (
+                            row.get(date_col),
+                            row.get(col).and_then(|v| v.parse::<f32>().ok()),
+                        ) {
+                            dates.push(d.to_string());
+                            values.insert(d.to_string(), v);
+                        }
+                    }
+                }
+            }
+            series.push(values);
+        }
+        dates.sort();
+        dates.dedup();
+        let all_values = series.iter().flat_map(|s| s.values());
+        let min = all_values.clone().fold(f32::INFINITY, |a, b| a.min(*b));
+        let max = all_values.fold(f32::NEG_INFINITY, |a, b| a.max(*b));
+        let settings = GraphVizSettings {
+            fill: Some((attr, min, max)),
+            ..settings.clone()
+        };
+        std::fs::create_dir_all(dir)?;
+        for (i, date) in dates.iter().enumerate() {
+            for (node, values) in self.nodes.iter_mut().zip(&series) {
+                node.set_attr("frame", NodeAttr::string(date));
+                match values.get(date) {
+                    Some(v) => node.set_attr(attr, NodeAttr::value(*v)),
+                    None => node.set_attr(attr, NodeAttr::string("")),
+                }
+            }
+            let filename = dir.join(format!("{:05}.{}", i, format));
+            journal::output(&filename);
+            if format == "dot" {
+                let mut file = BufWriter::new(File::create(&filename)?);
+                self.graph_print_dot(&mut file, &settings)?;
+                file.flush()?;
+            } else {
+                let mut dot = Vec::new();
+                self.graph_print_dot(&mut dot, &settings)?;
+                let mut neato = std::process::Command::new("neato")
+                    .arg("-n")
+                    .arg(format!("-T{format}"))
+                    .arg("-o")
+                    .arg(&filename)
+                    .stdin(std::process::Stdio::piped())
+                    .spawn()
+                    .map_err(|e| {
+                        NadiError::CommandError(format!(
+                            "Couldn't run neato to render the frames: {e}"
+                        ))
+                    })?;
+                neato.stdin.take().unwrap().write_all(&dot)?;
+                if !neato.wait()?.success() {
+                    return Err(NadiError::CommandError(format!(
+                        "neato failed to render {:?}",
+                        filename
+                    )));
+                }
+            }
+            writeln!(writer, "{}", filename.to_string_lossy())?;
+        }
+        Ok(())
+    }
+
+    /// Export the network as a static html site in the directory, see
+    /// the --html option
+    pub fn export_html(
+        &self,
+        writer: &mut impl Write,
+        settings: &GraphVizSettings,
+        dir: &Path,
+        data_dir: &Path,
+    ) -> Result<(), NadiError> {
+        if self.nodes.is_empty() {
+            return Ok(());
+        }
+        std::fs::create_dir_all(dir.join("nodes"))?;
+        let url = Template::parse_template("nodes/{name}.html")
+            .map_err(|e| NadiError::TemplateError(e.to_string()))?;
+        let settings = GraphVizSettings {
+            templates: Templates {
+                url: &url,
+                ..settings.templates.clone()
+            },
+            file_urls: false,
+            encode_urls: true,
+            ..settings.clone()
+        };
+        let mut dot = Vec::new();
+        self.graph_print_dot(&mut dot, &settings)?;
+        std::fs::write(dir.join("network.dot"), &dot)?;
+        journal::output(dir.join("network.dot"));
+        let figure = match std::process::Command::new("neato")
+            .arg("-n")
+            .arg("-Tsvg")
+            .arg("-o")
+            .arg(dir.join("network.svg"))
+            .stdin(std::process::Stdio::piped())
+            .spawn()
+        {
+            Ok(mut neato) => {
+                neato.stdin.take().unwrap().write_all(&dot)?;
+                neato.wait()?.success()
+            }
+            Err(_) => false,
+        };
+        if figure {
+            journal::output(dir.join("network.svg"));
+        } else {
+            journal::warn(
+                "Couldn't render the network with neato, linking the dot file".to_string(),
+            );
+        }
+
+        let title = html_escape(settings.title.unwrap_or("Network"));
+        journal::output(dir.join("index.html"));
+        let mut index = BufWriter::new(File::create(dir.join("index.html"))?);
+        let css = settings.theme.css();
+        writeln!(index, "<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\"><title>{title}</title>{css}</head><body>")?;
+        writeln!(index, "<h1>{title}</h1>")?;
+        if figure {
+            writeln!(
+                index,
+                "<object data=\"network.svg\" type=\"image/svg+xml\"></object>"
+            )?;
+        } else {
+            writeln!(index, "<p><a href=\"network.dot\">network.dot</a></p>")?;
+        }
+        writeln!(index, "<ul>")?;
+        for node in &self.nodes {
+            writeln!(
+                index,
+                "<li><a href=\"nodes/{}.html\">{}</a></li>",
+                encode_path(&node.name),
+                html_escape(&node.name)
+            )?;
+        }
+        writeln!(index, "</ul>\n</body></html>")?;
+        index.flush()?;
+
+        let node_link = |n: usize| {
+            let name = &self.nodes[n].name;
+            format!(
+                "<a href=\"{}.html\">{}</a>",
+                encode_path(name),
+                html_escape(name)
+            )
+        };
+        let data_dir = self.nodes[0].render_ops.wd.join(data_dir);
+        for node in &self.nodes {
+            let page_file = dir.join("nodes").join(format!("{}.html", node.name));
+            journal::output(&page_file);
+            let mut page = BufWriter::new(File::create(&page_file)?);
+            let name = html_escape(&node.name);
+            writeln!(page, "<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\"><title>{name}</title>{css}</head><body>")?;
+            writeln!(
+                page,
+                "<p><a href=\"../index.html\">{title}</a></p>\n<h1>{name}</h1>"
+            )?;
+            writeln!(page, "<h2>Attributes</h2>\n<table>")?;
+            let mut keys: Vec<&String> = node.attrs.keys().collect();
+            keys.sort();
+            for key in keys {
+                writeln!(
+                    page,
+                    "<tr><td>{}</td><td>{}</td></tr>",
+                    html_escape(key),
+                    html_escape(&node.attrs[key].localized(settings.locale))
+                )?;
+            }
+            writeln!(page, "</table>")?;
+            let upstream: Vec<String> = node.inputs.iter().map(|&i| node_link(i)).collect();
+            writeln!(page, "<h2>Upstream</h2>\n<p>{}</p>", upstream.join(", "))?;
+            let mut downstream = Vec::new();
+            let mut out = node.output;
+            while let Some(o) = out {
+                downstream.push(node_link(o));
+                out = self.nodes[o].output;
+            }
+            writeln!(
+                page,
+                "<h2>Downstream</h2>\n<p>{}</p>",
+                downstream.join(" &rarr; ")
+            )?;
+
+            let mut csvs = vec![data_dir.join(format!("{}.csv", node.name))];
+            if let Ok(entries) = std::fs::read_dir(data_dir.join(&node.name)) {
+                let mut files: Vec<PathBuf> = entries
+                    .filter_map(|e| e.ok().map(|e| e.path()))
+                    .filter(|p| p.extension().map(|e| e == "csv").unwrap_or(false))
+                    .collect();
+                files.sort();
+                csvs.extend(files);
+            }
+            for csv in csvs.iter().filter(|c| c.exists()) {
+                let (header, rows) = read_csv(csv)?;
+                journal::input(csv);
+                writeln!(
+                    page,
+                    "<h2>{}</h2>\n{}",
+                    html_escape(&csv.file_name().unwrap().to_string_lossy()),
+                    svg_line_plot(&header, &rows, settings.theme, settings.locale)
+                )?;
+            }
+            writeln!(page, "</body></html>")?;
+            page.flush()?;
+        }
+        writeln!(writer, "{}", dir.join("index.html").to_string_lossy())?;
+        Ok(())
+    }
+
+    /// Load the time varying attributes of the nodes from their
+    /// `nodes/{name}.csv` files, see [`Node::load_time_attrs_from_file`]
+    pub fn load_time_attrs(&mut self, variables: &[&str]) -> Result<(), NadiError> {
+        if variables.is_empty() {
+            return Ok(());
+        }
+        for i in 0..self.nodes.len() {
+            if let Some(filename) = self.time_attrs_file(&self.nodes[i].name) {
+                let date_col = &self.attrs.datetime_col;
+                self.nodes[i].load_time_attrs_from_file(filename, variables, date_col)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Csv file of the time varying attributes of the node from the
+    /// first attribute directory that has it
+    fn time_attrs_file(&self, name: &str) -> Option<PathBuf> {
+        self.attrs_dirs
+            .iter()
+            .map(|d| d.join(format!("{name}.csv")))
+            .find(|f| f.exists())
+    }
+
+    /// Whether any of the attribute directories has a file of the node
+    pub fn has_attrs_file(&self, name: &str) -> bool {
+        self.attrs_dirs
+            .iter()
+            .any(|d| d.join(self.attrs.file_name(name)).exists() || d.join(name).exists())
+    }
+
+    /// Save the attributes in the file of the node in the first
+    /// attribute directory, keeping its other attributes
+    pub fn save_attrs(&self, name: &str, attrs: Vec<(String, String)>) -> anyhow::Result<()> {
+        let dir = self
+            .attrs_dirs
+            .first()
+            .context("No directory for the attribute files")?;
+        self.attrs.save_in(dir, name, attrs)?;
+        Ok(())
+    }
+
+    /// Set the attribute of the nodes to the values returned by the
+    /// plugin function
+    pub fn apply_plugin(
+        &mut self,
+        plugin: &Plugin,
+        func: &str,
+        attr: &str,
+    ) -> Result<(), NadiError> {
+        for node in self.nodes.iter_mut() {
+            let mut attrs: Vec<String> =
+                node.attrs.iter().map(|(k, v)| format!("{k}={v}")).collect();
+            attrs.sort();
+            if let Some(val) = plugin.call(func, &node.name, &attrs.join("\n"))? {
+                node.set_attr(attr, NodeAttr::parse(&val));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn order(&mut self) {
+        let mut all_nodes: BTreeSet<usize> = (0..self.nodes.len()).collect();
+        let mut order_queue: Vec<usize> = Vec::with_capacity(self.nodes.len());
+        loop {
+            if all_nodes.is_empty() && order_queue.is_empty() {
+                break;
+            }
+
+            if order_queue.is_empty() {
+                let elem = *all_nodes.iter().next().unwrap();
+                order_queue.push(elem);
+                all_nodes.remove(&elem);
+            }
+
+            let n = order_queue.pop().unwrap();
+            let node: &Node = &self.nodes[n];
+            if node.inputs.is_empty() {
+                self.nodes[n].set_attr("order", NodeAttr::Number(1));
+            } else {
                 let uncalc_inputs: Vec<&usize> = node
                     .inputs
                     .iter()
@@ -499,7 +2085,52 @@ impl Network {
         }
     }
 
-    pub fn cumulate(&mut self, variables: Vec<&str>) -> Result<(), Error> {
+    /// Set the `importance` (number of upstream nodes routed through
+    /// the node) and `betweenness` (number of upstream and downstream
+    /// node pairs with the flow path through the node) attributes,
+    /// following all the outputs of the braided nodes; the nodes that
+    /// already have the attributes keep them
+    pub fn importance(&mut self) {
+        let mut upstream = vec![0usize; self.nodes.len()];
+        let mut downstream = vec![0usize; self.nodes.len()];
+        // the branches can join again, so the nodes reached are
+        // collected to not count them twice
+        for (i, down) in downstream.iter_mut().enumerate() {
+            let mut reached: HashSet<usize> = HashSet::new();
+            let mut stack = vec![i];
+            while let Some(n) = stack.pop() {
+                for (o, _) in self.downstream_shares(n) {
+                    if reached.insert(o) {
+                        stack.push(o);
+                    }
+                }
+            }
+            *down = reached.len();
+            for o in reached {
+                upstream[o] += 1;
+            }
+        }
+        let mut kept = 0;
+        for (i, node) in self.nodes.iter_mut().enumerate() {
+            for (key, value) in [
+                ("importance", upstream[i]),
+                ("betweenness", upstream[i] * downstream[i]),
+            ] {
+                if node.get_attr(key).is_some() {
+                    kept += 1;
+                } else {
+                    node.set_attr(key, NodeAttr::number(value));
+                }
+            }
+        }
+        if kept > 0 {
+            journal::warn(format!(
+                "{kept} importance or betweenness attributes from the node files are kept"
+            ));
+        }
+    }
+
+    pub fn cumulate(&mut self, variables: Vec<&str>) -> Result<(), NadiError> {
         if self.nodes.is_empty() {
             return Ok(());
         }
@@ -515,25 +2146,479 @@ impl Network {
             get_values(&cl, var, safe, &mut values)?;
             for node in &cl.nodes {
                 let val = *values.get(node.get_name()).unwrap();
-                let mut out = node.output;
-                loop {
-                    if let Some(o) = out {
-                        let mut v = *values.get(cl.nodes[o].get_name()).unwrap();
-                        v += val;
-                        values.insert(cl.nodes[o].get_name(), v);
-                        out = cl.nodes[o].output;
-                    } else {
-                        break;
-                    }
+                let mut downstream = cl.downstream_shares(node.index);
+                while let Some((o, share)) = downstream.pop() {
+                    *values.get_mut(cl.nodes[o].get_name()).unwrap() += val * share;
+                    downstream.extend(
+                        cl.downstream_shares(o)
+                            .into_iter()
+                            .map(|(d, s)| (d, s * share)),
+                    );
                 }
             }
             set_cum_values(self, vec!["cum_", pre], var, &values);
+            if safe {
+                // the missing values are taken as zero, so the counts
+                // tell which cumulative values are incomplete
+                let missing = cl.missing_counts(var);
+                for (node, n) in self.nodes.iter_mut().zip(missing) {
+                    node.set_attr(&format!("cum_{var}_n_missing"), NodeAttr::number(n));
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Number of nodes without a value of the attribute at or upstream
+    /// of each node, the nodes reaching it through multiple branches
+    /// are counted once
+    fn missing_counts(&self, var: &str) -> Vec<usize> {
+        let mut counts = vec![0; self.nodes.len()];
+        for node in &self.nodes {
+            if node.get_attr(var).and_then(|v| v.read_value()).is_some() {
+                continue;
+            }
+            let mut visited = HashSet::from([node.index]);
+            let mut stack = vec![node.index];
+            while let Some(i) = stack.pop() {
+                counts[i] += 1;
+                for (o, share) in self.downstream_shares(i) {
+                    if share > 0.0 && visited.insert(o) {
+                        stack.push(o);
+                    }
+                }
+            }
+        }
+        counts
+    }
+
+    /// Outputs of the node with the fraction of the flow going to
+    /// them, diversions send their split_fraction to the second
+    /// output and the other braided nodes split it equally
+    pub fn downstream_shares(&self, index: usize) -> Vec<(usize, f32)> {
+        let node = &self.nodes[index];
+        if let (Some(main), [diverted], Some(fraction)) =
+            (node.output, node.branches.as_slice(), node.split_fraction())
+        {
+            let fraction = fraction.clamp(0.0, 1.0);
+            return vec![(main, 1.0 - fraction), (*diverted, fraction)];
+        }
+        let outputs: Vec<usize> = node.output.iter().chain(&node.branches).copied().collect();
+        let share = 1.0 / outputs.len() as f32;
+        outputs.into_iter().map(|o| (o, share)).collect()
+    }
+
+    /// Find the closest node (by the number of links) that has both
+    /// the attribute and the area, and the value estimated from it by
+    /// the drainage area ratio; the donors at the same distance are
+    /// chosen by the closest area
+    pub fn area_ratio_donor(
+        &self,
+        index: usize,
+        var: &str,
+        area_attr: &str,
+    ) -> Option<(usize, f32)> {
+        let value = |n: &Node, key: &str| n.get_attr(key).and_then(|v| v.read_value());
+        let area = value(&self.nodes[index], area_attr)?;
+        let mut visited: HashSet<usize> = HashSet::from([index]);
+        let mut current: Vec<usize> = vec![index];
+        while !current.is_empty() {
+            let mut next = Vec::new();
+            for n in current {
+                let n = &self.nodes[n];
+                for &nb in n.inputs.iter().chain(n.output.iter()) {
+                    if visited.insert(nb) {
+                        next.push(nb);
+                    }
+                }
+            }
+            let donor = next
+                .iter()
+                .filter_map(|&n| {
+                    let node = &self.nodes[n];
+                    value(node, var)
+                        .zip(value(node, area_attr))
+                        .filter(|(_, a)| *a > 0.0)
+                        .map(|(v, a)| (n, v, area / a))
+                })
+                .min_by(|a, b| a.2.ln().abs().total_cmp(&b.2.ln().abs()))
+                .map(|(n, v, ratio)| (n, v * ratio));
+            if donor.is_some() {
+                return donor;
+            }
+            current = next;
+        }
+        None
+    }
+
+    /// Number of links from the node to all the nodes connected to it,
+    /// ignoring the direction of the links
+    pub fn link_distances(&self, index: usize) -> HashMap<usize, usize> {
+        let mut distances: HashMap<usize, usize> = HashMap::from([(index, 0)]);
+        let mut queue: VecDeque<usize> = VecDeque::from([index]);
+        while let Some(n) = queue.pop_front() {
+            let dist = distances[&n] + 1;
+            let node = &self.nodes[n];
+            for &nb in node.inputs.iter().chain(node.output.iter()) {
+                if let Entry::Vacant(e) = distances.entry(nb) {
+                    e.insert(dist);
+                    queue.push_back(nb);
+                }
+            }
+        }
+        distances
+    }
+
+    /// Strahler order of the nodes, the headwaters are order 1 and
+    /// the order increases where two inputs of the highest order meet
+    pub fn strahler_orders(&self) -> Vec<usize> {
+        let mut orders = vec![1; self.nodes.len()];
+        // inputs have larger indices than the nodes they flow into
+        for i in (0..self.nodes.len()).rev() {
+            let mut inputs: Vec<usize> = self.nodes[i].inputs.iter().map(|&n| orders[n]).collect();
+            inputs.sort_unstable();
+            orders[i] = match inputs.as_slice() {
+                [] => 1,
+                [.., a, b] if a == b => b + 1,
+                [.., b] => *b,
+            };
+        }
+        orders
+    }
+
+    /// Make the values for the whole network available to the
+    /// templates of every node as `{net.node_count}`,
+    /// `{net.total_area}` (sum over the outlets) and
+    /// `{net.outlet_name}`
+    pub fn set_net_vars(&mut self, area_attr: &str) {
+        let outlets: Vec<&Node> = self.nodes.iter().filter(|n| n.output.is_none()).collect();
+        let mut vars = vec![
+            ("net.node_count", self.nodes.len().to_string()),
+            (
+                "net.outlet_name",
+                outlets
+                    .iter()
+                    .map(|n| n.name.as_str())
+                    .collect::<Vec<&str>>()
+                    .join(", "),
+            ),
+        ];
+        let areas: Vec<f32> = outlets
+            .iter()
+            .filter_map(|n| n.get_attr(area_attr).and_then(|a| a.read_value()))
+            .collect();
+        if !areas.is_empty() {
+            vars.push(("net.total_area", areas.iter().sum::<f32>().to_string()));
+        }
+        for node in self.nodes.iter_mut() {
+            for (k, v) in &vars {
+                node.render_ops.variables.insert(k.to_string(), v.clone());
+            }
+        }
+    }
+
+    /// Set the environment variables as the env.NAME variables of the
+    /// templates, the unset ones are left out
+    pub fn set_env_vars(&mut self, names: &[&str]) {
+        let vars: Vec<(String, String)> = names
+            .iter()
+            .filter_map(|n| match std::env::var(n) {
+                Ok(v) => Some((format!("env.{n}"), v)),
+                Err(_) => {
+                    journal::warn(format!("Environment variable {n} is not set"));
+                    None
+                }
+            })
+            .collect();
+        for node in self.nodes.iter_mut() {
+            for (k, v) in &vars {
+                node.render_ops.variables.insert(k.clone(), v.clone());
+            }
+        }
+    }
+
+    /// Run the shell commands in the templates of the nodes and edges
+    pub fn allow_shell_commands(&mut self) {
+        for node in self.nodes.iter_mut() {
+            node.render_ops.shell_commands = true;
+        }
+    }
+
+    /// Render the text with the network variables, for the titles
+    pub fn render_net(&self, text: &str) -> Result<String, NadiError> {
+        let Some(node) = self.nodes.first() else {
+            return Ok(text.to_string());
+        };
+        let templ =
+            Template::parse_template(text).map_err(|e| NadiError::TemplateError(e.to_string()))?;
+        render_template(&templ, &node.render_ops)
+            .map_err(|e| NadiError::TemplateError(e.to_string()))
+    }
+
+    /// Basin descriptors of the network as (metric, value)
+    pub fn metrics(&self, length_attr: &str, area_attr: &str) -> Vec<(String, String)> {
+        let mut metrics = Vec::new();
+        let mut add = |m: &str, v: String| metrics.push((m.to_string(), v));
+        let outlets: Vec<&Node> = self.nodes.iter().filter(|n| n.output.is_none()).collect();
+        add("Nodes", self.nodes.len().to_string());
+        add(
+            "Headwaters",
+            self.nodes
+                .iter()
+                .filter(|n| n.inputs.is_empty())
+                .count()
+                .to_string(),
+        );
+        add("Outlets", outlets.len().to_string());
+        if self.nodes.is_empty() {
+            return metrics;
+        }
+
+        let orders = self.strahler_orders();
+        let max_order = orders.iter().copied().max().unwrap_or(0);
+        add("Strahler order", max_order.to_string());
+        // a stream of order k ends where the order changes downstream
+        let streams: Vec<usize> = (1..=max_order)
+            .map(|k| {
+                self.nodes
+                    .iter()
+                    .filter(|n| {
+                        orders[n.index] == k && n.output.map(|o| orders[o] != k).unwrap_or(true)
+                    })
+                    .count()
+            })
+            .collect();
+        let mut ratios = Vec::new();
+        for k in 1..max_order {
+            add(&format!("Streams of order {k}"), streams[k - 1].to_string());
+            if streams[k] > 0 {
+                let ratio = streams[k - 1] as f32 / streams[k] as f32;
+                add(
+                    &format!("Bifurcation ratio {}/{}", k, k + 1),
+                    format!("{:.2}", ratio),
+                );
+                ratios.push(ratio);
+            }
+        }
+        add(
+            &format!("Streams of order {max_order}"),
+            streams[max_order - 1].to_string(),
+        );
+        if !ratios.is_empty() {
+            let mean = ratios.iter().sum::<f32>() / ratios.len() as f32;
+            add("Mean bifurcation ratio", format!("{:.2}", mean));
+        }
+
+        // the farthest node from any node is an end of the diameter
+        let diameter = outlets
+            .iter()
+            .filter_map(|o| {
+                let dist = self.link_distances(o.index);
+                let (&far, _) = dist.iter().max_by_key(|(_, d)| **d)?;
+                self.link_distances(far).into_values().max()
+            })
+            .max()
+            .unwrap_or(0);
+        add("Diameter (links)", diameter.to_string());
+
+        let length = |n: &Node| {
+            self.reach_attr(n.index, length_attr)
+                .and_then(|l| l.read_value())
+        };
+        let has_length = self.nodes.iter().any(|n| length(n).is_some());
+        let mut links = vec![0usize; self.nodes.len()];
+        let mut path_length = vec![0.0f32; self.nodes.len()];
+        for i in 1..self.nodes.len() {
+            if let Some(o) = self.nodes[i].output {
+                links[i] = links[o] + 1;
+                path_length[i] = path_length[o] + length(&self.nodes[i]).unwrap_or(0.0);
+            }
         }
+        add(
+            "Longest flow path (links)",
+            links.iter().max().unwrap_or(&0).to_string(),
+        );
+        if has_length {
+            let longest = path_length.iter().copied().fold(0.0, f32::max);
+            add("Longest flow path (length)", longest.to_string());
+            let total: f32 = self.nodes.iter().filter_map(length).sum();
+            add("Total length", total.to_string());
+            let area: Option<f32> = outlets
+                .iter()
+                .map(|o| o.get_attr(area_attr).and_then(|a| a.read_value()))
+                .sum();
+            if let Some(area) = area.filter(|a| *a > 0.0) {
+                add("Drainage density", (total / area).to_string());
+            }
+        }
+        metrics
+    }
 
+    /// Estimate the attribute for the nodes without it, see
+    /// [`Network::area_ratio_donor`]; the nodes with a non numeric
+    /// value of the attribute are only estimated with `overwrite`
+    pub fn estimate_ungauged(
+        &mut self,
+        var: &str,
+        area_attr: &str,
+        overwrite: bool,
+    ) -> Result<(), NadiError> {
+        let mut estimates: Vec<(usize, f32, usize)> = Vec::new();
+        for node in &self.nodes {
+            match node.get_attr(var) {
+                Some(v) if v.read_value().is_some() => continue,
+                Some(v) if !overwrite => {
+                    return Err(NadiError::AttributeError(format!(
+                        "Node {} has a non numeric {} ({}), use --overwrite to replace it",
+                        node.name, var, v
+                    )))
+                }
+                _ => (),
+            }
+            if node.get_attr(area_attr).is_none() {
+                journal::warn(format!(
+                    "Node {} doesn't have attribute {}",
+                    node.name, area_attr
+                ));
+                continue;
+            }
+            match self.area_ratio_donor(node.index, var, area_attr) {
+                Some((d, v)) => estimates.push((node.index, v, d)),
+                None => journal::warn(format!("Node {} has no donor for {}", node.name, var)),
+            }
+        }
+        for node in self.nodes.iter_mut() {
+            node.set_attr(&format!("{var}_estimated"), NodeAttr::number(0usize));
+        }
+        for (n, v, d) in estimates {
+            let donor = self.nodes[d].name.clone();
+            self.nodes[n].set_attr(var, NodeAttr::value(v));
+            self.nodes[n].set_attr(&format!("{var}_estimated"), NodeAttr::number(1usize));
+            self.nodes[n].set_attr(&format!("{var}_donor"), NodeAttr::string(donor));
+        }
+        Ok(())
+    }
+
+    /// Leave-one-out validation of the area ratio estimate: each node
+    /// with the attribute is predicted from its donor as if it didn't
+    /// have it, and the errors are written as csv after the summary
+    /// metrics in comments
+    pub fn validate_estimate(
+        &self,
+        writer: &mut impl Write,
+        var: &str,
+        area_attr: &str,
+    ) -> Result<(), NadiError> {
+        let mut rows: Vec<(usize, usize, f32, f32)> = Vec::new();
+        for node in &self.nodes {
+            let obs = match node.get_attr(var).and_then(|v| v.read_value()) {
+                Some(v) => v,
+                None => continue,
+            };
+            if let Some((d, pred)) = self.area_ratio_donor(node.index, var, area_attr) {
+                rows.push((node.index, d, obs, pred));
+            }
+        }
+        if rows.is_empty() {
+            journal::warn(format!(
+                "No nodes with both {} and {} to validate",
+                var, area_attr
+            ));
+            return Ok(());
+        }
+        let pairs: Vec<(f64, f64)> = rows
+            .iter()
+            .map(|(_, _, o, p)| (*o as f64, *p as f64))
+            .collect();
+        for (name, value) in timeseries::error_metrics(&pairs) {
+            writeln!(writer, "# {name}: {value}")?;
+        }
+        writeln!(writer, "node,donor,observed,predicted,error,pct_error")?;
+        for (n, d, obs, pred) in rows {
+            let err = pred - obs;
+            writeln!(
+                writer,
+                "{},{},{},{},{},{}",
+                csv_field(&self.nodes[n].name),
+                csv_field(&self.nodes[d].name),
+                obs,
+                pred,
+                err,
+                err / obs * 100.0
+            )?;
+        }
         Ok(())
     }
 
     pub fn reindex(&mut self) {
+        let ranks = self.tributary_ranks(TributaryOrder::Order, "", "");
+        self.reindex_by(&ranks);
+    }
+
+    /// Rank of the nodes for [`Network::reindex_by`], the attributes
+    /// are used for the area and length orders
+    pub fn tributary_ranks(
+        &self,
+        by: TributaryOrder,
+        area_attr: &str,
+        length_attr: &str,
+    ) -> Vec<usize> {
+        let value = |n: &Node, attr: &str| {
+            n.get_attr(attr)
+                .and_then(|v| v.read_value())
+                .unwrap_or(f32::NEG_INFINITY)
+        };
+        let mut ranked: Vec<usize> = (0..self.nodes.len()).collect();
+        // ties go to the alphabetically first node instead of the
+        // order of the inputs in the file
+        ranked.sort_by(|&a, &b| self.nodes[b].name.cmp(&self.nodes[a].name));
+        match by {
+            TributaryOrder::Order => ranked.sort_by_key(|&n| {
+                self.nodes[n]
+                    .get_attr("order")
+                    .and_then(|o| o.read_number())
+                    .copied()
+                    .unwrap_or(0)
+            }),
+            TributaryOrder::Area => {
+                ranked.sort_by(|&a, &b| {
+                    value(&self.nodes[a], area_attr).total_cmp(&value(&self.nodes[b], area_attr))
+                });
+            }
+            TributaryOrder::Length => {
+                // longest path from a headwater to the output of the
+                // node, the inputs have larger indices than the nodes
+                // they flow into
+                let mut path = vec![0.0f32; self.nodes.len()];
+                for n in (0..self.nodes.len()).rev() {
+                    let up = self.nodes[n]
+                        .inputs
+                        .iter()
+                        .map(|&i| path[i])
+                        .fold(0.0, f32::max);
+                    let length = self
+                        .reach_attr(n, length_attr)
+                        .and_then(|v| v.read_value())
+                        .unwrap_or(0.0);
+                    path[n] = up + length.max(0.0);
+                }
+                ranked.sort_by(|&a, &b| path[a].total_cmp(&path[b]));
+            }
+            TributaryOrder::Name => (),
+            TributaryOrder::File => ranked.sort_by_key(|&n| std::cmp::Reverse(self.nodes[n].line)),
+        }
+        let mut ranks = vec![0; self.nodes.len()];
+        for (r, n) in ranked.into_iter().enumerate() {
+            ranks[n] = r;
+        }
+        ranks
+    }
+
+    /// Reorder the nodes from the outlet, the input with the highest
+    /// rank continues the level of its output and the others are
+    /// tributaries one level higher
+    pub fn reindex_by(&mut self, ranks: &[usize]) {
         if self.nodes.is_empty() {
             return;
         }
@@ -544,7 +2629,7 @@ impl Network {
         }
 
         let mut nodes: Vec<(usize, usize)> = Vec::new();
-        let mut all_nodes: HashSet<usize> = (0..self.nodes.len()).collect();
+        let mut all_nodes: BTreeSet<usize> = (0..self.nodes.len()).collect();
         let mut curr_nodes: VecDeque<(usize, usize)> = VecDeque::from([(output, 0)]);
         loop {
             if curr_nodes.is_empty() {
@@ -560,15 +2645,7 @@ impl Network {
             nodes.push((n, level));
             all_nodes.remove(&n);
             if !self.nodes[n].inputs.is_empty() {
-                let orders: Vec<usize> = self
-                    .nodes
-                    .iter()
-                    .map(|n| *n.get_attr("order").unwrap().read_number().unwrap())
-                    .collect();
-                self.nodes[n]
-                    .inputs
-                    .sort_by(|n1, n2| orders[*n1].cmp(&orders[*n2]));
-                // self.nodes[n].inputs.reverse();
+                self.nodes[n].inputs.sort_by_key(|i| ranks[*i]);
                 for &inp in self.nodes[n].inputs.iter() {
                     let level = if inp == self.nodes[n].inputs[self.nodes[n].inputs.len() - 1] {
                         level
@@ -590,6 +2667,9 @@ impl Network {
             if let Some(out) = n.output {
                 n.set_output(inputs_map[&out]);
             }
+            if !n.branches.is_empty() {
+                n.set_branches(n.branches.iter().map(|b| inputs_map[b]).collect());
+            }
             n.set_attr("level", NodeAttr::number(nodes[i].1))
         });
         let new_indices = new_nodes
@@ -600,20 +2680,76 @@ impl Network {
         self.nodes = new_nodes;
     }
 
-    pub fn simple_print(&self, template: &Template) {
-        for node in &self.nodes {
-            println!("{}", node.format(template));
+    /// Indices of the nodes sorted by the attribute, numbers are
+    /// compared by their value and the nodes without the attribute
+    /// go at the end
+    pub fn sorted_nodes(&self, attr: &str, descending: bool) -> Vec<usize> {
+        let mut ind: Vec<usize> = (0..self.nodes.len()).collect();
+        ind.sort_by(
+            |&a, &b| match (self.nodes[a].get_attr(attr), self.nodes[b].get_attr(attr)) {
+                (Some(x), Some(y)) => {
+                    let ord = match (x.read_value(), y.read_value()) {
+                        (Some(x), Some(y)) => x.total_cmp(&y),
+                        _ => x.to_string().cmp(&y.to_string()),
+                    };
+                    if descending {
+                        ord.reverse()
+                    } else {
+                        ord
+                    }
+                }
+                (x, y) => y.is_some().cmp(&x.is_some()),
+            },
+        );
+        ind
+    }
+
+    pub fn simple_print(
+        &self,
+        writer: &mut impl Write,
+        template: &Template,
+        sort: Option<(&str, bool)>,
+    ) -> Result<(), NadiError> {
+        let order = match sort {
+            Some((attr, descending)) => self.sorted_nodes(attr, descending),
+            None => (0..self.nodes.len()).collect(),
+        };
+        for n in order {
+            writeln!(writer, "{}", self.nodes[n].format(template))?;
         }
+        Ok(())
     }
 
-    pub fn graph_print(&self, template: &Template) {
+    pub fn graph_print(
+        &self,
+        writer: &mut impl Write,
+        settings: &AsciiSettings,
+    ) -> Result<(), NadiError> {
         if self.nodes.is_empty() {
-            return;
+            return Ok(());
+        }
+        let (line, branch, merge, mark, more) = if settings.unicode {
+            ('│', '├', '─', '●', '┊')
+        } else {
+            ('|', '+', '-', '*', ':')
+        };
+        // colors are assigned in the order the attribute values are
+        // first seen, so the output is the same on every run
+        let mut colors: HashMap<String, u8> = HashMap::new();
+        if let (Some(attr), true) = (settings.color_by, settings.colors) {
+            for node in &self.nodes {
+                let l = colors.len();
+                colors
+                    .entry(node.get_attr_repr(attr))
+                    .or_insert(ANSI_COLORS[l % ANSI_COLORS.len()]);
+            }
         }
 
         let mut graph_nodes: Vec<GraphNode> = Vec::new();
-        let mut all_nodes: HashSet<usize> = (1..self.nodes.len()).collect();
-        let mut curr_nodes: Vec<usize> = vec![0];
+        let mut all_nodes: BTreeSet<usize> = (1..self.nodes.len()).collect();
+        // nodes with their number of links from the outlet
+        let mut curr_nodes: Vec<(usize, usize)> = vec![(0, 0)];
+        let mut printed = 0;
         loop {
             if curr_nodes.is_empty() {
                 if all_nodes.is_empty() {
@@ -621,14 +2757,46 @@ impl Network {
                 } else {
                     eprint!("Error");
                     let elem = *all_nodes.iter().next().unwrap();
-                    curr_nodes.push(elem);
+                    curr_nodes.push((elem, 0));
                     all_nodes.remove(&elem);
                 }
             }
             let mut gnd = GraphNode::default();
-            let n = curr_nodes.pop().unwrap();
+            let (n, depth) = curr_nodes.pop().unwrap();
             let node = &self.nodes[n];
-            gnd.text = node.format(template);
+            let truncate = settings.max_depth.map(|d| depth > d).unwrap_or(false)
+                || settings.max_nodes.map(|m| printed >= m).unwrap_or(false);
+            if truncate {
+                // the line replaces the node and all its upstream nodes
+                let mut count = 0;
+                let mut upstream = vec![n];
+                while let Some(u) = upstream.pop() {
+                    count += 1;
+                    for inp in &self.nodes[u].inputs {
+                        if all_nodes.remove(inp) {
+                            upstream.push(*inp);
+                        }
+                    }
+                }
+                gnd.truncated = true;
+                gnd.text = format!(
+                    "(+{count} more {})",
+                    if count == 1 { "node" } else { "upstream nodes" }
+                );
+            } else {
+                printed += 1;
+                gnd.text = node.format(settings.label);
+            }
+            if let Some(width) = settings.max_label_width {
+                let ellipsis = if settings.unicode { "…" } else { "..." };
+                gnd.text = truncate_width(&gnd.text, width, ellipsis);
+            }
+            if let (Some(templ), false) = (settings.right_template, gnd.truncated) {
+                gnd.right = node.format(templ);
+            }
+            if let (Some(attr), false) = (settings.color_by, gnd.truncated) {
+                gnd.color = colors.get(&node.get_attr_repr(attr)).copied();
+            }
 
             let level = *node.get_attr("level").unwrap().read_number().unwrap();
             let par_level = *self.nodes[node.output.unwrap_or(node.index)]
@@ -639,13 +2807,17 @@ impl Network {
             gnd.pre = level;
             gnd.post = 0;
             gnd.merge = level != par_level;
+            let truncated = gnd.truncated;
             graph_nodes.push(gnd);
+            if truncated {
+                continue;
+            }
 
             // println!("{} {}", prefix, node.format(&self.node_template));
             // println!("{} {}", prefix, node.format(&self.node_template));
             for &inp in node.inputs.iter() {
                 if all_nodes.contains(&inp) {
-                    curr_nodes.push(inp);
+                    curr_nodes.push((inp, depth + 1));
                     all_nodes.remove(&inp);
                 }
             }
@@ -656,43 +2828,84 @@ impl Network {
             .map(|gnd| {
                 let mut graph_cmps = String::new();
                 for _ in 0..gnd.pre {
-                    graph_cmps.push_str(" |");
+                    graph_cmps.push(' ');
+                    graph_cmps.push(line);
                 }
                 if gnd.merge {
                     graph_cmps.pop();
-                    graph_cmps.push('+');
+                    graph_cmps.push(branch);
                 }
-                graph_cmps.push_str(if gnd.merge { "-*" } else { " *" });
+                graph_cmps.push(if gnd.merge { merge } else { ' ' });
                 for _ in 0..gnd.post {
-                    graph_cmps.push_str(" |");
+                    graph_cmps.push(' ');
+                    graph_cmps.push(line);
                 }
                 graph_cmps
             })
             .collect();
-        let max_width = graph_text.iter().map(|gt| gt.len()).max().unwrap_or(10);
-        graph_text
+        let max_width = graph_text
             .iter()
-            .zip(graph_nodes.iter().rev())
-            .for_each(|(pre, gnd)| {
-                println!("{1:0$}  {2}", max_width, pre, gnd.text);
-                for _ in 0..(gnd.pre + if gnd.merge { 0 } else { 1 }) {
-                    print!(" |");
-                }
-                for _ in 0..gnd.post {
-                    print!(" |");
-                }
-                println!();
-            })
+            .map(|gt| gt.width() + 1)
+            .max()
+            .unwrap_or(10);
+        let right_width = graph_nodes
+            .iter()
+            .map(|gnd| gnd.right.width())
+            .max()
+            .unwrap_or(0);
+        // only pad the labels when there is a column to align on the right
+        let text_width = if right_width > 0 {
+            graph_nodes
+                .iter()
+                .map(|gnd| gnd.text.width())
+                .max()
+                .unwrap_or(0)
+        } else {
+            0
+        };
+        for (pre, gnd) in graph_text.iter().zip(graph_nodes.iter().rev()) {
+            // the std formatting pads by chars, which misaligns the
+            // wide characters
+            let pad = max_width - pre.width() - 1;
+            let text_pad = text_width.saturating_sub(gnd.text.width());
+            let text = isolate_rtl(&gnd.text);
+            let mark = if gnd.truncated { more } else { mark };
+            match gnd.color {
+                Some(c) => write!(
+                    writer,
+                    "{pre}\x1b[{c}m{mark}{0:pad$}  {text}{0:text_pad$}\x1b[0m",
+                    ""
+                )?,
+                None => write!(writer, "{pre}{mark}{0:pad$}  {text}{0:text_pad$}", "")?,
+            }
+            if right_width > 0 {
+                let right_pad = right_width - gnd.right.width();
+                write!(writer, "  {0:right_pad$}{1}", "", isolate_rtl(&gnd.right))?;
+            }
+            writeln!(writer)?;
+            for _ in 0..(gnd.pre + if gnd.merge { 0 } else { 1 }) {
+                write!(writer, " {line}")?;
+            }
+            for _ in 0..gnd.post {
+                write!(writer, " {line}")?;
+            }
+            writeln!(writer)?;
+        }
+        Ok(())
     }
 
-    pub fn graph_print_dot(&self, settings: &GraphVizSettings) {
+    pub fn graph_print_dot(
+        &self,
+        writer: &mut impl Write,
+        settings: &GraphVizSettings,
+    ) -> Result<(), NadiError> {
         if self.nodes.is_empty() {
-            return;
+            return Ok(());
         }
 
         // Node index, x and y
         let mut graph_nodes: Vec<(usize, f64, f64)> = Vec::new();
-        let mut all_nodes: HashSet<usize> = (1..self.nodes.len()).collect();
+        let mut all_nodes: BTreeSet<usize> = (1..self.nodes.len()).collect();
         let mut curr_nodes: Vec<usize> = vec![0];
         loop {
             if curr_nodes.is_empty() {
@@ -722,19 +2935,8 @@ impl Network {
             }
         }
         if let Some(sb) = &settings.sort_by {
-            let mut ind: Vec<usize> = (0..graph_nodes.len()).collect();
-            let attrs: Vec<f32> = ind
-                .iter()
-                .map(|n| {
-                    self.nodes[*n]
-                        .get_attr(sb)
-                        .expect("Attribute should be present")
-                        .read_value()
-                        .expect("Attribute should have float value")
-                })
-                .collect();
-            ind.sort_by(|n1, n2| attrs[*n1].partial_cmp(&attrs[*n2]).unwrap());
-            let y_map: HashMap<usize, f64> = ind
+            let y_map: HashMap<usize, f64> = self
+                .sorted_nodes(sb, settings.descending)
                 .into_iter()
                 .enumerate()
                 .map(|(k, v)| (v, k as f64 * settings.node_offset))
@@ -744,6 +2946,29 @@ impl Network {
                 .map(|(n, x, _)| (n, x, y_map[&n]))
                 .collect();
         }
+        if let Some(scale) = settings.geo_scale {
+            graph_nodes = graph_nodes
+                .into_iter()
+                .map(|(n, _, _)| {
+                    let node = &self.nodes[n];
+                    let coord = |attr: &str| {
+                        node.get_attr(attr)
+                            .and_then(|v| v.read_value())
+                            .ok_or_else(|| {
+                                NadiError::AttributeError(format!(
+                                    "Node {} doesn't have attribute {}",
+                                    node.name, attr
+                                ))
+                            })
+                    };
+                    Ok((
+                        n,
+                        coord("lon")? as f64 * scale,
+                        coord("lat")? as f64 * scale,
+                    ))
+                })
+                .collect::<Result<_, NadiError>>()?;
+        }
         let max_x = graph_nodes
             .iter()
             .map(|(_, x, _)| x)
@@ -753,70 +2978,259 @@ impl Network {
             .map(|(_, _, y)| y)
             .fold(f64::NAN, |a, b| f64::max(a, *b));
 
-        println!("digraph network {{");
-        println!(" overlap=true;");
-        println!(" node [shape={},fixedsize=false];", settings.node_shape);
-
-        let horizontal = *settings.direction == GraphVizDirection::LeftToRight;
-        for (n, mut x, mut y) in &graph_nodes {
-            if horizontal {
-                (x, y) = (max_y - y, x);
+        writeln!(writer, "digraph network {{")?;
+        writeln!(writer, " overlap=true;")?;
+        write!(writer, "{}", settings.theme.dot_graph())?;
+        writeln!(
+            writer,
+            " node [shape={},fixedsize=false{}];",
+            settings.node_shape,
+            settings.theme.dot_node()
+        )?;
+        let edge_style = settings.theme.dot_edge();
+        if !edge_style.is_empty() {
+            writeln!(writer, " edge [{}];", &edge_style[1..])?;
+        }
+        if let Some(title) = settings.title {
+            write!(
+                writer,
+                " labelloc=t;\n fontsize=56;\n label=<<b>{}</b>",
+                html_escape(title)
+            )?;
+            if let Some(sub) = settings.subtitle {
+                write!(
+                    writer,
+                    "<br/><font point-size=\"42\">{}</font>",
+                    html_escape(sub)
+                )?;
             }
-            let node = &self.nodes[*n];
+            writeln!(writer, ">;")?;
+        }
+        if let Some(prelude) = settings.prelude {
+            writeln!(writer, "{}", prelude.trim_end())?;
+        }
+        // lowest corner of the nodes for the legend position
+        let (mut min_x, mut min_y) = (f64::INFINITY, f64::INFINITY);
+
+        // the levels are mirrored before the direction is applied, so
+        // all the directions flip the same axis
+        let level = |x: f64| if settings.flip_y { max_x - x } else { x };
+        for &(n, x, y) in &graph_nodes {
+            let (x, y, lx, ly) = if settings.geo_scale.is_some() {
+                // geographic positions are kept as they are
+                (x, y, x + settings.label_offset, y)
+            } else {
+                let (lx, ly) = settings.direction.position(
+                    level(max_x + settings.label_offset),
+                    y,
+                    max_y,
+                );
+                let (x, y) = settings.direction.position(level(x), y, max_y);
+                (x, y, lx, ly)
+            };
+            let (lx, ly) = if *settings.label_position == LabelPosition::Below {
+                (x, y - settings.label_offset)
+            } else {
+                (lx, ly)
+            };
+            min_x = min_x.min(x);
+            min_y = min_y.min(y);
+            let node = &self.nodes[n];
             // let riv_len = node.get_attr("riv_length").map(|l| ())
             let par = node.output.map(|o| self.nodes[o].index);
             let node_txt = node.format(&settings.templates.node);
             let label = node.format(&settings.templates.label);
-            let url = node.format(&settings.templates.url);
-            print!(
-                "{} [pos=\"{},{}!\", size={}, fixedsize=true",
-                node.index, x, y, settings.node_size
-            );
-
-            print!(",label=\"{}\"", node_txt);
-            if !url.is_empty() {
-                print!(",URL=\"{}\"", url);
-            }
-            println!("]");
-            print!(
-                "l{} [shape={},pos=\"{},{}!\", label=\"{}\",fontsize=42",
-                node.index,
-                settings.label_shape,
-                if horizontal {
-                    x
-                } else {
-                    max_x + settings.label_offset
-                },
-                if horizontal {
-                    max_x + settings.label_offset
+            let mut url = node.format_url(settings.templates.url, settings.file_urls);
+            if settings.encode_urls {
+                url = encode_path(&url);
+            }
+            let inline = *settings.label_position == LabelPosition::Inline;
+            // inline labels replace the node text and the node grows to
+            // fit them
+            write!(
+                writer,
+                "{} [pos=\"{},{}!\", size={}, fixedsize={}",
+                node.index, x, y, settings.node_size, !inline
+            )?;
+
+            write!(
+                writer,
+                ",label=\"{}\"",
+                if inline { &label } else { &node_txt }
+            )?;
+            let value = settings
+                .fill
+                .and_then(|(attr, min, max)| Some((node.get_attr(attr)?.read_value()?, min, max)));
+            if let Some((v, min, max)) = value {
+                let frac = if max > min {
+                    (v - min) / (max - min)
                 } else {
-                    y
-                },
-                label
-            );
+                    0.5
+                };
+                write!(
+                    writer,
+                    ",style=filled,fillcolor=\"{}\"",
+                    settings.theme.scale_color(frac)
+                )?;
+            } else if let Some(fill) = &settings.theme.node_fill {
+                write!(writer, ",style=filled,fillcolor=\"{}\"", fill)?;
+            }
             if !url.is_empty() {
-                print!(",URL=\"{}\"", url);
+                write!(writer, ",URL=\"{}\"", url)?;
+            }
+            if node.is_diversion() {
+                write!(writer, ",shape=diamond")?;
+            } else if node.output.is_none() {
+                write!(writer, ",peripheries=2")?;
+            }
+            writeln!(writer, "]")?;
+            if matches!(
+                settings.label_position,
+                LabelPosition::Right | LabelPosition::Below
+            ) {
+                write!(
+                    writer,
+                    "l{} [shape={},pos=\"{},{}!\", label=\"{}\",fontsize=42",
+                    node.index, settings.label_shape, lx, ly, label
+                )?;
+                if !url.is_empty() {
+                    write!(writer, ",URL=\"{}\"", url)?;
+                }
+                writeln!(writer, "]")?;
+                writeln!(writer, "{0} -> l{0} [color=none]", node.index)?;
             }
-            println!("]");
-            println!("{0} -> l{0} [color=none]", node.index);
+            let edge_label = |to: usize| -> Result<String, NadiError> {
+                Ok(match settings.edge {
+                    Some(templ) => format!(" [label=\"{}\"]", self.format_edge(n, to, templ)?),
+                    None => String::new(),
+                })
+            };
             if let Some(par) = par {
-                println!("{} -> {}", node.index, par);
+                writeln!(writer, "{} -> {}{}", node.index, par, edge_label(par)?)?;
+            }
+            for &b in &node.branches {
+                let label = edge_label(b)?;
+                let style = if label.is_empty() {
+                    " [style=dashed]".to_string()
+                } else {
+                    label.replace(" [", " [style=dashed,")
+                };
+                writeln!(writer, "{} -> {}{}", node.index, b, style)?;
+            }
+        }
+        if let Some(scale) = settings.geo_scale {
+            let min_gx = graph_nodes
+                .iter()
+                .map(|(_, x, _)| *x)
+                .fold(f64::INFINITY, f64::min);
+            let min_gy = graph_nodes
+                .iter()
+                .map(|(_, _, y)| *y)
+                .fold(f64::INFINITY, f64::min);
+            let offset = settings.node_offset;
+            if settings.scale_bar {
+                // length of a degree of longitude at the mean latitude
+                let mean_lat = graph_nodes.iter().map(|(_, _, y)| y / scale).sum::<f64>()
+                    / graph_nodes.len() as f64;
+                let km_per_deg = 111.32 * mean_lat.to_radians().cos();
+                let target = (max_x - min_gx) / scale * km_per_deg / 4.0;
+                if target > 0.0 {
+                    let pow = 10f64.powf(target.log10().floor());
+                    let km = [5.0, 2.0, 1.0]
+                        .iter()
+                        .map(|m| m * pow)
+                        .find(|l| *l <= target)
+                        .unwrap_or(pow);
+                    let len = km / km_per_deg * scale;
+                    let y = min_gy - 2.0 * offset;
+                    writeln!(
+                        writer,
+                        "sb0 [shape=point,pos=\"{},{}!\"]\nsb1 [shape=point,pos=\"{},{}!\"]",
+                        min_gx,
+                        y,
+                        min_gx + len,
+                        y
+                    )?;
+                    writeln!(
+                        writer,
+                        "sb0 -> sb1 [dir=both,arrowhead=tee,arrowtail=tee,label=\"{} km\",fontsize=42]",
+                        km
+                    )?;
+                }
+            }
+            if settings.north_arrow {
+                let x = max_x + 2.0 * offset;
+                writeln!(
+                    writer,
+                    "na0 [shape=point,style=invis,pos=\"{},{}!\"]\nna1 [shape=plaintext,label=\"N\",fontsize=42,pos=\"{},{}!\"]",
+                    x,
+                    max_y - 2.0 * offset,
+                    x,
+                    max_y
+                )?;
+                writeln!(writer, "na0 -> na1 [penwidth=3]")?;
+            }
+        }
+        if let Some((attr, min, max)) = settings.fill {
+            // color scale legend below the graph
+            write!(
+                writer,
+                "legend [shape=plaintext,pos=\"{},{}!\",fontsize=42,label=<<table border=\"0\" cellborder=\"1\" cellspacing=\"0\"><tr><td colspan=\"2\"><b>{}</b></td></tr>",
+                min_x,
+                min_y - 2.0 * settings.node_offset,
+                html_escape(attr)
+            )?;
+            for i in 0..5 {
+                let frac = i as f32 / 4.0;
+                write!(
+                    writer,
+                    "<tr><td bgcolor=\"{}\">   </td><td>{}</td></tr>",
+                    settings.theme.scale_color(frac),
+                    min + (max - min) * frac
+                )?;
             }
+            writeln!(writer, "</table>>]")?;
         }
-        println!("}}");
+        // node types legend, only when there are more than the outlets
+        let diversions = self.nodes.iter().any(|n| n.is_diversion());
+        let branches = self.nodes.iter().any(|n| !n.branches.is_empty());
+        if diversions || branches {
+            write!(
+                writer,
+                "types [shape=plaintext,pos=\"{},{}!\",fontsize=42,label=<<table border=\"0\" cellspacing=\"0\"><tr><td>◎</td><td align=\"left\">outlet</td></tr>",
+                max_x,
+                min_y - 2.0 * settings.node_offset
+            )?;
+            if diversions {
+                write!(writer, "<tr><td>◇</td><td align=\"left\">diversion</td></tr>")?;
+            }
+            if branches {
+                write!(writer, "<tr><td>- -</td><td align=\"left\">branch</td></tr>")?;
+            }
+            writeln!(writer, "</table>>]")?;
+        }
+        writeln!(writer, "}}")?;
+        Ok(())
     }
 
     fn generate_latex_table(
         &self,
-        latex_table: &Vec<(String, char, Template)>,
-        url_template: &Template,
-    ) {
+        writer: &mut impl Write,
+        settings: &LatexSettings,
+    ) -> Result<(), NadiError> {
         if self.nodes.is_empty() {
-            return;
+            return Ok(());
+        }
+        if let Some((attr, descending)) = settings.sort {
+            return self.generate_sorted_latex_table(
+                writer,
+                settings,
+                &self.sorted_nodes(attr, descending),
+            );
         }
         // Node index, x and y
         let mut graph_nodes: Vec<(usize, usize, usize)> = Vec::new();
-        let mut all_nodes: HashSet<usize> = (1..self.nodes.len()).collect();
+        let mut all_nodes: BTreeSet<usize> = (1..self.nodes.len()).collect();
         let mut curr_nodes: Vec<usize> = vec![0];
         loop {
             if curr_nodes.is_empty() {
@@ -841,11 +3255,86 @@ impl Network {
                 }
             }
         }
+        let rows: Vec<(usize, usize)> =
+            graph_nodes.iter().rev().map(|(n, x, _)| (*n, *x)).collect();
+        self.write_latex_table(writer, settings, &rows, "edge")
+    }
+
+    /// LaTeX table with the rows in the given order, the nodes are
+    /// put in columns such that the connection going up or down from
+    /// a node doesn't pass through the other nodes
+    fn generate_sorted_latex_table(
+        &self,
+        writer: &mut impl Write,
+        settings: &LatexSettings,
+        order: &[usize],
+    ) -> Result<(), NadiError> {
+        let mut row = vec![0; self.nodes.len()];
+        for (r, &n) in order.iter().enumerate() {
+            row[n] = r;
+        }
+        // rows spanned by the connection from each node to its output
+        let span = |n: usize| {
+            let r = row[n];
+            let o = self.nodes[n].output.map(|o| row[o]).unwrap_or(r);
+            (r.min(o), r.max(o))
+        };
+        let overlaps = |a: (usize, usize), b: (usize, usize)| {
+            a.0.max(b.0) < a.1.min(b.1)
+                || (a.0 == a.1 && b.0 < a.0 && a.0 < b.1)
+                || (b.0 == b.1 && a.0 < b.0 && b.0 < a.1)
+        };
+        // outputs come before their inputs, and the input continuing
+        // the level of its output before the other ones
+        let mut placing: Vec<usize> = (0..self.nodes.len()).collect();
+        placing.sort_by_key(|&n| {
+            let level = self.nodes[n]
+                .get_attr("level")
+                .and_then(|l| l.read_number())
+                .copied()
+                .unwrap_or(0);
+            (level, n)
+        });
+        let mut columns: Vec<Vec<(usize, usize)>> = Vec::new();
+        let mut x = vec![0; self.nodes.len()];
+        for n in placing {
+            let s = span(n);
+            let start = self.nodes[n].output.map(|o| x[o]).unwrap_or(0);
+            let col = (start..)
+                .find(|&c| {
+                    columns
+                        .get(c)
+                        .map(|spans| !spans.iter().any(|&t| overlaps(s, t)))
+                        .unwrap_or(true)
+                })
+                .unwrap_or(start);
+            if columns.len() <= col {
+                columns.resize(col + 1, Vec::new());
+            }
+            columns[col].push(s);
+            x[n] = col;
+        }
+        let rows: Vec<(usize, usize)> = order.iter().map(|&n| (n, x[n])).collect();
+        // vertical line in the column of the node and then a
+        // horizontal one to its output in that row
+        self.write_latex_table(writer, settings, &rows, "|-")
+    }
+
+    fn write_latex_table(
+        &self,
+        writer: &mut impl Write,
+        settings: &LatexSettings,
+        rows: &[(usize, usize)],
+        path: &str,
+    ) -> Result<(), NadiError> {
+        let latex_table = settings.columns;
+        let prelude = settings.prelude.unwrap_or_default().trim_end();
         let table_fmt: String = format!(
             "l{}",
             latex_table.iter().map(|(_, c, _)| c).collect::<String>()
         );
-        println!(
+        writeln!(
+            writer,
             r"\documentclass{{standalone}}
 
 \usepackage{{array}}
@@ -858,46 +3347,262 @@ impl Network {
 
 \newcommand{{\TikzNode}}[4][0]{{%
   \tikz[overlay,remember picture]{{\draw (#1 / 2 +0.5, 0.1) circle [radius=0.14] node (#2) {{\href{{#4}}{{\tiny #3}}}};}}}}
-
+{prelude}
 
 \begin{{document}}
 
   \begin{{tabular}}{{{table_fmt}}}
     \toprule"
-        );
-        print!("Connection");
+        )?;
+        write!(writer, "Connection")?;
         for (head, _, _) in latex_table {
-            print!(" & {head}");
+            write!(writer, " & {head}")?;
         }
-        println!(r"\\");
-        println!(r"\midrule");
+        writeln!(writer, r"\\")?;
+        writeln!(writer, r"\midrule")?;
         let mut connections_list: Vec<String> = Vec::new();
-        for (n, x, _) in graph_nodes.iter().rev() {
+        for (n, x) in rows {
             let node = &self.nodes[*n];
             // let riv_len = node.get_attr("riv_length").map(|l| ())
             let parent = node.output.map(|o| self.nodes[o].index);
-            let url = node.format(url_template);
-            print!("\\TikzNode[{x}]{{{0}}}{{{0}}}{{{url}}}", node.index);
+            let url = node.format_url(settings.url, settings.file_urls);
+            write!(writer, "\\TikzNode[{x}]{{{0}}}{{{0}}}{{{url}}}", node.index)?;
             for (_, _, templ) in latex_table {
-                let templ = node.format(&templ);
-                print!(" & {templ}");
+                let templ = node.format_localized(templ, settings.locale);
+                write!(writer, " & {templ}")?;
             }
-            println!(r"\\");
+            writeln!(writer, r"\\")?;
 
             if let Some(par) = parent {
-                connections_list.push(format!("\\path[->] ({}) edge ({});", node.index, par));
+                connections_list.push(format!("\\path[->] ({}) {path} ({});", node.index, par));
             }
         }
-        println!("\\bottomrule");
-        println!("\\end{{tabular}}");
+        writeln!(writer, "\\bottomrule")?;
+        writeln!(writer, "\\end{{tabular}}")?;
         // this causes a small extra space on the right side, couldn't fix it
-        println!("\\tikz[overlay,remember picture]{{");
+        writeln!(writer, "\\tikz[overlay,remember picture]{{")?;
         for conn in connections_list {
-            println!("{}", conn);
+            writeln!(writer, "{}", conn)?;
+        }
+        writeln!(writer, "}}")?;
+        writeln!(writer, r"\end{{document}}")?;
+        Ok(())
+    }
+}
+
+/// Read the `key=value` lines of an attributes file
+pub(crate) fn read_attrs_file(filename: &Path) -> Result<Vec<(String, NodeAttr)>, NadiError> {
+    read_attrs_file_with(filename, |_| true)
+}
+
+/// Attributes in the file that pass the filter, the values of the
+/// others are not parsed
+fn read_attrs_file_with(
+    filename: &Path,
+    keep: impl Fn(&str) -> bool,
+) -> Result<Vec<(String, NodeAttr)>, NadiError> {
+    let file = File::open(filename)?;
+    journal::input(filename);
+    let mut attrs = Vec::new();
+    for line in BufReader::new(file).lines() {
+        let line = line?.trim().to_string();
+        if line.starts_with('#') || line.is_empty() {
+            continue;
+        }
+        if let Some((key, val)) = line.split_once('=') {
+            let key = key.trim();
+            if keep(key) {
+                attrs.push((key.to_string(), NodeAttr::parse(val)));
+            }
+        }
+    }
+    Ok(attrs)
+}
+
+/// Rows of a csv file split into the trimmed cells
+pub(crate) type CsvRows = Vec<Vec<String>>;
+
+/// Read the csv file as text, the quoted cells can have commas
+pub(crate) fn read_csv(filename: &PathBuf) -> Result<(Vec<String>, CsvRows), NadiError> {
+    let df = CsvReader::from_path(filename)?
+        .has_header(true)
+        .with_comment_char(Some(b'#'))
+        // all the columns are read as text
+        .infer_schema(Some(0))
+        .finish()
+        .map_err(|e| match e {
+            PolarsError::NoData(_) => NadiError::ParseError {
+                file: filename.clone(),
+                line: 1,
+                msg: "Empty file".to_string(),
+            },
+            e => e.into(),
+        })?;
+    let header: Vec<String> = df
+        .get_column_names()
+        .iter()
+        .map(|c| c.trim().to_string())
+        .collect();
+    let mut rows: CsvRows = vec![Vec::with_capacity(header.len()); df.height()];
+    for column in df.get_columns() {
+        for (row, cell) in rows.iter_mut().zip(column.utf8()?) {
+            row.push(cell.unwrap_or_default().trim().to_string());
+        }
+    }
+    Ok((header, rows))
+}
+
+/// Read the csv file of the time varying attributes, returns the
+/// header, rows and the index of the date column
+fn read_time_attrs_csv(
+    filename: &PathBuf,
+    date_col: &str,
+) -> Result<(Vec<String>, CsvRows, usize), NadiError> {
+    let (header, rows) = read_csv(filename)?;
+    let date_col =
+        header
+            .iter()
+            .position(|c| c == date_col)
+            .ok_or_else(|| NadiError::ParseError {
+                file: filename.clone(),
+                line: 1,
+                msg: format!("No {date_col} column"),
+            })?;
+    Ok((header, rows, date_col))
+}
+
+/// Inline svg line plot of the numeric columns against the row
+/// number, the first column is used for the x axis labels
+fn svg_line_plot(
+    header: &[String],
+    rows: &[Vec<String>],
+    theme: &Theme,
+    locale: &LocaleArgs,
+) -> String {
+    let (width, height, pad) = (640.0, 240.0, 40.0);
+    let columns: Vec<(usize, Vec<Option<f64>>)> = (1..header.len())
+        .map(|c| {
+            let vals: Vec<Option<f64>> = rows
+                .iter()
+                .map(|r| r.get(c).and_then(|v| v.parse().ok()))
+                .collect();
+            (c, vals)
+        })
+        .filter(|(_, v)| v.iter().any(|v| v.is_some()))
+        .collect();
+    if columns.is_empty() || rows.len() < 2 {
+        return String::from("<p>No values to plot</p>");
+    }
+    let all = columns.iter().flat_map(|(_, v)| v.iter().flatten());
+    let min = all.clone().fold(f64::INFINITY, |a, b| a.min(*b));
+    let max = all.fold(f64::NEG_INFINITY, |a, b| a.max(*b));
+    let range = if max > min { max - min } else { 1.0 };
+    let x = |i: usize| pad + (width - 2.0 * pad) * i as f64 / (rows.len() - 1) as f64;
+    let y = |v: f64| height - pad - (height - 2.0 * pad) * (v - min) / range;
+    let first_col = |r: &Vec<String>| {
+        html_escape(&locale.localize(r.first().map(|s| s.as_str()).unwrap_or("")))
+    };
+    let mut svg = theme.svg_start(width, height);
+    let stroke_width = theme
+        .line_width
+        .map(|w| format!(" stroke-width=\"{w}\""))
+        .unwrap_or_default();
+    svg.push_str(&format!(
+        "<text x=\"{pad}\" y=\"{0}\">{1}</text><text x=\"{2}\" y=\"{0}\" text-anchor=\"end\">{3}</text>",
+        height - pad / 4.0,
+        first_col(&rows[0]),
+        width - pad,
+        first_col(&rows[rows.len() - 1]),
+    ));
+    svg.push_str(&format!(
+        "<text x=\"2\" y=\"{}\">{}</text><text x=\"2\" y=\"{}\">{}</text>",
+        pad,
+        locale.number(max),
+        height - pad,
+        locale.number(min)
+    ));
+    for (k, (c, vals)) in columns.iter().enumerate() {
+        let color = theme.line_color(k);
+        // breaks in the line at the missing values
+        let mut path = String::new();
+        let mut pen_up = true;
+        for (i, v) in vals.iter().enumerate() {
+            match v {
+                Some(v) => {
+                    let cmd = if pen_up { "M" } else { "L" };
+                    path.push_str(&format!("{}{:.1},{:.1} ", cmd, x(i), y(*v)));
+                    pen_up = false;
+                }
+                None => pen_up = true,
+            }
+        }
+        svg.push_str(&format!(
+            "<path d=\"{path}\" fill=\"none\" stroke=\"{color}\"{stroke_width}/><text x=\"{}\" y=\"{}\" fill=\"{color}\">{}</text>",
+            pad + 100.0 * k as f64,
+            pad / 2.0,
+            html_escape(&header[*c])
+        ));
+    }
+    svg.push_str("</svg>");
+    svg
+}
+
+/// Quote the csv field if it has commas, quotes or new lines
+pub fn csv_field(text: &str) -> Cow<'_, str> {
+    if text.contains([',', '"', '\n', '\r']) {
+        Cow::Owned(format!("\"{}\"", text.replace('"', "\"\"")))
+    } else {
+        Cow::Borrowed(text)
+    }
+}
+
+pub fn html_escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
+
+/// Nodes in a loop if the links have one, `links` gives the
+/// downstream nodes of a node
+fn find_loop<I: Iterator<Item = usize>>(
+    count: usize,
+    links: impl Fn(usize) -> I,
+) -> Option<Vec<usize>> {
+    // 0: not visited, 1: in the current path, 2: done
+    let mut state = vec![0u8; count];
+    for start in 0..count {
+        if state[start] != 0 {
+            continue;
+        }
+        let mut path: Vec<usize> = vec![start];
+        let mut stack: Vec<Vec<usize>> = vec![links(start).collect()];
+        state[start] = 1;
+        while let Some(next) = stack.last_mut() {
+            match next.pop() {
+                Some(n) if state[n] == 1 => {
+                    let pos = path.iter().position(|&p| p == n).unwrap();
+                    let mut cycle = path[pos..].to_vec();
+                    cycle.push(n);
+                    return Some(cycle);
+                }
+                Some(n) if state[n] == 0 => {
+                    state[n] = 1;
+                    path.push(n);
+                    stack.push(links(n).collect());
+                }
+                Some(_) => (),
+                None => {
+                    stack.pop();
+                    if let Some(n) = path.pop() {
+                        state[n] = 2;
+                    }
+                }
+            }
         }
-        println!("}}");
-        println!(r"\end{{document}}")
     }
+    None
 }
 
 fn set_cum_values(
@@ -919,7 +3624,7 @@ fn get_values<'a>(
     var: &str,
     safe: bool,
     values: &mut HashMap<&'a str, f32>,
-) -> Result<(), Error> {
+) -> Result<(), NadiError> {
     for node in &network.nodes {
         let val = if safe {
             node.get_attr(var)
@@ -927,14 +3632,52 @@ fn get_values<'a>(
                 .unwrap_or(0.0)
         } else {
             node.get_attr(var)
-                .context(format!("Node {} doesn't have attribute {}", node.name, var))?
+                .ok_or_else(|| {
+                    NadiError::AttributeError(format!(
+                        "Node {} doesn't have attribute {}",
+                        node.name, var
+                    ))
+                })?
                 .read_value()
-                .context(format!(
-                    "Node {}, attribute {} is not parsable as float",
-                    node.name, var
-                ))?
+                .ok_or_else(|| {
+                    NadiError::AttributeError(format!(
+                        "Node {}, attribute {} is not parsable as float",
+                        node.name, var
+                    ))
+                })?
         };
         values.insert(node.get_name(), val);
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn shell_quote_keeps_the_value_as_one_argument() {
+        assert_eq!(shell_quote("a b"), "'a b'");
+        assert_eq!(shell_quote("x'; rm -rf ~; '"), r"'x'\''; rm -rf ~; '\'''");
+        let out = std::process::Command::new("sh")
+            .arg("-c")
+            .arg(format!("printf %s {}", shell_quote("$(id) 'q' `id`")))
+            .output()
+            .unwrap();
+        assert_eq!(String::from_utf8(out.stdout).unwrap(), "$(id) 'q' `id`");
+    }
+
+    #[test]
+    fn edge_list_quotes_the_names() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("network.txt");
+        std::fs::write(&file, "a,1 -> b\"2\nb\"2 -> c\n").unwrap();
+        let net = Network::from_file(&file).unwrap();
+        let mut out = Vec::new();
+        net.write_edge_list(&mut out, None).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "from,to\n\"b\"\"2\",c\n\"a,1\",\"b\"\"2\"\n"
+        );
+    }
+}
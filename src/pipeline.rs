@@ -0,0 +1,234 @@
+use std::collections::HashSet;
+use std::path::{Component, Path, PathBuf};
+use std::process::Command;
+use std::time::SystemTime;
+
+use anyhow::Context;
+use clap::{Args, ValueHint};
+use serde::Deserialize;
+
+use crate::cliargs::{CliAction, GlobalArgs};
+
+#[derive(Args)]
+pub struct CliArgs {
+    /// Run the steps even if their outputs are up to date
+    #[arg(short, long, action)]
+    force: bool,
+    /// Only print the steps that would run
+    #[arg(short = 'n', long, action)]
+    dry_run: bool,
+    /// Only run these steps (and the steps they depend on)
+    #[arg(short, long, value_delimiter = ',')]
+    step: Vec<String>,
+    /// Pipeline file
+    ///
+    /// Toml file with `[[step]]` tables containing `name`, `command`
+    /// (arguments to nadi), `inputs`, `outputs` and optionally
+    /// `after` (names of the steps to run before). Paths are relative
+    /// to the pipeline file directory.
+    #[arg(value_hint=ValueHint::FilePath)]
+    pipeline: PathBuf,
+}
+
+#[derive(Deserialize)]
+struct Pipeline {
+    step: Vec<Step>,
+}
+
+#[derive(Deserialize)]
+struct Step {
+    name: String,
+    command: Vec<String>,
+    #[serde(default)]
+    inputs: Vec<PathBuf>,
+    #[serde(default)]
+    outputs: Vec<PathBuf>,
+    #[serde(default)]
+    after: Vec<String>,
+}
+
+impl Step {
+    /// Reason to run the step if it is out of date, steps without
+    /// outputs always run
+    fn stale(&self, wd: &Path) -> Option<String> {
+        if self.outputs.is_empty() {
+            return Some("no outputs".to_string());
+        }
+        let mtime = |p: &PathBuf| wd.join(p).metadata().and_then(|m| m.modified()).ok();
+        let mut oldest = SystemTime::now();
+        for out in &self.outputs {
+            match mtime(out) {
+                Some(t) => oldest = oldest.min(t),
+                None => return Some(format!("{:?} doesn't exist", out)),
+            }
+        }
+        self.inputs
+            .iter()
+            .find(|inp| mtime(inp).map(|t| t > oldest).unwrap_or(true))
+            .map(|inp| format!("{:?} changed", inp))
+    }
+}
+
+impl CliAction for CliArgs {
+    fn run(self, global: &GlobalArgs) -> anyhow::Result<()> {
+        // the steps are separate runs of nadi with their own options
+        if global.output.is_some() || global.format.is_some() {
+            anyhow::bail!("--output and --format go in the command of the steps for pipeline");
+        }
+        let contents = std::fs::read_to_string(&self.pipeline)
+            .with_context(|| format!("Couldn't read {:?}", self.pipeline))?;
+        let mut pipeline: Pipeline = toml::from_str(&contents)?;
+        // parent of a bare file name is empty, which is not a valid
+        // working directory for the commands
+        let wd = self
+            .pipeline
+            .parent()
+            .filter(|p| !p.as_os_str().is_empty())
+            .unwrap_or(Path::new("."))
+            .canonicalize()?;
+        // the same file can be written differently in the steps
+        // (e.g. ./a.csv and a.csv)
+        for step in &mut pipeline.step {
+            for path in step.inputs.iter_mut().chain(step.outputs.iter_mut()) {
+                *path = normalize(&wd.join(&path));
+            }
+        }
+        let order = run_order(&pipeline.step)?;
+        let selected = self.selected_steps(&pipeline.step)?;
+        let exe = std::env::current_exe()?;
+        let mut ran: HashSet<usize> = HashSet::new();
+        for i in order {
+            let step = &pipeline.step[i];
+            if !selected.contains(&i) {
+                continue;
+            }
+            let reason = if self.force {
+                Some("forced".to_string())
+            } else if let Some(&d) = dependencies(&pipeline.step, i)
+                .iter()
+                .find(|d| ran.contains(d))
+            {
+                Some(format!("step {} ran", pipeline.step[d].name))
+            } else {
+                step.stale(&wd)
+            };
+            let Some(reason) = reason else {
+                eprintln!("Skipping {}: up to date", step.name);
+                continue;
+            };
+            eprintln!("Running {}: {}", step.name, reason);
+            eprintln!("  nadi {}", step.command.join(" "));
+            if self.dry_run {
+                ran.insert(i);
+                continue;
+            }
+            let status = Command::new(&exe)
+                .args(&step.command)
+                .current_dir(&wd)
+                .status()
+                .with_context(|| format!("Couldn't run step {}", step.name))?;
+            if !status.success() {
+                anyhow::bail!("Step {} failed ({})", step.name, status);
+            }
+            if let Some(out) = step.outputs.iter().find(|o| !wd.join(o).exists()) {
+                anyhow::bail!("Step {} didn't produce {:?}", step.name, out);
+            }
+            ran.insert(i);
+        }
+        Ok(())
+    }
+}
+
+impl CliArgs {
+    /// Indices of the steps given in `--step` and the steps they
+    /// depend on, all the steps if none are given
+    fn selected_steps(&self, steps: &[Step]) -> anyhow::Result<HashSet<usize>> {
+        if self.step.is_empty() {
+            return Ok((0..steps.len()).collect());
+        }
+        let mut selected = HashSet::new();
+        let mut stack = Vec::new();
+        for name in &self.step {
+            let i = steps
+                .iter()
+                .position(|s| &s.name == name)
+                .with_context(|| format!("Step {} not in the pipeline", name))?;
+            stack.push(i);
+        }
+        while let Some(i) = stack.pop() {
+            if selected.insert(i) {
+                stack.extend(dependencies(steps, i));
+            }
+        }
+        Ok(selected)
+    }
+}
+
+/// Absolute path without the `.` and `..` components, the file
+/// doesn't need to exist
+fn normalize(path: &Path) -> PathBuf {
+    let mut normal = PathBuf::new();
+    for comp in path.components() {
+        match comp {
+            Component::CurDir => (),
+            Component::ParentDir => {
+                normal.pop();
+            }
+            c => normal.push(c),
+        }
+    }
+    normal
+}
+
+/// Steps that have to run before the step: the ones producing its
+/// inputs and the ones named in `after`
+fn dependencies(steps: &[Step], i: usize) -> Vec<usize> {
+    steps
+        .iter()
+        .enumerate()
+        .filter(|(j, s)| {
+            *j != i
+                && (steps[i].after.contains(&s.name)
+                    || s.outputs.iter().any(|o| steps[i].inputs.contains(o)))
+        })
+        .map(|(j, _)| j)
+        .collect()
+}
+
+/// Order of the steps such that each step runs after its
+/// dependencies, the order in the file is kept otherwise
+fn run_order(steps: &[Step]) -> anyhow::Result<Vec<usize>> {
+    let mut names: HashSet<&str> = HashSet::new();
+    for s in steps {
+        if !names.insert(&s.name) {
+            anyhow::bail!("Step {} is defined multiple times", s.name);
+        }
+        if let Some(a) = s
+            .after
+            .iter()
+            .find(|a| !steps.iter().any(|s| &s.name == *a))
+        {
+            anyhow::bail!("Step {} runs after unknown step {}", s.name, a);
+        }
+    }
+    let deps: Vec<Vec<usize>> = (0..steps.len()).map(|i| dependencies(steps, i)).collect();
+    let mut order = Vec::with_capacity(steps.len());
+    let mut done = vec![false; steps.len()];
+    while order.len() < steps.len() {
+        let next = (0..steps.len()).find(|&i| !done[i] && deps[i].iter().all(|&d| done[d]));
+        match next {
+            Some(i) => {
+                done[i] = true;
+                order.push(i);
+            }
+            None => {
+                let cycle: Vec<&str> = (0..steps.len())
+                    .filter(|&i| !done[i])
+                    .map(|i| steps[i].name.as_str())
+                    .collect();
+                anyhow::bail!("Steps have circular dependencies: {}", cycle.join(", "));
+            }
+        }
+    }
+    Ok(order)
+}
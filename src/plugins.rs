@@ -0,0 +1,112 @@
+//! Node functions from the dynamic libraries given with `--plugin`
+//!
+//! A plugin exports its functions with the C ABI as
+//!
+//! ```c
+//! char *func(const char *name, const char *attrs);
+//! void nadi_free(char *value);
+//! ```
+//!
+//! where `name` is the node name and `attrs` are the node attributes
+//! as `key=value` lines. The function returns the new value of the
+//! attribute, or NULL to leave the node without it. The returned
+//! strings are freed with `nadi_free` if the plugin exports it.
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::ffi::{c_char, CStr, CString};
+use std::path::{Path, PathBuf};
+
+use libloading::Library;
+
+use crate::errors::NadiError;
+
+type NodeFunc = unsafe extern "C" fn(*const c_char, *const c_char) -> *mut c_char;
+type FreeFunc = unsafe extern "C" fn(*mut c_char);
+
+pub struct Plugin {
+    name: String,
+    path: PathBuf,
+    /// Functions already looked up in the library, the pointers are
+    /// valid while the library is loaded
+    funcs: RefCell<HashMap<String, NodeFunc>>,
+    free: Option<FreeFunc>,
+    lib: Library,
+}
+
+impl Plugin {
+    /// Load the library, the plugin name is the file name without
+    /// the `lib` prefix and the extension
+    pub fn load(path: &Path) -> Result<Self, NadiError> {
+        let name = path
+            .file_stem()
+            .map(|s| s.to_string_lossy())
+            .ok_or_else(|| NadiError::PluginError(format!("Invalid plugin path {:?}", path)))?;
+        let name = name.strip_prefix("lib").unwrap_or(&name).to_string();
+        // SAFETY: the library initializers are run, the plugins are
+        // trusted the same way as the other commands run by nadi
+        let lib = unsafe { Library::new(path) }
+            .map_err(|e| NadiError::PluginError(format!("Couldn't load {:?}: {e}", path)))?;
+        // SAFETY: nadi_free is required to have the signature in the
+        // module documentation
+        let free = unsafe { lib.get::<FreeFunc>(b"nadi_free") }
+            .ok()
+            .map(|f| *f);
+        Ok(Self {
+            name,
+            path: path.to_path_buf(),
+            funcs: RefCell::new(HashMap::new()),
+            free,
+            lib,
+        })
+    }
+
+    /// Function of the plugin, looked up in the library only once
+    fn func(&self, func: &str) -> Result<NodeFunc, NadiError> {
+        if let Some(f) = self.funcs.borrow().get(func) {
+            return Ok(*f);
+        }
+        // SAFETY: the plugin functions are required to have the
+        // signature in the module documentation
+        let f: NodeFunc = unsafe { self.lib.get::<NodeFunc>(func.as_bytes()) }
+            .map(|f| *f)
+            .map_err(|_| {
+                NadiError::PluginError(format!(
+                    "Plugin {} ({:?}) doesn't have the function {func}",
+                    self.name, self.path
+                ))
+            })?;
+        self.funcs.borrow_mut().insert(func.to_string(), f);
+        Ok(f)
+    }
+
+    /// Call the function of the plugin for a node
+    pub fn call(&self, func: &str, node: &str, attrs: &str) -> Result<Option<String>, NadiError> {
+        let cstring = |s: &str| {
+            CString::new(s).map_err(|_| {
+                NadiError::PluginError(format!("Node {node} has a null byte in its attributes"))
+            })
+        };
+        let (node_c, attrs_c) = (cstring(node)?, cstring(attrs)?);
+        let f = self.func(func)?;
+        // SAFETY: the returned string is copied before it is freed by
+        // the plugin
+        unsafe {
+            let value = f(node_c.as_ptr(), attrs_c.as_ptr());
+            if value.is_null() {
+                return Ok(None);
+            }
+            let result = CStr::from_ptr(value).to_string_lossy().into_owned();
+            if let Some(free) = self.free {
+                free(value);
+            }
+            Ok(Some(result))
+        }
+    }
+}
+
+/// Find the plugin and function from `plugin:func` (or `plugin.func`
+/// in the templates)
+pub fn find<'a>(plugins: &'a [Plugin], spec: &'a str) -> Option<(&'a Plugin, &'a str)> {
+    let (name, func) = spec.split_once([':', '.'])?;
+    plugins.iter().find(|p| p.name == name).map(|p| (p, func))
+}
@@ -0,0 +1,468 @@
+//! Queries on the network given as the subcommands of `network`
+use std::cmp::Ordering;
+use std::collections::VecDeque;
+use std::io::Write;
+use std::path::PathBuf;
+
+use clap::{Args, Subcommand, ValueHint};
+use comfy_table::{presets, CellAlignment, Table};
+use regex::Regex;
+
+use crate::errors::NadiError;
+use crate::journal;
+use crate::locale::LocaleArgs;
+use crate::network::{csv_field, Network, Node};
+use crate::schema::Schema;
+
+#[derive(Subcommand)]
+pub enum NetworkQuery {
+    /// Find the nodes by name or attributes
+    Find(FindArgs),
+    /// Print the flow path between two nodes
+    Path(PathArgs),
+    /// List the nodes upstream of a node
+    Upstream(NeighborsArgs),
+    /// List the nodes downstream of a node
+    Downstream(NeighborsArgs),
+    /// Print the violations of the node attributes to a schema as csv
+    Check(CheckArgs),
+    /// Print the basin metrics of the network as a table
+    ///
+    /// Number of headwaters, Strahler order, bifurcation ratios,
+    /// diameter, longest flow path and the drainage density (if the
+    /// nodes have the --length-attr and --area-attr)
+    Metrics(MetricsArgs),
+}
+
+#[derive(Args)]
+pub struct FindArgs {
+    /// Attributes to show for the matching nodes
+    ///
+    /// Besides the node attributes, `index`, `inputs` and `output`
+    /// are the node index and the names of its neighbors
+    #[arg(short, long, value_delimiter = ',')]
+    show: Vec<String>,
+    /// Conditions the nodes have to match, all of them
+    ///
+    /// ATTR~REGEX matches the regular expression, and ATTR=VALUE,
+    /// ATTR!=VALUE, ATTR<VALUE, ATTR<=VALUE, ATTR>VALUE and
+    /// ATTR>=VALUE compare the values, as numbers if both are; the
+    /// attribute `name` is the node name, e.g. "name~Ohio" "area>100"
+    #[arg(required = true, value_parser=parse_condition)]
+    conditions: Vec<Condition>,
+}
+
+#[derive(Args)]
+pub struct PathArgs {
+    /// Attributes to show for the nodes in the path
+    #[arg(short, long, value_delimiter = ',')]
+    show: Vec<String>,
+    /// Node to start from
+    start: String,
+    /// Node to end at, downstream or upstream of the start
+    end: String,
+}
+
+#[derive(Args)]
+pub struct NeighborsArgs {
+    /// Only the nodes up to this many connections away
+    #[arg(short, long)]
+    depth: Option<usize>,
+    /// Write the connections between the nodes, including the given
+    /// node, as a connection file instead of the node names
+    #[arg(short, long)]
+    connections: bool,
+    /// Node to list the neighbors of
+    node: String,
+}
+
+#[derive(Args)]
+pub struct CheckArgs {
+    /// Schema of the node attributes, see --schema
+    #[arg(value_hint=ValueHint::FilePath)]
+    schema: PathBuf,
+}
+
+#[derive(Args)]
+pub struct MetricsArgs {
+    /// Attribute with the length of the stream from the node to its output
+    #[arg(long, default_value = "length")]
+    length_attr: String,
+    /// Attribute with the drainage area of the nodes
+    #[arg(long, default_value = "area")]
+    area_attr: String,
+}
+
+#[derive(Clone)]
+enum Op {
+    Matches(Regex),
+    Compare(Vec<Ordering>, String),
+}
+
+#[derive(Clone)]
+pub struct Condition {
+    attr: String,
+    op: Op,
+}
+
+fn parse_condition(arg: &str) -> anyhow::Result<Condition> {
+    let pos = arg
+        .find(['~', '=', '!', '<', '>'])
+        .filter(|&p| p > 0)
+        .ok_or_else(|| anyhow::Error::msg(format!("Condition should be ATTR<OP>VALUE: {arg}")))?;
+    let (attr, rest) = arg.split_at(pos);
+    let ops: [(&str, &[Ordering]); 7] = [
+        ("!=", &[Ordering::Less, Ordering::Greater]),
+        ("<=", &[Ordering::Less, Ordering::Equal]),
+        (">=", &[Ordering::Greater, Ordering::Equal]),
+        ("=", &[Ordering::Equal]),
+        ("<", &[Ordering::Less]),
+        (">", &[Ordering::Greater]),
+        ("~", &[]),
+    ];
+    let (op, value) = ops
+        .iter()
+        .find_map(|(o, ord)| rest.strip_prefix(o).map(|v| ((*o, *ord), v)))
+        .ok_or_else(|| anyhow::Error::msg(format!("Invalid operator in the condition {arg}")))?;
+    let op = match op {
+        ("~", _) => Op::Matches(Regex::new(value)?),
+        (_, ord) => Op::Compare(ord.to_vec(), value.to_string()),
+    };
+    Ok(Condition {
+        attr: attr.trim().to_string(),
+        op,
+    })
+}
+
+/// Value of the attribute of the node, including the `name`, `index`,
+/// `inputs` and `output` of the node
+fn node_value(net: &Network, node: &Node, attr: &str) -> Option<String> {
+    let name = |i: usize| net.nodes[i].get_name().to_string();
+    match attr {
+        "name" => Some(node.get_name().to_string()),
+        "index" => Some(node.get_index().to_string()),
+        "inputs" => Some(
+            node.get_inputs()
+                .iter()
+                .map(|&i| name(i))
+                .collect::<Vec<String>>()
+                .join(" "),
+        ),
+        "output" => node.get_output().map(name),
+        _ => node.get_attr(attr).map(|a| a.to_string()),
+    }
+}
+
+impl Condition {
+    fn matches(&self, net: &Network, node: &Node) -> bool {
+        let Some(value) = node_value(net, node, &self.attr) else {
+            return false;
+        };
+        match &self.op {
+            Op::Matches(re) => re.is_match(&value),
+            Op::Compare(ord, other) => {
+                let cmp = match (value.parse::<f64>(), other.parse::<f64>()) {
+                    (Ok(a), Ok(b)) => a.total_cmp(&b),
+                    _ => value.as_str().cmp(other.as_str()),
+                };
+                ord.contains(&cmp)
+            }
+        }
+    }
+}
+
+impl NetworkQuery {
+    /// Run the query, `length_attr` is the length of the reach from
+    /// the nodes to their output
+    pub fn run(
+        &self,
+        net: &Network,
+        length_attr: &str,
+        locale: &LocaleArgs,
+        writer: &mut impl Write,
+    ) -> Result<(), NadiError> {
+        match self {
+            Self::Find(args) => args.run(net, writer),
+            Self::Path(args) => args.run(net, length_attr, writer),
+            Self::Upstream(args) => args.run(net, true, writer),
+            Self::Downstream(args) => args.run(net, false, writer),
+            Self::Check(args) => args.run(net, writer),
+            Self::Metrics(args) => args.run(net, locale, writer),
+        }
+    }
+}
+
+impl MetricsArgs {
+    fn run(
+        &self,
+        net: &Network,
+        locale: &LocaleArgs,
+        writer: &mut impl Write,
+    ) -> Result<(), NadiError> {
+        let mut table = Table::new();
+        table
+            .load_preset(presets::UTF8_FULL_CONDENSED)
+            .set_header(["Metric", "Value"]);
+        for (metric, value) in net.metrics(&self.length_attr, &self.area_attr) {
+            table.add_row([metric, locale.localize(&value)]);
+        }
+        if let Some(c) = table.column_mut(1) {
+            c.set_cell_alignment(CellAlignment::Right);
+        }
+        writeln!(writer, "{table}")?;
+        Ok(())
+    }
+}
+
+impl CheckArgs {
+    fn run(&self, net: &Network, writer: &mut impl Write) -> Result<(), NadiError> {
+        let violations = Schema::from_file(&self.schema)?.violations(net);
+        writeln!(writer, "node,attribute,problem")?;
+        for (node, attr, problem) in &violations {
+            writeln!(
+                writer,
+                "{},{},{}",
+                csv_field(node),
+                csv_field(attr),
+                csv_field(problem)
+            )?;
+        }
+        journal::count("violations", violations.len());
+        if violations.is_empty() {
+            Ok(())
+        } else {
+            writer.flush()?;
+            Err(NadiError::AttributeError(format!(
+                "{} violations of the schema {:?}",
+                violations.len(),
+                self.schema
+            )))
+        }
+    }
+}
+
+impl FindArgs {
+    fn run(&self, net: &Network, writer: &mut impl Write) -> Result<(), NadiError> {
+        let mut columns: Vec<&str> = vec!["name"];
+        columns.extend(self.show.iter().map(|s| s.as_str()));
+        // the neighbors are always shown to locate the nodes
+        for col in ["inputs", "output"] {
+            if !columns.contains(&col) {
+                columns.push(col);
+            }
+        }
+        let mut table = Table::new();
+        table
+            .load_preset(presets::UTF8_FULL_CONDENSED)
+            .set_header(&columns);
+        let mut count = 0;
+        for node in &net.nodes {
+            if self.conditions.iter().all(|c| c.matches(net, node)) {
+                table.add_row(
+                    columns
+                        .iter()
+                        .map(|c| node_value(net, node, c).unwrap_or_default()),
+                );
+                count += 1;
+            }
+        }
+        if count == 0 {
+            return Err(NadiError::AttributeError(
+                "No nodes match the conditions".to_string(),
+            ));
+        }
+        writeln!(writer, "{table}")?;
+        Ok(())
+    }
+}
+
+fn node_index(net: &Network, name: &str) -> Result<usize, NadiError> {
+    net.indices
+        .get(name)
+        .copied()
+        .ok_or_else(|| NadiError::AttributeError(format!("Node {name} is not in the network")))
+}
+
+/// Nodes downstream of the node to the outlet, following the outputs
+/// and the branches of braided nodes
+fn flow_path(net: &Network, start: usize, end: usize) -> Option<Vec<usize>> {
+    let mut prev: Vec<Option<usize>> = vec![None; net.nodes.len()];
+    let mut queue = VecDeque::from([start]);
+    while let Some(n) = queue.pop_front() {
+        if n == end {
+            let mut path = vec![end];
+            while let Some(p) = prev[*path.last().unwrap()] {
+                path.push(p);
+            }
+            path.reverse();
+            return Some(path);
+        }
+        let node = &net.nodes[n];
+        for &o in node.get_output().iter().chain(node.get_branches()) {
+            if o != start && prev[o].is_none() {
+                prev[o] = Some(n);
+                queue.push_back(o);
+            }
+        }
+    }
+    None
+}
+
+impl PathArgs {
+    fn run(
+        &self,
+        net: &Network,
+        length_attr: &str,
+        writer: &mut impl Write,
+    ) -> Result<(), NadiError> {
+        let (start, end) = (node_index(net, &self.start)?, node_index(net, &self.end)?);
+        let path = match flow_path(net, start, end) {
+            Some(p) => p,
+            None => match flow_path(net, end, start) {
+                Some(p) => {
+                    journal::warn(format!(
+                        "{} is upstream of {}, the path is reversed",
+                        self.end, self.start
+                    ));
+                    p
+                }
+                None => {
+                    // the first node downstream of both, if any
+                    let mut below_start = Vec::new();
+                    let mut curr = Some(start);
+                    while let Some(n) = curr {
+                        below_start.push(n);
+                        curr = net.nodes[n].get_output();
+                    }
+                    let mut curr = Some(end);
+                    while let Some(n) = curr.filter(|n| !below_start.contains(n)) {
+                        curr = net.nodes[n].get_output();
+                    }
+                    let meet = curr
+                        .map(|n| format!(", they join at {}", net.nodes[n].get_name()))
+                        .unwrap_or_default();
+                    return Err(NadiError::AttributeError(format!(
+                        "No flow path between {} and {}{meet}",
+                        self.start, self.end
+                    )));
+                }
+            },
+        };
+
+        let mut columns: Vec<&str> = vec!["node"];
+        columns.extend(self.show.iter().map(|s| s.as_str()));
+        columns.extend([length_attr, "cumulative"]);
+        let mut table = Table::new();
+        table
+            .load_preset(presets::UTF8_FULL_CONDENSED)
+            .set_header(&columns);
+        let mut cumulative = 0.0;
+        let mut missing = Vec::new();
+        for (i, &n) in path.iter().enumerate() {
+            let node = &net.nodes[n];
+            let mut row = vec![node.get_name().to_string()];
+            row.extend(
+                self.show
+                    .iter()
+                    .map(|c| node_value(net, node, c).unwrap_or_default()),
+            );
+            // length of the hop to the next node in the path
+            let length = path.get(i + 1).map(|&next| {
+                net.edge_attr(n, next, length_attr)
+                    .or_else(|| node.get_attr(length_attr))
+                    .and_then(|l| l.read_value())
+            });
+            row.push(match length {
+                Some(Some(l)) => l.to_string(),
+                Some(None) => {
+                    missing.push(node.get_name());
+                    "?".to_string()
+                }
+                None => String::new(),
+            });
+            row.push(cumulative.to_string());
+            cumulative += length.flatten().unwrap_or(0.0);
+            table.add_row(row);
+        }
+        writeln!(writer, "{table}")?;
+        if !missing.is_empty() {
+            journal::warn(format!(
+                "Nodes without {length_attr}, not included in the cumulative length: {}",
+                missing.join(", ")
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl NeighborsArgs {
+    fn run(&self, net: &Network, upstream: bool, writer: &mut impl Write) -> Result<(), NadiError> {
+        let start = node_index(net, &self.node)?;
+        // the braided nodes are upstream of their branches too
+        let mut branch_inputs: Vec<Vec<usize>> = vec![Vec::new(); net.nodes.len()];
+        for node in &net.nodes {
+            for &b in node.get_branches() {
+                branch_inputs[b].push(node.get_index());
+            }
+        }
+        let neighbors = |n: usize| -> Vec<usize> {
+            let node = &net.nodes[n];
+            if upstream {
+                node.get_inputs()
+                    .iter()
+                    .chain(&branch_inputs[n])
+                    .copied()
+                    .collect()
+            } else {
+                node.get_output()
+                    .iter()
+                    .chain(node.get_branches())
+                    .copied()
+                    .collect()
+            }
+        };
+
+        let mut selected = vec![false; net.nodes.len()];
+        selected[start] = true;
+        let mut nodes = vec![start];
+        let mut queue = VecDeque::from([(start, 0)]);
+        while let Some((n, depth)) = queue.pop_front() {
+            if self.depth.is_some_and(|d| depth >= d) {
+                continue;
+            }
+            for o in neighbors(n) {
+                if !selected[o] {
+                    selected[o] = true;
+                    nodes.push(o);
+                    queue.push_back((o, depth + 1));
+                }
+            }
+        }
+
+        if !self.connections {
+            for &n in &nodes[1..] {
+                writeln!(writer, "{}", net.nodes[n].get_name())?;
+            }
+            return Ok(());
+        }
+        write!(writer, "{}", journal::metadata_header(&[]))?;
+        for &n in &nodes {
+            let node = &net.nodes[n];
+            let outputs: Vec<usize> = node
+                .get_output()
+                .iter()
+                .chain(node.get_branches())
+                .copied()
+                .filter(|&o| selected[o])
+                .collect();
+            for &o in &outputs {
+                writeln!(writer, "{} -> {}", node.get_name(), net.nodes[o].get_name())?;
+            }
+            // nodes without any connections in the selection
+            if outputs.is_empty() && !node.get_inputs().iter().any(|&i| selected[i]) {
+                writeln!(writer, "{}", node.get_name())?;
+            }
+        }
+        Ok(())
+    }
+}
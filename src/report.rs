@@ -0,0 +1,411 @@
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use anyhow::Context;
+use clap::{Args, ValueHint};
+use polars::export::chrono::{Datelike, NaiveDate};
+use serde::Deserialize;
+use string_template_plus::Template;
+
+use crate::cliargs::{CliAction, GlobalArgs};
+use crate::errors::{parse_toml, NadiError};
+use crate::journal;
+use crate::locale::LocaleArgs;
+use crate::network::{Network, Node};
+use crate::theme::Theme;
+use crate::timeseries::Discharges;
+
+#[derive(Args)]
+pub struct CliArgs {
+    /// Only write the LaTeX file, without running pdflatex
+    ///
+    /// The LaTeX file is saved next to the --output pdf file
+    /// (report.pdf by default)
+    #[arg(short, long)]
+    tex_only: bool,
+    /// Theme for the plots, see `nadi network --theme`
+    #[arg(long, default_value = "default", value_parser=Theme::parse)]
+    theme: Theme,
+    #[command(flatten)]
+    locale: LocaleArgs,
+    /// Report config file
+    ///
+    /// Toml file with `network` (connection file), `timeseries`
+    /// (template for the csv file of the nodes, default
+    /// "timeseries/{name}.csv"), `title` (template for the page
+    /// titles), `sections` (hydrograph, seasonality, duration and
+    /// stats in the order on the page), `attrs` (node attributes for
+    /// the stats table), `datetime_col`, `discharge_col` and
+    /// `prelude` (extra LaTeX for the preamble). Paths are relative
+    /// to the config file directory.
+    #[arg(value_hint=ValueHint::FilePath)]
+    config: PathBuf,
+}
+
+#[derive(Deserialize, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+enum Section {
+    /// Timeseries of the values
+    Hydrograph,
+    /// Mean of each month
+    Seasonality,
+    /// Flow duration curve
+    Duration,
+    /// Table of the statistics and the node attributes
+    Stats,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Config {
+    network: PathBuf,
+    #[serde(default = "default_timeseries")]
+    timeseries: String,
+    #[serde(default = "default_title")]
+    title: String,
+    #[serde(default = "default_sections")]
+    sections: Vec<Section>,
+    #[serde(default)]
+    attrs: Vec<String>,
+    #[serde(default = "default_datetime_col")]
+    datetime_col: String,
+    #[serde(default = "default_discharge_col")]
+    discharge_col: String,
+    #[serde(default)]
+    prelude: String,
+}
+
+fn default_timeseries() -> String {
+    "timeseries/{name}.csv".to_string()
+}
+
+fn default_title() -> String {
+    "{name}".to_string()
+}
+
+fn default_sections() -> Vec<Section> {
+    vec![
+        Section::Hydrograph,
+        Section::Seasonality,
+        Section::Duration,
+        Section::Stats,
+    ]
+}
+
+fn default_datetime_col() -> String {
+    "date".to_string()
+}
+
+fn default_discharge_col() -> String {
+    "flow".to_string()
+}
+
+impl Config {
+    fn from_file(filename: &Path) -> Result<Self, NadiError> {
+        let contents = std::fs::read_to_string(filename)?;
+        journal::input(filename);
+        parse_toml(filename, &contents)
+    }
+}
+
+/// Maximum number of points in the hydrograph, longer timeseries are
+/// averaged over the consecutive days to keep pdflatex within its
+/// memory limits
+const MAX_POINTS: usize = 2000;
+
+const MONTHS: &str = "Jan,Feb,Mar,Apr,May,Jun,Jul,Aug,Sep,Oct,Nov,Dec";
+
+fn latex_escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' | '%' | '$' | '#' | '_' | '{' | '}' => {
+                escaped.push('\\');
+                escaped.push(c);
+            }
+            '~' => escaped.push_str(r"\textasciitilde{}"),
+            '^' => escaped.push_str(r"\textasciicircum{}"),
+            '\\' => escaped.push_str(r"\textbackslash{}"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Date as the fractional year for the x axis
+fn year_fraction(date: NaiveDate) -> f64 {
+    let days = if date.leap_year() { 366.0 } else { 365.0 };
+    date.year() as f64 + date.ordinal0() as f64 / days
+}
+
+/// Style of the plot line, the width from the theme or the default
+fn line_style(theme: &Theme, default: &str) -> String {
+    match theme.line_width {
+        Some(w) => format!("theme0, line width={w}pt"),
+        None => format!("theme0, {default}"),
+    }
+}
+
+fn write_hydrograph(
+    writer: &mut impl Write,
+    values: &[(NaiveDate, f64)],
+    ylabel: &str,
+    theme: &Theme,
+) -> anyhow::Result<()> {
+    writeln!(
+        writer,
+        r"\begin{{tikzpicture}}
+\begin{{axis}}[width=\textwidth, height=5cm, ylabel={{{ylabel}}}, xticklabel style={{/pgf/number format/1000 sep={{}}}}, unbounded coords=jump]
+\addplot[{}] coordinates {{",
+        line_style(theme, "thin")
+    )?;
+    let step = values.len().div_ceil(MAX_POINTS).max(1);
+    let mut prev: Option<NaiveDate> = None;
+    for chunk in values.chunks(step) {
+        let date = chunk[0].0;
+        // break the line at the missing days
+        if prev.is_some_and(|p| (date - p).num_days() > step as i64) {
+            write!(writer, "({},nan) ", year_fraction(date))?;
+        }
+        let mean = chunk.iter().map(|v| v.1).sum::<f64>() / chunk.len() as f64;
+        write!(writer, "({},{}) ", year_fraction(date), mean)?;
+        prev = chunk.last().map(|v| v.0);
+    }
+    writeln!(writer, "}};\n\\end{{axis}}\n\\end{{tikzpicture}}\n")?;
+    Ok(())
+}
+
+fn write_seasonality(
+    writer: &mut impl Write,
+    values: &[(NaiveDate, f64)],
+    ylabel: &str,
+) -> anyhow::Result<()> {
+    let mut sums = [(0.0, 0usize); 12];
+    for (date, v) in values {
+        let m = date.month0() as usize;
+        sums[m] = (sums[m].0 + v, sums[m].1 + 1);
+    }
+    writeln!(
+        writer,
+        r"\begin{{tikzpicture}}
+\begin{{axis}}[width=0.48\textwidth, height=5cm, ybar, ymin=0, title={{Seasonality}}, ylabel={{{ylabel}}}, xtick={{1,...,12}}, xticklabels={{{MONTHS}}}, xticklabel style={{font=\tiny}}]
+\addplot[draw=theme0, fill=theme0!40] coordinates {{"
+    )?;
+    for (m, (sum, count)) in sums.iter().enumerate() {
+        if *count > 0 {
+            write!(writer, "({},{}) ", m + 1, sum / *count as f64)?;
+        }
+    }
+    writeln!(writer, "}};\n\\end{{axis}}\n\\end{{tikzpicture}}")?;
+    Ok(())
+}
+
+fn write_duration(
+    writer: &mut impl Write,
+    values: &[(NaiveDate, f64)],
+    ylabel: &str,
+    theme: &Theme,
+) -> anyhow::Result<()> {
+    let mut sorted: Vec<f64> = values.iter().map(|v| v.1).collect();
+    sorted.sort_by(|a, b| b.total_cmp(a));
+    let log = sorted.last().is_some_and(|v| *v > 0.0);
+    writeln!(
+        writer,
+        r"\begin{{tikzpicture}}
+\begin{{axis}}[width=0.48\textwidth, height=5cm, title={{Flow duration}}, xlabel={{Exceedance (\%)}}, ylabel={{{ylabel}}}, xmin=0, xmax=100{}]
+\addplot[{}] coordinates {{",
+        if log { ", ymode=log" } else { "" },
+        line_style(theme, "thick")
+    )?;
+    let n = sorted.len();
+    for p in 0..=200 {
+        let i = ((n - 1) * p) / 200;
+        // Weibull plotting position of the rank
+        write!(
+            writer,
+            "({},{}) ",
+            (i + 1) as f64 * 100.0 / (n + 1) as f64,
+            sorted[i]
+        )?;
+    }
+    writeln!(writer, "}};\n\\end{{axis}}\n\\end{{tikzpicture}}")?;
+    Ok(())
+}
+
+fn write_stats(
+    writer: &mut impl Write,
+    values: &[(NaiveDate, f64)],
+    node: &Node,
+    attrs: &[String],
+    locale: &LocaleArgs,
+) -> anyhow::Result<()> {
+    let mut sorted: Vec<f64> = values.iter().map(|v| v.1).collect();
+    sorted.sort_by(|a, b| a.total_cmp(b));
+    let n = sorted.len() as f64;
+    let mean = sorted.iter().sum::<f64>() / n;
+    let std = (sorted.iter().map(|v| (v - mean).powi(2)).sum::<f64>() / (n - 1.0).max(1.0)).sqrt();
+    let (start, end) = (values[0].0, values[values.len() - 1].0);
+    let days = (end - start).num_days() + 1;
+    let rows = vec![
+        ("Start".to_string(), start.to_string()),
+        ("End".to_string(), end.to_string()),
+        ("Days with values".to_string(), values.len().to_string()),
+        (
+            "Missing days".to_string(),
+            (days - values.len() as i64).to_string(),
+        ),
+        ("Mean".to_string(), format!("{mean:.3}")),
+        (
+            "Median".to_string(),
+            format!("{:.3}", sorted[sorted.len() / 2]),
+        ),
+        ("Minimum".to_string(), format!("{:.3}", sorted[0])),
+        (
+            "Maximum".to_string(),
+            format!("{:.3}", sorted[sorted.len() - 1]),
+        ),
+        ("Standard deviation".to_string(), format!("{std:.3}")),
+    ];
+    let mut rows: Vec<(String, String)> = rows
+        .into_iter()
+        .map(|(key, val)| (key, locale.localize(&val)))
+        .collect();
+    for attr in attrs {
+        let val = node
+            .get_attr(attr)
+            .map(|a| a.localized(locale))
+            .unwrap_or_default();
+        rows.push((attr.clone(), val));
+    }
+    writeln!(writer, "\n\\begin{{tabular}}{{lr}}\n\\toprule")?;
+    for (key, val) in rows {
+        writeln!(
+            writer,
+            "{} & {} \\\\",
+            latex_escape(&key),
+            latex_escape(&val)
+        )?;
+    }
+    writeln!(writer, "\\bottomrule\n\\end{{tabular}}\n")?;
+    Ok(())
+}
+
+impl CliAction for CliArgs {
+    fn run(self, global: &GlobalArgs) -> anyhow::Result<()> {
+        let pdf_file = global
+            .output
+            .clone()
+            .unwrap_or_else(|| PathBuf::from("report.pdf"));
+        let config = Config::from_file(&self.config)?;
+        let wd = self
+            .config
+            .parent()
+            .unwrap_or(&PathBuf::from("."))
+            .to_path_buf();
+        let net = Network::from_file(&wd.join(&config.network))?;
+        let title = Template::parse_template(&config.title)?;
+        let timeseries = Template::parse_template(&config.timeseries)?;
+        let ylabel = latex_escape(&config.discharge_col);
+
+        let tex_file = pdf_file.with_extension("tex");
+        journal::output(&tex_file);
+        let mut writer = BufWriter::new(File::create(&tex_file)?);
+        writeln!(
+            writer,
+            r"\documentclass{{article}}
+
+\usepackage[margin=2cm]{{geometry}}
+\usepackage{{booktabs}}
+\usepackage{{pgfplots}}
+\pgfplotsset{{compat=1.16}}
+\pgfkeys{{/pgf/number format/.cd, set decimal separator={{{{{}}}}}, set thousands separator={{{{{}}}}}, min exponent for 1000 sep=4}}
+{}{}
+
+\begin{{document}}",
+            self.locale.decimal_sep(),
+            self.locale.thousands_sep(),
+            self.theme.latex(),
+            config.prelude.trim_end()
+        )?;
+        let mut count = 0;
+        let mut failed = Vec::new();
+        for node in &net.nodes {
+            let path = wd.join(node.format(&timeseries));
+            if !path.exists() {
+                continue;
+            }
+            let values = Discharges::new(&path, &config.datetime_col, &config.discharge_col, None)
+                .and_then(|ts| ts.values());
+            let values = match values {
+                Ok(v) if v.is_empty() => {
+                    journal::warn(format!("{} doesn't have any values", node.get_name()));
+                    failed.push(node.get_name());
+                    continue;
+                }
+                Ok(v) => v,
+                Err(e) => {
+                    journal::warn(format!("Skipping {}: {e}", node.get_name()));
+                    failed.push(node.get_name());
+                    continue;
+                }
+            };
+            writeln!(
+                writer,
+                "\\section*{{{}}}\n",
+                latex_escape(&node.format(&title))
+            )?;
+            for section in &config.sections {
+                match section {
+                    Section::Hydrograph => {
+                        write_hydrograph(&mut writer, &values, &ylabel, &self.theme)?
+                    }
+                    Section::Seasonality => write_seasonality(&mut writer, &values, &ylabel)?,
+                    Section::Duration => {
+                        write_duration(&mut writer, &values, &ylabel, &self.theme)?
+                    }
+                    Section::Stats => {
+                        write_stats(&mut writer, &values, node, &config.attrs, &self.locale)?
+                    }
+                }
+            }
+            writeln!(writer, "\\newpage\n")?;
+            count += 1;
+        }
+        if !failed.is_empty() {
+            writeln!(
+                writer,
+                "\\section*{{Skipped nodes}}\n\nThe timeseries of these nodes couldn't be read: {}.\n",
+                latex_escape(&failed.join(", "))
+            )?;
+        }
+        writeln!(writer, r"\end{{document}}")?;
+        writer.flush()?;
+        journal::count("pages", count);
+        journal::count("skipped", failed.len());
+        if self.tex_only {
+            return Ok(());
+        }
+
+        let dir = match tex_file.parent() {
+            Some(d) if !d.as_os_str().is_empty() => d.to_path_buf(),
+            _ => PathBuf::from("."),
+        };
+        let output = Command::new("pdflatex")
+            .args([
+                "-interaction=nonstopmode",
+                "-halt-on-error",
+                "-output-directory",
+            ])
+            .arg(&dir)
+            .arg(&tex_file)
+            .output()
+            .context("Couldn't run pdflatex, use --tex-only to only write the LaTeX file")?;
+        if !output.status.success() {
+            anyhow::bail!("pdflatex failed, see {:?}", tex_file.with_extension("log"));
+        }
+        journal::output(&pdf_file);
+        Ok(())
+    }
+}
@@ -0,0 +1,292 @@
+use std::collections::HashMap;
+use std::io::Write;
+use std::path::PathBuf;
+
+use clap::{Args, ValueEnum};
+use polars::export::chrono::{Duration, NaiveDate};
+use string_template_plus::Template;
+
+use crate::cliargs::{CliAction, GlobalArgs, OutputFormat};
+use crate::journal;
+use crate::network::{csv_field, AttrsFiles, Network};
+use crate::timeseries::{write_daily, Discharges};
+
+#[derive(Args)]
+pub struct CliArgs {
+    /// Template for the csv file of the reservoir series of the nodes
+    ///
+    /// Relative to the connection file directory, the nodes with the
+    /// file are the reservoirs
+    #[arg(short, long, default_value = "reservoirs/{name}.csv", value_parser=Template::parse_template)]
+    series: Template,
+    /// Template for the inflow csv files written for the reservoirs
+    #[arg(short, long, default_value = "inflow/{name}.csv", value_parser=Template::parse_template)]
+    inflow: Template,
+    /// column name containing the storage at the end of the day
+    #[arg(long, default_value = "storage")]
+    storage_col: String,
+    /// column name containing the release (outflow) of the day
+    #[arg(long, default_value = "release")]
+    release_col: String,
+    /// column name containing the evaporation of the day, in the
+    /// --flow-unit or as a depth with --evap-depth
+    #[arg(long)]
+    evap_col: Option<String>,
+    /// Unit of the evaporation depth, the evaporation is the depth
+    /// times the surface area of the day in the --area-col
+    #[arg(
+        long,
+        rename_all = "lower",
+        value_enum,
+        requires = "evap_col",
+        requires = "area_col"
+    )]
+    evap_depth: Option<DepthUnit>,
+    /// column name containing the surface area of the reservoir
+    #[arg(long)]
+    area_col: Option<String>,
+    /// Unit of the surface area
+    #[arg(long, rename_all = "lower", value_enum, default_value = "km2")]
+    area_unit: AreaUnit,
+    /// Unit of the storage
+    #[arg(long, rename_all = "lower", value_enum, default_value = "m3")]
+    storage_unit: VolumeUnit,
+    /// Unit of the release, evaporation flow and the calculated inflow
+    #[arg(long, rename_all = "lower", value_enum, default_value = "cms")]
+    flow_unit: FlowUnit,
+    /// What to do with the days with missing values
+    #[arg(short, long, rename_all = "lower", value_enum, default_value = "blank")]
+    missing: MissingPolicy,
+    /// Longest gap in days filled by --missing interpolate
+    #[arg(long, default_value = "7")]
+    max_gap: i64,
+    #[command(flatten)]
+    attrs: AttrsFiles,
+    /// Connection file
+    connection_file: PathBuf,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, ValueEnum)]
+enum VolumeUnit {
+    /// Cubic meters
+    M3,
+    /// Million cubic meters
+    Mcm,
+    /// Acre-feet
+    Af,
+    /// Thousand acre-feet
+    Kaf,
+}
+
+impl VolumeUnit {
+    fn cubic_meters(&self) -> f64 {
+        match self {
+            Self::M3 => 1.0,
+            Self::Mcm => 1e6,
+            Self::Af => 1233.48183754752,
+            Self::Kaf => 1233481.83754752,
+        }
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, ValueEnum)]
+enum FlowUnit {
+    /// Cubic meters per second
+    Cms,
+    /// Cubic feet per second
+    Cfs,
+}
+
+impl FlowUnit {
+    fn cubic_meters_per_second(&self) -> f64 {
+        match self {
+            Self::Cms => 1.0,
+            Self::Cfs => 0.028316846592,
+        }
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, ValueEnum)]
+enum DepthUnit {
+    /// Millimeters per day
+    Mm,
+    /// Inches per day
+    In,
+}
+
+impl DepthUnit {
+    fn meters(&self) -> f64 {
+        match self {
+            Self::Mm => 0.001,
+            Self::In => 0.0254,
+        }
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, ValueEnum)]
+enum AreaUnit {
+    /// Square meters
+    M2,
+    /// Square kilometers
+    Km2,
+    /// Acres
+    Acre,
+}
+
+impl AreaUnit {
+    fn square_meters(&self) -> f64 {
+        match self {
+            Self::M2 => 1.0,
+            Self::Km2 => 1e6,
+            Self::Acre => 4046.8564224,
+        }
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, ValueEnum)]
+enum MissingPolicy {
+    /// Leave the inflow of the days without all the values blank
+    Blank,
+    /// Fill the gaps in the series up to --max-gap days linearly, the
+    /// missing evaporation is taken as zero
+    Interpolate,
+}
+
+type Series = HashMap<NaiveDate, f64>;
+
+/// Fill the gaps of up to `max_gap` days between the values linearly
+fn interpolate(series: &mut Series, max_gap: i64) {
+    let mut dates: Vec<NaiveDate> = series.keys().copied().collect();
+    dates.sort();
+    for w in dates.windows(2) {
+        let gap = (w[1] - w[0]).num_days();
+        if gap < 2 || gap - 1 > max_gap {
+            continue;
+        }
+        let (a, b) = (series[&w[0]], series[&w[1]]);
+        for d in 1..gap {
+            let value = a + (b - a) * d as f64 / gap as f64;
+            series.insert(w[0] + Duration::days(d), value);
+        }
+    }
+}
+
+impl CliArgs {
+    fn load(&self, path: &PathBuf, column: &str) -> anyhow::Result<Series> {
+        let ts = Discharges::new(path, self.attrs.datetime_col(), column, None)?;
+        Ok(ts.values()?.into_iter().collect())
+    }
+
+    /// Inflow of each day from the change in storage since the
+    /// previous day, the release and the evaporation
+    fn inflows(&self, path: &PathBuf) -> anyhow::Result<Vec<(NaiveDate, Option<f64>)>> {
+        let mut storage = self.load(path, &self.storage_col)?;
+        let mut release = self.load(path, &self.release_col)?;
+        let evap = match &self.evap_col {
+            Some(col) => Some(self.load(path, col)?),
+            None => None,
+        };
+        // the evaporation depth is converted to the flow with the area
+        let mut area = match (&self.evap_depth, &self.area_col) {
+            (Some(_), Some(col)) => Some(self.load(path, col)?),
+            _ => None,
+        };
+        if self.missing == MissingPolicy::Interpolate {
+            interpolate(&mut storage, self.max_gap);
+            interpolate(&mut release, self.max_gap);
+            if let Some(area) = &mut area {
+                interpolate(area, self.max_gap);
+            }
+        }
+        let (Some(start), Some(end)) = (storage.keys().min(), storage.keys().max()) else {
+            return Ok(Vec::new());
+        };
+        // change in storage per day in the flow unit
+        let factor =
+            self.storage_unit.cubic_meters() / 86400.0 / self.flow_unit.cubic_meters_per_second();
+        let depth_factor = self.evap_depth.map_or(1.0, |d| {
+            d.meters() * self.area_unit.square_meters()
+                / 86400.0
+                / self.flow_unit.cubic_meters_per_second()
+        });
+        let days = (*end - *start).num_days();
+        Ok((1..=days)
+            .map(|d| {
+                let date = *start + Duration::days(d);
+                let evap = match &evap {
+                    Some(e) => match (e.get(&date), self.missing) {
+                        (Some(v), _) => match &area {
+                            Some(a) => a.get(&date).map(|a| v * a * depth_factor),
+                            None => Some(*v),
+                        },
+                        (None, MissingPolicy::Interpolate) => Some(0.0),
+                        (None, MissingPolicy::Blank) => None,
+                    },
+                    None => Some(0.0),
+                };
+                let prev = storage.get(&(date - Duration::days(1)));
+                let inflow = match (storage.get(&date), prev, release.get(&date), evap) {
+                    (Some(s), Some(p), Some(r), Some(e)) => Some((s - p) * factor + r + e),
+                    _ => None,
+                };
+                (date, inflow)
+            })
+            .collect())
+    }
+}
+
+impl CliAction for CliArgs {
+    fn run(self, global: &GlobalArgs) -> anyhow::Result<()> {
+        global.format(&[OutputFormat::Csv])?;
+        let net = Network::from_file_attrs(&self.connection_file, false, &self.attrs)?;
+        let mut writer = global.writer()?;
+        writeln!(writer, "node,days,missing_days,negative_days,inflow_mean")?;
+        let mut count = 0;
+        for node in &net.nodes {
+            let path = node.file(&self.series);
+            if !path.exists() {
+                continue;
+            }
+            let inflows = match self.inflows(&path) {
+                Ok(i) => i,
+                Err(e) => {
+                    journal::warn(format!("Skipping {}: {e:#}", node.get_name()));
+                    continue;
+                }
+            };
+            let values: Vec<f64> = inflows.iter().filter_map(|(_, v)| *v).collect();
+            let missing = inflows.len() - values.len();
+            let negative = values.iter().filter(|v| **v < 0.0).count();
+            if negative > 0 {
+                journal::warn(format!(
+                    "{} days of negative inflow for {}",
+                    negative,
+                    node.get_name()
+                ));
+            }
+            let mean =
+                (!values.is_empty()).then(|| values.iter().sum::<f64>() / values.len() as f64);
+            let columns = [self.attrs.datetime_col(), "inflow"];
+            write_daily(&node.file(&self.inflow), &[&path], columns, &inflows)?;
+            if let Some(mean) = mean {
+                net.save_attrs(
+                    node.get_name(),
+                    vec![("inflow_mean".to_string(), mean.to_string())],
+                )?;
+            }
+            writeln!(
+                writer,
+                "{},{},{},{},{}",
+                csv_field(node.get_name()),
+                inflows.len(),
+                missing,
+                negative,
+                mean.map(|m| m.to_string()).unwrap_or_default()
+            )?;
+            count += 1;
+        }
+        journal::count("reservoirs", count);
+        writer.finish()?;
+        Ok(())
+    }
+}
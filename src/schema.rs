@@ -0,0 +1,115 @@
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use serde::Deserialize;
+
+use crate::errors::{parse_toml, NadiError};
+use crate::network::{Network, NodeAttr};
+
+#[derive(Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum AttrType {
+    String,
+    Integer,
+    Float,
+    List,
+}
+
+/// Rules for an attribute of the nodes
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct AttrRule {
+    #[serde(rename = "type")]
+    ty: Option<AttrType>,
+    #[serde(default)]
+    required: bool,
+    min: Option<f32>,
+    max: Option<f32>,
+    /// Units of the values, checked against the `{ATTR}_units`
+    /// attribute when the node has it
+    units: Option<String>,
+    /// Allowed values of the attribute
+    values: Option<Vec<String>>,
+}
+
+/// Attributes the nodes should have, read from a toml file with a
+/// `[attrs.NAME]` table for each attribute
+#[derive(Deserialize)]
+pub struct Schema {
+    attrs: BTreeMap<String, AttrRule>,
+}
+
+impl Schema {
+    pub fn from_file(filename: &Path) -> Result<Self, NadiError> {
+        let contents = std::fs::read_to_string(filename)?;
+        parse_toml(filename, &contents)
+    }
+
+    /// Problems with the node attributes as (node, attribute, message)
+    pub fn violations(&self, net: &Network) -> Vec<(String, String, String)> {
+        let mut problems = Vec::new();
+        for node in &net.nodes {
+            for (key, rule) in &self.attrs {
+                let mut problem = |msg: String| {
+                    problems.push((node.get_name().to_string(), key.clone(), msg));
+                };
+                let Some(attr) = node.get_attr(key) else {
+                    if rule.required {
+                        problem("missing".to_string());
+                    }
+                    continue;
+                };
+                let ty = match attr {
+                    NodeAttr::String(_) => AttrType::String,
+                    NodeAttr::Number(_) => AttrType::Integer,
+                    NodeAttr::Value(_) => AttrType::Float,
+                    NodeAttr::Vec(_) => AttrType::List,
+                };
+                match rule.ty {
+                    // integers are valid floats, and anything can be a string
+                    Some(AttrType::Float) if ty == AttrType::Integer => (),
+                    Some(AttrType::String) => (),
+                    Some(t) if t != ty => {
+                        problem(format!("{} is not {}", attr, t.name()));
+                        continue;
+                    }
+                    _ => (),
+                }
+                if let Some(val) = attr.read_value() {
+                    if rule.min.map(|m| val < m).unwrap_or(false) {
+                        problem(format!("{} is less than {}", val, rule.min.unwrap()));
+                    }
+                    if rule.max.map(|m| val > m).unwrap_or(false) {
+                        problem(format!("{} is more than {}", val, rule.max.unwrap()));
+                    }
+                } else if rule.min.is_some() || rule.max.is_some() {
+                    problem(format!("{} is not a number", attr));
+                }
+                if let Some(values) = &rule.values {
+                    if !values.contains(&attr.to_string()) {
+                        problem(format!("{} is not one of {}", attr, values.join(", ")));
+                    }
+                }
+                if let (Some(units), Some(node_units)) =
+                    (&rule.units, node.get_attr(&format!("{key}_units")))
+                {
+                    if &node_units.to_string() != units {
+                        problem(format!("units {} instead of {}", node_units, units));
+                    }
+                }
+            }
+        }
+        problems
+    }
+}
+
+impl AttrType {
+    fn name(&self) -> &str {
+        match self {
+            Self::String => "a string",
+            Self::Integer => "an integer",
+            Self::Float => "a float",
+            Self::List => "a list",
+        }
+    }
+}
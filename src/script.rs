@@ -0,0 +1,330 @@
+use std::cell::RefCell;
+use std::path::Path;
+use std::rc::Rc;
+
+use rhai::{Array, Dynamic, Engine, EvalAltResult, Map, Scope, FLOAT, INT};
+use string_template_plus::Template;
+
+use crate::errors::NadiError;
+use crate::journal;
+use crate::network::{Network, NodeAttr};
+
+/// Node of the network in the scripts
+#[derive(Clone)]
+struct ScriptNode {
+    net: Rc<RefCell<Network>>,
+    index: usize,
+}
+
+impl ScriptNode {
+    fn new(net: &Rc<RefCell<Network>>, index: usize) -> Self {
+        Self {
+            net: net.clone(),
+            index,
+        }
+    }
+}
+
+fn attr_to_dynamic(attr: &NodeAttr) -> Dynamic {
+    match attr {
+        NodeAttr::String(s) => s.clone().into(),
+        NodeAttr::Number(n) => (*n as INT).into(),
+        NodeAttr::Value(v) => (*v as FLOAT).into(),
+        NodeAttr::Vec(v) => v
+            .iter()
+            .map(|i| Dynamic::from(*i as INT))
+            .collect::<Array>()
+            .into(),
+    }
+}
+
+fn dynamic_to_attr(val: Dynamic) -> Result<NodeAttr, Box<EvalAltResult>> {
+    if let Ok(i) = val.as_int() {
+        return Ok(match usize::try_from(i) {
+            Ok(n) => NodeAttr::number(n),
+            Err(_) => NodeAttr::value(i as f32),
+        });
+    }
+    if let Ok(f) = val.as_float() {
+        return Ok(NodeAttr::value(f as f32));
+    }
+    if let Ok(b) = val.as_bool() {
+        return Ok(NodeAttr::number(b as usize));
+    }
+    if val.is_array() {
+        let indices = val
+            .into_array()?
+            .into_iter()
+            .map(|v| v.as_int().ok().and_then(|i| usize::try_from(i).ok()))
+            .collect::<Option<Vec<usize>>>()
+            .ok_or("Only arrays of positive integers can be node attributes")?;
+        return Ok(NodeAttr::vec(indices));
+    }
+    Ok(NodeAttr::string(val.to_string()))
+}
+
+fn build_engine(net: &Rc<RefCell<Network>>) -> Engine {
+    let mut engine = Engine::new();
+    engine
+        .register_type_with_name::<ScriptNode>("Node")
+        .register_get("name", |n: &mut ScriptNode| {
+            n.net.borrow().nodes[n.index].get_name().to_string()
+        })
+        .register_get("index", |n: &mut ScriptNode| n.index as INT)
+        .register_get("inputs", |n: &mut ScriptNode| {
+            let inputs = n.net.borrow().nodes[n.index].get_inputs().to_vec();
+            inputs
+                .into_iter()
+                .map(|i| Dynamic::from(ScriptNode::new(&n.net, i)))
+                .collect::<Array>()
+        })
+        .register_get("output", |n: &mut ScriptNode| {
+            match n.net.borrow().nodes[n.index].get_output() {
+                Some(o) => Dynamic::from(ScriptNode::new(&n.net, o)),
+                None => Dynamic::UNIT,
+            }
+        })
+        .register_fn("to_string", |n: &mut ScriptNode| {
+            n.net.borrow().nodes[n.index].get_name().to_string()
+        })
+        .register_fn("has", |n: &mut ScriptNode, attr: &str| {
+            n.net.borrow().nodes[n.index].get_attr(attr).is_some()
+        })
+        .register_fn("get", |n: &mut ScriptNode, attr: &str| {
+            n.net.borrow().nodes[n.index]
+                .get_attr(attr)
+                .map(attr_to_dynamic)
+                .unwrap_or(Dynamic::UNIT)
+        })
+        .register_fn(
+            "set",
+            |n: &mut ScriptNode, attr: &str, val: Dynamic| -> Result<(), Box<EvalAltResult>> {
+                let val = dynamic_to_attr(val)?;
+                n.net.borrow_mut().nodes[n.index].set_attr(attr, val);
+                Ok(())
+            },
+        )
+        .register_fn("attrs", |n: &mut ScriptNode| {
+            let mut keys: Vec<String> = n.net.borrow().nodes[n.index]
+                .get_attrs()
+                .keys()
+                .cloned()
+                .collect();
+            keys.sort();
+            keys.into_iter().map(Dynamic::from).collect::<Array>()
+        })
+        .register_fn(
+            "format",
+            |n: &mut ScriptNode, templ: &str| -> Result<String, Box<EvalAltResult>> {
+                let templ = Template::parse_template(templ).map_err(|e| e.to_string())?;
+                Ok(n.net.borrow().nodes[n.index].format(&templ))
+            },
+        );
+
+    let nodes_net = net.clone();
+    engine.register_fn("nodes", move || {
+        (0..nodes_net.borrow().nodes.len())
+            .map(|i| Dynamic::from(ScriptNode::new(&nodes_net, i)))
+            .collect::<Array>()
+    });
+    let node_net = net.clone();
+    engine.register_fn("node", move |name: &str| {
+        let index = node_net.borrow().indices.get(name).copied();
+        match index {
+            Some(i) => Dynamic::from(ScriptNode::new(&node_net, i)),
+            None => Dynamic::UNIT,
+        }
+    });
+    engine.register_fn(
+        "write",
+        |path: &str, text: &str| -> Result<(), Box<EvalAltResult>> {
+            std::fs::write(path, text).map_err(|e| format!("Couldn't write {path}: {e}"))?;
+            journal::output(path);
+            Ok(())
+        },
+    );
+    engine
+}
+
+/// Run the rhai script on the network, the attributes set by the
+/// script are available to the templates afterwards
+///
+/// The script gets `nodes()` (from the outlet to the upstream
+/// nodes) and `node(name)` to access the nodes, which have `name`,
+/// `index`, `inputs` and `output` properties and `get(attr)`,
+/// `set(attr, value)`, `has(attr)`, `attrs()` and `format(template)`
+/// methods; `write(path, text)` saves a file.
+pub fn run_script(net: Network, script: &Path) -> Result<Network, NadiError> {
+    journal::input(script);
+    let net = Rc::new(RefCell::new(net));
+    let engine = build_engine(&net);
+    let result = engine.run_file(script.to_path_buf());
+    drop(engine);
+    result.map_err(|e| NadiError::ScriptError(format!("{}: {}", script.display(), e)))?;
+    Rc::try_unwrap(net)
+        .map(RefCell::into_inner)
+        .map_err(|_| NadiError::ScriptError("Network is still in use by the script".to_string()))
+}
+
+/// Numbers in the array, other values are skipped
+fn numbers(arr: &Array) -> impl Iterator<Item = FLOAT> + '_ {
+    arr.iter().filter_map(|v| {
+        v.as_float()
+            .ok()
+            .or_else(|| v.as_int().ok().map(|i| i as FLOAT))
+    })
+}
+
+fn expression_engine() -> Engine {
+    let mut engine = Engine::new();
+    engine
+        .register_fn("sum", |arr: Array| numbers(&arr).sum::<FLOAT>())
+        .register_fn("count", |arr: Array| numbers(&arr).count() as INT)
+        .register_fn("max", |arr: Array| {
+            numbers(&arr)
+                .reduce(FLOAT::max)
+                .map_or(Dynamic::UNIT, Dynamic::from)
+        })
+        .register_fn("min", |arr: Array| {
+            numbers(&arr)
+                .reduce(FLOAT::min)
+                .map_or(Dynamic::UNIT, Dynamic::from)
+        })
+        .register_fn("mean", |arr: Array| {
+            let n = numbers(&arr).count();
+            if n == 0 {
+                Dynamic::UNIT
+            } else {
+                Dynamic::from(numbers(&arr).sum::<FLOAT>() / n as FLOAT)
+            }
+        });
+    engine
+}
+
+/// Names of the attributes used as `{prefix}.NAME` in the expression
+fn referenced_attrs(expr: &str, prefix: &str) -> Vec<String> {
+    let pattern = format!("{prefix}.");
+    let mut names = Vec::new();
+    for (i, _) in expr.match_indices(&pattern) {
+        let before = expr[..i].chars().last();
+        if before
+            .map(|c| c.is_alphanumeric() || c == '_')
+            .unwrap_or(false)
+        {
+            continue;
+        }
+        let name: String = expr[i + pattern.len()..]
+            .chars()
+            .take_while(|c| c.is_alphanumeric() || *c == '_')
+            .collect();
+        if !name.is_empty() && !names.contains(&name) {
+            names.push(name);
+        }
+    }
+    names
+}
+
+fn is_identifier(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars
+        .next()
+        .map(|c| c.is_alphabetic() || c == '_')
+        .unwrap_or(false)
+        && chars.all(|c| c.is_alphanumeric() || c == '_')
+}
+
+/// Evaluate `NAME = EXPRESSION` for the nodes from the upstream ones
+/// to the outlet and set the result as the attribute NAME
+///
+/// The node attributes are variables in the expression (`()` if the
+/// node doesn't have it), and
+/// `upstream.ATTR` and `inputs.ATTR` are the arrays of the attribute
+/// values of all the upstream nodes and the immediate inputs; so
+/// `inputs.NAME` has the values already calculated for the inputs.
+/// `sum`, `min`, `max`, `mean` and `count` reduce those arrays.
+pub fn cumulate_expression(net: &mut Network, spec: &str) -> Result<(), NadiError> {
+    let (name, expr) = spec
+        .split_once('=')
+        .map(|(n, e)| (n.trim(), e.trim()))
+        .filter(|(n, _)| is_identifier(n))
+        .ok_or_else(|| {
+            NadiError::ScriptError(format!("Cumulate expression should be NAME = EXPR: {spec}"))
+        })?;
+    let engine = expression_engine();
+    let ast = engine
+        .compile_expression(expr)
+        .map_err(|e| NadiError::ScriptError(format!("{expr}: {e}")))?;
+    let upstream_attrs = referenced_attrs(expr, "upstream");
+    let inputs_attrs = referenced_attrs(expr, "inputs");
+
+    // attributes missing in a node are () so that `ATTR ?? 0` works
+    let mut attr_names: Vec<String> = net
+        .nodes
+        .iter()
+        .flat_map(|n| n.get_attrs().keys())
+        .filter(|k| is_identifier(k))
+        .cloned()
+        .collect();
+    attr_names.sort();
+    attr_names.dedup();
+
+    let n = net.nodes.len();
+    // nodes in the depth first order, the upstream nodes of a node
+    // are the ones right after it
+    let mut order: Vec<usize> = Vec::with_capacity(n);
+    let mut stack: Vec<usize> = (0..n)
+        .filter(|&i| net.nodes[i].get_output().is_none())
+        .collect();
+    while let Some(i) = stack.pop() {
+        order.push(i);
+        stack.extend_from_slice(net.nodes[i].get_inputs());
+    }
+    let mut position = vec![0; n];
+    for (p, &i) in order.iter().enumerate() {
+        position[i] = p;
+    }
+    let mut upstream_count = vec![0; n];
+    // the inputs of the nodes have larger indices than themselves
+    for i in (0..n).rev() {
+        upstream_count[i] = net.nodes[i]
+            .get_inputs()
+            .iter()
+            .map(|&inp| upstream_count[inp] + 1)
+            .sum();
+        let start = position[i] + 1;
+        let upstream = &order[start..start + upstream_count[i]];
+
+        let values = |nodes: &[usize], attrs: &[String]| -> Map {
+            attrs
+                .iter()
+                .map(|a| {
+                    let arr: Array = nodes
+                        .iter()
+                        .map(|&u| {
+                            net.nodes[u]
+                                .get_attr(a)
+                                .map(attr_to_dynamic)
+                                .unwrap_or(Dynamic::UNIT)
+                        })
+                        .collect();
+                    (a.into(), arr.into())
+                })
+                .collect()
+        };
+        let mut scope = Scope::new();
+        for key in &attr_names {
+            let val = net.nodes[i].get_attr(key).map(attr_to_dynamic);
+            scope.push_constant(key.as_str(), val.unwrap_or(Dynamic::UNIT));
+        }
+        scope.push_constant("upstream", values(upstream, &upstream_attrs));
+        scope.push_constant("inputs", values(net.nodes[i].get_inputs(), &inputs_attrs));
+        let result: Dynamic = engine.eval_ast_with_scope(&mut scope, &ast).map_err(|e| {
+            NadiError::ScriptError(format!("Node {}: {e}", net.nodes[i].get_name()))
+        })?;
+        if !result.is_unit() {
+            let val = dynamic_to_attr(result).map_err(|e| NadiError::ScriptError(e.to_string()))?;
+            net.nodes[i].set_attr(name, val);
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,260 @@
+use std::collections::HashMap;
+use std::io::Write;
+use std::path::PathBuf;
+
+use clap::{Args, ValueEnum};
+use rand::{distributions::Distribution, rngs::StdRng, SeedableRng};
+use sha2::{Digest, Sha256};
+use statrs::distribution::Normal;
+use string_template_plus::Template;
+
+use crate::cliargs::{CliAction, GlobalArgs, OutputFormat};
+use crate::journal;
+use crate::network::csv_field;
+use crate::timeseries::{write_series, NodeSeries, Statistic};
+
+#[derive(Args)]
+pub struct CliArgs {
+    /// Model of the monthly flows
+    #[arg(
+        short,
+        long,
+        rename_all = "lower",
+        value_enum,
+        default_value = "thomas-fiering"
+    )]
+    model: Model,
+    /// Number of synthetic traces for each node
+    #[arg(short = 'n', long, default_value = "100")]
+    traces: usize,
+    /// Years in each trace [default: years of the record]
+    #[arg(short, long)]
+    years: Option<usize>,
+    /// Seed of the random numbers, the traces of each node use the
+    /// seed with the node name
+    #[arg(long, default_value = "0")]
+    seed: u64,
+    /// Generate lognormal flows with the monthly mean, standard
+    /// deviation and lag-1 correlation of the flows, which keeps the
+    /// synthetic flows positive; otherwise the negative ones are set
+    /// to zero
+    #[arg(short, long)]
+    log: bool,
+    #[command(flatten)]
+    series: NodeSeries,
+    /// Template for the csv files of the synthetic traces written for
+    /// the nodes, with the trace, year, month and flow columns
+    #[arg(short, long, default_value = "synthetic/{name}.csv", value_parser=Template::parse_template)]
+    synthetic: Template,
+    /// Connection file
+    connection_file: PathBuf,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, ValueEnum)]
+enum Model {
+    /// Mean, standard deviation and lag-1 correlation with the
+    /// previous month for each month
+    ThomasFiering,
+    /// Monthly mean and standard deviation with a single lag-1
+    /// correlation of the standardized flows
+    Ar1,
+}
+
+/// Parameters of the month, the correlation is with the previous month
+#[derive(Clone, Copy)]
+struct MonthParams {
+    mean: f64,
+    sd: f64,
+    r1: f64,
+}
+
+fn mean_sd(values: &[f64]) -> (f64, f64) {
+    let n = values.len() as f64;
+    let mean = values.iter().sum::<f64>() / n;
+    let var = values.iter().map(|v| (v - mean).powi(2)).sum::<f64>() / (n - 1.0);
+    (mean, var.sqrt())
+}
+
+/// Pearson correlation of the pairs, limited so that the variance of
+/// the random part of the models stays positive
+fn correlation(pairs: &[(f64, f64)]) -> f64 {
+    if pairs.len() < 3 {
+        return 0.0;
+    }
+    let a: Vec<f64> = pairs.iter().map(|p| p.0).collect();
+    let b: Vec<f64> = pairs.iter().map(|p| p.1).collect();
+    let ((ma, sa), (mb, sb)) = (mean_sd(&a), mean_sd(&b));
+    let cov = pairs.iter().map(|(x, y)| (x - ma) * (y - mb)).sum::<f64>() / (a.len() - 1) as f64;
+    let r = cov / (sa * sb);
+    if r.is_finite() {
+        r.clamp(-0.99, 0.99)
+    } else {
+        0.0
+    }
+}
+
+/// Parameters of the logarithms of the flows that give the lognormal
+/// flows the fitted mean, standard deviation and lag-1 correlation,
+/// None if any monthly mean isn't positive
+fn lognormal(params: &[MonthParams; 12]) -> Option<[MonthParams; 12]> {
+    if params.iter().any(|p| p.mean <= 0.0) {
+        return None;
+    }
+    let cv: Vec<f64> = params.iter().map(|p| p.sd / p.mean).collect();
+    let sd: Vec<f64> = cv.iter().map(|c| (1.0 + c * c).ln().sqrt()).collect();
+    let mut log = *params;
+    for (m, p) in log.iter_mut().enumerate() {
+        let prev = (m + 11) % 12;
+        p.mean = params[m].mean.ln() - sd[m] * sd[m] / 2.0;
+        p.sd = sd[m];
+        let r = (1.0 + params[m].r1 * cv[m] * cv[prev]).ln() / (sd[m] * sd[prev]);
+        p.r1 = if r.is_finite() {
+            r.clamp(-0.99, 0.99)
+        } else {
+            0.0
+        };
+    }
+    Some(log)
+}
+
+/// Random numbers of the node from the seed and the node name, so the
+/// traces of a node don't depend on the other nodes of the network
+fn node_rng(seed: u64, name: &str) -> StdRng {
+    let mut hasher = Sha256::new();
+    hasher.update(seed.to_le_bytes());
+    hasher.update(name.as_bytes());
+    StdRng::from_seed(hasher.finalize().into())
+}
+
+impl CliArgs {
+    /// Parameters of the 12 months from the (year, month, value) of
+    /// the monthly flows
+    fn fit(&self, monthly: &[(i32, u32, f64)]) -> Option<[MonthParams; 12]> {
+        let values: HashMap<(i32, u32), f64> =
+            monthly.iter().map(|&(y, m, v)| ((y, m), v)).collect();
+        let mut params = [MonthParams {
+            mean: 0.0,
+            sd: 0.0,
+            r1: 0.0,
+        }; 12];
+        for m in 1..=12 {
+            let month: Vec<f64> = monthly.iter().filter(|v| v.1 == m).map(|v| v.2).collect();
+            if month.len() < 2 {
+                return None;
+            }
+            let (mean, sd) = mean_sd(&month);
+            params[m as usize - 1].mean = mean;
+            params[m as usize - 1].sd = sd;
+        }
+        let previous = |y: i32, m: u32| if m == 1 { (y - 1, 12) } else { (y, m - 1) };
+        let standard = |(y, m): (i32, u32)| {
+            let p = params[m as usize - 1];
+            values.get(&(y, m)).map(|v| (v - p.mean) / p.sd)
+        };
+        // the pairs of all the months without the month
+        let pairs = |m: Option<u32>| -> Vec<(f64, f64)> {
+            monthly
+                .iter()
+                .filter(|v| m.unwrap_or(v.1) == v.1)
+                .filter_map(|&(y, m, _)| standard(previous(y, m)).zip(standard((y, m))))
+                .collect()
+        };
+        let r1: Vec<f64> = match self.model {
+            Model::ThomasFiering => (1..=12).map(|m| correlation(&pairs(Some(m)))).collect(),
+            Model::Ar1 => vec![correlation(&pairs(None)); 12],
+        };
+        for (p, r) in params.iter_mut().zip(r1) {
+            p.r1 = r;
+        }
+        Some(params)
+    }
+
+    /// Synthetic monthly flows of a trace, each month is the mean plus
+    /// the correlated part of the previous month's standardized flow
+    /// and a random part: Q_j = μ_j + r_j σ_j z_{j-1} + σ_j √(1 - r_j²) ε
+    fn generate(&self, params: &[MonthParams; 12], years: usize, rng: &mut StdRng) -> Vec<f64> {
+        let normal = Normal::new(0.0, 1.0).unwrap();
+        let mut z: f64 = normal.sample(rng);
+        let mut flows = Vec::with_capacity(years * 12);
+        for i in 0..years * 12 {
+            let p = params[i % 12];
+            if i > 0 {
+                let e: f64 = normal.sample(rng);
+                z = p.r1 * z + (1.0 - p.r1 * p.r1).sqrt() * e;
+            }
+            let q = p.mean + p.sd * z;
+            flows.push(if self.log { q.exp() } else { q.max(0.0) });
+        }
+        flows
+    }
+}
+
+impl CliAction for CliArgs {
+    fn run(self, global: &GlobalArgs) -> anyhow::Result<()> {
+        global.format(&[OutputFormat::Csv])?;
+        let net = self.series.network(&self.connection_file)?;
+        let mut writer = global.writer()?;
+        writeln!(writer, "node,month,mean,sd,r1")?;
+        let mut count = 0;
+        for (node, path, ts) in self.series.nodes(&net) {
+            let monthly = match ts.period_values(Statistic::Mean, true, false, 0) {
+                Ok(m) => m,
+                Err(e) => {
+                    journal::warn(format!("Skipping {}: {e}", node.get_name()));
+                    continue;
+                }
+            };
+            let Some(params) = self.fit(&monthly) else {
+                journal::warn(format!(
+                    "Not enough years of monthly flows for {}",
+                    node.get_name()
+                ));
+                continue;
+            };
+            for (m, p) in params.iter().enumerate() {
+                writeln!(
+                    writer,
+                    "{},{},{},{},{}",
+                    csv_field(node.get_name()),
+                    m + 1,
+                    p.mean,
+                    p.sd,
+                    p.r1
+                )?;
+            }
+            let years = self.years.unwrap_or_else(|| {
+                let first = monthly.first().map_or(0, |v| v.0);
+                let last = monthly.last().map_or(0, |v| v.0);
+                (last - first + 1) as usize
+            });
+            // the lognormal flows are generated from their logarithms
+            let model = if self.log {
+                let Some(log) = lognormal(&params) else {
+                    journal::warn(format!(
+                        "Monthly mean flows of {} aren't positive for --log",
+                        node.get_name()
+                    ));
+                    continue;
+                };
+                log
+            } else {
+                params
+            };
+            let mut rng = node_rng(self.seed, node.get_name());
+            let rows = (1..=self.traces).flat_map(|t| {
+                let trace = self.generate(&model, years, &mut rng);
+                trace
+                    .into_iter()
+                    .enumerate()
+                    .map(move |(i, q)| format!("{t},{},{},{q}", i / 12 + 1, i % 12 + 1))
+                    .collect::<Vec<String>>()
+            });
+            let header = format!("trace,year,month,{}", self.series.discharge_col);
+            write_series(&node.file(&self.synthetic), &[&path], &header, rows)?;
+            count += 1;
+        }
+        journal::count("nodes", count);
+        writer.finish()?;
+        Ok(())
+    }
+}
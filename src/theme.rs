@@ -0,0 +1,282 @@
+use std::path::Path;
+
+use serde::Deserialize;
+
+use crate::errors::{parse_toml, NadiError};
+use crate::journal;
+
+/// Colors, font and line widths of the graphviz, svg, html and plot
+/// outputs; the unset values are left to the defaults of the outputs
+#[derive(Deserialize, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct Theme {
+    /// Background color of the figures and pages
+    pub background: Option<String>,
+    /// Color of the text and the node outlines
+    pub foreground: Option<String>,
+    /// Font family, graphviz font name or css font-family; only
+    /// letters, digits, spaces and `-_.,` are allowed
+    pub font: Option<String>,
+    /// Fill color of the nodes
+    pub node_fill: Option<String>,
+    /// Shape of the graphviz nodes, unless given with --node-shape
+    pub node_shape: Option<String>,
+    /// Color of the connections
+    pub edge_color: Option<String>,
+    /// Width of the connections and plot lines in points
+    pub line_width: Option<f64>,
+    /// Colors of the plot lines, cycled through
+    pub palette: Vec<String>,
+    /// Colors of the low and high ends of the node fill scale
+    pub scale: [String; 2],
+}
+
+impl Default for Theme {
+    fn default() -> Self {
+        Self {
+            background: None,
+            foreground: None,
+            font: None,
+            node_fill: None,
+            node_shape: None,
+            edge_color: None,
+            line_width: None,
+            palette: colors(&[
+                "#1f77b4", "#ff7f0e", "#2ca02c", "#d62728", "#9467bd", "#8c564b",
+            ]),
+            scale: ["#2c7bb6".to_string(), "#d7191c".to_string()],
+        }
+    }
+}
+
+fn colors(list: &[&str]) -> Vec<String> {
+    list.iter().map(|c| c.to_string()).collect()
+}
+
+fn is_color(color: &str) -> bool {
+    color.len() == 7 && color.starts_with('#') && color[1..].chars().all(|c| c.is_ascii_hexdigit())
+}
+
+/// Font names go into the dot, css and svg attributes without
+/// escaping, so only the characters of the font names are allowed
+fn is_font(font: &str) -> bool {
+    !font.trim().is_empty()
+        && font
+            .chars()
+            .all(|c| c.is_alphanumeric() || matches!(c, ' ' | '-' | '_' | '.' | ','))
+}
+
+fn rgb(color: &str) -> [f32; 3] {
+    let channel = |i: usize| u8::from_str_radix(&color[i..i + 2], 16).unwrap_or(0) as f32;
+    [channel(1), channel(3), channel(5)]
+}
+
+impl Theme {
+    /// Builtin theme (default, dark, print or colorblind) or a toml
+    /// file with the values to change from the default theme
+    pub fn parse(arg: &str) -> anyhow::Result<Self> {
+        let theme = match arg {
+            "default" => Self::default(),
+            "dark" => Self {
+                background: Some("#1e1e1e".to_string()),
+                foreground: Some("#e0e0e0".to_string()),
+                node_fill: Some("#2d2d2d".to_string()),
+                edge_color: Some("#a0a0a0".to_string()),
+                palette: colors(&[
+                    "#4fc3f7", "#ffb74d", "#81c784", "#e57373", "#ba68c8", "#a1887f",
+                ]),
+                scale: ["#4575b4".to_string(), "#f46d43".to_string()],
+                ..Self::default()
+            },
+            "print" => Self {
+                background: Some("#ffffff".to_string()),
+                foreground: Some("#000000".to_string()),
+                font: Some("Times".to_string()),
+                node_fill: Some("#ffffff".to_string()),
+                edge_color: Some("#000000".to_string()),
+                line_width: Some(1.5),
+                palette: colors(&["#000000", "#666666", "#999999", "#333333"]),
+                scale: ["#f0f0f0".to_string(), "#252525".to_string()],
+                ..Self::default()
+            },
+            // Okabe-Ito colors
+            "colorblind" => Self {
+                palette: colors(&[
+                    "#0072b2", "#e69f00", "#009e73", "#cc79a7", "#56b4e9", "#d55e00", "#f0e442",
+                    "#000000",
+                ]),
+                scale: ["#0072b2".to_string(), "#e69f00".to_string()],
+                ..Self::default()
+            },
+            path => Self::from_file(Path::new(path))?,
+        };
+        Ok(theme)
+    }
+
+    fn from_file(filename: &Path) -> Result<Self, NadiError> {
+        let contents = std::fs::read_to_string(filename).map_err(|e| {
+            NadiError::AttributeError(format!(
+                "Theme should be default, dark, print, colorblind or a toml file: {e}"
+            ))
+        })?;
+        journal::input(filename);
+        let theme: Self = parse_toml(filename, &contents)?;
+        let all_colors = [
+            &theme.background,
+            &theme.foreground,
+            &theme.node_fill,
+            &theme.edge_color,
+        ]
+        .into_iter()
+        .flatten()
+        .chain(&theme.palette)
+        .chain(&theme.scale);
+        for color in all_colors {
+            if !is_color(color) {
+                return Err(NadiError::AttributeError(format!(
+                    "Color {color} in {} should be #rrggbb",
+                    filename.display()
+                )));
+            }
+        }
+        if let Some(font) = theme.font.as_ref().filter(|f| !is_font(f)) {
+            return Err(NadiError::AttributeError(format!(
+                "Font {font:?} in {} should only have letters, digits, spaces and -_.,",
+                filename.display()
+            )));
+        }
+        if theme.palette.is_empty() {
+            return Err(NadiError::AttributeError(format!(
+                "Palette in {} should have at least one color",
+                filename.display()
+            )));
+        }
+        Ok(theme)
+    }
+
+    /// Color of the k-th plot line
+    pub fn line_color(&self, k: usize) -> &str {
+        &self.palette[k % self.palette.len()]
+    }
+
+    /// Color between the low (0.0) and high (1.0) ends of the scale
+    pub fn scale_color(&self, frac: f32) -> String {
+        let frac = frac.clamp(0.0, 1.0);
+        let (low, high) = (rgb(&self.scale[0]), rgb(&self.scale[1]));
+        let mix = |i: usize| (low[i] + (high[i] - low[i]) * frac) as u8;
+        format!("#{:02x}{:02x}{:02x}", mix(0), mix(1), mix(2))
+    }
+
+    /// Graph attributes for graphviz
+    pub fn dot_graph(&self) -> String {
+        let mut attrs = String::new();
+        if let Some(bg) = &self.background {
+            attrs.push_str(&format!(" bgcolor=\"{bg}\";\n"));
+        }
+        if let Some(fg) = &self.foreground {
+            attrs.push_str(&format!(" fontcolor=\"{fg}\";\n"));
+        }
+        if let Some(font) = &self.font {
+            attrs.push_str(&format!(" fontname=\"{font}\";\n"));
+        }
+        attrs
+    }
+
+    /// Default node attributes for graphviz, each starting with a comma
+    pub fn dot_node(&self) -> String {
+        self.dot_attrs(self.foreground.as_ref())
+    }
+
+    /// Edge attributes for graphviz, each starting with a comma
+    pub fn dot_edge(&self) -> String {
+        self.dot_attrs(self.edge_color.as_ref())
+    }
+
+    fn dot_attrs(&self, color: Option<&String>) -> String {
+        let mut attrs = String::new();
+        if let Some(c) = color {
+            attrs.push_str(&format!(",color=\"{c}\""));
+        }
+        if let Some(fg) = &self.foreground {
+            attrs.push_str(&format!(",fontcolor=\"{fg}\""));
+        }
+        if let Some(font) = &self.font {
+            attrs.push_str(&format!(",fontname=\"{font}\""));
+        }
+        if let Some(w) = self.line_width {
+            attrs.push_str(&format!(",penwidth={w}"));
+        }
+        attrs
+    }
+
+    /// Style sheet for the html pages
+    pub fn css(&self) -> String {
+        let mut body = String::new();
+        if let Some(bg) = &self.background {
+            body.push_str(&format!("background:{bg};"));
+        }
+        if let Some(fg) = &self.foreground {
+            body.push_str(&format!("color:{fg};"));
+        }
+        if let Some(font) = &self.font {
+            body.push_str(&format!("font-family:{font};"));
+        }
+        if body.is_empty() {
+            return body;
+        }
+        format!(
+            "<style>body{{{body}}} a{{color:{};}}</style>",
+            self.line_color(0)
+        )
+    }
+
+    /// Opening tag of an svg figure with the font and the text color,
+    /// followed by the background
+    pub fn svg_start(&self, width: f64, height: f64) -> String {
+        let mut svg = format!(
+            "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{width}\" height=\"{height}\" font-size=\"12\""
+        );
+        if let Some(font) = &self.font {
+            svg.push_str(&format!(" font-family=\"{font}\""));
+        }
+        if let Some(fg) = &self.foreground {
+            svg.push_str(&format!(" fill=\"{fg}\""));
+        }
+        svg.push('>');
+        if let Some(bg) = &self.background {
+            svg.push_str(&format!(
+                "<rect width=\"100%\" height=\"100%\" fill=\"{bg}\"/>"
+            ));
+        }
+        svg
+    }
+
+    /// Color definitions for the LaTeX preamble, the plot lines are
+    /// `theme0`, `theme1`, etc.
+    pub fn latex(&self) -> String {
+        let define = |name: &str, color: &str| {
+            format!(
+                "\\definecolor{{{name}}}{{HTML}}{{{}}}\n",
+                color[1..].to_uppercase()
+            )
+        };
+        let mut defs: String = self
+            .palette
+            .iter()
+            .enumerate()
+            .map(|(k, c)| define(&format!("theme{k}"), c))
+            .collect();
+        if let Some(bg) = &self.background {
+            defs.push_str(&define("themebg", bg));
+            defs.push_str("\\pagecolor{themebg}\n");
+        }
+        if let Some(fg) = &self.foreground {
+            defs.push_str(&define("themefg", fg));
+            defs.push_str("\\AtBeginDocument{\\color{themefg}}\n");
+            defs.push_str(
+                "\\pgfplotsset{every axis/.append style={axis line style=themefg, tick style=themefg}}\n",
+            );
+        }
+        defs
+    }
+}
@@ -1,34 +1,95 @@
 use polars::{
-    export::chrono::{NaiveDate, ParseError},
+    export::chrono::{self, NaiveDate},
+    io::mmap::MmapBytesReader,
     lazy::dsl::{first, when},
     prelude::*,
 };
 
+use anyhow::Context;
 use clap::{Args, ValueEnum, ValueHint};
-use std::{fs::File, path::PathBuf, str::FromStr};
+use comfy_table::{presets, CellAlignment, Table};
+use std::{
+    fs::File,
+    io::{BufRead, BufReader, BufWriter, Cursor, Read, Stdout, Write},
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use flate2::{read::MultiGzDecoder, write::GzEncoder, Compression};
+use rand::{rngs::StdRng, Rng, SeedableRng};
 
-use crate::cliargs::CliAction;
+use crate::cliargs::{CliAction, GlobalArgs, OutputFormat};
+use crate::errors::NadiError;
+use crate::journal;
+use crate::locale::LocaleArgs;
+use crate::network::{read_attrs_file, AttrsFiles, Network, Node};
+use string_template_plus::{Render, RenderOptions, Template};
 
 #[derive(Args)]
 pub struct CliArgs {
-    /// Date Range to filter the timeseries by
-    #[arg(short, long, default_value = "",value_hint=ValueHint::Other)]
+    /// Date Range to filter the timeseries by (START..END)
+    ///
+    /// The dates are YYYY-MM-DD, or YYYY-MM and YYYY for the first
+    /// (START) or last (END) day of the month or year, e.g. 2001..2010
+    /// is from 2001-01-01 to 2010-12-31; either of them can be left
+    /// out for an open range, e.g. 2000-01-01.. or ..2010-12
+    #[arg(short, long, default_value = "", value_name = "START..END", value_hint=ValueHint::Other)]
     date_range: DateRange,
-    /// column name containing date and/or time in csv
-    #[arg(long, default_value = "date",value_hint=ValueHint::Other)]
-    datetime_col: String,
+    /// Reindex to every day in the date range, absent days are null
+    ///
+    /// Without the start or end of --date-range the first or last
+    /// date of the data is used
+    #[arg(long)]
+    complete_dates: bool,
     /// column name containing discharges in csv
     #[arg(long, default_value = "flow", value_hint=ValueHint::Other)]
     discharge_col: String,
-    /// Print in a abridged format that can't be piped
+    /// Print an aligned table that can't be piped
     #[arg(short, long, conflicts_with = "output")]
     no_pipe: bool,
-    /// Print a barplot
+    /// Maximum number of rows to show in the table
+    #[arg(long, requires = "no_pipe")]
+    max_rows: Option<usize>,
+    /// Number of decimal places for the floating point values
+    #[arg(long)]
+    precision: Option<usize>,
+    /// Numbers and dates of the --no-pipe table
+    #[command(flatten)]
+    locale: LocaleArgs,
+    /// Print a text plot of the given column
     #[arg(short, long, conflicts_with = "output")]
     plot: Option<String>,
-    /// output file path
-    #[arg(short, long)]
+    /// Kind of the text plot
+    #[arg(
+        short = 'k',
+        long,
+        rename_all = "lower",
+        default_value = "bar",
+        value_enum,
+        requires = "plot"
+    )]
+    plot_kind: PlotKind,
+    /// Number of bins for the histogram plot
+    #[arg(long, default_value = "10", requires = "plot")]
+    bins: usize,
+    /// Format and file of the output, from the --format and --output
+    /// options of nadi
+    #[arg(skip)]
+    format: OutputFormat,
+    #[arg(skip)]
     output: Option<PathBuf>,
+    /// Write a commented metadata header in the output
+    ///
+    /// The header contains the metadata from the input file header,
+    /// the ones given with --metadata and the command used
+    #[arg(short = 'H', long)]
+    header: bool,
+    /// Extra metadata for the output header (KEY=VALUE)
+    #[arg(short, long, value_delimiter = ',', value_parser=parse_metadata, requires = "header")]
+    metadata: Vec<(String, String)>,
+    /// Number of rows per chunk while reading and streaming the data
+    #[arg(long)]
+    chunk_size: Option<usize>,
     /// Action to perform
     #[arg(
         short,
@@ -42,6 +103,67 @@ pub struct CliArgs {
     /// extra args for the command
     #[arg(short, long, value_delimiter = ',')]
     args: Vec<String>,
+    /// Normalize the seasonality of each station by its mean
+    #[arg(long)]
+    normalize: bool,
+    /// Number of realizations for na-fill-donor with the residuals of
+    /// the regression resampled
+    ///
+    /// Adds the {discharge_col}_lower and {discharge_col}_upper
+    /// columns with the --band of the filled values; the band of the
+    /// statistics of the filled timeseries are written as comments
+    /// before the csv
+    #[arg(long)]
+    ensemble: Option<usize>,
+    /// Width of the uncertainty band of the --ensemble in percent
+    #[arg(long, default_value = "90", requires = "ensemble", value_parser=parse_band)]
+    band: f64,
+    /// Seed for the random resampling of the --ensemble
+    #[arg(long, default_value = "0", requires = "ensemble")]
+    seed: u64,
+    /// Maximum lag in days for the autocorrelation command
+    #[arg(long, default_value = "365")]
+    max_lag: usize,
+    /// Statistic of each year for the change-point command
+    #[arg(long, rename_all = "lower", value_enum, default_value = "mean")]
+    annual_stat: Statistic,
+    /// Statistics for the export-attrs command
+    ///
+    /// Saved as {discharge_col}_{stat} attributes
+    #[arg(
+        long,
+        rename_all = "lower",
+        value_enum,
+        value_delimiter = ',',
+        default_value = "mean,min,max,count"
+    )]
+    stats: Vec<Statistic>,
+    // node attribute files for export-attrs and --per-area, named
+    // after the input file, and the date column of the input
+    #[command(flatten)]
+    attrs: AttrsFiles,
+    /// Divide the flows by the drainage area of the node (yield)
+    ///
+    /// The area is the --area-attr in the node attributes file of
+    /// the input (see --attrs-dir), and export-attrs saves the
+    /// statistics as {discharge_col}_yield_{stat}
+    #[arg(long)]
+    per_area: bool,
+    /// Attribute with the drainage area of the node for --per-area
+    #[arg(long, default_value = "area", requires = "per_area")]
+    area_attr: String,
+    /// What to do with the duplicated dates in the clean command
+    #[arg(long, rename_all = "kebab-case", default_value = "error", value_enum)]
+    duplicates: DuplicateStrategy,
+    /// Period for each file of the split command
+    #[arg(long, rename_all = "kebab-case", default_value = "year", value_enum)]
+    split_by: SplitBy,
+    /// Filename template for the split command
+    ///
+    /// Variables: station, year and month (for --split-by month); the
+    /// default is "{station}_{year}.csv" or "{station}_{year}-{month}.csv"
+    #[arg(long, value_parser=Template::parse_template)]
+    split_template: Option<Template>,
     /// input csv file
     #[arg(required = true)]
     input: PathBuf,
@@ -57,18 +179,131 @@ pub enum TsProcess {
     NaValues,
     #[value(alias = "nff")]
     NaFillForward,
+    /// Fill the missing values with the next value, up to the number
+    /// of days in --args
     #[value(alias = "nfb")]
     NaFillBackward,
+    /// Fill the missing values with the value in --args
     #[value(alias = "nfv")]
     NaFillValue,
+    /// Fill the missing values from the donor timeseries (first file
+    /// in --args) by the regression of the log flows, see --ensemble
+    #[value(alias = "nfd")]
+    NaFillDonor,
+    /// Leave-one-out validation of na-fill-donor: each day with both
+    /// values is predicted by the regression fitted without it, the
+    /// error metrics are written as comments before the csv
+    #[value(alias = "vd")]
+    ValidateDonor,
+    /// Correct the bias of the modeled timeseries with the monthly
+    /// quantile maps to the observed timeseries (first file in
+    /// --args) on the overlapping days; the --date-range limits the
+    /// days used for the maps, all the modeled values are corrected
+    #[value(alias = "bc")]
+    BiasCorrect,
     #[value(alias = "sm")]
     MonthlySeasonality,
     #[value(alias = "sd")]
     DailySeasonality,
+    /// Monthly seasonality of the input and the files in --args, one
+    /// column per station
+    #[value(alias = "sc")]
+    CompareSeasonality,
     #[value(alias = "ay")]
     AggAnnual,
     #[value(alias = "am")]
     AggMonthly,
+    /// Autocorrelation and partial autocorrelation of the daily
+    /// values up to --max-lag days
+    #[value(alias = "acf")]
+    Autocorrelation,
+    /// Power of the daily values at each frequency, to find the
+    /// periodicities
+    #[value(alias = "pg")]
+    Periodogram,
+    /// Pettitt test for a change point in the annual values of the
+    /// --annual-stat
+    #[value(alias = "cp")]
+    ChangePoint,
+    /// Write one file per period, see --split-by and --split-template
+    #[value(alias = "sp")]
+    Split,
+    /// Report duplicate, unordered and missing dates and write the
+    /// sorted timeseries without duplicates, see --duplicates
+    #[value(alias = "cl")]
+    Clean,
+    /// Write the --stats to the node attributes file, see --attrs-dir
+    #[value(alias = "ea")]
+    ExportAttrs,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, ValueEnum)]
+pub enum Statistic {
+    Mean,
+    Min,
+    Max,
+    Median,
+    Std,
+    /// Number of days with values
+    Count,
+    /// Number of days without values
+    Missing,
+    /// First date
+    Start,
+    /// Last date
+    End,
+}
+
+impl Statistic {
+    fn expr(&self, ts: &Discharges) -> Expr {
+        let q = col(ts.discharge_col);
+        let d = col(ts.datetime_col);
+        match self {
+            Self::Mean => q.mean(),
+            Self::Min => q.min(),
+            Self::Max => q.max(),
+            Self::Median => q.median(),
+            Self::Std => q.std(1),
+            Self::Count => q.clone().count() - q.null_count(),
+            Self::Missing => q.null_count(),
+            Self::Start => d.min(),
+            Self::End => d.max(),
+        }
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, ValueEnum)]
+pub enum DuplicateStrategy {
+    /// Keep the first value of the duplicated dates
+    KeepFirst,
+    /// Use the mean of the values of the duplicated dates
+    Mean,
+    /// Exit with an error if there are duplicated dates
+    Error,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, ValueEnum)]
+pub enum SplitBy {
+    #[value(alias = "y")]
+    Year,
+    /// Water year from October to September, named by the ending year
+    #[value(alias = "wy")]
+    WaterYear,
+    #[value(alias = "m")]
+    Month,
+}
+
+#[derive(Copy, Clone, ValueEnum)]
+pub enum PlotKind {
+    /// Normalized value of each row
+    #[value(alias = "b")]
+    Bar,
+    /// Distribution of the values in bins
+    #[value(alias = "h", alias = "hist")]
+    Histogram,
+    /// Values at the percentiles
+    #[value(alias = "p")]
+    Percentile,
 }
 
 #[derive(Clone)]
@@ -77,142 +312,860 @@ pub struct DateRange {
     end: Option<NaiveDate>,
 }
 
+fn parse_metadata(arg: &str) -> Result<(String, String), anyhow::Error> {
+    let (key, val) = arg
+        .split_once('=')
+        .context("Metadata should be in KEY=VALUE format")?;
+    Ok((key.trim().to_string(), val.trim().to_string()))
+}
+
+fn parse_band(arg: &str) -> Result<f64, anyhow::Error> {
+    let band: f64 = arg.parse()?;
+    if !(0.0..=100.0).contains(&band) {
+        anyhow::bail!("Band should be from 0 to 100 percent");
+    }
+    Ok(band)
+}
+
+/// Compression of the file from its extension
+#[derive(Clone, Copy, PartialEq)]
+enum Compressed {
+    None,
+    Gzip,
+    Zstd,
+}
+
+impl Compressed {
+    fn from_path(path: &Path) -> Self {
+        match path.extension().and_then(|e| e.to_str()) {
+            Some("gz") => Self::Gzip,
+            Some("zst") => Self::Zstd,
+            _ => Self::None,
+        }
+    }
+}
+
+/// Open the file for reading, decompressed if it ends with .gz or .zst
+fn open_input(filename: &Path) -> std::io::Result<Box<dyn Read>> {
+    let file = File::open(filename)?;
+    Ok(match Compressed::from_path(filename) {
+        Compressed::None => Box::new(file),
+        Compressed::Gzip => Box::new(MultiGzDecoder::new(file)),
+        Compressed::Zstd => Box::new(zstd::Decoder::new(file)?),
+    })
+}
+
+/// Output file or stdout, compressed if the file ends with .gz or
+/// .zst
+pub enum Output {
+    Stdout(Stdout),
+    Plain(BufWriter<File>),
+    Gzip(GzEncoder<BufWriter<File>>),
+    Zstd(zstd::Encoder<'static, BufWriter<File>>),
+}
+
+impl Output {
+    /// Finish the compressed stream and flush the file; the errors
+    /// would be lost if it was only dropped
+    pub fn finish(self) -> std::io::Result<()> {
+        let mut file = match self {
+            Self::Stdout(mut s) => return s.flush(),
+            Self::Plain(f) => f,
+            Self::Gzip(e) => e.finish()?,
+            Self::Zstd(e) => e.finish()?,
+        };
+        file.flush()?;
+        file.into_inner()?.sync_all()
+    }
+}
+
+impl Write for Output {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            Self::Stdout(s) => s.write(buf),
+            Self::Plain(f) => f.write(buf),
+            Self::Gzip(e) => e.write(buf),
+            Self::Zstd(e) => e.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            Self::Stdout(s) => s.flush(),
+            Self::Plain(f) => f.flush(),
+            Self::Gzip(e) => e.flush(),
+            Self::Zstd(e) => e.flush(),
+        }
+    }
+}
+
+/// Create the file for writing, compressed if it ends with .gz or
+/// .zst; call [`Output::finish`] after writing
+pub fn create_output(filename: &Path) -> std::io::Result<Output> {
+    let file = BufWriter::new(File::create(filename)?);
+    Ok(match Compressed::from_path(filename) {
+        Compressed::None => Output::Plain(file),
+        Compressed::Gzip => Output::Gzip(GzEncoder::new(file, Compression::default())),
+        Compressed::Zstd => Output::Zstd(zstd::Encoder::new(file, 0)?),
+    })
+}
+
+/// Write the csv file of the series calculated from the source
+/// files, after the metadata header; the directories are created if
+/// needed
+pub fn write_series(
+    path: &Path,
+    sources: &[&Path],
+    header: &str,
+    rows: impl IntoIterator<Item = String>,
+) -> anyhow::Result<()> {
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    journal::output(path);
+    let mut file = create_output(path).with_context(|| format!("Couldn't create {path:?}"))?;
+    write!(file, "{}", journal::metadata_header(sources))?;
+    writeln!(file, "{header}")?;
+    for row in rows {
+        writeln!(file, "{row}")?;
+    }
+    file.finish()
+        .with_context(|| format!("Couldn't write {path:?}"))?;
+    Ok(())
+}
+
+/// Write the daily values with [`write_series`], the missing values
+/// are left blank
+pub fn write_daily(
+    path: &Path,
+    sources: &[&Path],
+    columns: [&str; 2],
+    values: &[(NaiveDate, Option<f64>)],
+) -> anyhow::Result<()> {
+    let rows = values.iter().map(|(date, value)| {
+        let value = value.map(|v| v.to_string()).unwrap_or_default();
+        format!("{date},{value}")
+    });
+    write_series(path, sources, &columns.join(","), rows)
+}
+
+/// Read the `# key: value` lines at the start of the file
+fn read_metadata_header(filename: &Path) -> anyhow::Result<Vec<(String, String)>> {
+    let reader = BufReader::new(open_input(filename)?);
+    let mut metadata = Vec::new();
+    for line in reader.lines() {
+        let line = line?;
+        let Some(line) = line.strip_prefix('#') else {
+            break;
+        };
+        if let Some((key, val)) = line.split_once(':') {
+            metadata.push((key.trim().to_string(), val.trim().to_string()));
+        }
+    }
+    Ok(metadata)
+}
+
+/// Date from YYYY-MM-DD, or the first (last if `is_end`) day of the
+/// YYYY-MM month or the YYYY year
+fn period_date(d: &str, is_end: bool) -> Result<NaiveDate, String> {
+    let parts: Vec<&str> = d.split('-').collect();
+    let num = |p: &str| {
+        p.parse::<u32>()
+            .map_err(|_| format!("{p:?} isn't a number"))
+    };
+    let (year, month) = match parts[..] {
+        [_, _, _] => return NaiveDate::parse_from_str(d, "%Y-%m-%d").map_err(|e| e.to_string()),
+        [y] => (num(y)? as i32, None),
+        [y, m] => (num(y)? as i32, Some(num(m)?)),
+        _ => return Err("too many parts".to_string()),
+    };
+    let first = |y: i32, m: u32| NaiveDate::from_ymd_opt(y, m, 1);
+    let date = match (month, is_end) {
+        (None, false) => first(year, 1),
+        (None, true) => NaiveDate::from_ymd_opt(year, 12, 31),
+        (Some(m), false) => first(year, m),
+        // day before the start of the next month
+        (Some(12), true) => NaiveDate::from_ymd_opt(year, 12, 31),
+        (Some(m), true) => first(year, m + 1).and_then(|d| d.pred_opt()),
+    };
+    date.ok_or_else(|| "month or year out of range".to_string())
+}
+
 impl FromStr for DateRange {
-    type Err = ParseError;
+    type Err = String;
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let (start, end) = s.split_once(",").unwrap_or((s.trim(), ""));
-        Ok(DateRange {
-            start: if start.is_empty() {
-                None
-            } else {
-                Some(NaiveDate::parse_from_str(start, "%Y-%m-%d")?)
-            },
-            end: if end.is_empty() {
-                None
-            } else {
-                Some(NaiveDate::parse_from_str(end, "%Y-%m-%d")?)
-            },
-        })
+        const EXPECTED: &str = "expected START..END with the dates as YYYY-MM-DD, YYYY-MM \
+                                or YYYY, either of them can be left out (e.g. 2000-01-01..)";
+        // START,END is the older syntax
+        let (start, end) = s
+            .split_once("..")
+            .or_else(|| s.split_once(','))
+            .unwrap_or((s, ""));
+        let date = |d: &str, is_end: bool| -> Result<Option<NaiveDate>, String> {
+            let d = d.trim();
+            if d.is_empty() {
+                return Ok(None);
+            }
+            period_date(d, is_end)
+                .map(Some)
+                .map_err(|e| format!("Invalid date {d:?} ({e}), {EXPECTED}"))
+        };
+        let range = DateRange {
+            start: date(start, false)?,
+            end: date(end, true)?,
+        };
+        if let (Some(start), Some(end)) = (range.start, range.end) {
+            if start > end {
+                return Err(format!(
+                    "Start of the date range {start} is after its end {end}"
+                ));
+            }
+        }
+        Ok(range)
     }
 }
 
 impl CliAction for CliArgs {
-    fn run(self) -> anyhow::Result<()> {
-        let mut ts = Discharges::new(&self.input, &self.datetime_col, &self.discharge_col);
-        ts.data_table = apply_date_range(&ts, &self);
+    fn run(mut self, global: &GlobalArgs) -> anyhow::Result<()> {
+        if global.format.is_some() && (self.no_pipe || self.plot.is_some()) {
+            anyhow::bail!("--format can't be used with --no-pipe or --plot");
+        }
+        if self.header && global.format.is_some_and(|f| f != OutputFormat::Csv) {
+            anyhow::bail!("--header can't be used with the json formats");
+        }
+        self.format =
+            global.format(&[OutputFormat::Csv, OutputFormat::Json, OutputFormat::Ndjson])?;
+        self.output = global.output.clone();
+        // polars reads these when its thread pool and the streaming
+        // engine are initialized, so they need to be set before any
+        // dataframe operation
+        if global.jobs > 0 {
+            std::env::set_var("POLARS_MAX_THREADS", global.jobs.to_string());
+        }
+        if let Some(chunk) = self.chunk_size {
+            std::env::set_var("POLARS_STREAMING_CHUNK_SIZE", chunk.to_string());
+        }
+        let mut ts = Discharges::new(
+            &self.input,
+            self.attrs.datetime_col(),
+            &self.discharge_col,
+            self.chunk_size,
+        )?;
+        let rows = ts.data_table.height();
+        if rows == 0 {
+            anyhow::bail!("{:?} doesn't have any rows after the header", self.input);
+        }
+        journal::count("rows", rows);
+        // bias-correct uses the --date-range only for the quantile maps
+        let filtered = match self.command {
+            TsProcess::BiasCorrect => ts.data_table.clone(),
+            _ => apply_date_range(&ts, &self),
+        };
+        if filtered.height() == 0 {
+            let span = ts
+                .lazy()
+                .select([
+                    col(ts.datetime_col).min().alias("start"),
+                    col(ts.datetime_col).max().alias("end"),
+                ])
+                .collect()?;
+            anyhow::bail!(
+                "None of the {rows} rows are in the --date-range, the data is from {} to {}",
+                span.column("start")?.get(0)?,
+                span.column("end")?.get(0)?
+            );
+        }
+        ts.data_table = filtered;
+        if self.complete_dates {
+            ts.data_table = complete_calendar(&ts, &self)?;
+        }
+        if self.per_area {
+            let name = node_name(&self)?;
+            let filename = self.attrs.find_file(&name).with_context(|| {
+                format!("No attributes file for {name} in the --attrs-dir for --per-area")
+            })?;
+            let area = read_attrs_file(&filename)
+                .with_context(|| format!("Couldn't read the node attributes {:?}", filename))?
+                .into_iter()
+                .find(|(k, _)| *k == self.area_attr)
+                .and_then(|(_, v)| v.read_value())
+                .filter(|a| *a > 0.0)
+                .with_context(|| format!("No positive {} in {:?}", self.area_attr, filename))?;
+            ts.data_table = ts
+                .lazy()
+                .with_column(col(ts.discharge_col) / lit(area as f64))
+                .collect()?;
+        }
 
+        let mut writer = global.writer()?;
+        // split writes the header in each of the files instead
+        if self.header && self.command != TsProcess::Split {
+            write_metadata_header(&mut writer, &ts, &self)?;
+        }
         match self.command {
-            TsProcess::Min7Day => calc_min7day(&ts, &self),
-            TsProcess::Echo => echo(&ts, &self),
-            TsProcess::NaValues => missing_data(&ts, &self),
-            TsProcess::MonthlySeasonality => monthly_seasonality(&ts, &self),
-            TsProcess::DailySeasonality => daily_seasonality(&ts, &self),
-            TsProcess::AggMonthly => monthly_mean(&ts, &self),
-            TsProcess::AggAnnual => annual_mean(&ts, &self),
-            TsProcess::NaFillForward => na_fill_forward(&ts, &self),
-            _ => (),
+            TsProcess::Min7Day => calc_min7day(&ts, &self, &mut writer)?,
+            TsProcess::Echo => echo(&ts, &self, &mut writer)?,
+            TsProcess::NaValues => missing_data(&ts, &self, &mut writer)?,
+            TsProcess::MonthlySeasonality => monthly_seasonality(&ts, &self, &mut writer)?,
+            TsProcess::DailySeasonality => daily_seasonality(&ts, &self, &mut writer)?,
+            TsProcess::CompareSeasonality => compare_seasonality(&ts, &self, &mut writer)?,
+            TsProcess::AggMonthly => monthly_mean(&ts, &self, &mut writer)?,
+            TsProcess::AggAnnual => annual_mean(&ts, &self, &mut writer)?,
+            TsProcess::Autocorrelation => autocorrelation(&ts, &self, &mut writer)?,
+            TsProcess::Periodogram => periodogram(&ts, &self, &mut writer)?,
+            TsProcess::ChangePoint => change_point(&ts, &self, &mut writer)?,
+            TsProcess::NaFillForward => na_fill_forward(&ts, &self, &mut writer)?,
+            TsProcess::NaFillBackward => na_fill_backward(&ts, &self, &mut writer)?,
+            TsProcess::NaFillValue => na_fill_value(&ts, &self, &mut writer)?,
+            TsProcess::NaFillDonor => na_fill_donor(&ts, &self, &mut writer)?,
+            TsProcess::ValidateDonor => validate_donor(&ts, &self, &mut writer)?,
+            TsProcess::BiasCorrect => bias_correct(&ts, &self, &mut writer)?,
+            TsProcess::Split => split_files(&ts, &self, &mut writer)?,
+            TsProcess::Clean => clean(&ts, &self, &mut writer)?,
+            TsProcess::ExportAttrs => export_attrs(&ts, &self, &mut writer)?,
         }
+        writer.finish()?;
         Ok(())
     }
 }
 
-fn dataframe_output(mut outdf: DataFrame, args: &CliArgs) {
-    if let Some(output) = &args.output {
-        let file = File::create(output).unwrap();
-        CsvWriter::new(file).finish(&mut outdf).unwrap();
+fn write_metadata_header(
+    writer: &mut impl Write,
+    ts: &Discharges,
+    args: &CliArgs,
+) -> anyhow::Result<()> {
+    let command = std::env::args().collect::<Vec<String>>().join(" ");
+    let mut metadata: Vec<(&str, &str)> = Vec::new();
+    let own = args
+        .metadata
+        .iter()
+        .map(|(k, v)| (k.as_str(), v.as_str()))
+        .chain([("command", command.as_str())]);
+    // the later values override the ones from the input header
+    for (key, val) in ts
+        .metadata
+        .iter()
+        .map(|(k, v)| (k.as_str(), v.as_str()))
+        .chain(own)
+    {
+        match metadata.iter_mut().find(|(k, _)| *k == key) {
+            Some(m) => m.1 = val,
+            None => metadata.push((key, val)),
+        }
+    }
+    for (key, val) in metadata {
+        writeln!(writer, "# {key}: {val}")?;
+    }
+    Ok(())
+}
+
+fn dataframe_output(
+    mut outdf: DataFrame,
+    args: &CliArgs,
+    writer: &mut impl Write,
+) -> anyhow::Result<()> {
+    if args.format != OutputFormat::Csv {
+        json_output(&outdf, args.format == OutputFormat::Ndjson, writer)?;
+    } else if args.output.is_some() {
+        CsvWriter::new(writer)
+            .with_float_precision(args.precision)
+            .finish(&mut outdf)?;
     } else if args.no_pipe {
-        println!("{}", outdf);
+        table_output(&outdf, args, writer)?;
     } else if let Some(plt_col) = &args.plot {
-        let outdf = outdf
-            .clone()
-            .lazy()
-            .with_column(
-                (col(plt_col) - col(plt_col).min()) / (col(plt_col).max() - col(plt_col).min()),
-            )
-            .collect()
-            .unwrap();
-
+        match args.plot_kind {
+            PlotKind::Bar => barplot(&outdf, plt_col, writer)?,
+            PlotKind::Histogram => histogram_plot(&outdf, plt_col, args.bins, writer)?,
+            PlotKind::Percentile => percentile_plot(&outdf, plt_col, writer)?,
+        }
+    } else {
         let nrow = outdf.shape().0;
         let schema: Vec<String> = outdf.schema().iter().map(|s| s.0.to_string()).collect();
-        let col_ind = schema
-            .iter()
-            .enumerate()
-            .filter(|(_, c)| c == &plt_col)
-            .next()
-            .unwrap()
-            .0;
-        let head_str: Vec<&str> = schema
-            .iter()
-            .enumerate()
-            .filter_map(|(i, v)| if i != col_ind { Some(v.as_str()) } else { None })
-            .collect();
-        println!("{}", head_str.join(","));
-        if outdf.is_empty() {
-            return;
+        writeln!(writer, "{}", schema.join(","))?;
+        if outdf.height() == 0 {
+            return Ok(());
         }
-        let mut row = outdf.get_row(0).unwrap();
+        let mut row = outdf.get_row(0)?;
         for i in 0..nrow {
-            outdf.get_row_amortized(i, &mut row).unwrap();
+            outdf.get_row_amortized(i, &mut row)?;
             let row_str: Vec<String> = row
                 .0
                 .iter()
-                .enumerate()
-                .filter_map(|(i, v)| {
-                    if i != col_ind {
-                        Some(format!("{}", v))
-                    } else {
-                        None
-                    }
-                })
+                .map(|v| format_value(v, args.precision))
                 .collect();
-            print!("{}", row_str.join(","));
-            let rep: usize = match row.0[col_ind] {
-                AnyValue::Float64(f) => (f * 100.0) as usize,
-                _ => 0,
-            };
-            println!("\t {}", "#".repeat(rep));
+            writeln!(writer, "{}", row_str.join(","))?;
         }
-    } else {
-        let nrow = outdf.shape().0;
-        let schema: Vec<String> = outdf.schema().iter().map(|s| s.0.to_string()).collect();
-        println!("{}", schema.join(","));
-        if outdf.is_empty() {
-            return;
+    }
+    Ok(())
+}
+
+fn barplot(outdf: &DataFrame, plt_col: &str, writer: &mut impl Write) -> anyhow::Result<()> {
+    let outdf = outdf
+        .clone()
+        .lazy()
+        .with_column(
+            (col(plt_col) - col(plt_col).min()) / (col(plt_col).max() - col(plt_col).min()),
+        )
+        .collect()?;
+
+    let nrow = outdf.shape().0;
+    let schema: Vec<String> = outdf.schema().iter().map(|s| s.0.to_string()).collect();
+    let col_ind = schema
+        .iter()
+        .enumerate()
+        .find(|(_, c)| c == &plt_col)
+        .unwrap()
+        .0;
+    let head_str: Vec<&str> = schema
+        .iter()
+        .enumerate()
+        .filter_map(|(i, v)| if i != col_ind { Some(v.as_str()) } else { None })
+        .collect();
+    writeln!(writer, "{}", head_str.join(","))?;
+    if outdf.height() == 0 {
+        return Ok(());
+    }
+    let mut row = outdf.get_row(0)?;
+    for i in 0..nrow {
+        outdf.get_row_amortized(i, &mut row)?;
+        let row_str: Vec<String> = row
+            .0
+            .iter()
+            .enumerate()
+            .filter_map(|(i, v)| {
+                if i != col_ind {
+                    Some(format!("{}", v))
+                } else {
+                    None
+                }
+            })
+            .collect();
+        write!(writer, "{}", row_str.join(","))?;
+        let rep: usize = match row.0[col_ind] {
+            AnyValue::Float64(f) => (f * 100.0) as usize,
+            _ => 0,
+        };
+        writeln!(writer, "\t {}", "#".repeat(rep))?;
+    }
+    Ok(())
+}
+
+fn plot_values(outdf: &DataFrame, plt_col: &str) -> anyhow::Result<Vec<f64>> {
+    let values = outdf.column(plt_col)?.cast(&DataType::Float64)?;
+    Ok(values.f64()?.into_iter().flatten().collect())
+}
+
+/// Text histogram of the values in the column with equal width bins
+fn histogram_plot(
+    outdf: &DataFrame,
+    plt_col: &str,
+    bins: usize,
+    writer: &mut impl Write,
+) -> anyhow::Result<()> {
+    let values = plot_values(outdf, plt_col)?;
+    writeln!(writer, "lower,upper,count")?;
+    if values.is_empty() || bins == 0 {
+        return Ok(());
+    }
+    let min = values.iter().cloned().fold(f64::INFINITY, f64::min);
+    let max = values.iter().cloned().fold(f64::NEG_INFINITY, f64::max);
+    let width = (max - min) / bins as f64;
+    let mut counts = vec![0usize; bins];
+    for v in &values {
+        let b = if width > 0.0 {
+            (((v - min) / width) as usize).min(bins - 1)
+        } else {
+            0
+        };
+        counts[b] += 1;
+    }
+    let max_count = *counts.iter().max().unwrap_or(&1);
+    for (i, c) in counts.iter().enumerate() {
+        let lower = min + width * i as f64;
+        let rep = c * 100 / max_count;
+        writeln!(
+            writer,
+            "{},{},{}\t {}",
+            lower,
+            lower + width,
+            c,
+            "#".repeat(rep)
+        )?;
+    }
+    Ok(())
+}
+
+/// Text plot of the values at every 5th percentile of the column
+fn percentile_plot(
+    outdf: &DataFrame,
+    plt_col: &str,
+    writer: &mut impl Write,
+) -> anyhow::Result<()> {
+    let mut values = plot_values(outdf, plt_col)?;
+    writeln!(writer, "percentile,{}", plt_col)?;
+    if values.is_empty() {
+        return Ok(());
+    }
+    values.sort_by(|a, b| a.total_cmp(b));
+    let min = values[0];
+    let max = values[values.len() - 1];
+    for p in (0..=100).step_by(5) {
+        let val = percentile(&values, p as f64);
+        let rep = if max > min {
+            ((val - min) / (max - min) * 100.0) as usize
+        } else {
+            0
+        };
+        writeln!(writer, "{},{}\t {}", p, val, "#".repeat(rep))?;
+    }
+    Ok(())
+}
+
+/// Value at the percentile of the sorted values, linearly
+/// interpolated between the closest ranks
+fn percentile(sorted: &[f64], p: f64) -> f64 {
+    let rank = (sorted.len() - 1) as f64 * p / 100.0;
+    let lo = rank.floor() as usize;
+    let hi = rank.ceil() as usize;
+    sorted[lo] + (sorted[hi] - sorted[lo]) * (rank - lo as f64)
+}
+
+fn format_value(val: &AnyValue, precision: Option<usize>) -> String {
+    match (val, precision) {
+        (AnyValue::Float64(f), Some(p)) => format!("{f:.p$}"),
+        (AnyValue::Float32(f), Some(p)) => format!("{f:.p$}"),
+        (v, _) => v.to_string(),
+    }
+}
+
+/// Print the dataframe as an aligned table for human inspection,
+/// numeric columns are right aligned
+fn table_output(df: &DataFrame, args: &CliArgs, writer: &mut impl Write) -> anyhow::Result<()> {
+    let mut table = Table::new();
+    table
+        .load_preset(presets::UTF8_FULL_CONDENSED)
+        .set_header(df.get_column_names());
+    let nrow = df.height();
+    let shown = args.max_rows.map_or(nrow, |m| m.min(nrow));
+    if shown > 0 {
+        let mut row = df.get_row(0)?;
+        for i in 0..shown {
+            df.get_row_amortized(i, &mut row)?;
+            table.add_row(row.0.iter().map(|v| match v {
+                AnyValue::Utf8(s) => s.to_string(),
+                v => args.locale.localize(&format_value(v, args.precision)),
+            }));
         }
-        let mut row = outdf.get_row(0).unwrap();
-        for i in 0..nrow {
-            outdf.get_row_amortized(i, &mut row).unwrap();
-            let row_str: Vec<String> = row.0.iter().map(|v| format!("{}", v)).collect();
-            println!("{}", row_str.join(","));
+    }
+    if shown < nrow {
+        table.add_row(vec!["…"; df.width()]);
+    }
+    for (i, s) in df.get_columns().iter().enumerate() {
+        if s.dtype().is_numeric() {
+            if let Some(c) = table.column_mut(i) {
+                c.set_cell_alignment(CellAlignment::Right);
+            }
         }
     }
+    writeln!(writer, "{table}")?;
+    writeln!(writer, "{} rows × {} columns", nrow, df.width())?;
+    Ok(())
+}
+
+fn anyvalue_to_json(val: &AnyValue) -> serde_json::Value {
+    match val {
+        AnyValue::Null => serde_json::Value::Null,
+        AnyValue::Boolean(b) => (*b).into(),
+        AnyValue::Utf8(s) => (*s).into(),
+        AnyValue::Int32(i) => (*i).into(),
+        AnyValue::Int64(i) => (*i).into(),
+        AnyValue::UInt32(i) => (*i).into(),
+        AnyValue::UInt64(i) => (*i).into(),
+        AnyValue::Float32(f) => serde_json::Number::from_f64(*f as f64).into(),
+        AnyValue::Float64(f) => serde_json::Number::from_f64(*f).into(),
+        v => v.to_string().into(),
+    }
+}
+
+/// Write the rows as JSON objects, either as a single array or one
+/// object per line; keys are kept in the column order
+fn json_output(df: &DataFrame, lines: bool, writer: &mut impl Write) -> anyhow::Result<()> {
+    let names: Vec<String> = df
+        .get_column_names()
+        .iter()
+        .map(serde_json::to_string)
+        .collect::<Result<_, _>>()?;
+    if !lines {
+        write!(writer, "[")?;
+    }
+    if df.height() > 0 {
+        let mut row = df.get_row(0)?;
+        for i in 0..df.height() {
+            df.get_row_amortized(i, &mut row)?;
+            if !lines && i > 0 {
+                write!(writer, ",")?;
+            }
+            write!(writer, "{{")?;
+            for (j, (k, v)) in names.iter().zip(row.0.iter()).enumerate() {
+                if j > 0 {
+                    write!(writer, ",")?;
+                }
+                write!(writer, "{}:{}", k, anyvalue_to_json(v))?;
+            }
+            write!(writer, "}}")?;
+            if lines {
+                writeln!(writer)?;
+            }
+        }
+    }
+    if !lines {
+        writeln!(writer, "]")?;
+    }
+    Ok(())
+}
+
+/// Timeseries csv files of the nodes, for the commands that run on
+/// every node of a network
+#[derive(Args)]
+pub struct NodeSeries {
+    /// Template for the timeseries csv file of the nodes
+    ///
+    /// Relative to the connection file directory, the nodes without
+    /// the file are skipped
+    #[arg(short, long, default_value = "timeseries/{name}.csv", value_parser=Template::parse_template)]
+    pub timeseries: Template,
+    /// column name containing discharges in the timeseries csv
+    #[arg(long, default_value = "flow")]
+    pub discharge_col: String,
+    #[command(flatten)]
+    pub attrs: AttrsFiles,
+}
+
+impl NodeSeries {
+    pub fn datetime_col(&self) -> &str {
+        self.attrs.datetime_col()
+    }
+
+    /// Network of the connection file with the node attributes from
+    /// the attribute directories
+    pub fn network(&self, connection_file: &PathBuf) -> Result<Network, NadiError> {
+        Network::from_file_attrs(connection_file, false, &self.attrs)
+    }
+
+    pub fn load(&self, path: &PathBuf) -> Result<Discharges<'_>, NadiError> {
+        Discharges::new(path, self.datetime_col(), &self.discharge_col, None)
+    }
+
+    /// Timeseries file of the node, None if it doesn't exist
+    pub fn file(&self, node: &Node) -> Option<PathBuf> {
+        let path = node.file(&self.timeseries);
+        path.exists().then_some(path)
+    }
+
+    /// Timeseries of the nodes that have the file, the files that
+    /// can't be read are skipped with a warning
+    pub fn nodes<'a>(
+        &'a self,
+        net: &'a Network,
+    ) -> impl Iterator<Item = (&'a Node, PathBuf, Discharges<'a>)> {
+        net.nodes.iter().filter_map(move |node| {
+            let path = self.file(node)?;
+            match self.load(&path) {
+                Ok(ts) => Some((node, path, ts)),
+                Err(e) => {
+                    journal::warn(format!("Skipping {}: {e}", node.get_name()));
+                    None
+                }
+            }
+        })
+    }
 }
 
 pub struct Discharges<'a> {
     datetime_col: &'a str,
     discharge_col: &'a str,
     data_table: DataFrame,
+    /// metadata from the commented header of the file
+    metadata: Vec<(String, String)>,
 }
 
 impl<'a> Discharges<'a> {
-    pub fn new(filename: &PathBuf, datetime_col: &'a str, discharge_col: &'a str) -> Self {
+    pub fn new(
+        filename: &PathBuf,
+        datetime_col: &'a str,
+        discharge_col: &'a str,
+        chunk_size: Option<usize>,
+    ) -> Result<Self, NadiError> {
         let columns = vec![datetime_col.to_string(), discharge_col.to_string()];
         let schema = Schema::from_iter(vec![
             Field::new(datetime_col, DataType::Date),
             Field::new(discharge_col, DataType::Float64),
         ]);
-        let data_table = CsvReader::from_path(filename)
-            .unwrap()
+        let metadata = read_metadata_header(filename).unwrap_or_default();
+        // compressed files are read into memory as the csv reader
+        // needs to seek
+        let empty = || NadiError::ParseError {
+            file: filename.clone(),
+            line: 1,
+            msg: format!(
+                "Empty file, expected a csv with the {datetime_col} and {discharge_col} columns"
+            ),
+        };
+        let source: Box<dyn MmapBytesReader> = match Compressed::from_path(filename) {
+            Compressed::None => {
+                let file = File::open(filename)?;
+                if file.metadata()?.len() == 0 {
+                    return Err(empty());
+                }
+                Box::new(file)
+            }
+            _ => {
+                let mut bytes = Vec::new();
+                open_input(filename)?.read_to_end(&mut bytes)?;
+                if bytes.is_empty() {
+                    return Err(empty());
+                }
+                Box::new(Cursor::new(bytes))
+            }
+        };
+        let mut reader = CsvReader::new(source)
             .has_header(true)
+            .with_comment_char(Some(b'#'))
             .with_columns(Some(columns))
-            .with_dtypes(Some(Arc::new(schema)))
-            .finish()
-            .unwrap();
-        Self {
+            .with_dtypes(Some(Arc::new(schema)));
+        if let Some(chunk) = chunk_size {
+            reader = reader.with_chunk_size(chunk);
+        }
+        let data_table = reader.finish()?;
+        journal::input(filename);
+        Ok(Self {
             datetime_col,
             discharge_col,
             data_table,
+            metadata,
+        })
+    }
+
+    /// Lazy frame of the data with the streaming engine enabled, so
+    /// the groupbys on long records are processed in chunks
+    pub fn lazy(&self) -> LazyFrame {
+        self.data_table.clone().lazy().with_streaming(true)
+    }
+
+    /// Number of days both timeseries have values on, and the Pearson
+    /// correlation of the values on those days
+    pub fn overlap_correlation(
+        &self,
+        other: &Discharges,
+    ) -> Result<(usize, Option<f64>), NadiError> {
+        let select = |ts: &Discharges, name: &str| {
+            ts.lazy()
+                .select([
+                    col(ts.datetime_col).alias("date"),
+                    col(ts.discharge_col).cast(DataType::Float64).alias(name),
+                ])
+                .collect()
+        };
+        let joined = select(self, "a")?
+            .inner_join(&select(other, "b")?, ["date"], ["date"])?
+            .drop_nulls::<String>(None)?;
+        let n = joined.height();
+        let a: Vec<f64> = joined.column("a")?.f64()?.into_no_null_iter().collect();
+        let b: Vec<f64> = joined.column("b")?.f64()?.into_no_null_iter().collect();
+        if n < 2 {
+            return Ok((n, None));
+        }
+        let mean_a = a.iter().sum::<f64>() / n as f64;
+        let mean_b = b.iter().sum::<f64>() / n as f64;
+        let (mut cov, mut var_a, mut var_b) = (0.0, 0.0, 0.0);
+        for (x, y) in a.iter().zip(b.iter()) {
+            cov += (x - mean_a) * (y - mean_b);
+            var_a += (x - mean_a).powi(2);
+            var_b += (y - mean_b).powi(2);
         }
+        let corr = cov / (var_a * var_b).sqrt();
+        Ok((n, corr.is_finite().then_some(corr)))
+    }
+
+    /// Value of the statistic for each calendar or water year
+    /// (October to September, named by the ending year), or each month
+    /// of the years, as (year, month, value) sorted by the year and the
+    /// month; the month is 0 for the years. The periods with less than
+    /// `min_days` days with values are left out.
+    pub fn period_values(
+        &self,
+        stat: Statistic,
+        monthly: bool,
+        water_year: bool,
+        min_days: usize,
+    ) -> anyhow::Result<Vec<(i32, u32, f64)>> {
+        if matches!(stat, Statistic::Start | Statistic::End) {
+            anyhow::bail!("Statistic of the periods should be a number");
+        }
+        let date = col(self.datetime_col);
+        let year = date.clone().dt().year();
+        let month = date.clone().dt().month().cast(DataType::Int32);
+        let year = if water_year {
+            when(month.gt_eq(lit(10)))
+                .then(year.clone() + lit(1))
+                .otherwise(year)
+        } else {
+            year
+        };
+        let mut keys = vec![year.alias("year")];
+        if monthly {
+            keys.push(date.dt().month().alias("month"));
+        }
+        let df = self
+            .lazy()
+            .groupby(keys)
+            .agg([
+                stat.expr(self).cast(DataType::Float64).alias("value"),
+                col(self.discharge_col)
+                    .is_not_null()
+                    .sum()
+                    .cast(DataType::Int64)
+                    .alias("days"),
+            ])
+            .filter(col("days").gt_eq(lit(min_days as i64)))
+            .with_column(if monthly {
+                col("month")
+            } else {
+                lit(0u32).alias("month")
+            })
+            .drop_nulls(None)
+            .sort_by_exprs([col("year"), col("month")], [false, false], false, false)
+            .collect()?;
+        let years = df.column("year")?.i32()?.into_no_null_iter();
+        let months = df.column("month")?.u32()?.into_no_null_iter();
+        let values = df.column("value")?.f64()?.into_no_null_iter();
+        Ok(years
+            .zip(months)
+            .zip(values)
+            .map(|((y, m), v)| (y, m, v))
+            .collect())
+    }
+
+    /// Dates and values of the days with values, sorted by the date
+    pub fn values(&self) -> Result<Vec<(NaiveDate, f64)>, NadiError> {
+        let epoch = NaiveDate::from_ymd_opt(1970, 1, 1).unwrap();
+        let df = self
+            .lazy()
+            .select([
+                col(self.datetime_col).cast(DataType::Int32).alias("day"),
+                col(self.discharge_col)
+                    .cast(DataType::Float64)
+                    .alias("value"),
+            ])
+            .drop_nulls(None)
+            .sort("day", SortOptions::default())
+            .collect()?;
+        let days = df.column("day")?.i32()?.into_no_null_iter();
+        let values = df.column("value")?.f64()?.into_no_null_iter();
+        Ok(days
+            .zip(values)
+            .map(|(d, v)| (epoch + chrono::Duration::days(d as i64), v))
+            .collect())
     }
 
     pub fn derived(self, df: DataFrame) -> Self {
@@ -220,14 +1173,13 @@ impl<'a> Discharges<'a> {
             datetime_col: self.datetime_col,
             discharge_col: self.discharge_col,
             data_table: df,
+            metadata: self.metadata,
         }
     }
 }
 
 fn apply_date_range(ts: &Discharges, args: &CliArgs) -> DataFrame {
-    ts.data_table
-        .clone()
-        .lazy()
+    ts.lazy()
         .filter(
             col(ts.datetime_col)
                 .gt_eq(
@@ -249,19 +1201,54 @@ fn apply_date_range(ts: &Discharges, args: &CliArgs) -> DataFrame {
         .unwrap()
 }
 
+fn complete_calendar(ts: &Discharges, args: &CliArgs) -> anyhow::Result<DataFrame> {
+    let epoch = NaiveDate::from_ymd_opt(1970, 1, 1).unwrap();
+    let days = ts
+        .data_table
+        .column(ts.datetime_col)?
+        .cast(&DataType::Int32)?;
+    let days = days.i32()?;
+    // bias-correct keeps all the modeled days
+    let range = match args.command {
+        TsProcess::BiasCorrect => (None, None),
+        _ => (args.date_range.start, args.date_range.end),
+    };
+    let start = range
+        .0
+        .map(|d| (d - epoch).num_days() as i32)
+        .or(days.min());
+    let end = range
+        .1
+        .map(|d| (d - epoch).num_days() as i32)
+        .or(days.max());
+    let (start, end) = match (start, end) {
+        (Some(s), Some(e)) => (s, e),
+        _ => return Ok(ts.data_table.clone()),
+    };
+    let calendar =
+        Series::new(ts.datetime_col, (start..=end).collect::<Vec<i32>>()).cast(&DataType::Date)?;
+    Ok(DataFrame::new(vec![calendar])?.left_join(
+        &ts.data_table,
+        [ts.datetime_col],
+        [ts.datetime_col],
+    )?)
+}
+
 // fn apply_kernel_ma(df: DataFrame, col_name: &str, kernel: Vec<f64>) -> DataFrame {
 //     // df.clone().lazy().with_column(col(col_name).)
 //     df
 // }
 
-pub fn echo(ts: &Discharges, args: &CliArgs) {
-    dataframe_output(ts.data_table.clone(), args);
+pub fn echo(ts: &Discharges, args: &CliArgs, writer: &mut impl Write) -> anyhow::Result<()> {
+    dataframe_output(ts.data_table.clone(), args, writer)
 }
 
-pub fn calc_min7day(ts: &Discharges, args: &CliArgs) {
+pub fn calc_min7day(
+    ts: &Discharges,
+    args: &CliArgs,
+    writer: &mut impl Write,
+) -> anyhow::Result<()> {
     let min_7day = ts
-        .data_table
-        .clone()
         .lazy()
         .with_columns(
             &[col(ts.datetime_col).cast(DataType::Datetime(TimeUnit::Milliseconds, None))],
@@ -275,85 +1262,984 @@ pub fn calc_min7day(ts: &Discharges, args: &CliArgs) {
         })])
         .groupby([col(ts.datetime_col).dt().year()])
         .agg([col(ts.discharge_col).min()])
-        .collect()
-        .unwrap();
+        .collect()?;
 
-    dataframe_output(min_7day, args);
+    dataframe_output(min_7day, args, writer)
 }
 
-pub fn na_fill_forward(ts: &Discharges, args: &CliArgs) {
+pub fn na_fill_forward(
+    ts: &Discharges,
+    args: &CliArgs,
+    writer: &mut impl Write,
+) -> anyhow::Result<()> {
     let threshold: Option<u32> = args
         .args
-        .get(0)
-        .map(|s| s.parse().expect("Threshold needs to be integer"));
+        .first()
+        .map(|s| s.parse().context("Threshold needs to be integer"))
+        .transpose()?;
     let nafill = ts
-        .data_table
-        .clone()
         .lazy()
         .with_columns(&[col(ts.discharge_col).forward_fill(threshold)])
-        .collect()
-        .unwrap();
-    dataframe_output(nafill, args);
+        .collect()?;
+    dataframe_output(nafill, args, writer)
 }
 
-pub fn monthly_seasonality(ts: &Discharges, args: &CliArgs) {
-    let seasonality = ts
+pub fn na_fill_backward(
+    ts: &Discharges,
+    args: &CliArgs,
+    writer: &mut impl Write,
+) -> anyhow::Result<()> {
+    let threshold: Option<u32> = args
+        .args
+        .first()
+        .map(|s| s.parse().context("Threshold needs to be integer"))
+        .transpose()?;
+    let nafill = ts
+        .lazy()
+        .with_columns(&[col(ts.discharge_col).backward_fill(threshold)])
+        .collect()?;
+    dataframe_output(nafill, args, writer)
+}
+
+pub fn na_fill_value(
+    ts: &Discharges,
+    args: &CliArgs,
+    writer: &mut impl Write,
+) -> anyhow::Result<()> {
+    let value: f64 = args
+        .args
+        .first()
+        .context("Fill value is needed in --args")?
+        .parse()
+        .context("Fill value needs to be a number")?;
+    let nafill = ts
+        .lazy()
+        .with_columns(&[col(ts.discharge_col).fill_null(lit(value))])
+        .collect()?;
+    dataframe_output(nafill, args, writer)
+}
+
+/// Joined table, and the values of the timeseries and the donor
+type DonorJoin = (DataFrame, Vec<Option<f64>>, Vec<Option<f64>>);
+
+/// Values of the timeseries and the donor timeseries (first file in
+/// --args) on the dates of the timeseries
+fn join_donor(ts: &Discharges, args: &CliArgs) -> anyhow::Result<DonorJoin> {
+    let donor_file = PathBuf::from(
+        args.args
+            .first()
+            .context("Donor timeseries file is needed in --args")?,
+    );
+    let mut donor = Discharges::new(
+        &donor_file,
+        ts.datetime_col,
+        ts.discharge_col,
+        args.chunk_size,
+    )?;
+    donor.data_table = apply_date_range(&donor, args);
+    let donor = donor
+        .lazy()
+        .select([col(ts.datetime_col), col(ts.discharge_col).alias("__donor")])
+        .collect()?;
+    let joined = ts
         .data_table
-        .clone()
+        .left_join(&donor, [ts.datetime_col], [ts.datetime_col])?;
+    let flow: Vec<Option<f64>> = joined
+        .column(ts.discharge_col)?
+        .f64()?
+        .into_iter()
+        .collect();
+    let donor: Vec<Option<f64>> = joined.column("__donor")?.f64()?.into_iter().collect();
+    Ok((joined, flow, donor))
+}
+
+/// Intercept and slope of the least squares line of the points
+fn linear_fit(pairs: &[(f64, f64)]) -> anyhow::Result<(f64, f64)> {
+    if pairs.len() < 2 {
+        anyhow::bail!("Less than 2 days with positive values in both timeseries");
+    }
+    let n = pairs.len() as f64;
+    let mean_x = pairs.iter().map(|p| p.0).sum::<f64>() / n;
+    let mean_y = pairs.iter().map(|p| p.1).sum::<f64>() / n;
+    let (mut sxy, mut sxx) = (0.0, 0.0);
+    for (x, y) in pairs {
+        sxy += (x - mean_x) * (y - mean_y);
+        sxx += (x - mean_x).powi(2);
+    }
+    if sxx == 0.0 {
+        anyhow::bail!("Donor timeseries is constant on the overlapping days");
+    }
+    let slope = sxy / sxx;
+    Ok((mean_y - slope * mean_x, slope))
+}
+
+/// Number of values, bias, mean absolute error, root mean square
+/// error, mean absolute percent error and Nash-Sutcliffe efficiency
+/// of the (observed, predicted) pairs
+pub fn error_metrics(pairs: &[(f64, f64)]) -> Vec<(&'static str, f64)> {
+    let n = pairs.len() as f64;
+    let mean_obs = pairs.iter().map(|p| p.0).sum::<f64>() / n;
+    let sse: f64 = pairs.iter().map(|(o, p)| (p - o).powi(2)).sum();
+    let sst: f64 = pairs.iter().map(|(o, _)| (o - mean_obs).powi(2)).sum();
+    vec![
+        ("n", n),
+        ("bias", pairs.iter().map(|(o, p)| p - o).sum::<f64>() / n),
+        ("mae", pairs.iter().map(|(o, p)| (p - o).abs()).sum::<f64>() / n),
+        ("rmse", (sse / n).sqrt()),
+        (
+            "mape",
+            pairs.iter().map(|(o, p)| ((p - o) / o).abs()).sum::<f64>() / n * 100.0,
+        ),
+        ("nse", 1.0 - sse / sst),
+    ]
+}
+
+/// Fill the missing values by `exp(a) * donor^b` fitted on the days
+/// both timeseries have positive values; the ensemble adds the
+/// resampled residuals of the fit to the filled values
+pub fn na_fill_donor(
+    ts: &Discharges,
+    args: &CliArgs,
+    writer: &mut impl Write,
+) -> anyhow::Result<()> {
+    let (joined, flow, donor) = join_donor(ts, args)?;
+    let ln = |v: Option<f64>| v.filter(|v| *v > 0.0).map(f64::ln);
+    let pairs: Vec<(f64, f64)> = donor
+        .iter()
+        .zip(&flow)
+        .filter_map(|(d, q)| Some((ln(*d)?, ln(*q)?)))
+        .collect();
+    let (intercept, slope) = linear_fit(&pairs)?;
+    let residuals: Vec<f64> = pairs
+        .iter()
+        .map(|(x, y)| y - intercept - slope * x)
+        .collect();
+    let estimate = |d: Option<f64>| ln(d).map(|x| intercept + slope * x);
+
+    let filled: Vec<Option<f64>> = flow
+        .iter()
+        .zip(&donor)
+        .map(|(q, d)| q.or_else(|| estimate(*d).map(f64::exp)))
+        .collect();
+    let is_filled: Vec<bool> = flow
+        .iter()
+        .zip(&filled)
+        .map(|(q, f)| q.is_none() && f.is_some())
+        .collect();
+    let count = is_filled.iter().filter(|f| **f).count();
+    journal::count("filled", count);
+    journal::count("fit_days", pairs.len());
+
+    let mut columns = vec![
+        joined.column(ts.datetime_col)?.clone(),
+        Series::new(ts.discharge_col, &filled),
+        Series::new("filled", &is_filled),
+    ];
+    if let Some(realizations) = args.ensemble.filter(|n| *n > 0) {
+        let mut rng = StdRng::seed_from_u64(args.seed);
+        let (lo, hi) = ((100.0 - args.band) / 2.0, (100.0 + args.band) / 2.0);
+        // values of the filled days and the statistics of the whole
+        // timeseries in each realization
+        let mut values: Vec<Vec<f64>> = vec![Vec::with_capacity(realizations); flow.len()];
+        let mut stats: [Vec<f64>; 3] = Default::default();
+        for _ in 0..realizations {
+            let series: Vec<f64> = flow
+                .iter()
+                .zip(&donor)
+                .enumerate()
+                .filter_map(|(i, (q, d))| {
+                    let v = q.or_else(|| {
+                        let r = residuals[rng.gen_range(0..residuals.len())];
+                        estimate(*d).map(|e| (e + r).exp())
+                    })?;
+                    if is_filled[i] {
+                        values[i].push(v);
+                    }
+                    Some(v)
+                })
+                .collect();
+            stats[0].push(series.iter().sum::<f64>() / series.len() as f64);
+            stats[1].push(series.iter().cloned().fold(f64::INFINITY, f64::min));
+            stats[2].push(series.iter().cloned().fold(f64::NEG_INFINITY, f64::max));
+        }
+        values
+            .iter_mut()
+            .for_each(|v| v.sort_by(|a, b| a.total_cmp(b)));
+        // the observed values don't have any uncertainty
+        let band = |p: f64| -> Vec<Option<f64>> {
+            values
+                .iter()
+                .zip(&filled)
+                .map(|(v, f)| {
+                    if v.is_empty() {
+                        *f
+                    } else {
+                        Some(percentile(v, p))
+                    }
+                })
+                .collect()
+        };
+        let lower = band(lo);
+        let upper = band(hi);
+        columns.push(Series::new(&format!("{}_lower", ts.discharge_col), lower));
+        columns.push(Series::new(&format!("{}_upper", ts.discharge_col), upper));
+
+        let values: Vec<f64> = filled.iter().flatten().copied().collect();
+        let deterministic = [
+            values.iter().sum::<f64>() / values.len() as f64,
+            values.iter().cloned().fold(f64::INFINITY, f64::min),
+            values.iter().cloned().fold(f64::NEG_INFINITY, f64::max),
+        ];
+        for ((name, value), mut stat) in ["mean", "min", "max"].iter().zip(deterministic).zip(stats)
+        {
+            stat.sort_by(|a, b| a.total_cmp(b));
+            if args.format == OutputFormat::Csv {
+                writeln!(
+                    writer,
+                    "# {name}: {value} ({}% band {} - {})",
+                    args.band,
+                    percentile(&stat, lo),
+                    percentile(&stat, hi)
+                )?;
+            }
+        }
+    }
+    dataframe_output(DataFrame::new(columns)?, args, writer)
+}
+
+/// Predict each day with positive values in both timeseries from the
+/// regression of na-fill-donor fitted on the other days, the error
+/// metrics are written as comments before the values
+pub fn validate_donor(
+    ts: &Discharges,
+    args: &CliArgs,
+    writer: &mut impl Write,
+) -> anyhow::Result<()> {
+    if args.format != OutputFormat::Csv {
+        anyhow::bail!("validate-donor writes the error metrics as csv comments, use --format csv");
+    }
+    let (joined, flow, donor) = join_donor(ts, args)?;
+    let ln = |v: Option<f64>| v.filter(|v| *v > 0.0).map(f64::ln);
+    let days: Vec<(usize, (f64, f64))> = donor
+        .iter()
+        .zip(&flow)
+        .enumerate()
+        .filter_map(|(i, (d, q))| Some((i, (ln(*d)?, ln(*q)?))))
+        .collect();
+    let pairs: Vec<(f64, f64)> = days.iter().map(|d| d.1).collect();
+    if pairs.len() < 3 {
+        anyhow::bail!("Less than 3 days with positive values in both timeseries");
+    }
+    let (intercept, slope) = linear_fit(&pairs)?;
+    let n = pairs.len() as f64;
+    let mean_x = pairs.iter().map(|p| p.0).sum::<f64>() / n;
+    let sxx: f64 = pairs.iter().map(|(x, _)| (x - mean_x).powi(2)).sum();
+    // the residual of the fit without the day is the residual of the
+    // full fit divided by (1 - leverage of the day)
+    let mut predicted: Vec<Option<f64>> = vec![None; flow.len()];
+    for (i, (x, y)) in &days {
+        let leverage = 1.0 / n + (x - mean_x).powi(2) / sxx;
+        let residual = (y - intercept - slope * x) / (1.0 - leverage);
+        predicted[*i] = Some((y - residual).exp());
+    }
+    let observed: Vec<Option<f64>> = flow
+        .iter()
+        .zip(&predicted)
+        .map(|(q, p)| p.and(*q))
+        .collect();
+    let metrics = error_metrics(
+        &observed
+            .iter()
+            .zip(&predicted)
+            .filter_map(|(o, p)| Some(((*o)?, (*p)?)))
+            .collect::<Vec<_>>(),
+    );
+    journal::count("validated", days.len());
+    for (name, value) in metrics {
+        writeln!(writer, "# {name}: {value}")?;
+    }
+    let error: Vec<Option<f64>> = observed
+        .iter()
+        .zip(&predicted)
+        .map(|(o, p)| Some(p.as_ref()? - o.as_ref()?))
+        .collect();
+    let outdf = DataFrame::new(vec![
+        joined.column(ts.datetime_col)?.clone(),
+        Series::new("observed", &observed),
+        Series::new("predicted", &predicted),
+        Series::new("error", &error),
+    ])?
+    .lazy()
+    .filter(col("predicted").is_not_null())
+    .collect()?;
+    dataframe_output(outdf, args, writer)
+}
+
+/// Empirical quantile mapping of the value from the sorted modeled
+/// values to the sorted observed values; the values outside the
+/// modeled range are scaled by the ratio at the end of the range
+fn quantile_map(modeled: &[f64], observed: &[f64], value: f64) -> f64 {
+    let (m_min, m_max) = (modeled[0], modeled[modeled.len() - 1]);
+    let (o_min, o_max) = (observed[0], observed[observed.len() - 1]);
+    if value < m_min || value > m_max {
+        let (m, o) = if value < m_min {
+            (m_min, o_min)
+        } else {
+            (m_max, o_max)
+        };
+        return if m != 0.0 {
+            o * value / m
+        } else {
+            o + value - m
+        };
+    }
+    let i = modeled.partition_point(|v| *v < value).max(1);
+    let step = modeled[i] - modeled[i - 1];
+    let rank = if step > 0.0 {
+        (i - 1) as f64 + (value - modeled[i - 1]) / step
+    } else {
+        i as f64
+    };
+    percentile(observed, rank / (modeled.len() - 1) as f64 * 100.0)
+}
+
+pub fn bias_correct(
+    ts: &Discharges,
+    args: &CliArgs,
+    writer: &mut impl Write,
+) -> anyhow::Result<()> {
+    let observed_file = PathBuf::from(
+        args.args
+            .first()
+            .context("Observed timeseries file is needed in --args")?,
+    );
+    let mut observed = Discharges::new(
+        &observed_file,
+        ts.datetime_col,
+        ts.discharge_col,
+        args.chunk_size,
+    )?;
+    observed.data_table = apply_date_range(&observed, args);
+    let observed = observed
+        .lazy()
+        .select([
+            col(ts.datetime_col),
+            col(ts.discharge_col).alias("__observed"),
+        ])
+        .collect()?;
+    let joined = ts
+        .lazy()
+        .with_column(col(ts.datetime_col).dt().month().alias("__month"))
+        .collect()?
+        .left_join(&observed, [ts.datetime_col], [ts.datetime_col])?;
+    let modeled: Vec<Option<f64>> = joined
+        .column(ts.discharge_col)?
+        .f64()?
+        .into_iter()
+        .collect();
+    let observed: Vec<Option<f64>> = joined.column("__observed")?.f64()?.into_iter().collect();
+    let months: Vec<Option<u32>> = joined.column("__month")?.u32()?.into_iter().collect();
+
+    // sorted modeled and observed values of the overlapping days of
+    // each month
+    let mut maps: Vec<(Vec<f64>, Vec<f64>)> = vec![Default::default(); 12];
+    for ((m, q), o) in months.iter().zip(&modeled).zip(&observed) {
+        if let (Some(m), Some(q), Some(o)) = (m, q, o) {
+            maps[*m as usize - 1].0.push(*q);
+            maps[*m as usize - 1].1.push(*o);
+        }
+    }
+    for (i, (m, o)) in maps.iter_mut().enumerate() {
+        if m.len() < 2 {
+            anyhow::bail!(
+                "Less than 2 overlapping days with values in month {}",
+                i + 1
+            );
+        }
+        m.sort_by(|a, b| a.total_cmp(b));
+        o.sort_by(|a, b| a.total_cmp(b));
+    }
+    let corrected: Vec<Option<f64>> = months
+        .iter()
+        .zip(&modeled)
+        .map(|(m, q)| {
+            let (m, q) = ((*m)?, (*q)?);
+            let (mod_sorted, obs_sorted) = &maps[m as usize - 1];
+            Some(quantile_map(mod_sorted, obs_sorted, q))
+        })
+        .collect();
+    let overlap: usize = maps.iter().map(|m| m.0.len()).sum();
+    let count = corrected.iter().flatten().count();
+    journal::count("corrected", count);
+    journal::count("overlapping_days", overlap);
+
+    let columns = vec![
+        joined.column(ts.datetime_col)?.clone(),
+        Series::new(ts.discharge_col, &corrected),
+        Series::new(&format!("{}_modeled", ts.discharge_col), &modeled),
+    ];
+    dataframe_output(DataFrame::new(columns)?, args, writer)
+}
+
+pub fn monthly_seasonality(
+    ts: &Discharges,
+    args: &CliArgs,
+    writer: &mut impl Write,
+) -> anyhow::Result<()> {
+    let seasonality = ts
         .lazy()
         .groupby(&[col(ts.datetime_col).dt().month().alias("month")])
         .agg([col("flow").mean()])
         .sort("month", SortOptions::default())
-        .collect()
-        .unwrap();
-    dataframe_output(seasonality, args);
+        .collect()?;
+    dataframe_output(seasonality, args, writer)
+}
+
+fn station_seasonality(ts: &Discharges, name: &str, normalize: bool) -> anyhow::Result<DataFrame> {
+    let mut seasonality = ts
+        .lazy()
+        .groupby(&[col(ts.datetime_col).dt().month().alias("month")])
+        .agg([col(ts.discharge_col).mean().alias(name)]);
+    if normalize {
+        seasonality = seasonality.with_column(col(name) / col(name).mean());
+    }
+    Ok(seasonality.collect()?)
+}
+
+/// Monthly seasonality of multiple stations in a single table, the
+/// columns are named after the file names of the stations
+pub fn compare_seasonality(
+    ts: &Discharges,
+    args: &CliArgs,
+    writer: &mut impl Write,
+) -> anyhow::Result<()> {
+    let paths: Vec<PathBuf> = std::iter::once(args.input.clone())
+        .chain(args.args.iter().map(PathBuf::from))
+        .collect();
+    let stem = |path: &PathBuf| {
+        path.file_stem()
+            .map(|s| s.to_string_lossy().to_string())
+            .unwrap_or_else(|| path.to_string_lossy().to_string())
+    };
+    // the files with the same name in different directories are named
+    // by their path
+    let mut names: Vec<String> = Vec::with_capacity(paths.len());
+    for path in &paths {
+        let name = stem(path);
+        if paths.iter().filter(|p| stem(p) == name).count() == 1 {
+            names.push(name);
+            continue;
+        }
+        let name = path.to_string_lossy().to_string();
+        if names.contains(&name) {
+            anyhow::bail!("{:?} is given more than once", path);
+        }
+        names.push(name);
+    }
+    let mut table = df!("month" => (1u32..=12).collect::<Vec<u32>>())?;
+    let first = station_seasonality(ts, &names[0], args.normalize)?;
+    table = table.left_join(&first, ["month"], ["month"])?;
+    for (path, name) in paths.iter().zip(&names).skip(1) {
+        let mut other = Discharges::new(path, ts.datetime_col, ts.discharge_col, args.chunk_size)?;
+        other.data_table = apply_date_range(&other, args);
+        let season = station_seasonality(&other, name, args.normalize)?;
+        table = table.left_join(&season, ["month"], ["month"])?;
+    }
+    dataframe_output(table, args, writer)
 }
 
-pub fn daily_seasonality(ts: &Discharges, args: &CliArgs) {
+pub fn daily_seasonality(
+    ts: &Discharges,
+    args: &CliArgs,
+    writer: &mut impl Write,
+) -> anyhow::Result<()> {
     let seasonality = ts
-        .data_table
-        .clone()
         .lazy()
         .groupby(&[col(ts.datetime_col).dt().ordinal_day().alias("day")])
         .agg([col("flow").mean()])
         .sort("day", SortOptions::default())
-        .collect()
-        .unwrap();
-    dataframe_output(seasonality, args);
+        .collect()?;
+    dataframe_output(seasonality, args, writer)
+}
+
+/// Number of days in the year, or in the month of the year if given
+fn days_in_period(year: Expr, month: Option<Expr>) -> Expr {
+    let leap = (year.clone() % lit(4)).eq(lit(0)).and(
+        (year.clone() % lit(100))
+            .neq(lit(0))
+            .or((year % lit(400)).eq(lit(0))),
+    );
+    match month {
+        None => when(leap).then(lit(366)).otherwise(lit(365)),
+        Some(m) => when(m.clone().eq(lit(2)))
+            .then(when(leap).then(lit(29)).otherwise(lit(28)))
+            .when(
+                m.clone()
+                    .eq(lit(4))
+                    .or(m.clone().eq(lit(6)))
+                    .or(m.clone().eq(lit(9)))
+                    .or(m.eq(lit(11))),
+            )
+            .then(lit(30))
+            .otherwise(lit(31)),
+    }
 }
 
-pub fn annual_mean(ts: &Discharges, args: &CliArgs) {
+/// Number of distinct days with values in the aggregation, so the
+/// duplicated dates aren't counted twice
+fn valid_days(ts: &Discharges) -> Expr {
+    col(ts.datetime_col)
+        .filter(col(ts.discharge_col).is_not_null())
+        .n_unique()
+        .alias("valid")
+}
+
+/// Completeness columns from the count of valid values in the
+/// aggregation and the expected days in the period
+fn completeness(expected: Expr) -> [Expr; 2] {
+    [
+        col("valid").eq(expected.clone()).alias("complete"),
+        (col("valid").cast(DataType::Float64) * lit(100.0) / expected.cast(DataType::Float64))
+            .alias("pct_complete"),
+    ]
+}
+
+pub fn annual_mean(ts: &Discharges, args: &CliArgs, writer: &mut impl Write) -> anyhow::Result<()> {
     let annual = ts
-        .data_table
-        .clone()
         .lazy()
         .groupby(&[col(ts.datetime_col).dt().year().alias("year")])
-        .agg([col("flow").mean(), col("flow").count().alias("count")])
+        .agg([
+            col(ts.discharge_col).mean(),
+            col(ts.discharge_col).count().alias("count"),
+            valid_days(ts),
+        ])
+        .with_columns(completeness(days_in_period(col("year"), None)))
+        .drop_columns(["valid"])
         .sort("year", SortOptions::default())
-        .collect()
-        .unwrap();
-    dataframe_output(annual, args);
+        .collect()?;
+    dataframe_output(annual, args, writer)
 }
 
-pub fn monthly_mean(ts: &Discharges, args: &CliArgs) {
+pub fn monthly_mean(
+    ts: &Discharges,
+    args: &CliArgs,
+    writer: &mut impl Write,
+) -> anyhow::Result<()> {
     let monthly = ts
-        .data_table
-        .clone()
         .lazy()
         .groupby_stable(&[
             col(ts.datetime_col).dt().year().alias("year"),
             col(ts.datetime_col).dt().month().alias("month"),
         ])
-        .agg([col("flow").mean(), col("flow").count().alias("count")])
-        .collect()
-        .unwrap();
-    dataframe_output(monthly, args);
+        .agg([
+            col(ts.discharge_col).mean(),
+            col(ts.discharge_col).count().alias("count"),
+            valid_days(ts),
+        ])
+        .with_columns(completeness(days_in_period(
+            col("year"),
+            Some(col("month")),
+        )))
+        .drop_columns(["valid"])
+        .collect()?;
+    dataframe_output(monthly, args, writer)
 }
 
-pub fn missing_data(ts: &Discharges, args: &CliArgs) {
+/// Deviations from the mean of the values for every day from the
+/// first to the last date, the missing days are None
+fn daily_anomalies(ts: &Discharges) -> anyhow::Result<Vec<Option<f64>>> {
     let df = ts
-        .data_table
+        .lazy()
+        .select([
+            col(ts.datetime_col).cast(DataType::Int32).alias("day"),
+            col(ts.discharge_col).cast(DataType::Float64),
+        ])
+        .drop_nulls(None)
+        .collect()?;
+    let days: Vec<i32> = df.column("day")?.i32()?.into_no_null_iter().collect();
+    let values: Vec<f64> = df
+        .column(ts.discharge_col)?
+        .f64()?
+        .into_no_null_iter()
+        .collect();
+    let (Some(start), Some(end)) = (days.iter().min(), days.iter().max()) else {
+        anyhow::bail!("No values in the timeseries");
+    };
+    let mean = values.iter().sum::<f64>() / values.len() as f64;
+    let mut daily = vec![None; (end - start) as usize + 1];
+    for (d, v) in days.iter().zip(&values) {
+        daily[(d - start) as usize] = Some(v - mean);
+    }
+    Ok(daily)
+}
+
+/// Autocorrelation of the lags from the pairs of days with values,
+/// and the partial autocorrelation from it by the Durbin-Levinson
+/// recursion; the bound is the 95% confidence for white noise
+pub fn autocorrelation(
+    ts: &Discharges,
+    args: &CliArgs,
+    writer: &mut impl Write,
+) -> anyhow::Result<()> {
+    let daily = daily_anomalies(ts)?;
+    let valid: Vec<f64> = daily.iter().flatten().copied().collect();
+    let variance = valid.iter().map(|v| v * v).sum::<f64>() / valid.len() as f64;
+    let max_lag = args.max_lag.min(daily.len().saturating_sub(1));
+    let acf: Vec<f64> = (0..=max_lag)
+        .map(|k| {
+            let (sum, n) = daily
+                .iter()
+                .zip(&daily[k..])
+                .filter_map(|(a, b)| Some(a.as_ref()? * b.as_ref()?))
+                .fold((0.0, 0), |(s, n), p| (s + p, n + 1));
+            if n == 0 || variance == 0.0 {
+                f64::NAN
+            } else {
+                sum / n as f64 / variance
+            }
+        })
+        .collect();
+
+    let mut pacf = vec![1.0; acf.len()];
+    let mut phi: Vec<f64> = Vec::with_capacity(acf.len());
+    for k in 1..acf.len() {
+        let num = acf[k]
+            - phi
+                .iter()
+                .enumerate()
+                .map(|(j, p)| p * acf[k - 1 - j])
+                .sum::<f64>();
+        let den = 1.0
+            - phi
+                .iter()
+                .enumerate()
+                .map(|(j, p)| p * acf[j + 1])
+                .sum::<f64>();
+        let pkk = num / den;
+        let prev = phi.clone();
+        for (j, p) in phi.iter_mut().enumerate() {
+            *p -= pkk * prev[k - 2 - j];
+        }
+        phi.push(pkk);
+        pacf[k] = pkk;
+    }
+
+    let bound = 1.96 / (valid.len() as f64).sqrt();
+    let table = df!(
+        "lag" => (0..=max_lag as u32).collect::<Vec<u32>>(),
+        "acf" => acf,
+        "pacf" => pacf,
+        "bound" => vec![bound; max_lag + 1]
+    )?;
+    dataframe_output(table, args, writer)
+}
+
+/// Periodogram of the daily values by the discrete Fourier
+/// transform, the missing days are taken as the mean
+pub fn periodogram(ts: &Discharges, args: &CliArgs, writer: &mut impl Write) -> anyhow::Result<()> {
+    let daily: Vec<f64> = daily_anomalies(ts)?
+        .into_iter()
+        .map(|v| v.unwrap_or(0.0))
+        .collect();
+    let n = daily.len();
+    let (mut freq, mut period, mut power) = (Vec::new(), Vec::new(), Vec::new());
+    for k in 1..=n / 2 {
+        let w = 2.0 * std::f64::consts::PI * k as f64 / n as f64;
+        let (re, im) = daily
+            .iter()
+            .enumerate()
+            .fold((0.0, 0.0), |(re, im), (t, v)| {
+                let a = w * t as f64;
+                (re + v * a.cos(), im - v * a.sin())
+            });
+        freq.push(k as f64 / n as f64);
+        period.push(n as f64 / k as f64);
+        power.push((re * re + im * im) / n as f64);
+    }
+    let table = df!(
+        "frequency" => freq,
+        "period" => period,
+        "power" => power
+    )?;
+    dataframe_output(table, args, writer)
+}
+
+/// Pettitt test on the annual values, the change year is the first
+/// year after the change and the p value is the approximate
+/// significance of the change
+pub fn change_point(
+    ts: &Discharges,
+    args: &CliArgs,
+    writer: &mut impl Write,
+) -> anyhow::Result<()> {
+    let (years, values): (Vec<i32>, Vec<f64>) = ts
+        .period_values(args.annual_stat, false, false, 0)?
+        .into_iter()
+        .map(|(y, _, v)| (y, v))
+        .unzip();
+    let n = values.len();
+    if n < 3 {
+        anyhow::bail!("Need at least 3 years for the change point, found {n}");
+    }
+    // U_t = sum of sign(x_i - x_j) for i <= t < j, updated for each t
+    let mut u = 0i64;
+    let (mut k, mut t) = (0, 0);
+    for (i, xi) in values.iter().enumerate().take(n - 1) {
+        u += values
+            .iter()
+            .map(|xj| (xi > xj) as i64 - (xi < xj) as i64)
+            .sum::<i64>();
+        if u.abs() > k {
+            (k, t) = (u.abs(), i);
+        }
+    }
+    let (k, nf) = (k as f64, n as f64);
+    let p = (2.0 * (-6.0 * k * k / (nf.powi(3) + nf.powi(2))).exp()).min(1.0);
+    let mean = |v: &[f64]| v.iter().sum::<f64>() / v.len() as f64;
+    let result = df!(
+        "years" => [n as u32],
+        "change_year" => [years[t + 1]],
+        "k" => [k],
+        "p_value" => [p],
+        "mean_before" => [mean(&values[..=t])],
+        "mean_after" => [mean(&values[t + 1..])]
+    )?;
+    dataframe_output(result, args, writer)
+}
+
+/// Split the timeseries into one csv file per period, the names of
+/// the written files are printed to the writer
+pub fn split_files(ts: &Discharges, args: &CliArgs, writer: &mut impl Write) -> anyhow::Result<()> {
+    let year = col(ts.datetime_col).dt().year();
+    let month = col(ts.datetime_col).dt().month().cast(DataType::Int32);
+    let key = match args.split_by {
+        SplitBy::Year => year,
+        SplitBy::WaterYear => when(month.clone().gt_eq(lit(10)))
+            .then(year.clone() + lit(1))
+            .otherwise(year),
+        SplitBy::Month => year * lit(100) + month,
+    };
+    let df = ts.lazy().with_column(key.alias("_period")).collect()?;
+    let periods: Vec<i32> = df
+        .column("_period")?
+        .unique_stable()?
+        .i32()?
+        .into_iter()
+        .flatten()
+        .collect();
+    let default_templ;
+    let templ = match &args.split_template {
+        Some(t) => t,
+        None => {
+            default_templ = Template::parse_template(match args.split_by {
+                SplitBy::Month => "{station}_{year}-{month}.csv",
+                _ => "{station}_{year}.csv",
+            })?;
+            &default_templ
+        }
+    };
+    let station = args
+        .input
+        .file_stem()
+        .map(|s| s.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let mut op = RenderOptions::default();
+    op.variables.insert("station".to_string(), station);
+    for period in periods {
+        let (y, m) = match args.split_by {
+            SplitBy::Month => (period / 100, Some(period % 100)),
+            _ => (period, None),
+        };
+        op.variables.insert("year".to_string(), y.to_string());
+        if let Some(m) = m {
+            op.variables.insert("month".to_string(), format!("{m:02}"));
+        }
+        let filename = PathBuf::from(templ.render(&op)?);
+        if let Some(parent) = filename.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let mut part = df
+            .clone()
+            .lazy()
+            .filter(col("_period").eq(lit(period)))
+            .drop_columns(["_period"])
+            .collect()?;
+        journal::output(&filename);
+        let mut file =
+            create_output(&filename).with_context(|| format!("Couldn't create {filename:?}"))?;
+        if args.header {
+            write_metadata_header(&mut file, ts, args)?;
+        }
+        CsvWriter::new(&mut file)
+            .with_float_precision(args.precision)
+            .finish(&mut part)?;
+        file.finish()
+            .with_context(|| format!("Couldn't write {filename:?}"))?;
+        writeln!(writer, "{}", filename.to_string_lossy())?;
+    }
+    Ok(())
+}
+
+pub fn clean(ts: &Discharges, args: &CliArgs, writer: &mut impl Write) -> anyhow::Result<()> {
+    let days = col(ts.datetime_col).cast(DataType::Int32);
+    let unordered = ts
+        .lazy()
+        .filter(days.clone().lt(days.clone().shift(1)))
+        .collect()?;
+    if unordered.height() > 0 {
+        let dates: Vec<String> = unordered
+            .column(ts.datetime_col)?
+            .rechunk()
+            .iter()
+            .map(|d| format!("  {}", d))
+            .collect();
+        journal::warn(format!(
+            "{} dates are out of order:\n{}",
+            unordered.height(),
+            dates.join("\n")
+        ));
+    }
+    let duplicates = ts
+        .lazy()
+        .groupby_stable([col(ts.datetime_col)])
+        .agg([col(ts.discharge_col).count().alias("count")])
+        .filter(col("count").gt(lit(1)))
+        .collect()?;
+    if duplicates.height() > 0 {
+        let dates = duplicates.column(ts.datetime_col)?.rechunk();
+        let counts = duplicates.column("count")?.rechunk();
+        let lines: Vec<String> = dates
+            .iter()
+            .zip(counts.iter())
+            .map(|(d, c)| format!("  {} ({} times)", d, c))
+            .collect();
+        journal::warn(format!(
+            "{} dates are duplicated:\n{}",
+            duplicates.height(),
+            lines.join("\n")
+        ));
+    }
+    let sorted = ts.lazy().sort(
+        ts.datetime_col,
+        SortOptions {
+            maintain_order: true,
+            ..Default::default()
+        },
+    );
+    let cleaned = match args.duplicates {
+        DuplicateStrategy::Error if duplicates.height() > 0 => {
+            anyhow::bail!("Duplicated dates found, use --duplicates to choose how to fix them")
+        }
+        DuplicateStrategy::Mean => sorted
+            .groupby_stable([col(ts.datetime_col)])
+            .agg([col(ts.discharge_col).mean()]),
+        _ => sorted.unique_stable(
+            Some(vec![ts.datetime_col.to_string()]),
+            UniqueKeepStrategy::First,
+        ),
+    }
+    .collect()?;
+    let gaps = cleaned
         .clone()
+        .lazy()
+        .with_column((days.clone() - days.shift(1) - lit(1)).alias("missing"))
+        .filter(col("missing").gt(lit(0)))
+        .collect()?;
+    if gaps.height() > 0 {
+        let dates = gaps.column(ts.datetime_col)?.rechunk();
+        let missing = gaps.column("missing")?.rechunk();
+        let lines: Vec<String> = dates
+            .iter()
+            .zip(missing.iter())
+            .map(|(d, m)| format!("  {} days missing before {}", m, d))
+            .collect();
+        journal::warn(format!(
+            "{} gaps in the dates:\n{}",
+            gaps.height(),
+            lines.join("\n")
+        ));
+    }
+    dataframe_output(cleaned, args, writer)
+}
+
+/// Update the node attributes file with the statistics, other lines
+/// in the file are kept as they are
+pub fn export_attrs(
+    ts: &Discharges,
+    args: &CliArgs,
+    writer: &mut impl Write,
+) -> anyhow::Result<()> {
+    let exprs: Vec<Expr> = args
+        .stats
+        .iter()
+        .enumerate()
+        .map(|(i, s)| s.expr(ts).alias(&i.to_string()))
+        .collect();
+    let df = ts.lazy().select(exprs).collect()?;
+    let row = df.get_row(0)?;
+    let attrs: Vec<(String, String)> = args
+        .stats
+        .iter()
+        .zip(row.0.iter())
+        .map(|(s, v)| {
+            let name = s.to_possible_value().unwrap().get_name().to_string();
+            let val = match v {
+                AnyValue::Null => String::new(),
+                v => v.to_string(),
+            };
+            let infix = if args.per_area { "_yield" } else { "" };
+            (format!("{}{infix}_{}", ts.discharge_col, name), val)
+        })
+        .collect();
+
+    let filename = args.attrs.save(&node_name(args)?, attrs)?;
+    writeln!(writer, "{}", filename.to_string_lossy())?;
+    Ok(())
+}
+
+/// Name of the node for the attributes file, from the input file name
+fn node_name(args: &CliArgs) -> anyhow::Result<String> {
+    Ok(args
+        .input
+        .file_stem()
+        .context("Input file doesn't have a name")?
+        .to_string_lossy()
+        .to_string())
+}
+
+/// Set the attributes in the node attributes file, replacing the
+/// lines of the same attributes and keeping the others
+pub fn update_attrs_file(filename: &Path, attrs: Vec<(String, String)>) -> anyhow::Result<()> {
+    let mut lines: Vec<String> = match File::open(filename) {
+        Ok(f) => BufReader::new(f).lines().collect::<Result<_, _>>()?,
+        Err(_) => Vec::new(),
+    };
+    for (key, val) in attrs {
+        let line = format!("{key}={val}");
+        match lines.iter_mut().find(|l| {
+            l.split_once('=')
+                .map(|(k, _)| k.trim() == key)
+                .unwrap_or(false)
+        }) {
+            Some(l) => *l = line,
+            None => lines.push(line),
+        }
+    }
+    journal::output(filename);
+    let mut file = BufWriter::new(File::create(filename)?);
+    for line in lines {
+        writeln!(file, "{line}")?;
+    }
+    file.flush()?;
+    Ok(())
+}
+
+pub fn missing_data(
+    ts: &Discharges,
+    args: &CliArgs,
+    writer: &mut impl Write,
+) -> anyhow::Result<()> {
+    let df = ts
         .lazy()
         .select([
             col(ts.datetime_col).alias("start_date"),
@@ -375,9 +2261,8 @@ pub fn missing_data(ts: &Discharges, args: &CliArgs) {
         ])
         .drop_columns(["isna_blk"])
         .sort("start_date", SortOptions::default())
-        .collect()
-        .unwrap();
-    dataframe_output(df, args);
+        .collect()?;
+    dataframe_output(df, args, writer)
 }
 
 // pub fn run() {
@@ -387,3 +2272,80 @@ pub fn missing_data(ts: &Discharges, args: &CliArgs) {
 //     annual_mean(&ts);
 //     monthly_mean(&ts);
 // }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+
+    #[derive(Parser)]
+    struct Cli {
+        #[command(flatten)]
+        global: GlobalArgs,
+        #[command(subcommand)]
+        command: Command,
+    }
+
+    #[derive(clap::Subcommand)]
+    enum Command {
+        Timeseries(CliArgs),
+    }
+
+    fn parse(args: &[&str]) -> (GlobalArgs, CliArgs) {
+        let cli = Cli::parse_from(std::iter::once(&"nadi").chain(args));
+        let Command::Timeseries(ts) = cli.command;
+        (cli.global, ts)
+    }
+
+    fn run(contents: &str, args: &[&str]) -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let input = dir.path().join("flow.csv");
+        std::fs::write(&input, contents)?;
+        let output = dir.path().join("out.csv");
+        let mut cli_args = vec!["-o", output.to_str().unwrap(), "timeseries"];
+        cli_args.push(input.to_str().unwrap());
+        cli_args.extend(args);
+        let (global, ts) = parse(&cli_args);
+        ts.run(&global)
+    }
+
+    #[test]
+    fn empty_file_is_a_parse_error() {
+        let dir = tempfile::tempdir().unwrap();
+        let input = dir.path().join("flow.csv");
+        std::fs::write(&input, "").unwrap();
+        let ts = Discharges::new(&input, "date", "flow", None);
+        assert!(matches!(ts, Err(NadiError::ParseError { line: 1, .. })));
+    }
+
+    #[test]
+    fn header_without_rows_is_an_error() {
+        let err = run("date,flow\n", &[]).unwrap_err();
+        assert!(err.to_string().contains("doesn't have any rows"));
+    }
+
+    #[test]
+    fn date_range_without_rows_reports_the_data_span() {
+        let err = run(
+            "date,flow\n2020-01-01,1.0\n2020-01-02,2.0\n",
+            &["--date-range", "2000..2001"],
+        )
+        .unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("the data is from 2020-01-01 to 2020-01-02"));
+    }
+
+    #[test]
+    fn empty_output_only_has_the_header() {
+        let df = DataFrame::new(vec![
+            Series::new_empty("date", &DataType::Date),
+            Series::new_empty("flow", &DataType::Float64),
+        ])
+        .unwrap();
+        let (_, args) = parse(&["timeseries", "flow.csv"]);
+        let mut out = Vec::new();
+        dataframe_output(df, &args, &mut out).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), "date,flow\n");
+    }
+}
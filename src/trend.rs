@@ -0,0 +1,216 @@
+use std::io::Write;
+use std::path::PathBuf;
+
+use clap::Args;
+use statrs::distribution::{ContinuousCDF, Normal};
+
+use crate::cliargs::{CliAction, GlobalArgs, OutputFormat};
+use crate::journal;
+use crate::network::csv_field;
+use crate::timeseries::{Discharges, NodeSeries, Statistic};
+
+#[derive(Args)]
+pub struct CliArgs {
+    /// Seasonal Mann-Kendall test on the monthly values
+    ///
+    /// Each month is tested separately and the results are combined,
+    /// so the seasonal cycle and its serial structure don't affect
+    /// the trend
+    #[arg(short, long)]
+    seasonal: bool,
+    /// Statistic of each year, or each month with --seasonal
+    #[arg(
+        short = 'S',
+        long,
+        rename_all = "lower",
+        value_enum,
+        default_value = "mean"
+    )]
+    stat: Statistic,
+    #[command(flatten)]
+    series: NodeSeries,
+    /// Test the timeseries of every node of the connection file
+    ///
+    /// The INPUT is the connection file, and the results are saved
+    /// as the {discharge_col}_trend (Sen's slope per year) and
+    /// {discharge_col}_trend_p attributes of the nodes
+    #[arg(short, long)]
+    network: bool,
+    /// Divide the slopes by the drainage area of the nodes (yield)
+    ///
+    /// The attributes are saved as {discharge_col}_yield_trend and
+    /// {discharge_col}_yield_trend_p instead
+    #[arg(long, requires = "network")]
+    per_area: bool,
+    /// Attribute with the drainage area of the nodes for --per-area
+    #[arg(long, default_value = "area", requires = "per_area")]
+    area_attr: String,
+    /// Timeseries csv file, or the connection file with --network
+    input: PathBuf,
+}
+
+/// Result of the Mann-Kendall test
+struct Trend {
+    n: usize,
+    s: i64,
+    tau: f64,
+    z: f64,
+    p_value: f64,
+    /// Sen's slope per year
+    slope: f64,
+}
+
+impl Trend {
+    const HEADER: &'static str = "n,s,tau,z,p_value,slope";
+
+    fn row(&self) -> String {
+        format!(
+            "{},{},{},{},{},{}",
+            self.n, self.s, self.tau, self.z, self.p_value, self.slope
+        )
+    }
+}
+
+/// Mann-Kendall test of the groups of (year, value), each group is
+/// tested separately and the S statistics and their variances are
+/// added together for the combined test
+fn mann_kendall(groups: &[Vec<(f64, f64)>]) -> Option<Trend> {
+    let (mut n, mut s, mut var, mut pairs) = (0, 0i64, 0.0, 0usize);
+    let mut slopes = Vec::new();
+    for group in groups {
+        for (i, (ti, xi)) in group.iter().enumerate() {
+            for (tj, xj) in &group[i + 1..] {
+                s += (xj > xi) as i64 - (xj < xi) as i64;
+                slopes.push((xj - xi) / (tj - ti));
+            }
+        }
+        let m = group.len() as f64;
+        var += m * (m - 1.0) * (2.0 * m + 5.0);
+        // correction for the tied values
+        let mut values: Vec<f64> = group.iter().map(|g| g.1).collect();
+        values.sort_by(|a, b| a.total_cmp(b));
+        let mut start = 0;
+        for i in 1..=values.len() {
+            if i == values.len() || values[i] != values[start] {
+                let t = (i - start) as f64;
+                var -= t * (t - 1.0) * (2.0 * t + 5.0);
+                start = i;
+            }
+        }
+        n += group.len();
+        pairs += group.len() * group.len().saturating_sub(1) / 2;
+    }
+    var /= 18.0;
+    if pairs == 0 || var <= 0.0 {
+        return None;
+    }
+    let z = match s {
+        0 => 0.0,
+        s if s > 0 => (s - 1) as f64 / var.sqrt(),
+        s => (s + 1) as f64 / var.sqrt(),
+    };
+    let normal = Normal::new(0.0, 1.0).unwrap();
+    slopes.sort_by(|a, b| a.total_cmp(b));
+    let mid = slopes.len() / 2;
+    let slope = if slopes.len() % 2 == 0 {
+        (slopes[mid - 1] + slopes[mid]) / 2.0
+    } else {
+        slopes[mid]
+    };
+    Some(Trend {
+        n,
+        s,
+        tau: s as f64 / pairs as f64,
+        z,
+        p_value: 2.0 * (1.0 - normal.cdf(z.abs())),
+        slope,
+    })
+}
+
+impl CliArgs {
+    fn trend(&self, ts: &Discharges) -> anyhow::Result<Option<Trend>> {
+        let values = ts.period_values(self.stat, self.seasonal, false, 0)?;
+        let groups: Vec<Vec<(f64, f64)>> = if self.seasonal {
+            (1..=12)
+                .map(|m| {
+                    values
+                        .iter()
+                        .filter(|v| v.1 == m)
+                        .map(|(y, _, v)| (*y as f64, *v))
+                        .collect()
+                })
+                .collect()
+        } else {
+            vec![values.iter().map(|(y, _, v)| (*y as f64, *v)).collect()]
+        };
+        Ok(mann_kendall(&groups))
+    }
+}
+
+impl CliAction for CliArgs {
+    fn run(self, global: &GlobalArgs) -> anyhow::Result<()> {
+        global.format(&[OutputFormat::Csv])?;
+        let mut writer = global.writer()?;
+        if !self.network {
+            let trend = self
+                .trend(&self.series.load(&self.input)?)?
+                .ok_or_else(|| anyhow::Error::msg("Not enough values for the trend"))?;
+            writeln!(writer, "{}", Trend::HEADER)?;
+            writeln!(writer, "{}", trend.row())?;
+            writer.finish()?;
+            return Ok(());
+        }
+
+        let net = self.series.network(&self.input)?;
+        let name = if self.per_area {
+            format!("{}_yield_trend", self.series.discharge_col)
+        } else {
+            format!("{}_trend", self.series.discharge_col)
+        };
+        writeln!(writer, "node,{}", Trend::HEADER)?;
+        let mut count = 0;
+        for (node, _, ts) in self.series.nodes(&net) {
+            let mut trend = match self.trend(&ts) {
+                Ok(Some(t)) => t,
+                Ok(None) => {
+                    journal::warn(format!(
+                        "Not enough values for the trend of {}",
+                        node.get_name()
+                    ));
+                    continue;
+                }
+                Err(e) => {
+                    journal::warn(format!("Skipping {}: {e}", node.get_name()));
+                    continue;
+                }
+            };
+            if self.per_area {
+                let area = node
+                    .get_attr(&self.area_attr)
+                    .and_then(|a| a.read_value())
+                    .filter(|a| *a > 0.0);
+                let Some(area) = area else {
+                    journal::warn(format!(
+                        "{} doesn't have the {}",
+                        node.get_name(),
+                        self.area_attr
+                    ));
+                    continue;
+                };
+                trend.slope /= area as f64;
+            }
+            writeln!(writer, "{},{}", csv_field(node.get_name()), trend.row())?;
+            net.save_attrs(
+                node.get_name(),
+                vec![
+                    (name.clone(), trend.slope.to_string()),
+                    (format!("{name}_p"), trend.p_value.to_string()),
+                ],
+            )?;
+            count += 1;
+        }
+        journal::count("trends", count);
+        writer.finish()?;
+        Ok(())
+    }
+}
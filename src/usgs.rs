@@ -1,38 +1,274 @@
+use std::collections::HashMap;
 use std::io::Write;
-use std::{fs::File, path::PathBuf};
+use std::{
+    fs::File,
+    path::{Path, PathBuf},
+};
 
+use anyhow::Context;
 use clap::{Args, ValueEnum, ValueHint};
+use string_template_plus::{Render, RenderOptions, Template};
 
-use crate::cliargs::CliAction;
+use crate::cliargs::{CliAction, GlobalArgs, OutputFormat};
+use crate::journal;
 
 #[derive(Args)]
 pub struct CliArgs {
     /// USGS Site no
-    #[arg(short, long, value_delimiter = ',', required = true)]
+    #[arg(
+        short,
+        long,
+        value_delimiter = ',',
+        required_unless_present = "migrate"
+    )]
     site_no: Vec<String>,
-    /// Type of data (u/d/t/b)
+    /// Type of data (u/d/t/b/p)
     ///
-    /// [upstream (u), downstream (d), tributories (t), basin (b)]
+    /// [upstream (u), downstream (d), tributories (t), basin (b),
+    /// annual peak flow (p)]
+    ///
+    /// [default: t, unless --parameter is given]
     #[arg(
         short,
         long,
         rename_all = "lower",
-        default_value = "t",
         value_enum,
         hide_possible_values = true
     )]
     data: Vec<GeoInfo>,
-    #[arg(short, long, value_hint=ValueHint::DirPath, default_value=".")]
+    /// NWIS parameter codes to download the daily values of
+    ///
+    /// e.g. 00060 (discharge), 00065 (stage), 72019 (groundwater
+    /// level), 00010 (temperature); the values are saved in the column
+    /// named after the parameter code
+    #[arg(short, long, value_delimiter = ',')]
+    parameter: Vec<String>,
+    #[arg(short = 'O', long, value_hint=ValueHint::DirPath, default_value=".")]
     output_dir: PathBuf,
+    /// Template for the file names in the output directory
+    ///
+    /// Has {site}, {datatype} (UM, DM, UT, basin, peaks or the
+    /// parameter code) and {ext}; e.g. '{site}/{datatype}.{ext}' to
+    /// save the files of each site in its own directory
+    #[arg(short, long, default_value = "{site}_{datatype}.{ext}", value_parser=Template::parse_template)]
+    name_template: Template,
+    /// Move the files downloaded with the default names in the output
+    /// directory to the names from --name-template
+    #[arg(long, conflicts_with_all = ["site_no", "data", "parameter"])]
+    migrate: bool,
+    /// Don't access the network, only the --fixtures responses are used
+    #[arg(long)]
+    offline: bool,
+    /// Directory with the saved responses of the USGS servers
+    ///
+    /// The responses found here are used instead of downloading them,
+    /// and the new downloads are saved here; with --offline it can
+    /// serve canned responses for the tests
+    #[arg(long, value_hint=ValueHint::DirPath)]
+    fixtures: Option<PathBuf>,
+    /// Only download the values after the last date in the existing
+    /// parameter files and append them
+    #[arg(short, long)]
+    update: bool,
+    #[command(flatten)]
+    http: HttpArgs,
+}
+
+/// Proxy and certificates of the connections to the servers
+#[derive(Args)]
+pub struct HttpArgs {
+    /// Proxy for the downloads, e.g. http://proxy.example.com:8080
+    ///
+    /// The HTTP_PROXY, HTTPS_PROXY and NO_PROXY environment variables
+    /// are used when it is not given
+    #[arg(long)]
+    proxy: Option<String>,
+    /// PEM file with the additional CA certificates to trust
+    ///
+    /// For the proxies and servers with internal CAs, defaults to the
+    /// SSL_CERT_FILE environment variable
+    #[arg(long, value_hint=ValueHint::FilePath)]
+    ca_bundle: Option<PathBuf>,
+}
+
+impl HttpArgs {
+    pub fn client(&self) -> anyhow::Result<reqwest::blocking::Client> {
+        let mut builder = reqwest::blocking::Client::builder();
+        if let Some(proxy) = &self.proxy {
+            builder = builder.proxy(
+                reqwest::Proxy::all(proxy).with_context(|| format!("Invalid proxy {proxy}"))?,
+            );
+        }
+        let ca_bundle = self
+            .ca_bundle
+            .clone()
+            .or_else(|| std::env::var_os("SSL_CERT_FILE").map(PathBuf::from));
+        if let Some(path) = ca_bundle {
+            let pem = std::fs::read_to_string(&path)
+                .with_context(|| format!("Couldn't read {:?}", path))?;
+            let end = "-----END CERTIFICATE-----";
+            for cert in pem.split_inclusive(end).filter(|c| c.contains(end)) {
+                let cert = reqwest::Certificate::from_pem(cert.trim().as_bytes())
+                    .with_context(|| format!("Invalid certificate in {:?}", path))?;
+                builder = builder.add_root_certificate(cert);
+            }
+        }
+        Ok(builder.build()?)
+    }
+}
+
+/// Where the responses for the URLs come from
+struct Fetcher<'a> {
+    client: reqwest::blocking::Client,
+    offline: bool,
+    fixtures: Option<&'a Path>,
+}
+
+impl Fetcher<'_> {
+    /// File name of the saved response for the URL
+    fn fixture_name(url: &str) -> String {
+        url.split_once("://")
+            .map(|(_, u)| u)
+            .unwrap_or(url)
+            .chars()
+            .map(|c| {
+                if c.is_ascii_alphanumeric() || c == '-' || c == '.' {
+                    c
+                } else {
+                    '_'
+                }
+            })
+            .collect()
+    }
+
+    fn get(&self, url: &str) -> anyhow::Result<Vec<u8>> {
+        let fixture = self.fixtures.map(|d| d.join(Self::fixture_name(url)));
+        if let Some(path) = fixture.as_ref().filter(|p| p.exists()) {
+            journal::input(path);
+            return Ok(std::fs::read(path)?);
+        }
+        if self.offline {
+            anyhow::bail!("Network access is disabled by --offline: {url}");
+        }
+        let bytes = self
+            .client
+            .get(url)
+            .send()?
+            .error_for_status()?
+            .bytes()?
+            .to_vec();
+        if let Some(path) = fixture {
+            std::fs::create_dir_all(path.parent().unwrap_or(Path::new(".")))?;
+            std::fs::write(&path, &bytes)?;
+        }
+        Ok(bytes)
+    }
+}
+
+/// Path of the downloaded file from the name template, the parent
+/// directories are created
+fn data_path(
+    dir: &Path,
+    templ: &Template,
+    site_no: &str,
+    datatype: &str,
+    ext: &str,
+) -> anyhow::Result<PathBuf> {
+    let op = RenderOptions {
+        wd: dir.to_path_buf(),
+        variables: HashMap::from([
+            ("site".to_string(), site_no.to_string()),
+            ("datatype".to_string(), datatype.to_string()),
+            ("ext".to_string(), ext.to_string()),
+        ]),
+        shell_commands: false,
+    };
+    let path = dir.join(templ.render(&op)?);
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    Ok(path)
+}
+
+/// Move the `{site}_{datatype}.{ext}` files in the directory to the
+/// names from the template
+fn migrate_files(dir: &Path, templ: &Template, writer: &mut impl Write) -> anyhow::Result<()> {
+    let mut moved = 0;
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if !path.is_file() {
+            continue;
+        }
+        let (Some(stem), Some(ext)) = (
+            path.file_stem().and_then(|s| s.to_str()),
+            path.extension().and_then(|s| s.to_str()),
+        ) else {
+            continue;
+        };
+        let Some((site, datatype)) = stem.split_once('_').filter(|(s, d)| {
+            !s.is_empty() && s.chars().all(|c| c.is_ascii_digit()) && !d.is_empty()
+        }) else {
+            continue;
+        };
+        let target = data_path(dir, templ, site, datatype, ext)?;
+        if target == path {
+            continue;
+        }
+        if target.exists() {
+            journal::warn(format!(
+                "Not moving {:?}: {:?} already exists",
+                path, target
+            ));
+            continue;
+        }
+        std::fs::rename(&path, &target)
+            .with_context(|| format!("Couldn't move {:?} to {:?}", path, target))?;
+        journal::output(&target);
+        writeln!(writer, "{}", target.to_string_lossy())?;
+        moved += 1;
+    }
+    journal::count("files moved", moved);
+    Ok(())
 }
 
 impl CliAction for CliArgs {
-    fn run(self) -> anyhow::Result<()> {
+    fn run(self, global: &GlobalArgs) -> anyhow::Result<()> {
+        global.format(&[OutputFormat::Csv])?;
+        // the paths of the files written are printed like in split
+        let mut writer = global.writer()?;
+        if self.migrate {
+            migrate_files(&self.output_dir, &self.name_template, &mut writer)?;
+            writer.finish()?;
+            return Ok(());
+        }
+        let fetcher = Fetcher {
+            client: self.http.client()?,
+            offline: self.offline,
+            fixtures: self.fixtures.as_deref(),
+        };
+        let data = if self.data.is_empty() && self.parameter.is_empty() {
+            vec![GeoInfo::Tributories]
+        } else {
+            self.data
+        };
         for site in self.site_no {
-            for data in &self.data {
-                data.download(&site, &self.output_dir);
+            for d in &data {
+                let path = d.download(&fetcher, &site, &self.output_dir, &self.name_template)?;
+                writeln!(writer, "{}", path.to_string_lossy())?;
+            }
+            for param in &self.parameter {
+                let path = download_daily_values(
+                    &fetcher,
+                    &site,
+                    param,
+                    &self.output_dir,
+                    &self.name_template,
+                    self.update,
+                )?;
+                writeln!(writer, "{}", path.to_string_lossy())?;
             }
         }
+        writer.finish()?;
         Ok(())
     }
 }
@@ -47,6 +283,8 @@ pub enum GeoInfo {
     Tributories,
     #[value(alias = "b")]
     Basin,
+    #[value(alias = "p")]
+    PeakFlow,
 }
 
 impl GeoInfo {
@@ -56,23 +294,189 @@ impl GeoInfo {
             Self::Downstream => "navigate/DM",
             Self::Tributories => "navigate/UT",
             Self::Basin => "basin",
+            Self::PeakFlow => "peaks",
         }
     }
 
     pub fn usgs_url(&self, site_no: &str) -> String {
+        if let Self::PeakFlow = self {
+            return format!("https://nwis.waterdata.usgs.gov/nwis/peak?site_no={site_no}&agency_cd=USGS&format=rdb");
+        }
         let dt = self.usgs_abbr();
         format!("https://labs.waterdata.usgs.gov/api/nldi/linked-data/nwissite/USGS-{site_no}/{dt}?f=json")
     }
 
-    pub fn download(&self, site_no: &str, dir: &PathBuf) {
+    fn download(
+        &self,
+        fetcher: &Fetcher,
+        site_no: &str,
+        dir: &Path,
+        templ: &Template,
+    ) -> anyhow::Result<PathBuf> {
         let url = self.usgs_url(site_no);
-        let bytes = reqwest::blocking::get(url).unwrap().bytes().unwrap();
-        let filepath = dir.join(format!(
-            "{}_{}.json",
-            site_no,
-            self.usgs_abbr().split('/').last().unwrap()
-        ));
-        let mut file = File::create(filepath).unwrap();
-        file.write_all(&bytes).unwrap();
+        let bytes = fetcher.get(&url)?;
+        if let Self::PeakFlow = self {
+            let filepath = data_path(dir, templ, site_no, "peaks", "csv")?;
+            journal::output(&filepath);
+            let mut file = File::create(&filepath)?;
+            peaks_from_rdb(&String::from_utf8_lossy(&bytes), &mut file)?;
+            return Ok(filepath);
+        }
+        let datatype = self.usgs_abbr().rsplit('/').next().unwrap();
+        let filepath = data_path(dir, templ, site_no, datatype, "json")?;
+        journal::output(&filepath);
+        let mut file = File::create(&filepath)?;
+        file.write_all(&bytes)?;
+        Ok(filepath)
+    }
+}
+
+/// Download the daily mean values of the parameter for the whole
+/// period of record as a csv with date, parameter code and the
+/// qualification codes columns
+fn download_daily_values(
+    fetcher: &Fetcher,
+    site_no: &str,
+    param: &str,
+    dir: &Path,
+    templ: &Template,
+    update: bool,
+) -> anyhow::Result<PathBuf> {
+    let filepath = data_path(dir, templ, site_no, param, "csv")?;
+    let last_date = if update && filepath.exists() {
+        last_stored_date(&filepath)?
+    } else {
+        None
+    };
+    let start = last_date.as_deref().unwrap_or("1800-01-01");
+    let url = format!("https://waterservices.usgs.gov/nwis/dv/?format=rdb&sites={site_no}&parameterCd={param}&statCd=00003&startDT={start}");
+    let text = String::from_utf8_lossy(&fetcher.get(&url)?).to_string();
+    journal::output(&filepath);
+    let rows = match &last_date {
+        Some(date) => {
+            let mut file = File::options().append(true).open(&filepath)?;
+            daily_values_from_rdb(&text, param, &mut file, Some(date))?
+        }
+        None => {
+            let mut file = File::create(&filepath)?;
+            daily_values_from_rdb(&text, param, &mut file, None)?
+        }
+    };
+    // the rows appended by --update are counted separately
+    let key = match last_date {
+        Some(_) => format!("{site_no}_{param} new rows"),
+        None => format!("{site_no}_{param} rows"),
+    };
+    journal::count(&key, rows);
+    Ok(filepath)
+}
+
+/// Date in the last row of the parameter file
+fn last_stored_date(filepath: &Path) -> anyhow::Result<Option<String>> {
+    let text = std::fs::read_to_string(filepath)?;
+    Ok(text
+        .lines()
+        .skip(1)
+        .filter_map(|l| l.split(',').next())
+        .filter(|d| !d.trim().is_empty())
+        .last()
+        .map(|d| d.trim().to_string()))
+}
+
+/// Write the values as csv rows, only the ones after the date
+/// without the header if it is given; returns the number of rows
+fn daily_values_from_rdb(
+    rdb: &str,
+    param: &str,
+    writer: &mut impl Write,
+    after: Option<&str>,
+) -> anyhow::Result<usize> {
+    let mut lines = rdb.lines().filter(|l| !l.starts_with('#'));
+    let header: Vec<&str> = match (lines.next(), after) {
+        (Some(h), _) => h.split('\t').collect(),
+        // no values since the last update
+        (None, Some(_)) => return Ok(0),
+        (None, None) => anyhow::bail!("Empty response"),
+    };
+    lines.next();
+    // value columns are named like "{ts_id}_{param}_{stat}"
+    let value_col = header
+        .iter()
+        .position(|h| h.contains(&format!("_{param}_")) && !h.ends_with("_cd"))
+        .with_context(|| format!("No values for parameter {param}"))?;
+    let date_col = header
+        .iter()
+        .position(|h| *h == "datetime")
+        .context("No datetime column")?;
+    let code_col = header
+        .iter()
+        .position(|h| *h == format!("{}_cd", header[value_col]));
+    if after.is_none() {
+        writeln!(writer, "date,{0},{0}_cd", param)?;
+    }
+    let mut count = 0;
+    for line in lines {
+        let row: Vec<&str> = line.split('\t').collect();
+        let get = |i: usize| row.get(i).copied().unwrap_or("");
+        if after.map(|d| get(date_col) <= d).unwrap_or(false) {
+            continue;
+        }
+        count += 1;
+        writeln!(
+            writer,
+            "{},{},\"{}\"",
+            get(date_col),
+            get(value_col),
+            code_col.map(get).unwrap_or("")
+        )?;
+    }
+    Ok(count)
+}
+
+/// Convert the NWIS peak flow RDB file into a csv table with the
+/// water year of each annual peak
+///
+/// Peak dates with unknown month or day have them as "00", as in the
+/// original file.
+fn peaks_from_rdb(rdb: &str, writer: &mut impl Write) -> std::io::Result<()> {
+    let mut lines = rdb.lines().filter(|l| !l.starts_with('#'));
+    let header: Vec<&str> = match lines.next() {
+        Some(h) => h.split('\t').collect(),
+        None => return Ok(()),
+    };
+    // the line after the header has the field sizes and types
+    lines.next();
+    let index = |name: &str| header.iter().position(|h| *h == name);
+    let fields = [
+        index("peak_dt"),
+        index("peak_va"),
+        index("peak_cd"),
+        index("gage_ht"),
+        index("gage_ht_cd"),
+    ];
+    writeln!(
+        writer,
+        "water_year,date,peak,peak_cd,gage_height,gage_height_cd"
+    )?;
+    for line in lines {
+        let row: Vec<&str> = line.split('\t').collect();
+        let values: Vec<&str> = fields
+            .iter()
+            .map(|f| f.and_then(|i| row.get(i)).copied().unwrap_or(""))
+            .collect();
+        let mut date = values[0].split('-');
+        let year: Option<i32> = date.next().and_then(|y| y.parse().ok());
+        let month: u32 = date.next().and_then(|m| m.parse().ok()).unwrap_or(0);
+        let water_year = match year {
+            Some(y) if month >= 10 => (y + 1).to_string(),
+            Some(y) => y.to_string(),
+            None => String::new(),
+        };
+        writeln!(
+            writer,
+            "{},{},{},\"{}\",{},\"{}\"",
+            water_year, values[0], values[1], values[2], values[3], values[4]
+        )?;
     }
+    Ok(())
 }
@@ -0,0 +1,180 @@
+use std::io::Write;
+use std::path::PathBuf;
+
+use clap::Args;
+
+use crate::cliargs::{CliAction, GlobalArgs, OutputFormat};
+use crate::journal;
+use crate::network::csv_field;
+use crate::timeseries::{Discharges, NodeSeries, Statistic};
+
+#[derive(Args)]
+pub struct CliArgs {
+    /// Percentiles of the annual values between the year types
+    #[arg(short, long, value_delimiter = ',', default_value = "30,70")]
+    breaks: Vec<f64>,
+    /// Names of the year types from the driest, one more than --breaks
+    #[arg(short, long, value_delimiter = ',', default_value = "dry,normal,wet")]
+    labels: Vec<String>,
+    /// Statistic of each year
+    #[arg(
+        short = 'S',
+        long,
+        rename_all = "lower",
+        value_enum,
+        default_value = "mean"
+    )]
+    stat: Statistic,
+    /// Water years from October to September, named by the ending year
+    #[arg(short, long)]
+    water_year: bool,
+    /// Minimum number of days with values for a year to be classified
+    ///
+    /// The years with more missing days, like the partial years at the
+    /// start and end of the record, are left out
+    #[arg(long, default_value = "330")]
+    min_days: usize,
+    #[command(flatten)]
+    series: NodeSeries,
+    /// Classify the years of every node of the connection file
+    ///
+    /// The INPUT is the connection file, and the years of each type
+    /// are saved as the {discharge_col}_{label}_years attributes of
+    /// the nodes (comma separated)
+    #[arg(short, long)]
+    network: bool,
+    /// Timeseries csv file, or the connection file with --network
+    input: PathBuf,
+}
+
+/// Type of a year from the percentile of its value in the record
+struct YearType<'a> {
+    year: i32,
+    value: f64,
+    percentile: f64,
+    label: &'a str,
+}
+
+impl YearType<'_> {
+    const HEADER: &'static str = "year,value,percentile,type";
+
+    fn row(&self) -> String {
+        format!(
+            "{},{},{:.1},{}",
+            self.year, self.value, self.percentile, self.label
+        )
+    }
+}
+
+impl CliArgs {
+    fn check(&self) -> anyhow::Result<()> {
+        if self.labels.len() != self.breaks.len() + 1 {
+            anyhow::bail!(
+                "{} --labels are needed for {} --breaks",
+                self.breaks.len() + 1,
+                self.breaks.len()
+            );
+        }
+        if self.breaks.iter().any(|b| !(0.0..=100.0).contains(b))
+            || self.breaks.windows(2).any(|w| w[0] >= w[1])
+        {
+            anyhow::bail!("--breaks should be increasing percentiles between 0 and 100");
+        }
+        Ok(())
+    }
+
+    /// Statistic of each year with at least --min-days days of values
+    fn year_values(&self, ts: &Discharges) -> anyhow::Result<Vec<(i32, f64)>> {
+        let values = ts.period_values(self.stat, false, self.water_year, self.min_days)?;
+        Ok(values.into_iter().map(|(y, _, v)| (y, v)).collect())
+    }
+
+    /// Year types from the Weibull plotting position (rank / (n + 1))
+    /// of the annual values, the tied values get their average rank
+    fn classify(&self, values: &[(i32, f64)]) -> Vec<YearType<'_>> {
+        let mut sorted: Vec<f64> = values.iter().map(|v| v.1).collect();
+        sorted.sort_by(|a, b| a.total_cmp(b));
+        let n = sorted.len() as f64;
+        values
+            .iter()
+            .map(|&(year, value)| {
+                let below = sorted.partition_point(|v| *v < value);
+                let equal = sorted[below..].partition_point(|v| *v <= value);
+                let rank = below as f64 + (equal as f64 + 1.0) / 2.0;
+                let percentile = rank / (n + 1.0) * 100.0;
+                let class = self.breaks.iter().filter(|b| percentile > **b).count();
+                YearType {
+                    year,
+                    value,
+                    percentile,
+                    label: &self.labels[class],
+                }
+            })
+            .collect()
+    }
+}
+
+impl CliAction for CliArgs {
+    fn run(self, global: &GlobalArgs) -> anyhow::Result<()> {
+        self.check()?;
+        global.format(&[OutputFormat::Csv])?;
+        let mut writer = global.writer()?;
+        if !self.network {
+            let values = self.year_values(&self.series.load(&self.input)?)?;
+            if values.is_empty() {
+                anyhow::bail!("No years with at least {} days of values", self.min_days);
+            }
+            writeln!(writer, "{}", YearType::HEADER)?;
+            for yt in self.classify(&values) {
+                writeln!(writer, "{}", yt.row())?;
+            }
+            writer.finish()?;
+            return Ok(());
+        }
+
+        let net = self.series.network(&self.input)?;
+        writeln!(writer, "node,{}", YearType::HEADER)?;
+        let mut count = 0;
+        for (node, _, ts) in self.series.nodes(&net) {
+            let values = match self.year_values(&ts) {
+                Ok(v) => v,
+                Err(e) => {
+                    journal::warn(format!("Skipping {}: {e}", node.get_name()));
+                    continue;
+                }
+            };
+            if values.is_empty() {
+                journal::warn(format!(
+                    "No years with at least {} days of values for {}",
+                    self.min_days,
+                    node.get_name()
+                ));
+                continue;
+            }
+            let types = self.classify(&values);
+            for yt in &types {
+                writeln!(writer, "{},{}", csv_field(node.get_name()), yt.row())?;
+            }
+            let attrs = self
+                .labels
+                .iter()
+                .map(|label| {
+                    let years: Vec<String> = types
+                        .iter()
+                        .filter(|yt| yt.label == label)
+                        .map(|yt| yt.year.to_string())
+                        .collect();
+                    (
+                        format!("{}_{}_years", self.series.discharge_col, label),
+                        years.join(","),
+                    )
+                })
+                .collect();
+            net.save_attrs(node.get_name(), attrs)?;
+            count += 1;
+        }
+        journal::count("nodes", count);
+        writer.finish()?;
+        Ok(())
+    }
+}
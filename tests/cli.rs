@@ -0,0 +1,220 @@
+//! Runs of the nadi binary on the small datasets in tests/fixtures
+//!
+//! The usgs tests use the canned responses in tests/fixtures/usgs
+//! with --offline, so they don't need the network.
+use std::path::PathBuf;
+
+use assert_cmd::Command;
+use predicates::prelude::*;
+
+fn fixtures() -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures")
+}
+
+fn nadi() -> Command {
+    let mut cmd = Command::cargo_bin("nadi").unwrap();
+    cmd.current_dir(fixtures());
+    cmd
+}
+
+#[test]
+fn network_prints_the_node_attributes() {
+    nadi()
+        .args(["network", "network.txt", "-l", "{name} {area}"])
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("a 10"))
+        .stdout(predicate::str::contains("d 100"));
+}
+
+#[test]
+fn network_estimates_the_missing_attributes() {
+    nadi()
+        .args([
+            "network",
+            "network.txt",
+            "-E",
+            "flow",
+            "-l",
+            "{name} {flow}",
+        ])
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("c 16"));
+}
+
+#[test]
+fn network_without_the_file_fails() {
+    nadi()
+        .args(["network", "missing.txt"])
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("No such file"));
+}
+
+#[test]
+fn timeseries_reports_the_missing_values() {
+    nadi()
+        .args(["timeseries", "timeseries/a.csv", "-c", "na"])
+        .assert()
+        .success()
+        .stdout(predicate::str::starts_with("start_date,count,isna"))
+        .stdout(predicate::str::contains("2020-01-04,1,true"));
+}
+
+#[test]
+fn timeseries_fills_forward() {
+    nadi()
+        .args(["timeseries", "timeseries/a.csv", "-c", "nff"])
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("2020-01-04,6.0"));
+}
+
+#[test]
+fn timeseries_rejects_header_with_json() {
+    nadi()
+        .args([
+            "--format",
+            "json",
+            "timeseries",
+            "timeseries/a.csv",
+            "--header",
+        ])
+        .assert()
+        .failure();
+}
+
+#[test]
+fn connection_from_points_and_streams() {
+    nadi()
+        .args([
+            "connection",
+            "-p",
+            "name",
+            "points.geojson",
+            "streams.geojson",
+        ])
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("a -> c"))
+        .stdout(predicate::str::contains("c -> d"));
+}
+
+#[test]
+fn connection_with_the_layer_index() {
+    nadi()
+        .args([
+            "connection",
+            "-p",
+            "name",
+            "points.geojson:0",
+            "streams.geojson:0",
+        ])
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("a -> c"));
+}
+
+#[test]
+fn connection_with_a_missing_layer_fails() {
+    nadi()
+        .args(["connection", "points.geojson:3", "streams.geojson"])
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("Layer 3 doesn't exist"));
+}
+
+#[test]
+fn connection_from_points_csv() {
+    nadi()
+        .args([
+            "connection",
+            "-p",
+            "name",
+            "--points-csv",
+            "--points-srs",
+            "EPSG:4326",
+            "points.csv",
+            "streams.geojson",
+        ])
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("a -> c"))
+        .stdout(predicate::str::contains("c -> d"));
+}
+
+#[test]
+fn connection_points_csv_needs_the_srs() {
+    nadi()
+        .args([
+            "connection",
+            "--points-csv",
+            "points.csv",
+            "streams.geojson",
+        ])
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("--points-srs"));
+}
+
+#[test]
+fn connection_without_points_fails() {
+    nadi()
+        .args([
+            "connection",
+            "-p",
+            "name",
+            "--points-csv",
+            "--points-srs",
+            "EPSG:4326",
+            "points_empty.csv",
+            "streams.geojson",
+        ])
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("No points"));
+}
+
+#[test]
+fn connection_without_streams_fails() {
+    nadi()
+        .args([
+            "connection",
+            "-p",
+            "name",
+            "points.geojson",
+            "streams_empty.geojson",
+        ])
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("No streams"));
+}
+
+#[test]
+fn usgs_peaks_from_the_fixtures() {
+    let dir = tempfile::tempdir().unwrap();
+    nadi()
+        .args("usgs --offline --fixtures usgs -s 01646500 -d p -O".split(' '))
+        .arg(dir.path())
+        .assert()
+        .success();
+    let peaks = std::fs::read_to_string(dir.path().join("01646500_peaks.csv")).unwrap();
+    assert_eq!(
+        peaks,
+        "water_year,date,peak,peak_cd,gage_height,gage_height_cd\n\
+         2018,2018-06-04,45000,\"\",12.5,\"\"\n\
+         2019,2018-11-16,52000,\"2\",13.8,\"\"\n"
+    );
+}
+
+#[test]
+fn usgs_offline_without_fixture_fails() {
+    let dir = tempfile::tempdir().unwrap();
+    nadi()
+        .args("usgs --offline --fixtures usgs -s 00000000 -d p -O".split(' '))
+        .arg(dir.path())
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("disabled by --offline"));
+}